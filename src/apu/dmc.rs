@@ -0,0 +1,221 @@
+// Output unit periods in CPU cycles
+pub const NTSC_RATES: [u16; 16] = [428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54];
+pub const PAL_RATES: [u16; 16] = [398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50];
+
+// Delta modulation channel, $4010 - $4013. Plays 1 bit delta samples fetched from $C000 - $FFFF
+// by the DMA unit, or whatever is written to $4011
+#[derive(Debug, Clone)]
+pub struct Dmc {
+    rates: &'static [u16; 16],
+    irq_enabled: bool,
+    looping: bool,
+    timer_period: u16,
+    timer: u16,
+    // $4012 and $4013
+    sample_address: u16,
+    sample_length: u16,
+    // Memory reader
+    current_address: u16,
+    bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+    // Sample fetch handed to the DMA unit and not back yet
+    fetching: bool,
+    // Output unit
+    shift: u8,
+    bits_remaining: u8,
+    silence: bool,
+    level: u8,
+    pub irq: bool
+}
+
+impl Dmc {
+
+    pub fn new() -> Self {
+        Self {
+            rates: &NTSC_RATES,
+            irq_enabled: false,
+            looping: false,
+            timer_period: NTSC_RATES[0],
+            timer: NTSC_RATES[0],
+            sample_address: 0xc000,
+            sample_length: 1,
+            current_address: 0xc000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            fetching: false,
+            shift: 0,
+            bits_remaining: 8,
+            silence: true,
+            level: 0,
+            irq: false
+        }
+    }
+
+    // Rate table of the region
+    pub fn set_rates(&mut self, rates: &'static [u16; 16]) {
+        self.rates = rates;
+    }
+
+    pub fn write(&mut self, register: u16, data: u8) {
+        match register & 0x03 {
+            0 => {
+                self.irq_enabled = data & 0x80 != 0;
+                self.looping = data & 0x40 != 0;
+                self.timer_period = self.rates[(data & 0x0f) as usize];
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+            },
+            // Direct load, how games stream PCM
+            1 => self.level = data & 0x7f,
+            2 => self.sample_address = 0xc000 | (data as u16) << 6,
+            _ => self.sample_length = (data as u16) << 4 | 0x0001
+        }
+    }
+
+    // Enable bit of $4015, restarts the sample only if the previous one has finished
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.irq = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    // Sample bytes still to be fetched, reported in $4015
+    pub fn active(&self) -> bool {
+        self.bytes_remaining > 0
+    }
+
+    // Address the memory reader wants next, handed to the DMA unit once
+    pub fn sample_request(&mut self) -> Option<u16> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 && !self.fetching {
+            self.fetching = true;
+            Some(self.current_address)
+        } else {
+            None
+        }
+    }
+
+    // Byte fetched by the DMA unit
+    pub fn load_sample(&mut self, data: u8) {
+        self.fetching = false;
+        self.sample_buffer = Some(data);
+        // The channel was disabled while the DMA unit waited for a read cycle
+        if self.bytes_remaining == 0 {
+            return;
+        }
+        // Wraps from $FFFF to $8000
+        self.current_address = if self.current_address == 0xffff { 0x8000 } else { self.current_address + 1 };
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    pub fn sample_buffer(&self) -> Option<u8> {
+        self.sample_buffer
+    }
+
+    // Called once for every CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer > 1 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.timer_period;
+
+        if !self.silence {
+            // Level moves by 2 and stays inside 0 - 127
+            if self.shift & 0x01 != 0 {
+                if self.level <= 125 {
+                    self.level += 2;
+                }
+            } else if self.level >= 2 {
+                self.level -= 2;
+            }
+        }
+        self.shift >>= 1;
+
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(data) => {
+                    self.silence = false;
+                    self.shift = data;
+                },
+                None => self.silence = true
+            }
+        }
+    }
+
+    // 0 - 127
+    pub fn output(&self) -> u8 {
+        self.level
+    }
+
+}
+
+impl Default for Dmc {
+
+    fn default() -> Self {
+        Self::new()
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    pub fn plays_sample_and_raises_irq() {
+        let mut dmc = Dmc::new();
+        // IRQ, fastest rate, one byte at $C040
+        dmc.write(0, 0x80 | 0x0f);
+        dmc.write(1, 0x40);
+        dmc.write(2, 0x01);
+        dmc.write(3, 0x00);
+        dmc.set_enabled(true);
+        assert!(dmc.active());
+
+        assert_eq!(dmc.sample_request(), Some(0xc040));
+        assert_eq!(dmc.sample_request(), None);
+        dmc.load_sample(0xff);
+        assert!(!dmc.active());
+        assert!(dmc.irq);
+
+        // The byte is picked up at the end of the current silent output cycle, which still runs
+        // at the power on rate, then every bit raises the level
+        for _ in 0..428 + 15 * 54 {
+            dmc.clock_timer();
+        }
+        assert_eq!(dmc.output(), 0x40 + 16);
+        assert_eq!(dmc.sample_buffer(), None);
+    }
+
+    #[test]
+    pub fn looping_restarts_sample() {
+        let mut dmc = Dmc::new();
+        dmc.write(0, 0x40);
+        dmc.write(2, 0xff);
+        dmc.set_enabled(true);
+        assert_eq!(dmc.sample_request(), Some(0xffc0));
+        dmc.load_sample(0x00);
+        assert!(dmc.active());
+        assert!(!dmc.irq);
+    }
+
+}
@@ -0,0 +1,50 @@
+// Volume envelope of the pulse and noise channels, clocked by the quarter frame signal
+#[derive(Debug, Default, Clone)]
+pub struct Envelope {
+    start: bool,
+    divider: u8,
+    decay: u8,
+    // Decay restarts at 15 instead of staying at 0
+    looping: bool,
+    // Output the volume bits instead of the decay level
+    constant: bool,
+    // Constant volume or decay period
+    volume: u8
+}
+
+impl Envelope {
+
+    // Low 6 bits of $4000 / $4004 / $400C: loop, constant volume and volume
+    pub fn write(&mut self, data: u8) {
+        self.looping = data & 0x20 != 0;
+        self.constant = data & 0x10 != 0;
+        self.volume = data & 0x0f;
+    }
+
+    // Writes to the fourth channel register restart the envelope on the next clock
+    pub fn restart(&mut self) {
+        self.start = true;
+    }
+
+    pub fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.constant { self.volume } else { self.decay }
+    }
+
+}
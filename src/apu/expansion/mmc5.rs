@@ -0,0 +1,125 @@
+use crate::apu::expansion::ExpansionAudio;
+use crate::cartridge::nsf::ExpansionChip;
+use crate::apu::pulse::Pulse;
+
+// CPU cycles between the envelope and length counter clocks, a fixed 240 Hz
+const FRAME_PERIOD: u16 = 7457;
+// Pulses are as loud as the APU ones, full scale PCM about as loud as both of them
+const PULSE_LEVEL: f32 = 1.0 / 15.0;
+const PCM_LEVEL: f32 = 2.0 / 255.0;
+
+// MMC5 sound: two APU style pulses without sweep at $5000 - $5007 and an 8 bit PCM
+// channel at $5011. PCM read mode, which samples PRG reads, is not emulated
+#[derive(Debug, Clone)]
+pub struct Mmc5Audio {
+    pulse1: Pulse,
+    pulse2: Pulse,
+    pcm: u8,
+    pcm_read_mode: bool,
+    frame_timer: u16,
+    // Pulse timers run on every other CPU cycle
+    odd_cycle: bool
+}
+
+impl Mmc5Audio {
+
+    pub fn new() -> Self {
+        Self {
+            pulse1: Pulse::without_sweep(),
+            pulse2: Pulse::without_sweep(),
+            pcm: 0,
+            pcm_read_mode: false,
+            frame_timer: 0,
+            odd_cycle: false
+        }
+    }
+
+}
+
+impl Default for Mmc5Audio {
+
+    fn default() -> Self {
+        Self::new()
+    }
+
+}
+
+impl ExpansionAudio for Mmc5Audio {
+
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            // No sweep registers at $5001 and $5005
+            0x5000 | 0x5002 | 0x5003 => self.pulse1.write(addr, data),
+            0x5004 | 0x5006 | 0x5007 => self.pulse2.write(addr, data),
+            0x5010 => self.pcm_read_mode = data & 0x01 != 0,
+            // Writing 0 has no effect
+            0x5011 if !self.pcm_read_mode && data != 0 => self.pcm = data,
+            0x5015 => {
+                self.pulse1.length.set_enabled(data & 0x01 != 0);
+                self.pulse2.length.set_enabled(data & 0x02 != 0);
+            },
+            _ => {}
+        }
+    }
+
+    fn read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x5015 => Some(self.pulse1.length.active() as u8 | (self.pulse2.length.active() as u8) << 1),
+            _ => None
+        }
+    }
+
+    fn clock(&mut self) {
+        if self.odd_cycle {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+        self.odd_cycle = !self.odd_cycle;
+
+        self.frame_timer += 1;
+        if self.frame_timer == FRAME_PERIOD {
+            self.frame_timer = 0;
+            for pulse in [&mut self.pulse1, &mut self.pulse2] {
+                pulse.clock_quarter_frame();
+                pulse.clock_half_frame();
+            }
+        }
+    }
+
+    fn output(&self) -> f32 {
+        (self.pulse1.output() + self.pulse2.output()) as f32 * PULSE_LEVEL + self.pcm as f32 * PCM_LEVEL
+    }
+
+    fn chip(&self) -> ExpansionChip {
+        ExpansionChip::Mmc5
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    pub fn pulses_and_pcm() {
+        let mut mmc5 = Mmc5Audio::new();
+        mmc5.write(0x5015, 0x01);
+        // Constant volume 15, 50% duty, a period far beyond what the APU sweep unit allows
+        mmc5.write(0x5000, 0xbf);
+        mmc5.write(0x5002, 0xff);
+        mmc5.write(0x5003, 0x07);
+        assert_eq!(mmc5.read(0x5015), Some(0x01));
+        let high = (0..2 * 0x800 * 8).filter(|_| {
+            mmc5.clock();
+            mmc5.output() > 0.0
+        }).count();
+        assert!(high > 0x800 * 6);
+
+        mmc5.write(0x5015, 0x00);
+        assert_eq!(mmc5.read(0x5015), Some(0x00));
+        mmc5.write(0x5011, 0xff);
+        assert_eq!(mmc5.output(), 2.0);
+    }
+
+}
@@ -0,0 +1,30 @@
+pub mod vrc6;
+pub mod vrc7;
+pub mod mmc5;
+
+use crate::cartridge::nsf::ExpansionChip;
+
+// Output of every chip a board can have, in `ExpansionChip::ALL` order. Each one goes to its own
+// mixer channel
+pub type ExpansionLevels = [f32; 6];
+
+// Sound chip on the cartridge, mixed into the audio line next to the 2A03. Chips decode their
+// own registers, so a board or the NSF player can pass every CPU access through
+pub trait ExpansionAudio {
+    fn write(&mut self, addr: u16, data: u8);
+    // None for addresses the chip does not drive
+    fn read(&mut self, _addr: u16) -> Option<u8> {
+        None
+    }
+    // Called once for every CPU cycle
+    fn clock(&mut self);
+    // Level in units of one APU pulse channel at full volume, which the mixer scales to
+    // the 2A03 output; 2.0 is as loud as both pulses together
+    fn output(&self) -> f32;
+    // Which chip this is, selects the mixer channel
+    fn chip(&self) -> ExpansionChip;
+    // Boards report their chips to the mixer through this, see `Mapper::audio_output`
+    fn add_output(&self, levels: &mut ExpansionLevels) {
+        levels[self.chip().index()] += self.output();
+    }
+}
@@ -0,0 +1,194 @@
+use crate::apu::expansion::ExpansionAudio;
+use crate::cartridge::nsf::ExpansionChip;
+
+// Pulse at volume 15 is about as loud as an APU pulse
+const LEVEL: f32 = 1.0 / 15.0;
+
+// 16 step pulse with 8 duty settings, or a constant level in digitized mode
+#[derive(Debug, Default, Clone)]
+struct Vrc6Pulse {
+    volume: u8,
+    duty: u8,
+    digitized: bool,
+    period: u16,
+    enabled: bool,
+    timer: u16,
+    step: u8
+}
+
+impl Vrc6Pulse {
+
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.digitized = data & 0x80 != 0;
+                self.duty = (data >> 4) & 0x07;
+                self.volume = data & 0x0f;
+            },
+            1 => self.period = (self.period & 0x0f00) | data as u16,
+            _ => {
+                self.period = (self.period & 0x00ff) | ((data & 0x0f) as u16) << 8;
+                self.enabled = data & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 15;
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer == 0 {
+            self.timer = self.period >> shift;
+            self.step = self.step.wrapping_sub(1) & 0x0f;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && (self.digitized || self.step <= self.duty) { self.volume } else { 0 }
+    }
+
+}
+
+// Adds the rate to an accumulator every other step and resets it after the seventh add
+#[derive(Debug, Default, Clone)]
+struct Vrc6Saw {
+    rate: u8,
+    period: u16,
+    enabled: bool,
+    timer: u16,
+    step: u8,
+    accumulator: u8
+}
+
+impl Vrc6Saw {
+
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => self.rate = data & 0x3f,
+            1 => self.period = (self.period & 0x0f00) | data as u16,
+            _ => {
+                self.period = (self.period & 0x00ff) | ((data & 0x0f) as u16) << 8;
+                self.enabled = data & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer == 0 {
+            self.timer = self.period >> shift;
+            self.step += 1;
+            if self.step == 14 {
+                self.step = 0;
+                self.accumulator = 0;
+            } else if self.step & 0x01 == 0 {
+                // Rates above 42 overflow, games use that for distortion
+                self.accumulator = self.accumulator.wrapping_add(self.rate);
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    // 0 - 31
+    fn output(&self) -> u8 {
+        self.accumulator >> 3
+    }
+
+}
+
+// Konami VRC6 sound: two pulses and a sawtooth, registers at $9000 - $B002 in the
+// mapper 24 layout (mapper 26 swaps A0 and A1 before passing them on)
+#[derive(Debug, Default, Clone)]
+pub struct Vrc6Audio {
+    pulses: [Vrc6Pulse; 2],
+    saw: Vrc6Saw,
+    // $9003
+    halt: bool,
+    shift: u8
+}
+
+impl Vrc6Audio {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+}
+
+impl ExpansionAudio for Vrc6Audio {
+
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x9000..=0x9002 => self.pulses[0].write(addr & 0x03, data),
+            0x9003 => {
+                self.halt = data & 0x01 != 0;
+                self.shift = if data & 0x04 != 0 { 8 } else if data & 0x02 != 0 { 4 } else { 0 };
+            },
+            0xa000..=0xa002 => self.pulses[1].write(addr & 0x03, data),
+            0xb000..=0xb002 => self.saw.write(addr & 0x03, data),
+            _ => {}
+        }
+    }
+
+    fn clock(&mut self) {
+        if self.halt {
+            return;
+        }
+        self.pulses[0].clock(self.shift);
+        self.pulses[1].clock(self.shift);
+        self.saw.clock(self.shift);
+    }
+
+    fn output(&self) -> f32 {
+        (self.pulses[0].output() + self.pulses[1].output() + self.saw.output()) as f32 * LEVEL
+    }
+
+    fn chip(&self) -> ExpansionChip {
+        ExpansionChip::Vrc6
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    pub fn pulse_duty_and_saw() {
+        let mut vrc6 = Vrc6Audio::new();
+        // Duty 7 is high for 8 of 16 steps
+        vrc6.write(0x9000, 0x7f);
+        vrc6.write(0x9001, 0x00);
+        vrc6.write(0x9002, 0x80);
+        let high = (0..16).filter(|_| {
+            vrc6.clock();
+            vrc6.output() > 0.0
+        }).count();
+        assert_eq!(high, 8);
+
+        // Saw alone: 6 adds of 42 reach 252, then the accumulator starts over
+        vrc6.write(0x9002, 0x00);
+        vrc6.write(0xb000, 42);
+        vrc6.write(0xb002, 0x80);
+        let levels: Vec<u8> = (0..14).map(|_| {
+            vrc6.clock();
+            vrc6.saw.output()
+        }).collect();
+        assert_eq!(levels[11], 252 >> 3);
+        assert_eq!(levels[13], 0);
+    }
+
+}
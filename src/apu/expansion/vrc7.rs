@@ -0,0 +1,454 @@
+use std::f32::consts::PI;
+
+use crate::apu::expansion::ExpansionAudio;
+use crate::cartridge::nsf::ExpansionChip;
+use crate::timing::{Timing, NTSC_CPU_FREQUENCY};
+
+// Built-in instruments 1 - 15, as read from the chip
+const PATCHES: [[u8; 8]; 15] = [
+    [0x03, 0x21, 0x05, 0x06, 0xe8, 0x81, 0x42, 0x27],
+    [0x13, 0x41, 0x14, 0x0d, 0xd8, 0xf6, 0x23, 0x12],
+    [0x11, 0x11, 0x08, 0x08, 0xfa, 0xb2, 0x20, 0x12],
+    [0x31, 0x61, 0x0c, 0x07, 0xa8, 0x64, 0x61, 0x27],
+    [0x32, 0x21, 0x1e, 0x06, 0xe1, 0x76, 0x01, 0x28],
+    [0x02, 0x01, 0x06, 0x00, 0xa3, 0xe2, 0xf4, 0xf4],
+    [0x21, 0x61, 0x1d, 0x07, 0x82, 0x81, 0x11, 0x07],
+    [0x23, 0x21, 0x22, 0x17, 0xa2, 0x72, 0x01, 0x17],
+    [0x35, 0x11, 0x25, 0x00, 0x40, 0x73, 0x72, 0x01],
+    [0xb5, 0x01, 0x0f, 0x0f, 0xa8, 0xa5, 0x51, 0x02],
+    [0x17, 0xc1, 0x24, 0x07, 0xf8, 0xf8, 0x22, 0x12],
+    [0x71, 0x23, 0x11, 0x06, 0x65, 0x74, 0x18, 0x16],
+    [0x01, 0x02, 0xd3, 0x05, 0xc9, 0x95, 0x03, 0x02],
+    [0x61, 0x63, 0x0c, 0x00, 0x94, 0xc0, 0x33, 0xf6],
+    [0x21, 0x72, 0x0d, 0x00, 0xc1, 0xd5, 0x56, 0x06]
+];
+
+// Frequency multiplier settings
+const MULTIPLIERS: [f32; 16] = [0.5, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0, 10.0, 12.0, 12.0, 15.0, 15.0];
+// Key scale attenuation in dB at 6 dB per octave, by the upper four F-number bits
+const KEY_SCALE_LEVELS: [f32; 16] = [0.0, 18.0, 24.0, 27.0, 30.0, 32.25, 33.75, 35.25, 36.0, 37.5, 38.25, 39.0, 39.75, 40.5, 41.25, 42.0];
+// KSL 0 - 3: off, 1.5, 3 and 6 dB per octave
+const KEY_SCALE_FACTORS: [f32; 4] = [0.0, 0.25, 0.5, 1.0];
+
+// The chip has its own 3.58 MHz oscillator, twice the NTSC CPU clock, and takes 72 of its clocks
+// per sample. It is emulated once every 36 CPU cycles and adjusted to the console's CPU clock
+const CYCLES_PER_SAMPLE: u8 = 36;
+const CHIP_SAMPLE_RATE: f32 = (NTSC_CPU_FREQUENCY / CYCLES_PER_SAMPLE as f64) as f32;
+// Envelope range, anything quieter is off
+const MAX_ATTENUATION: f32 = 48.0;
+// Time for a full attack and a full decay at rate 1, every rate step is twice as fast
+const ATTACK_TIME: f32 = 2.826;
+const DECAY_TIME: f32 = 39.28;
+// Rate used for key off while the channel's sustain bit is set
+const SUSTAIN_RELEASE_RATE: u8 = 5;
+// Rate used for key off on percussive patches
+const PERCUSSIVE_RELEASE_RATE: u8 = 7;
+// Tremolo of 4.8 dB at 3.7 Hz and vibrato of 14 cents at 6.4 Hz
+const AM_DEPTH: f32 = 4.8;
+const AM_RATE: f32 = 3.7;
+const PM_DEPTH: f32 = 0.0081;
+const PM_RATE: f32 = 6.4;
+// A carrier at full volume swings about as far as one APU pulse
+const LEVEL: f32 = 1.0;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Stage {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    Off
+}
+
+// Operator settings taken from a patch, 0 is the modulator and 1 the carrier
+#[derive(Debug, Copy, Clone)]
+struct OperatorPatch {
+    tremolo: bool,
+    vibrato: bool,
+    sustained: bool,
+    key_scale_rate: bool,
+    multiplier: f32,
+    key_scale_level: f32,
+    rectified: bool,
+    attack: u8,
+    decay: u8,
+    sustain_level: f32,
+    release: u8
+}
+
+impl OperatorPatch {
+
+    fn new(patch: &[u8; 8], operator: usize) -> Self {
+        let flags = patch[operator];
+        Self {
+            tremolo: flags & 0x80 != 0,
+            vibrato: flags & 0x40 != 0,
+            sustained: flags & 0x20 != 0,
+            key_scale_rate: flags & 0x10 != 0,
+            multiplier: MULTIPLIERS[(flags & 0x0f) as usize],
+            key_scale_level: KEY_SCALE_FACTORS[(patch[2 + operator] >> 6) as usize],
+            rectified: patch[3] & (0x08 << operator) != 0,
+            attack: patch[4 + operator] >> 4,
+            decay: patch[4 + operator] & 0x0f,
+            sustain_level: (patch[6 + operator] >> 4) as f32 * 3.0,
+            release: patch[6 + operator] & 0x0f
+        }
+    }
+
+}
+
+// Phase and envelope of one operator
+#[derive(Debug, Copy, Clone)]
+struct Operator {
+    // In cycles, 0.0 - 1.0
+    phase: f32,
+    stage: Stage,
+    // Envelope in dB
+    envelope: f32
+}
+
+impl Operator {
+
+    fn new() -> Self {
+        Self { phase: 0.0, stage: Stage::Off, envelope: MAX_ATTENUATION }
+    }
+
+    fn key_on(&mut self) {
+        self.phase = 0.0;
+        self.stage = Stage::Attack;
+    }
+
+    fn key_off(&mut self) {
+        if self.stage != Stage::Off {
+            self.stage = Stage::Release;
+        }
+    }
+
+    // dB per sample for a rate, 0 stops the envelope
+    fn decay_step(rate: u8, key_scale: u8, sample_rate: f32) -> f32 {
+        if rate == 0 {
+            return 0.0;
+        }
+        let effective = (rate * 4 + key_scale).min(63) as f32;
+        MAX_ATTENUATION / (DECAY_TIME / 2f32.powf((effective - 4.0) / 4.0) * sample_rate)
+    }
+
+    fn clock_envelope(&mut self, patch: &OperatorPatch, key_scale: u8, sustain: bool, sample_rate: f32) {
+        match self.stage {
+            Stage::Attack => {
+                if patch.attack == 0 {
+                    return;
+                }
+                let effective = (patch.attack * 4 + key_scale).min(63) as f32;
+                if effective >= 60.0 {
+                    self.envelope = 0.0;
+                } else {
+                    // Exponential rise, from silence to full level in the attack time
+                    let time = ATTACK_TIME / 2f32.powf((effective - 4.0) / 4.0);
+                    let rate = (480f32.ln() / (time * sample_rate)).min(1.0);
+                    self.envelope -= self.envelope * rate;
+                }
+                if self.envelope < 0.1 {
+                    self.envelope = 0.0;
+                    self.stage = Stage::Decay;
+                }
+            },
+            Stage::Decay => {
+                self.envelope += Self::decay_step(patch.decay, key_scale, sample_rate);
+                if self.envelope >= patch.sustain_level {
+                    self.envelope = patch.sustain_level;
+                    self.stage = Stage::Sustain;
+                }
+            },
+            // Percussive patches keep fading while the key is held
+            Stage::Sustain => {
+                if !patch.sustained {
+                    self.envelope += Self::decay_step(patch.release, key_scale, sample_rate);
+                }
+            },
+            Stage::Release => {
+                let rate = if sustain { SUSTAIN_RELEASE_RATE } else if patch.sustained { patch.release } else { PERCUSSIVE_RELEASE_RATE };
+                self.envelope += Self::decay_step(rate, key_scale, sample_rate);
+            },
+            Stage::Off => {}
+        }
+        if self.envelope >= MAX_ATTENUATION {
+            self.envelope = MAX_ATTENUATION;
+            if self.stage != Stage::Attack {
+                self.stage = Stage::Off;
+            }
+        }
+    }
+
+    // Sine, with the negative half cut off for rectified waveforms
+    fn output(&self, modulation: f32, attenuation: f32, rectified: bool) -> f32 {
+        if self.stage == Stage::Off {
+            return 0.0;
+        }
+        let sine = (2.0 * PI * self.phase + modulation).sin();
+        if rectified && sine < 0.0 {
+            return 0.0;
+        }
+        sine * 10f32.powf(-(self.envelope + attenuation) / 20.0)
+    }
+
+}
+
+// Two operator FM channel
+#[derive(Debug, Copy, Clone)]
+struct FmChannel {
+    fnum: u16,
+    block: u8,
+    key: bool,
+    sustain: bool,
+    instrument: u8,
+    volume: u8,
+    modulator: Operator,
+    carrier: Operator,
+    // Last two modulator outputs for self feedback
+    feedback: [f32; 2],
+    output: f32
+}
+
+impl FmChannel {
+
+    fn new() -> Self {
+        Self {
+            fnum: 0,
+            block: 0,
+            key: false,
+            sustain: false,
+            instrument: 0,
+            volume: 0,
+            modulator: Operator::new(),
+            carrier: Operator::new(),
+            feedback: [0.0; 2],
+            output: 0.0
+        }
+    }
+
+    // Octave and top F-number bit, 0 - 15
+    fn key_code(&self) -> u8 {
+        self.block << 1 | (self.fnum >> 8) as u8
+    }
+
+    fn key_scale_attenuation(&self, patch: &OperatorPatch) -> f32 {
+        let level = KEY_SCALE_LEVELS[(self.fnum >> 5) as usize] - 6.0 * (7 - self.block) as f32;
+        level.max(0.0) * patch.key_scale_level
+    }
+
+    fn set_key(&mut self, key: bool) {
+        if key && !self.key {
+            self.modulator.key_on();
+            self.carrier.key_on();
+        } else if !key && self.key {
+            self.modulator.key_off();
+            self.carrier.key_off();
+        }
+        self.key = key;
+    }
+
+    fn clock(&mut self, patch: &[u8; 8], tremolo: f32, vibrato: f32, sample_rate: f32) {
+        let patches = [OperatorPatch::new(patch, 0), OperatorPatch::new(patch, 1)];
+        let key_code = self.key_code();
+        // Phase step at the chip's own sample rate, converted to the emulated one
+        let base = self.fnum as f32 * (1 << self.block) as f32 / (1 << 19) as f32 * CHIP_SAMPLE_RATE / sample_rate;
+
+        for (operator, patch) in [&mut self.modulator, &mut self.carrier].iter_mut().zip(patches.iter()) {
+            let key_scale = if patch.key_scale_rate { key_code } else { key_code >> 2 };
+            operator.clock_envelope(patch, key_scale, self.sustain, sample_rate);
+            let pitch = if patch.vibrato { 1.0 + vibrato } else { 1.0 };
+            operator.phase = (operator.phase + base * patch.multiplier * pitch).fract();
+        }
+
+        let [modulator, carrier] = patches;
+        let feedback_level = patch[3] & 0x07;
+        let feedback = if feedback_level == 0 {
+            0.0
+        } else {
+            (self.feedback[0] + self.feedback[1]) * PI * 2f32.powi(feedback_level as i32 - 7)
+        };
+        let modulator_attenuation = (patch[2] & 0x3f) as f32 * 0.75
+            + self.key_scale_attenuation(&modulator)
+            + if modulator.tremolo { tremolo } else { 0.0 };
+        let modulation = self.modulator.output(feedback, modulator_attenuation, modulator.rectified);
+        self.feedback = [self.feedback[1], modulation];
+
+        let carrier_attenuation = self.volume as f32 * 3.0
+            + self.key_scale_attenuation(&carrier)
+            + if carrier.tremolo { tremolo } else { 0.0 };
+        self.output = self.carrier.output(modulation * 4.0 * PI, carrier_attenuation, carrier.rectified);
+    }
+
+}
+
+// Konami VRC7 sound, a cut down YM2413 (OPLL) with six FM channels, 15 built-in instruments
+// and one user defined. Address at $9010, data at $9030
+pub struct Vrc7Audio {
+    address: u8,
+    custom: [u8; 8],
+    channels: [FmChannel; 6],
+    divider: u8,
+    // Samples per second at the console's CPU clock
+    sample_rate: f32,
+    // LFO phases in cycles
+    am_phase: f32,
+    pm_phase: f32,
+    output: f32
+}
+
+impl Vrc7Audio {
+
+    pub fn new(timing: &Timing) -> Self {
+        Self {
+            address: 0,
+            custom: [0; 8],
+            channels: [FmChannel::new(); 6],
+            divider: 0,
+            sample_rate: (timing.cpu_frequency() / CYCLES_PER_SAMPLE as f64) as f32,
+            am_phase: 0.0,
+            pm_phase: 0.0,
+            output: 0.0
+        }
+    }
+
+    fn write_register(&mut self, data: u8) {
+        let channel = (self.address & 0x0f) as usize;
+        match self.address {
+            0x00..=0x07 => self.custom[self.address as usize] = data,
+            0x10..=0x15 => self.channels[channel].fnum = (self.channels[channel].fnum & 0x100) | data as u16,
+            0x20..=0x25 => {
+                let channel = &mut self.channels[channel];
+                channel.fnum = (channel.fnum & 0x0ff) | ((data & 0x01) as u16) << 8;
+                channel.block = (data >> 1) & 0x07;
+                channel.sustain = data & 0x20 != 0;
+                channel.set_key(data & 0x10 != 0);
+            },
+            0x30..=0x35 => {
+                self.channels[channel].instrument = data >> 4;
+                self.channels[channel].volume = data & 0x0f;
+            },
+            _ => {}
+        }
+    }
+
+    fn patch(&self, instrument: u8) -> [u8; 8] {
+        if instrument == 0 { self.custom } else { PATCHES[instrument as usize - 1] }
+    }
+
+    fn clock_sample(&mut self) {
+        self.am_phase = (self.am_phase + AM_RATE / self.sample_rate).fract();
+        self.pm_phase = (self.pm_phase + PM_RATE / self.sample_rate).fract();
+        let tremolo = AM_DEPTH * 0.5 * (1.0 + (2.0 * PI * self.am_phase).sin());
+        let vibrato = PM_DEPTH * (2.0 * PI * self.pm_phase).sin();
+
+        let mut output = 0.0;
+        for i in 0..self.channels.len() {
+            let patch = self.patch(self.channels[i].instrument);
+            self.channels[i].clock(&patch, tremolo, vibrato, self.sample_rate);
+            output += self.channels[i].output;
+        }
+        self.output = output;
+    }
+
+}
+
+impl Default for Vrc7Audio {
+
+    fn default() -> Self {
+        Self::new(&Timing::default())
+    }
+
+}
+
+impl ExpansionAudio for Vrc7Audio {
+
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x9010 => self.address = data,
+            0x9030 => self.write_register(data),
+            _ => {}
+        }
+    }
+
+    fn clock(&mut self) {
+        self.divider += 1;
+        if self.divider == CYCLES_PER_SAMPLE {
+            self.divider = 0;
+            self.clock_sample();
+        }
+    }
+
+    fn output(&self) -> f32 {
+        self.output * LEVEL
+    }
+
+    fn chip(&self) -> ExpansionChip {
+        ExpansionChip::Vrc7
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::cartridge::Region;
+
+    // Instrument 3 (piano) at full volume, A4: F-number 288 in octave 4
+    fn play_a4(timing: &Timing) -> Vrc7Audio {
+        let mut vrc7 = Vrc7Audio::new(timing);
+        let mut write = |register: u8, data: u8| {
+            vrc7.write(0x9010, register);
+            vrc7.write(0x9030, data);
+        };
+        write(0x30, 0x30);
+        write(0x10, 0x20);
+        write(0x20, 0x10 | 4 << 1 | 0x01);
+        vrc7
+    }
+
+    // Peak and rising zero crossings over a tenth of a second
+    fn measure(vrc7: &mut Vrc7Audio, timing: &Timing) -> (f32, usize) {
+        let mut peak = 0.0f32;
+        let mut crossings = 0;
+        let mut previous = 0.0;
+        for _ in 0..(timing.cpu_frequency() / 10.0) as usize {
+            vrc7.clock();
+            let output = vrc7.output();
+            if previous < 0.0 && output >= 0.0 {
+                crossings += 1;
+            }
+            previous = output;
+            peak = peak.max(output.abs());
+        }
+        (peak, crossings)
+    }
+
+    #[test]
+    pub fn key_on_plays_a_tone() {
+        let timing = Timing::default();
+        let mut vrc7 = play_a4(&timing);
+        let (peak, crossings) = measure(&mut vrc7, &timing);
+        assert!(peak > 0.2 && peak <= 1.0);
+        // About 44 cycles of 440 Hz in a tenth of a second
+        assert!((43..=45).contains(&crossings));
+
+        // Released notes fade out
+        vrc7.write(0x9010, 0x20);
+        vrc7.write(0x9030, 0x00);
+        for _ in 0..(timing.cpu_frequency() * 2.0) as usize {
+            vrc7.clock();
+        }
+        assert!(vrc7.output().abs() < 0.01);
+    }
+
+    #[test]
+    pub fn pitch_follows_real_time_on_pal() {
+        let timing = Timing::new(Region::Pal);
+        let mut vrc7 = play_a4(&timing);
+        let (_, crossings) = measure(&mut vrc7, &timing);
+        assert!((43..=45).contains(&crossings));
+    }
+
+}
@@ -0,0 +1,75 @@
+use std::f32::consts::PI;
+
+// First order IIR filter running at the output sample rate
+#[derive(Debug, Clone)]
+pub enum Filter {
+    HighPass { alpha: f32, previous_input: f32, previous_output: f32 },
+    LowPass { alpha: f32, previous_output: f32 }
+}
+
+impl Filter {
+
+    pub fn high_pass(cutoff: f32, sample_rate: f32) -> Self {
+        let rc = 1.0 / (2.0 * PI * cutoff);
+        Filter::HighPass { alpha: rc / (rc + 1.0 / sample_rate), previous_input: 0.0, previous_output: 0.0 }
+    }
+
+    pub fn low_pass(cutoff: f32, sample_rate: f32) -> Self {
+        let rc = 1.0 / (2.0 * PI * cutoff);
+        let dt = 1.0 / sample_rate;
+        Filter::LowPass { alpha: dt / (rc + dt), previous_output: 0.0 }
+    }
+
+    pub fn process(&mut self, input: f32) -> f32 {
+        match self {
+            Filter::HighPass { alpha, previous_input, previous_output } => {
+                let output = *alpha * (*previous_output + input - *previous_input);
+                *previous_input = input;
+                *previous_output = output;
+                output
+            },
+            Filter::LowPass { alpha, previous_output } => {
+                let output = *previous_output + *alpha * (input - *previous_output);
+                *previous_output = output;
+                output
+            }
+        }
+    }
+
+}
+
+// Filters between the DAC and the audio output of the console: two high-pass filters at
+// 90 Hz and 440 Hz and a 14 kHz low-pass on the NES, the Famicom only has a 37 Hz high-pass
+pub fn nes_chain(sample_rate: f32) -> Vec<Filter> {
+    vec![
+        Filter::high_pass(90.0, sample_rate),
+        Filter::high_pass(440.0, sample_rate),
+        Filter::low_pass(14_000.0, sample_rate)
+    ]
+}
+
+pub fn famicom_chain(sample_rate: f32) -> Vec<Filter> {
+    vec![Filter::high_pass(37.0, sample_rate)]
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    pub fn high_pass_removes_dc() {
+        let mut chain = nes_chain(44_100.0);
+        let mut output = 1.0;
+        for _ in 0..44_100 {
+            output = chain.iter_mut().fold(0.5, |sample, filter| filter.process(sample));
+        }
+        assert!(output.abs() < 0.001);
+
+        // A 1 kHz square wave mostly passes
+        let mut filter = Filter::low_pass(14_000.0, 44_100.0);
+        let peak = (0..441).map(|i| filter.process(if (i / 22) & 0x01 == 0 { 1.0 } else { -1.0 })).fold(0.0f32, f32::max);
+        assert!(peak > 0.95);
+    }
+
+}
@@ -0,0 +1,45 @@
+// Note lengths selected by the upper 5 bits of the fourth channel register
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30
+];
+
+// Silences a channel after a number of half frames
+#[derive(Debug, Default, Clone)]
+pub struct LengthCounter {
+    // Channel enable bit in $4015
+    enabled: bool,
+    // Stops counting, shares its bit with the envelope loop flag or the linear counter control
+    pub halt: bool,
+    counter: u8
+}
+
+impl LengthCounter {
+
+    // Disabling the channel clears the counter right away
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+
+    // Upper 5 bits of the fourth channel register, ignored while the channel is disabled
+    pub fn load(&mut self, data: u8) {
+        if self.enabled {
+            self.counter = LENGTH_TABLE[(data >> 3) as usize];
+        }
+    }
+
+    // Half frame clock
+    pub fn clock(&mut self) {
+        if !self.halt && self.counter > 0 {
+            self.counter -= 1;
+        }
+    }
+
+    pub fn active(&self) -> bool {
+        self.counter > 0
+    }
+
+}
@@ -0,0 +1,323 @@
+use crate::apu::expansion::ExpansionLevels;
+use crate::apu::filter::{self, Filter};
+use crate::apu::resampler::Resampler;
+use crate::timing::NTSC_CPU_FREQUENCY;
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+
+// Sound channels of the 2A03 and one for each cartridge sound chip, in `ExpansionChip::ALL` order
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Channel {
+    Pulse1 = 0,
+    Pulse2 = 1,
+    Triangle = 2,
+    Noise = 3,
+    Dmc = 4,
+    Vrc6 = 5,
+    Vrc7 = 6,
+    Fds = 7,
+    Mmc5 = 8,
+    N163 = 9,
+    Sunsoft5b = 10
+}
+
+// First expansion chip channel
+const EXPANSION: usize = Channel::Vrc6 as usize;
+
+impl Channel {
+
+    pub const ALL: [Channel; 11] = [
+        Channel::Pulse1, Channel::Pulse2, Channel::Triangle, Channel::Noise, Channel::Dmc,
+        Channel::Vrc6, Channel::Vrc7, Channel::Fds, Channel::Mmc5, Channel::N163, Channel::Sunsoft5b
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Channel::Pulse1 => "pulse1",
+            Channel::Pulse2 => "pulse2",
+            Channel::Triangle => "triangle",
+            Channel::Noise => "noise",
+            Channel::Dmc => "dmc",
+            Channel::Vrc6 => "vrc6",
+            Channel::Vrc7 => "vrc7",
+            Channel::Fds => "fds",
+            Channel::Mmc5 => "mmc5",
+            Channel::N163 => "n163",
+            Channel::Sunsoft5b => "5b"
+        }
+    }
+
+}
+
+// One resampled and filtered output
+struct Stream {
+    resampler: Resampler,
+    filters: Vec<Filter>,
+    // Resampled but not yet filtered
+    resampled: Vec<f32>
+}
+
+impl Stream {
+
+    fn new(cpu_frequency: f64, sample_rate: u32, famicom: bool) -> Self {
+        Self {
+            resampler: Resampler::new(cpu_frequency, sample_rate as f64),
+            filters: filter_chain(sample_rate, famicom),
+            resampled: Vec::new()
+        }
+    }
+
+    fn push(&mut self, level: f32, limit: usize) {
+        self.resampler.push(level);
+        // Keeps at most `limit` samples when nobody takes them
+        if self.resampler.buffered() > limit {
+            self.resampler.read(&mut self.resampled);
+            if self.resampled.len() > limit {
+                let excess = self.resampled.len() - limit;
+                self.resampled.drain(..excess);
+            }
+        }
+    }
+
+    fn take(&mut self) -> Vec<f32> {
+        self.resampler.read(&mut self.resampled);
+        let filters = &mut self.filters;
+        self.resampled.drain(..).map(|sample| filters.iter_mut().fold(sample, |sample, filter| filter.process(sample))).collect()
+    }
+
+}
+
+fn filter_chain(sample_rate: u32, famicom: bool) -> Vec<Filter> {
+    if famicom {
+        filter::famicom_chain(sample_rate as f32)
+    } else {
+        filter::nes_chain(sample_rate as f32)
+    }
+}
+
+// Combines the channel outputs like the 2A03's DAC and turns them into samples at the host rate
+pub struct Mixer {
+    // Nonlinear DAC response, indexed by pulse1 + pulse2 and 3 * triangle + 2 * noise + dmc
+    pulse_table: [f32; 31],
+    tnd_table: [f32; 203],
+    // Per channel gain, 1.0 is the console's own level
+    volumes: [f32; 11],
+    muted: [bool; 11],
+    cpu_frequency: f64,
+    sample_rate: u32,
+    famicom: bool,
+    output: Stream,
+    // Every channel on its own, for recording them separately
+    channels: Option<Vec<Stream>>
+}
+
+impl Mixer {
+
+    pub fn new(sample_rate: u32) -> Self {
+        let mut pulse_table = [0.0; 31];
+        for (n, level) in pulse_table.iter_mut().enumerate().skip(1) {
+            *level = 95.52 / (8128.0 / n as f32 + 100.0);
+        }
+        let mut tnd_table = [0.0; 203];
+        for (n, level) in tnd_table.iter_mut().enumerate().skip(1) {
+            *level = 163.67 / (24329.0 / n as f32 + 100.0);
+        }
+
+        Self {
+            pulse_table,
+            tnd_table,
+            volumes: [1.0; 11],
+            muted: [false; 11],
+            cpu_frequency: NTSC_CPU_FREQUENCY,
+            sample_rate,
+            famicom: false,
+            output: Stream::new(NTSC_CPU_FREQUENCY, sample_rate, false),
+            channels: None
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // Clock rate of the channel outputs and rate of the produced samples, drops buffered samples
+    pub fn set_rates(&mut self, cpu_frequency: f64, sample_rate: u32) {
+        self.cpu_frequency = cpu_frequency;
+        self.sample_rate = sample_rate;
+        self.rebuild();
+    }
+
+    // Famicom consoles only have one high-pass filter
+    pub fn set_famicom_filters(&mut self, famicom: bool) {
+        self.famicom = famicom;
+        self.rebuild();
+    }
+
+    // Also produces every channel on its own, see `take_channel_samples`
+    pub fn set_channel_capture(&mut self, enabled: bool) {
+        self.channels = if enabled {
+            Some(Channel::ALL.iter().map(|_| Stream::new(self.cpu_frequency, self.sample_rate, self.famicom)).collect())
+        } else {
+            None
+        };
+    }
+
+    fn rebuild(&mut self) {
+        self.output = Stream::new(self.cpu_frequency, self.sample_rate, self.famicom);
+        if self.channels.is_some() {
+            self.set_channel_capture(true);
+        }
+    }
+
+    pub fn set_volume(&mut self, channel: Channel, volume: f32) {
+        self.volumes[channel as usize] = volume.max(0.0);
+    }
+
+    pub fn volume(&self, channel: Channel) -> f32 {
+        self.volumes[channel as usize]
+    }
+
+    pub fn set_muted(&mut self, channel: Channel, muted: bool) {
+        self.muted[channel as usize] = muted;
+    }
+
+    pub fn muted(&self, channel: Channel) -> bool {
+        self.muted[channel as usize]
+    }
+
+    // Output level 0.0 - 1.0 for raw channel outputs (pulse, triangle and noise 0 - 15, DMC 0 - 127)
+    pub fn mix(&self, outputs: [u8; 5]) -> f32 {
+        let mut levels = [0.0; 5];
+        for (i, level) in levels.iter_mut().enumerate() {
+            if !self.muted[i] {
+                *level = outputs[i] as f32 * self.volumes[i];
+            }
+        }
+        let pulse = lookup(&self.pulse_table, levels[0] + levels[1]);
+        let tnd = lookup(&self.tnd_table, 3.0 * levels[2] + 2.0 * levels[3] + levels[4]);
+        pulse + tnd
+    }
+
+    // Level of one channel as if the others were silent. Expansion audio is mixed linearly
+    // after the DAC, 1.0 is as loud as one pulse at full volume
+    fn channel_level(&self, channel: Channel, outputs: [u8; 5], expansion: &ExpansionLevels) -> f32 {
+        if self.muted[channel as usize] {
+            return 0.0;
+        }
+        let volume = self.volumes[channel as usize];
+        match channel {
+            Channel::Pulse1 | Channel::Pulse2 => lookup(&self.pulse_table, outputs[channel as usize] as f32 * volume),
+            Channel::Triangle => lookup(&self.tnd_table, 3.0 * outputs[2] as f32 * volume),
+            Channel::Noise => lookup(&self.tnd_table, 2.0 * outputs[3] as f32 * volume),
+            Channel::Dmc => lookup(&self.tnd_table, outputs[4] as f32 * volume),
+            _ => expansion[channel as usize - EXPANSION] * volume * self.pulse_table[15]
+        }
+    }
+
+    // Called once for every CPU cycle with the channel outputs and the expansion chip levels
+    pub fn clock(&mut self, outputs: [u8; 5], expansion: ExpansionLevels) {
+        let limit = self.sample_rate as usize;
+        let chips: f32 = Channel::ALL[EXPANSION..].iter().map(|&channel| self.channel_level(channel, outputs, &expansion)).sum();
+        let level = self.mix(outputs) + chips;
+        self.output.push(level, limit);
+
+        if let Some(mut channels) = self.channels.take() {
+            for (i, stream) in channels.iter_mut().enumerate() {
+                let level = self.channel_level(Channel::ALL[i], outputs, &expansion);
+                stream.push(level, limit);
+            }
+            self.channels = Some(channels);
+        }
+    }
+
+    // Samples at the host rate produced since the last call, centered around zero by the filters.
+    // The count only depends on the number of clocks, so reading after every frame splits the
+    // audio at the same samples on every run
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        self.output.take()
+    }
+
+    // Samples of every channel in `Channel::ALL` order, aligned with `take_samples`;
+    // empty without channel capture
+    pub fn take_channel_samples(&mut self) -> Vec<Vec<f32>> {
+        match &mut self.channels {
+            Some(channels) => channels.iter_mut().map(|stream| stream.take()).collect(),
+            None => Vec::new()
+        }
+    }
+
+}
+
+// Table lookup between entries, volume scaling makes the index fractional
+fn lookup(table: &[f32], index: f32) -> f32 {
+    let last = (table.len() - 1) as f32;
+    if index >= last {
+        // Louder than the console can get, the DAC curve continues roughly linearly
+        return table[table.len() - 1] * index / last;
+    }
+    let whole = index.floor();
+    let low = table[whole as usize];
+    let high = table[whole as usize + 1];
+    low + (high - low) * (index - whole)
+}
+
+impl Default for Mixer {
+
+    fn default() -> Self {
+        Self::new(DEFAULT_SAMPLE_RATE)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    pub fn nonlinear_mixing() {
+        let mut mixer = Mixer::default();
+        assert_eq!(mixer.mix([0; 5]), 0.0);
+        // Full pulse output is about 0.2575, full triangle, noise and DMC about 0.7425
+        assert!((mixer.mix([15, 15, 0, 0, 0]) - 0.2575).abs() < 0.0005);
+        assert!((mixer.mix([0, 0, 15, 15, 127]) - 0.7425).abs() < 0.0005);
+        // Nonlinear: two channels are less than twice as loud as one
+        assert!(mixer.mix([15, 15, 0, 0, 0]) < 2.0 * mixer.mix([15, 0, 0, 0, 0]));
+
+        mixer.set_muted(Channel::Pulse2, true);
+        assert_eq!(mixer.mix([15, 15, 0, 0, 0]), mixer.mix([15, 0, 0, 0, 0]));
+        let level = mixer.mix([7, 0, 0, 0, 0]);
+        mixer.set_volume(Channel::Pulse1, 0.5);
+        assert!((mixer.mix([14, 0, 0, 0, 0]) - level).abs() < 0.0001);
+    }
+
+    #[test]
+    pub fn produces_host_rate_samples() {
+        let mut mixer = Mixer::new(48_000);
+        // 440 Hz square wave from pulse 1 for a tenth of a second
+        let period = (NTSC_CPU_FREQUENCY / 440.0) as usize;
+        for cycle in 0..(NTSC_CPU_FREQUENCY / 10.0) as usize {
+            mixer.clock([if cycle % period < period / 2 { 15 } else { 0 }, 0, 0, 0, 0], [0.0; 6]);
+        }
+        let samples = mixer.take_samples();
+        assert!((4795..=4800).contains(&samples.len()));
+        let peak = samples.iter().fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+        assert!(peak > 0.05 && peak < 0.3);
+        assert!(mixer.take_samples().is_empty());
+    }
+
+    #[test]
+    pub fn chips_have_their_own_channels() {
+        let mut mixer = Mixer::default();
+        let chips = [1.0, 0.0, 0.0, 0.0, 0.0, 2.0];
+        assert!((mixer.channel_level(Channel::Sunsoft5b, [0; 5], &chips) - 2.0 * mixer.pulse_table[15]).abs() < 0.0001);
+        assert_eq!(mixer.channel_level(Channel::Fds, [0; 5], &chips), 0.0);
+
+        mixer.set_muted(Channel::Vrc6, true);
+        assert_eq!(mixer.channel_level(Channel::Vrc6, [0; 5], &chips), 0.0);
+        assert!(mixer.channel_level(Channel::Sunsoft5b, [0; 5], &chips) > 0.0);
+        mixer.set_volume(Channel::Sunsoft5b, 0.5);
+        assert!((mixer.channel_level(Channel::Sunsoft5b, [0; 5], &chips) - mixer.pulse_table[15]).abs() < 0.0001);
+    }
+
+}
@@ -0,0 +1,337 @@
+pub mod envelope;
+pub mod length_counter;
+pub mod pulse;
+pub mod triangle;
+pub mod noise;
+pub mod dmc;
+pub mod expansion;
+pub mod filter;
+pub mod resampler;
+pub mod mixer;
+pub mod register_log;
+pub mod wav;
+
+use crate::apu::expansion::ExpansionLevels;
+use crate::cartridge::Region;
+use crate::timing::Timing;
+use dmc::Dmc;
+use mixer::Mixer;
+use noise::Noise;
+use pulse::Pulse;
+use triangle::Triangle;
+
+// CPU cycles of the frame counter steps: three quarter frames, then the end of the sequence
+// over three cycles for the 4-step mode; the last two entries end the 5-step mode
+const NTSC_FRAME_STEPS: [u32; 6] = [7457, 14913, 22371, 29828, 29829, 29830];
+const NTSC_FRAME_STEPS_5: [u32; 6] = [7457, 14913, 22371, 29829, 37281, 37282];
+const PAL_FRAME_STEPS: [u32; 6] = [8313, 16627, 24939, 33252, 33253, 33254];
+const PAL_FRAME_STEPS_5: [u32; 6] = [8313, 16627, 24939, 33253, 41565, 41566];
+
+// $4015 status and enable bits
+pub enum StatusFlags {
+    Pulse1 = 1 << 0,
+    Pulse2 = 1 << 1,
+    Triangle = 1 << 2,
+    Noise = 1 << 3,
+    Dmc = 1 << 4,
+    FrameIrq = 1 << 6,
+    DmcIrq = 1 << 7
+}
+
+// Audio processing unit of the 2A03
+pub struct Apu {
+    pub pulse1: Pulse,
+    pub pulse2: Pulse,
+    pub triangle: Triangle,
+    pub noise: Noise,
+    pub dmc: Dmc,
+    pub mixer: Mixer,
+    // Cartridge sound chips, mixed in with the 2A03 channels
+    expansion: ExpansionLevels,
+    // CPU cycles since power on, pulse timers run on every other one
+    cycle: u64,
+    // Frame counter ($4017)
+    frame_steps: &'static [u32; 6],
+    frame_steps_5: &'static [u32; 6],
+    five_step: bool,
+    irq_inhibit: bool,
+    frame_irq: bool,
+    frame_cycle: u32,
+    // CPU cycles until a $4017 write resets the sequence
+    frame_reset: Option<u8>
+}
+
+impl Apu {
+
+    pub fn new() -> Self {
+        Self {
+            pulse1: Pulse::new(true),
+            pulse2: Pulse::new(false),
+            triangle: Triangle::new(),
+            noise: Noise::new(),
+            dmc: Dmc::new(),
+            mixer: Mixer::default(),
+            expansion: [0.0; 6],
+            cycle: 0,
+            frame_steps: &NTSC_FRAME_STEPS,
+            frame_steps_5: &NTSC_FRAME_STEPS_5,
+            five_step: false,
+            irq_inhibit: false,
+            frame_irq: false,
+            frame_cycle: 0,
+            frame_reset: None
+        }
+    }
+
+    // Frame counter, noise and DMC periods differ on PAL, Dendy keeps the NTSC ones
+    pub fn set_timing(&mut self, timing: Timing) {
+        if timing.region == Region::Pal {
+            self.frame_steps = &PAL_FRAME_STEPS;
+            self.frame_steps_5 = &PAL_FRAME_STEPS_5;
+            self.noise.set_periods(&noise::PAL_PERIODS);
+            self.dmc.set_rates(&dmc::PAL_RATES);
+        } else {
+            self.frame_steps = &NTSC_FRAME_STEPS;
+            self.frame_steps_5 = &NTSC_FRAME_STEPS_5;
+            self.noise.set_periods(&noise::NTSC_PERIODS);
+            self.dmc.set_rates(&dmc::NTSC_RATES);
+        }
+        let sample_rate = self.mixer.sample_rate();
+        self.mixer.set_rates(timing.cpu_frequency(), sample_rate);
+    }
+
+    // Reset silences all channels, the frame counter mode is kept
+    pub fn reset(&mut self) {
+        self.write(0x4015, 0x00);
+        self.frame_irq = false;
+        self.frame_cycle = 0;
+    }
+
+    // $4000 - $4013, $4015 and $4017
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4000..=0x4003 => self.pulse1.write(addr, data),
+            0x4004..=0x4007 => self.pulse2.write(addr, data),
+            0x4008..=0x400b => self.triangle.write(addr, data),
+            0x400c..=0x400f => self.noise.write(addr, data),
+            0x4010..=0x4013 => self.dmc.write(addr, data),
+            0x4015 => {
+                self.pulse1.length.set_enabled(data & StatusFlags::Pulse1 as u8 != 0);
+                self.pulse2.length.set_enabled(data & StatusFlags::Pulse2 as u8 != 0);
+                self.triangle.length.set_enabled(data & StatusFlags::Triangle as u8 != 0);
+                self.noise.length.set_enabled(data & StatusFlags::Noise as u8 != 0);
+                self.dmc.set_enabled(data & StatusFlags::Dmc as u8 != 0);
+            },
+            0x4017 => {
+                self.five_step = data & 0x80 != 0;
+                self.irq_inhibit = data & 0x40 != 0;
+                if self.irq_inhibit {
+                    self.frame_irq = false;
+                }
+                // Takes effect 3 or 4 CPU cycles later, depending on the APU cycle parity
+                self.frame_reset = Some(if self.cycle & 0x01 == 0 { 3 } else { 4 });
+            },
+            _ => {}
+        }
+    }
+
+    // $4015 read, clears the frame interrupt
+    pub fn read_status(&mut self) -> u8 {
+        let mut status = 0x00;
+        if self.pulse1.length.active() {
+            status |= StatusFlags::Pulse1 as u8;
+        }
+        if self.pulse2.length.active() {
+            status |= StatusFlags::Pulse2 as u8;
+        }
+        if self.triangle.length.active() {
+            status |= StatusFlags::Triangle as u8;
+        }
+        if self.noise.length.active() {
+            status |= StatusFlags::Noise as u8;
+        }
+        if self.dmc.active() {
+            status |= StatusFlags::Dmc as u8;
+        }
+        if self.frame_irq {
+            status |= StatusFlags::FrameIrq as u8;
+        }
+        if self.dmc.irq {
+            status |= StatusFlags::DmcIrq as u8;
+        }
+        self.frame_irq = false;
+        status
+    }
+
+    // State of the APU IRQ line
+    pub fn irq(&self) -> bool {
+        self.frame_irq || self.dmc.irq
+    }
+
+    // Called once for every CPU cycle
+    pub fn clock(&mut self) {
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+        if self.cycle & 0x01 == 1 {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+        self.clock_frame_counter();
+        self.mixer.clock(self.outputs(), self.expansion);
+        self.cycle += 1;
+    }
+
+    // CPU cycles since power on
+    pub fn cycles(&self) -> u64 {
+        self.cycle
+    }
+
+    // Levels of the cartridge's sound chips, see `ExpansionAudio::output`
+    pub fn set_expansion_output(&mut self, levels: ExpansionLevels) {
+        self.expansion = levels;
+    }
+
+    // Raw channel outputs in mixer order
+    pub fn outputs(&self) -> [u8; 5] {
+        [self.pulse1.output(), self.pulse2.output(), self.triangle.output(), self.noise.output(), self.dmc.output()]
+    }
+
+    fn clock_frame_counter(&mut self) {
+        if let Some(delay) = self.frame_reset {
+            if delay == 0 {
+                self.frame_reset = None;
+                self.frame_cycle = 0;
+                // The 5-step mode clocks everything right away
+                if self.five_step {
+                    self.quarter_frame();
+                    self.half_frame();
+                }
+            } else {
+                self.frame_reset = Some(delay - 1);
+            }
+        }
+
+        self.frame_cycle += 1;
+        let steps = if self.five_step { self.frame_steps_5 } else { self.frame_steps };
+        let step = match steps.iter().position(|cycle| *cycle == self.frame_cycle) {
+            Some(step) => step,
+            None => return
+        };
+
+        match (step, self.five_step) {
+            (0, _) | (2, _) => self.quarter_frame(),
+            (1, _) => {
+                self.quarter_frame();
+                self.half_frame();
+            },
+            (3, false) => self.set_frame_irq(),
+            (4, false) => {
+                self.quarter_frame();
+                self.half_frame();
+                self.set_frame_irq();
+            },
+            (5, false) => {
+                self.set_frame_irq();
+                self.frame_cycle = 0;
+            },
+            (4, true) => {
+                self.quarter_frame();
+                self.half_frame();
+            },
+            (5, true) => self.frame_cycle = 0,
+            _ => {}
+        }
+    }
+
+    fn set_frame_irq(&mut self) {
+        if !self.irq_inhibit {
+            self.frame_irq = true;
+        }
+    }
+
+    // Envelopes and the triangle linear counter
+    fn quarter_frame(&mut self) {
+        self.pulse1.clock_quarter_frame();
+        self.pulse2.clock_quarter_frame();
+        self.triangle.clock_quarter_frame();
+        self.noise.clock_quarter_frame();
+    }
+
+    // Length counters and sweep units
+    fn half_frame(&mut self) {
+        self.pulse1.clock_half_frame();
+        self.pulse2.clock_half_frame();
+        self.triangle.clock_half_frame();
+        self.noise.clock_half_frame();
+    }
+
+}
+
+impl Default for Apu {
+
+    fn default() -> Self {
+        Self::new()
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    pub fn frame_irq_in_four_step_mode() {
+        let mut apu = Apu::new();
+        for _ in 0..29827 {
+            apu.clock();
+        }
+        assert!(!apu.irq());
+        apu.clock();
+        assert!(apu.irq());
+        assert_eq!(apu.read_status() & StatusFlags::FrameIrq as u8, StatusFlags::FrameIrq as u8);
+        assert!(!apu.irq());
+
+        // Inhibit clears the flag and keeps it clear, so does the 5-step mode
+        apu.write(0x4017, 0x40);
+        for _ in 0..60000 {
+            apu.clock();
+        }
+        assert!(!apu.irq());
+        apu.write(0x4017, 0x80);
+        for _ in 0..60000 {
+            apu.clock();
+        }
+        assert!(!apu.irq());
+    }
+
+    #[test]
+    pub fn length_counters_in_status() {
+        let mut apu = Apu::new();
+        // Loads are ignored while a channel is disabled
+        apu.write(0x4003, 0x08);
+        assert_eq!(apu.read_status() & 0x0f, 0x00);
+
+        apu.write(0x4015, 0x0f);
+        apu.write(0x4003, 0x18);
+        apu.write(0x400f, 0x18);
+        assert_eq!(apu.read_status() & 0x0f, 0x09);
+
+        // Length index 3 is 2 half frames, 5-step mode clocks one on the write
+        apu.write(0x4003, 0x18);
+        apu.write(0x4017, 0x80);
+        for _ in 0..5 {
+            apu.clock();
+        }
+        assert_eq!(apu.read_status() & 0x01, 0x01);
+        for _ in 0..14913 {
+            apu.clock();
+        }
+        assert_eq!(apu.read_status() & 0x01, 0x00);
+
+        apu.write(0x4015, 0x00);
+        assert_eq!(apu.read_status() & 0x0f, 0x00);
+    }
+
+}
@@ -0,0 +1,122 @@
+use crate::apu::envelope::Envelope;
+use crate::apu::length_counter::LengthCounter;
+
+// Timer periods in CPU cycles
+pub const NTSC_PERIODS: [u16; 16] = [4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068];
+pub const PAL_PERIODS: [u16; 16] = [4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778];
+
+// Pseudo random noise channel, $400C - $400F
+#[derive(Debug, Clone)]
+pub struct Noise {
+    periods: &'static [u16; 16],
+    // Timer is clocked every CPU cycle
+    timer_period: u16,
+    timer: u16,
+    // 15 bit linear feedback shift register
+    shift: u16,
+    // Feedback from bit 6 instead of bit 1, a short 93 step sequence
+    short_mode: bool,
+    envelope: Envelope,
+    pub length: LengthCounter
+}
+
+impl Noise {
+
+    pub fn new() -> Self {
+        Self {
+            periods: &NTSC_PERIODS,
+            timer_period: NTSC_PERIODS[0],
+            timer: 0,
+            shift: 0x0001,
+            short_mode: false,
+            envelope: Envelope::default(),
+            length: LengthCounter::default()
+        }
+    }
+
+    // Period table of the region
+    pub fn set_periods(&mut self, periods: &'static [u16; 16]) {
+        self.periods = periods;
+    }
+
+    pub fn write(&mut self, register: u16, data: u8) {
+        match register & 0x03 {
+            0 => {
+                self.length.halt = data & 0x20 != 0;
+                self.envelope.write(data);
+            },
+            1 => {},
+            2 => {
+                self.short_mode = data & 0x80 != 0;
+                self.timer_period = self.periods[(data & 0x0f) as usize];
+            },
+            _ => {
+                self.length.load(data);
+                self.envelope.restart();
+            }
+        }
+    }
+
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period - 1;
+            let tap = if self.short_mode { 6 } else { 1 };
+            let feedback = (self.shift ^ (self.shift >> tap)) & 0x01;
+            self.shift = (self.shift >> 1) | feedback << 14;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length.clock();
+    }
+
+    // 0 - 15, silent while bit 0 of the shift register is set
+    pub fn output(&self) -> u8 {
+        if !self.length.active() || self.shift & 0x01 != 0 { 0 } else { self.envelope.output() }
+    }
+
+}
+
+impl Default for Noise {
+
+    fn default() -> Self {
+        Self::new()
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // Steps until the shift register is back at its start value
+    fn sequence_length(short_mode: bool) -> usize {
+        let mut noise = Noise::new();
+        noise.write(2, if short_mode { 0x80 } else { 0x00 });
+        let start = noise.shift;
+        let mut steps = 0;
+        loop {
+            for _ in 0..4 {
+                noise.clock_timer();
+            }
+            steps += 1;
+            if noise.shift == start {
+                return steps;
+            }
+        }
+    }
+
+    #[test]
+    pub fn lfsr_modes() {
+        assert_eq!(sequence_length(false), 32767);
+        assert_eq!(sequence_length(true), 93);
+    }
+
+}
@@ -0,0 +1,166 @@
+use crate::apu::envelope::Envelope;
+use crate::apu::length_counter::LengthCounter;
+
+// Waveforms for the four duty cycles, 12.5%, 25%, 50% and 25% negated
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1]
+];
+
+// Square wave channel, $4000 - $4003 and $4004 - $4007
+#[derive(Debug, Default, Clone)]
+pub struct Pulse {
+    // Pulse 1 negates sweep changes with the one's complement, going one further down
+    ones_complement: bool,
+    // MMC5 pulses have no sweep unit and are never muted by it
+    sweep_unit: bool,
+    duty: u8,
+    step: u8,
+    // 11 bit timer, clocked every APU cycle (two CPU cycles)
+    timer_period: u16,
+    timer: u16,
+    envelope: Envelope,
+    pub length: LengthCounter,
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_divider: u8,
+    sweep_reload: bool
+}
+
+impl Pulse {
+
+    // `ones_complement` is set for pulse 1
+    pub fn new(ones_complement: bool) -> Self {
+        Self { ones_complement, sweep_unit: true, ..Self::default() }
+    }
+
+    // Pulse without a sweep unit, as on the MMC5
+    pub fn without_sweep() -> Self {
+        Self::default()
+    }
+
+    pub fn write(&mut self, register: u16, data: u8) {
+        match register & 0x03 {
+            0 => {
+                self.duty = data >> 6;
+                self.length.halt = data & 0x20 != 0;
+                self.envelope.write(data);
+            },
+            1 => {
+                self.sweep_enabled = data & 0x80 != 0;
+                self.sweep_period = (data >> 4) & 0x07;
+                self.sweep_negate = data & 0x08 != 0;
+                self.sweep_shift = data & 0x07;
+                self.sweep_reload = true;
+            },
+            2 => self.timer_period = (self.timer_period & 0x0700) | data as u16,
+            _ => {
+                self.timer_period = (self.timer_period & 0x00ff) | ((data & 0x07) as u16) << 8;
+                self.length.load(data);
+                // Restarts the waveform, the timer is left alone
+                self.step = 0;
+                self.envelope.restart();
+            }
+        }
+    }
+
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.step = (self.step + 1) & 0x07;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length.clock();
+        if !self.sweep_unit {
+            return;
+        }
+
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.sweep_muting() {
+            self.timer_period = self.sweep_target();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    // Period the sweep unit is heading for, computed all the time
+    fn sweep_target(&self) -> u16 {
+        let change = self.timer_period >> self.sweep_shift;
+        if !self.sweep_negate {
+            self.timer_period + change
+        } else if self.ones_complement {
+            self.timer_period.saturating_sub(change + 1)
+        } else {
+            self.timer_period.saturating_sub(change)
+        }
+    }
+
+    // Periods below 8 and targets above $7FF silence the channel, even with the sweep disabled
+    fn sweep_muting(&self) -> bool {
+        self.timer_period < 8 || (self.sweep_unit && self.sweep_target() > 0x07ff)
+    }
+
+    // 0 - 15
+    pub fn output(&self) -> u8 {
+        if !self.length.active() || self.sweep_muting() || DUTY_TABLE[self.duty as usize][self.step as usize] == 0 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    pub fn sweep_negation_differs_between_channels() {
+        let mut pulse1 = Pulse::new(true);
+        let mut pulse2 = Pulse::new(false);
+        for pulse in [&mut pulse1, &mut pulse2] {
+            pulse.length.set_enabled(true);
+            // Sweep enabled, period 0, negate, shift 1
+            pulse.write(1, 0x80 | 0x08 | 0x01);
+            pulse.write(2, 0x00);
+            pulse.write(3, 0x01);
+            pulse.clock_half_frame();
+        }
+        assert_eq!(pulse1.timer_period, 0x100 - 0x80 - 1);
+        assert_eq!(pulse2.timer_period, 0x100 - 0x80);
+    }
+
+    #[test]
+    pub fn muted_by_sweep_target() {
+        let mut pulse = Pulse::new(false);
+        pulse.length.set_enabled(true);
+        // Constant volume 15, 75% duty. Negate keeps the target in range, with shift 0 the
+        // target would be twice the period
+        pulse.write(0, 0xc0 | 0x10 | 0x0f);
+        pulse.write(1, 0x08);
+        pulse.write(2, 0xff);
+        pulse.write(3, 0x07);
+        assert_eq!(pulse.output(), 15);
+        // Target $7FF + $3FF is out of range, even though the sweep is disabled
+        pulse.write(1, 0x01);
+        assert_eq!(pulse.output(), 0);
+    }
+
+}
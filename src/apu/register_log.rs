@@ -0,0 +1,298 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::cartridge::nsf::ExpansionChip;
+
+// VGM timestamps count samples at 44.1 kHz
+const VGM_RATE: f64 = 44_100.0;
+const VGM_VERSION: u32 = 0x171;
+const VGM_HEADER_SIZE: usize = 0x100;
+// Clock the VRC7 is logged with, it runs the OPLL core from a 3.58 MHz crystal
+const YM2413_CLOCK: u32 = 3_579_545;
+// AY-3-8910 type byte for the YM2149 the 5B is derived from
+const AY_TYPE_YM2149: u8 = 0x10;
+
+// One write to a sound register
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RegisterWrite {
+    // CPU cycles since the log started
+    pub cycle: u64,
+    pub addr: u16,
+    pub data: u8
+}
+
+// GD3 tag of an exported VGM file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VgmTags {
+    pub track: String,
+    pub game: String,
+    pub author: String,
+    pub date: String,
+    pub ripper: String,
+    pub notes: String
+}
+
+// Timestamped writes to the 2A03 sound registers and the cartridge's expansion audio,
+// exported as VGM or CSV. VGM 1.71 only knows the APU with the FDS, the YM2413 (VRC7) and the
+// AY-3-8910 (5B), writes to the other chips are only in the CSV log
+pub struct RegisterLog {
+    // `ExpansionChip` flags of the cartridge, decides which writes past $4017 are sound
+    chips: u8,
+    cpu_frequency: f64,
+    // APU cycle the log started on
+    start: u64,
+    // Length of the log, in CPU cycles
+    length: u64,
+    writes: Vec<RegisterWrite>,
+    // First byte the DMC fetched from every sample address
+    samples: BTreeMap<u16, u8>
+}
+
+impl RegisterLog {
+
+    pub fn new(cpu_frequency: f64, chips: u8, start: u64) -> Self {
+        Self { chips, cpu_frequency, start, length: 0, writes: Vec::new(), samples: BTreeMap::new() }
+    }
+
+    pub fn writes(&self) -> &[RegisterWrite] {
+        &self.writes
+    }
+
+    // Logs the write if it goes to a sound register, `cycle` counts APU cycles
+    pub fn record_write(&mut self, cycle: u64, addr: u16, data: u8) {
+        if self.chip_name(addr).is_some() {
+            let cycle = cycle.saturating_sub(self.start);
+            self.writes.push(RegisterWrite { cycle, addr, data });
+            self.length = self.length.max(cycle);
+        }
+    }
+
+    // Sample byte read by the DMC, VGM players need the sample memory
+    pub fn record_sample(&mut self, addr: u16, data: u8) {
+        self.samples.entry(addr).or_insert(data);
+    }
+
+    // Extends the log to `cycle`, so silence after the last write is kept
+    pub fn close(&mut self, cycle: u64) {
+        self.length = self.length.max(cycle.saturating_sub(self.start));
+    }
+
+    fn has_chip(&self, chip: ExpansionChip) -> bool {
+        self.chips & chip as u8 != 0
+    }
+
+    // Chip a register belongs to, None for anything that is not sound
+    fn chip_name(&self, addr: u16) -> Option<&'static str> {
+        match addr {
+            0x4000..=0x4013 | 0x4015 | 0x4017 => Some("apu"),
+            0x4023 | 0x4040..=0x4097 if self.has_chip(ExpansionChip::Fds) => Some("fds"),
+            0x4800..=0x4fff | 0xf800..=0xffff if self.has_chip(ExpansionChip::N163) => Some("n163"),
+            0x5000..=0x5015 if self.has_chip(ExpansionChip::Mmc5) => Some("mmc5"),
+            0x9000..=0x9003 | 0xa000..=0xa002 | 0xb000..=0xb002 if self.has_chip(ExpansionChip::Vrc6) => Some("vrc6"),
+            0x9010 | 0x9030 if self.has_chip(ExpansionChip::Vrc7) => Some("vrc7"),
+            0xc000..=0xffff if self.has_chip(ExpansionChip::Sunsoft5b) => Some("5b"),
+            _ => None
+        }
+    }
+
+    fn seconds(&self, cycle: u64) -> f64 {
+        cycle as f64 / self.cpu_frequency
+    }
+
+    // One line per write: cycle, time in seconds, chip, address and value
+    pub fn write_csv<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writeln!(writer, "cycle,seconds,chip,address,value")?;
+        for write in &self.writes {
+            let chip = self.chip_name(write.addr).unwrap_or_default();
+            writeln!(writer, "{},{:.6},{},${:04x},${:02x}", write.cycle, self.seconds(write.cycle), chip, write.addr, write.data)?;
+        }
+        writer.flush()
+    }
+
+    pub fn write_vgm<W: Write>(&self, mut writer: W, tags: &VgmTags) -> io::Result<()> {
+        let sample = |cycle: u64| (self.seconds(cycle) * VGM_RATE).round() as u64;
+
+        let mut data = Vec::new();
+        // DMC samples go first as NES APU RAM blocks, one per run of consecutive addresses
+        let mut runs: Vec<(u16, Vec<u8>)> = Vec::new();
+        for (&addr, &byte) in &self.samples {
+            match runs.last_mut() {
+                Some((start, bytes)) if *start as usize + bytes.len() == addr as usize => bytes.push(byte),
+                _ => runs.push((addr, vec![byte]))
+            }
+        }
+        for (start, bytes) in runs {
+            data.extend_from_slice(&[0x67, 0x66, 0xc2]);
+            data.extend_from_slice(&(bytes.len() as u32 + 2).to_le_bytes());
+            data.extend_from_slice(&start.to_le_bytes());
+            data.extend_from_slice(&bytes);
+        }
+
+        let mut now = 0;
+        // Register selected on the VRC7 and 5B
+        let mut vrc7_addr = 0x00;
+        let mut ay_addr = None;
+        for write in &self.writes {
+            let command = match (self.chip_name(write.addr), write.addr) {
+                (Some("apu"), addr) => Some([0xb4, (addr - 0x4000) as u8, write.data]),
+                (Some("fds"), 0x4023) => Some([0xb4, 0x3f, write.data]),
+                (Some("fds"), addr @ 0x4040..=0x407f) => Some([0xb4, (addr - 0x4040) as u8 + 0x40, write.data]),
+                (Some("fds"), addr @ 0x4080..=0x409e) => Some([0xb4, (addr - 0x4080) as u8 + 0x20, write.data]),
+                (Some("vrc7"), 0x9010) => {
+                    vrc7_addr = write.data;
+                    None
+                },
+                (Some("vrc7"), _) => Some([0x51, vrc7_addr, write.data]),
+                (Some("5b"), 0xc000..=0xdfff) => {
+                    // The upper nibble must be zero to select a register
+                    ay_addr = if write.data & 0xf0 == 0 { Some(write.data) } else { None };
+                    None
+                },
+                (Some("5b"), _) => ay_addr.map(|addr| [0xa0, addr, write.data]),
+                _ => None
+            };
+            if let Some(command) = command {
+                let time = sample(write.cycle);
+                push_wait(&mut data, time - now);
+                now = time;
+                data.extend_from_slice(&command);
+            }
+        }
+        let total = sample(self.length);
+        push_wait(&mut data, total - now);
+        data.push(0x66);
+
+        let gd3 = gd3(tags);
+        let mut header = vec![0x00; VGM_HEADER_SIZE];
+        let file_size = VGM_HEADER_SIZE + data.len() + gd3.len();
+        let mut put = |offset: usize, value: u32| header[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        put(0x00, u32::from_le_bytes(*b"Vgm "));
+        put(0x04, (file_size - 0x04) as u32);
+        put(0x08, VGM_VERSION);
+        if self.has_chip(ExpansionChip::Vrc7) {
+            put(0x10, YM2413_CLOCK);
+        }
+        put(0x14, (VGM_HEADER_SIZE + data.len() - 0x14) as u32);
+        put(0x18, total as u32);
+        put(0x34, (VGM_HEADER_SIZE - 0x34) as u32);
+        if self.has_chip(ExpansionChip::Sunsoft5b) {
+            // The 5B divides its clock by two before the AY tone counters
+            put(0x74, (self.cpu_frequency / 2.0).round() as u32);
+        }
+        // Bit 31 adds the FDS
+        let fds = if self.has_chip(ExpansionChip::Fds) { 0x8000_0000 } else { 0 };
+        put(0x84, self.cpu_frequency.round() as u32 | fds);
+        if self.has_chip(ExpansionChip::Sunsoft5b) {
+            header[0x78] = AY_TYPE_YM2149;
+            header[0x79] = 0x01;
+        }
+
+        writer.write_all(&header)?;
+        writer.write_all(&data)?;
+        writer.write_all(&gd3)?;
+        writer.flush()
+    }
+
+    pub fn save_csv<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        self.write_csv(BufWriter::new(File::create(path)?))
+    }
+
+    pub fn save_vgm<P: AsRef<Path>>(&self, path: P, tags: &VgmTags) -> io::Result<()> {
+        self.write_vgm(BufWriter::new(File::create(path)?), tags)
+    }
+
+}
+
+// Shortest wait commands for `samples`
+fn push_wait(data: &mut Vec<u8>, mut samples: u64) {
+    while samples > 0 {
+        match samples {
+            1..=16 => {
+                data.push(0x70 + samples as u8 - 1);
+                samples = 0;
+            },
+            735 => {
+                data.push(0x62);
+                samples = 0;
+            },
+            882 => {
+                data.push(0x63);
+                samples = 0;
+            },
+            _ => {
+                let wait = samples.min(0xffff);
+                data.push(0x61);
+                data.extend_from_slice(&(wait as u16).to_le_bytes());
+                samples -= wait;
+            }
+        }
+    }
+}
+
+// English and Japanese names, system, author, date, ripper and notes as UTF-16 strings
+fn gd3(tags: &VgmTags) -> Vec<u8> {
+    let strings = [
+        &tags.track, "", &tags.game, "", "Nintendo Entertainment System", "", &tags.author, "",
+        &tags.date, &tags.ripper, &tags.notes
+    ];
+    let mut text = Vec::new();
+    for string in strings.iter() {
+        for unit in string.encode_utf16().chain(Some(0)) {
+            text.extend_from_slice(&unit.to_le_bytes());
+        }
+    }
+    let mut gd3 = b"Gd3 ".to_vec();
+    gd3.extend_from_slice(&0x100u32.to_le_bytes());
+    gd3.extend_from_slice(&(text.len() as u32).to_le_bytes());
+    gd3.extend_from_slice(&text);
+    gd3
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::timing::NTSC_CPU_FREQUENCY;
+
+    #[test]
+    pub fn exports_vgm_and_csv() {
+        let mut log = RegisterLog::new(NTSC_CPU_FREQUENCY, ExpansionChip::Vrc7 as u8, 1000);
+        log.record_write(1000, 0x4015, 0x0f);
+        // Not sound, and VRC6 is not on the cartridge
+        log.record_write(1010, 0x2000, 0x80);
+        log.record_write(1020, 0x9000, 0x3f);
+        log.record_write(1000 + 29781, 0x9010, 0x30);
+        log.record_write(1000 + 29781, 0x9030, 0x0f);
+        log.record_sample(0xc000, 0xaa);
+        log.record_sample(0xc001, 0x55);
+        log.close(1000 + 2 * 29781);
+        assert_eq!(log.writes().len(), 3);
+
+        let mut csv = Vec::new();
+        log.write_csv(&mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert_eq!(csv.lines().nth(1), Some("0,0.000000,apu,$4015,$0f"));
+        assert_eq!(csv.lines().nth(3), Some("29781,0.016640,vrc7,$9030,$0f"));
+
+        let mut vgm = Vec::new();
+        log.write_vgm(&mut vgm, &VgmTags::default()).unwrap();
+        assert_eq!(&vgm[..4], b"Vgm ");
+        let field = |offset: usize| u32::from_le_bytes([vgm[offset], vgm[offset + 1], vgm[offset + 2], vgm[offset + 3]]);
+        assert_eq!(field(0x04) as usize, vgm.len() - 4);
+        assert_eq!(field(0x08), 0x171);
+        assert_eq!(field(0x10), YM2413_CLOCK);
+        assert_eq!(field(0x18), 1468);
+        assert_eq!(field(0x84), 1_789_773);
+        let gd3 = 0x14 + field(0x14) as usize;
+        assert_eq!(&vgm[gd3..gd3 + 4], b"Gd3 ");
+        assert_eq!(&vgm[0x100..gd3], &[
+            0x67, 0x66, 0xc2, 0x04, 0x00, 0x00, 0x00, 0x00, 0xc0, 0xaa, 0x55,
+            0xb4, 0x15, 0x0f,
+            0x61, 0xde, 0x02, 0x51, 0x30, 0x0f,
+            0x61, 0xde, 0x02, 0x66
+        ][..]);
+    }
+
+}
@@ -0,0 +1,118 @@
+use std::f64::consts::PI;
+
+// Kernel resolution: fractional positions between two output samples and taps per kernel
+const PHASES: usize = 64;
+const TAPS: usize = 16;
+// Cutoff relative to the output Nyquist frequency, leaves room for the kernel's transition band
+const CUTOFF: f64 = 0.9;
+
+// Band-limited step synthesis in the style of blip_buf: the input is a sample at the CPU clock
+// rate that only changes in steps, each step is added to the output as a windowed sinc
+// impulse and integrated, so the output has no content above its Nyquist frequency
+#[derive(Debug, Clone)]
+pub struct Resampler {
+    // Output samples per input clock
+    ratio: f64,
+    // Position of the next input clock in output samples, relative to deltas[0]
+    position: f64,
+    // Band-limited impulses of the steps, integrated when read
+    deltas: Vec<f32>,
+    amplitude: f32,
+    sum: f32,
+    kernel: Vec<[f32; TAPS]>
+}
+
+impl Resampler {
+
+    pub fn new(clock_rate: f64, sample_rate: f64) -> Self {
+        let kernel = (0..=PHASES).map(|phase| {
+            let offset = phase as f64 / PHASES as f64;
+            let mut taps = [0.0; TAPS];
+            for (i, tap) in taps.iter_mut().enumerate() {
+                // Distance from the step in output samples, centered on the kernel
+                let x = i as f64 - (TAPS / 2) as f64 + 1.0 - offset;
+                let sinc = if x == 0.0 { 1.0 } else { (PI * CUTOFF * x).sin() / (PI * CUTOFF * x) };
+                // Blackman window over the kernel width
+                let w = (x + TAPS as f64 / 2.0) / TAPS as f64;
+                let window = 0.42 - 0.5 * (2.0 * PI * w).cos() + 0.08 * (4.0 * PI * w).cos();
+                *tap = (sinc * window) as f32;
+            }
+            // Every step has to add up to its full height
+            let sum: f32 = taps.iter().sum();
+            taps.iter_mut().for_each(|tap| *tap /= sum);
+            taps
+        }).collect();
+
+        Self { ratio: sample_rate / clock_rate, position: 0.0, deltas: vec![0.0; TAPS], amplitude: 0.0, sum: 0.0, kernel }
+    }
+
+    // Input clock rate changes with the region
+    pub fn set_rates(&mut self, clock_rate: f64, sample_rate: f64) {
+        self.ratio = sample_rate / clock_rate;
+    }
+
+    // Input sample for one clock
+    pub fn push(&mut self, amplitude: f32) {
+        if amplitude != self.amplitude {
+            self.add_step(amplitude - self.amplitude);
+            self.amplitude = amplitude;
+        }
+        self.position += self.ratio;
+    }
+
+    fn add_step(&mut self, delta: f32) {
+        let whole = self.position.floor();
+        let phase = ((self.position - whole) * PHASES as f64).round() as usize;
+        let start = whole as usize;
+        if self.deltas.len() < start + TAPS {
+            self.deltas.resize(start + TAPS, 0.0);
+        }
+        for (sample, tap) in self.deltas[start..start + TAPS].iter_mut().zip(self.kernel[phase].iter()) {
+            *sample += delta * tap;
+        }
+    }
+
+    // Output samples waiting to be read
+    pub fn buffered(&self) -> usize {
+        self.position as usize
+    }
+
+    // Moves the output samples no later step can change anymore to `output`
+    pub fn read(&mut self, output: &mut Vec<f32>) {
+        let complete = self.position.floor() as usize;
+        if self.deltas.len() < complete {
+            self.deltas.resize(complete, 0.0);
+        }
+        for delta in self.deltas.drain(..complete) {
+            self.sum += delta;
+            output.push(self.sum);
+        }
+        self.position -= complete as f64;
+        if self.deltas.len() < TAPS {
+            self.deltas.resize(TAPS, 0.0);
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    pub fn steps_settle_at_their_height() {
+        let mut resampler = Resampler::new(1_789_773.0, 44_100.0);
+        let mut output = Vec::new();
+        for i in 0..1_789_773 / 10 {
+            resampler.push(if i < 1000 { 0.0 } else { 0.5 });
+        }
+        resampler.read(&mut output);
+        assert!((4409..=4411).contains(&output.len()));
+        assert!((output[output.len() - 1] - 0.5).abs() < 0.0001);
+        assert_eq!(output[0], 0.0);
+        // Band-limited, so the step rings a little around its edge
+        assert!(output.iter().any(|sample| *sample > 0.5));
+    }
+
+}
@@ -0,0 +1,80 @@
+use crate::apu::length_counter::LengthCounter;
+
+// 32 step triangle, 15 down to 0 and back up
+const SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0,
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15
+];
+
+// Triangle channel, $4008 - $400B
+#[derive(Debug, Default, Clone)]
+pub struct Triangle {
+    // Timer is clocked every CPU cycle
+    timer_period: u16,
+    timer: u16,
+    step: u8,
+    pub length: LengthCounter,
+    // Linear counter, a second and finer length control
+    linear_counter: u8,
+    linear_reload_value: u8,
+    linear_reload: bool,
+    // Keeps reloading the linear counter, also halts the length counter
+    control: bool
+}
+
+impl Triangle {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn write(&mut self, register: u16, data: u8) {
+        match register & 0x03 {
+            0 => {
+                self.control = data & 0x80 != 0;
+                self.length.halt = self.control;
+                self.linear_reload_value = data & 0x7f;
+            },
+            1 => {},
+            2 => self.timer_period = (self.timer_period & 0x0700) | data as u16,
+            _ => {
+                self.timer_period = (self.timer_period & 0x00ff) | ((data & 0x07) as u16) << 8;
+                self.length.load(data);
+                self.linear_reload = true;
+            }
+        }
+    }
+
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            // The sequencer stops, holding its level, when either counter is zero
+            if self.length.active() && self.linear_counter > 0 {
+                self.step = (self.step + 1) & 0x1f;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length.clock();
+    }
+
+    // 0 - 15
+    pub fn output(&self) -> u8 {
+        SEQUENCE[self.step as usize]
+    }
+
+}
@@ -0,0 +1,175 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::apu::mixer::Channel;
+
+// Size of the RIFF and fmt headers up to the data chunk contents
+const HEADER_SIZE: u32 = 44;
+
+// Sample encoding of a WAV file
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SampleFormat {
+    Pcm16,
+    Float32
+}
+
+impl SampleFormat {
+
+    fn bytes(&self) -> u16 {
+        match self {
+            SampleFormat::Pcm16 => 2,
+            SampleFormat::Float32 => 4
+        }
+    }
+
+    // WAVE_FORMAT_PCM or WAVE_FORMAT_IEEE_FLOAT
+    fn tag(&self) -> u16 {
+        match self {
+            SampleFormat::Pcm16 => 1,
+            SampleFormat::Float32 => 3
+        }
+    }
+
+}
+
+// Mono WAV file written as samples come in, the chunk sizes are filled in by `finish`
+pub struct WavWriter<W: Write + Seek> {
+    writer: W,
+    format: SampleFormat,
+    samples: u32
+}
+
+impl WavWriter<BufWriter<File>> {
+
+    pub fn create<P: AsRef<Path>>(path: P, sample_rate: u32, format: SampleFormat) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?), sample_rate, format)
+    }
+
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+
+    pub fn new(mut writer: W, sample_rate: u32, format: SampleFormat) -> io::Result<Self> {
+        let block_align = format.bytes();
+        writer.write_all(b"RIFF")?;
+        // RIFF size, patched later
+        writer.write_all(&(HEADER_SIZE - 8).to_le_bytes())?;
+        writer.write_all(b"WAVEfmt ")?;
+        writer.write_all(&16u32.to_le_bytes())?;
+        writer.write_all(&format.tag().to_le_bytes())?;
+        // One channel
+        writer.write_all(&1u16.to_le_bytes())?;
+        writer.write_all(&sample_rate.to_le_bytes())?;
+        writer.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        writer.write_all(&block_align.to_le_bytes())?;
+        writer.write_all(&(block_align * 8).to_le_bytes())?;
+        writer.write_all(b"data")?;
+        writer.write_all(&0u32.to_le_bytes())?;
+        Ok(Self { writer, format, samples: 0 })
+    }
+
+    // Samples from -1.0 to 1.0, PCM clips anything outside
+    pub fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        for sample in samples {
+            match self.format {
+                SampleFormat::Pcm16 => {
+                    let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16;
+                    self.writer.write_all(&value.to_le_bytes())?;
+                },
+                SampleFormat::Float32 => self.writer.write_all(&sample.to_le_bytes())?
+            }
+        }
+        self.samples += samples.len() as u32;
+        Ok(())
+    }
+
+    pub fn samples_written(&self) -> u32 {
+        self.samples
+    }
+
+    // Fills in the chunk sizes and hands back the writer
+    pub fn finish(mut self) -> io::Result<W> {
+        let data_size = self.samples * self.format.bytes() as u32;
+        self.writer.seek(SeekFrom::Start(4))?;
+        self.writer.write_all(&(HEADER_SIZE - 8 + data_size).to_le_bytes())?;
+        self.writer.seek(SeekFrom::Start(HEADER_SIZE as u64 - 4))?;
+        self.writer.write_all(&data_size.to_le_bytes())?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+}
+
+// Records the mix and optionally every channel next to it, `game.wav` gets `game_pulse1.wav` and so on
+pub struct AudioRecorder {
+    mix: WavWriter<BufWriter<File>>,
+    channels: Vec<WavWriter<BufWriter<File>>>
+}
+
+impl AudioRecorder {
+
+    pub fn create<P: AsRef<Path>>(path: P, sample_rate: u32, format: SampleFormat, channels: bool) -> io::Result<Self> {
+        let path = path.as_ref();
+        let mix = WavWriter::create(path, sample_rate, format)?;
+        let channels = if channels {
+            Channel::ALL.iter().map(|channel| WavWriter::create(channel_path(path, *channel), sample_rate, format)).collect::<io::Result<_>>()?
+        } else {
+            Vec::new()
+        };
+        Ok(Self { mix, channels })
+    }
+
+    // Samples from `Nes::audio_samples` and `Nes::audio_channel_samples`, taken after every frame
+    pub fn record(&mut self, mix: &[f32], channels: &[Vec<f32>]) -> io::Result<()> {
+        self.mix.write_samples(mix)?;
+        for (writer, samples) in self.channels.iter_mut().zip(channels) {
+            writer.write_samples(samples)?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        self.mix.finish()?;
+        for writer in self.channels {
+            writer.finish()?;
+        }
+        Ok(())
+    }
+
+}
+
+fn channel_path(path: &Path, channel: Channel) -> PathBuf {
+    let stem = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+    path.with_file_name(format!("{}_{}.wav", stem, channel.name()))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    pub fn writes_pcm_and_float() {
+        let mut wav = WavWriter::new(Cursor::new(Vec::new()), 44_100, SampleFormat::Pcm16).unwrap();
+        wav.write_samples(&[0.0, 1.0, -2.0]).unwrap();
+        let data = wav.finish().unwrap().into_inner();
+        assert_eq!(data.len(), 50);
+        assert_eq!(&data[..4], b"RIFF");
+        assert_eq!(&data[4..8], &42u32.to_le_bytes());
+        assert_eq!(&data[24..28], &44_100u32.to_le_bytes());
+        assert_eq!(&data[40..44], &6u32.to_le_bytes());
+        assert_eq!(&data[44..], &[0x00, 0x00, 0xff, 0x7f, 0x01, 0x80]);
+
+        let mut wav = WavWriter::new(Cursor::new(Vec::new()), 48_000, SampleFormat::Float32).unwrap();
+        wav.write_samples(&[0.5]).unwrap();
+        let data = wav.finish().unwrap().into_inner();
+        assert_eq!(&data[20..22], &3u16.to_le_bytes());
+        assert_eq!(&data[34..36], &32u16.to_le_bytes());
+        assert_eq!(&data[44..], &0.5f32.to_le_bytes());
+    }
+
+}
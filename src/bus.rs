@@ -1,24 +1,134 @@
-const RAM_SIZE: usize = 64 * 1024;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::apu::register_log::RegisterLog;
+use crate::apu::Apu;
+use crate::cartridge::Cartridge;
+use crate::dma::Dma;
+use crate::input::controller::Controller;
+use crate::input::{Peripheral, Port};
+use crate::ppu::Ppu;
+
+const RAM_SIZE: usize = 2 * 1024;
 
 pub struct Bus {
-    // Fake ram (TODO: change for real ram later)
-    pub ram: [u8; RAM_SIZE]
+    // 2 KiB work RAM, mirrored up to $1FFF
+    pub ram: [u8; RAM_SIZE],
+    pub ppu: Ppu,
+    pub apu: Apu,
+    pub cartridge: Option<Rc<RefCell<Cartridge>>>,
+    pub dma: Dma,
+    // Devices in controller ports 1 and 2 and the expansion port, indexed by `Port`
+    pub ports: [Option<Box<dyn Peripheral>>; 3],
+    // CPU cycle and data of the last read of $4016 and $4017. Reads on back to back cycles keep
+    // the port's /OE low, so the controller only sees the first
+    port_reads: [Option<(u64, u8)>; 2],
+    // Sound register writes, while logging
+    pub register_log: Option<RegisterLog>,
+    // Last value seen on the data bus, read back from unmapped addresses
+    open_bus: u8,
+    // Address of the last CPU read, repeated while DMA halts the CPU
+    pub last_read: u16
 }
 
 impl Bus {
 
     pub fn new() -> Self {
         Self {
-            ram: [0x00; RAM_SIZE]
+            ram: [0x00; RAM_SIZE],
+            ppu: Ppu::new(),
+            apu: Apu::new(),
+            cartridge: None,
+            dma: Dma::new(),
+            ports: [Some(Box::new(Controller::new())), Some(Box::new(Controller::new())), None],
+            port_reads: [None; 2],
+            register_log: None,
+            open_bus: 0x00,
+            last_read: 0x0000
         }
     }
 
+    pub fn insert_cartridge(&mut self, cartridge: Cartridge) {
+        let cartridge = Rc::new(RefCell::new(cartridge));
+        self.ppu.connect_cartridge(cartridge.clone());
+        self.cartridge = Some(cartridge);
+    }
+
     pub fn write(&mut self, addr: u16, data: u8) {
-        self.ram[addr as usize] = data;
+        self.open_bus = data;
+        if let Some(log) = &mut self.register_log {
+            log.record_write(self.apu.cycles(), addr, data);
+        }
+        match addr {
+            0x0000..=0x1fff => self.ram[addr as usize & (RAM_SIZE - 1)] = data,
+            0x2000..=0x3fff => self.ppu.cpu_write(addr, data),
+            0x4000..=0x4013 | 0x4015 | 0x4017 => self.apu.write(addr, data),
+            0x4014 => self.dma.request_oam(data),
+            0x4016 => {
+                for device in self.ports.iter_mut().flatten() {
+                    device.write(data);
+                }
+            },
+            0x4020..=0xffff => {
+                if let Some(cartridge) = &self.cartridge {
+                    cartridge.borrow_mut().cpu_write(addr, data);
+                }
+            },
+            _ => {}
+        }
+    }
+
+    // Reads have side effects on PPU and mapper registers
+    pub fn read(&mut self, addr: u16) -> u8 {
+        self.last_read = addr;
+        self.dma_read(addr)
     }
-    
-    pub fn read(&self, addr: u16) -> u8 {
-        self.ram[addr as usize]
+
+    // Dummy and alignment cycles of the DMA unit repeat the read the CPU was halted on
+    pub fn repeat_read(&mut self) {
+        self.dma_read(self.last_read);
     }
-    
+
+    // Read by the DMA unit, the CPU keeps its halted address
+    pub fn dma_read(&mut self, addr: u16) -> u8 {
+        let data = match addr {
+            0x0000..=0x1fff => Some(self.ram[addr as usize & (RAM_SIZE - 1)]),
+            0x2000..=0x3fff => Some(self.ppu.cpu_read(addr)),
+            // Bit 5 is not driven
+            0x4015 => Some(self.apu.read_status() | (self.open_bus & 0x20)),
+            // Only the low bits are driven, the rest is whatever was last on the bus
+            0x4016 | 0x4017 => {
+                let port = addr as usize - 0x4016;
+                let cycle = self.apu.cycles();
+                let data = match self.port_reads[port] {
+                    Some((last, data)) if cycle <= last + 1 => data,
+                    _ => {
+                        let mut data = self.open_bus & 0xe0;
+                        if let Some(device) = &mut self.ports[port] {
+                            data |= device.read(addr, &self.ppu) & 0x19;
+                        }
+                        if let Some(device) = &mut self.ports[Port::Expansion as usize] {
+                            data |= device.read(addr, &self.ppu) & 0x1e;
+                        }
+                        data
+                    }
+                };
+                self.port_reads[port] = Some((cycle, data));
+                Some(data)
+            },
+            0x4020..=0xffff => self.cartridge.as_ref().and_then(|cartridge| cartridge.borrow_mut().cpu_read(addr)),
+            _ => None
+        };
+        self.open_bus = data.unwrap_or(self.open_bus);
+        self.open_bus
+    }
+
+}
+
+impl Default for Bus {
+
+    fn default() -> Self {
+        Self::new()
+    }
+
 }
@@ -0,0 +1,281 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use lazy_static::lazy_static;
+
+use crate::cartridge::hash;
+use crate::cartridge::{Mirroring, Region, RomImage};
+
+lazy_static! {
+
+    // Database shipped with the emulator, data/nes20db.xml
+    static ref BUNDLED: RomDatabase = RomDatabase::parse(include_str!("../../data/nes20db.xml"));
+}
+
+// Board description of a known dump, fields missing in the database are left as they are
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RomEntry {
+    pub sha1: Option<String>,
+    pub mapper: Option<u16>,
+    pub submapper: Option<u8>,
+    pub mirroring: Option<Mirroring>,
+    pub battery: Option<bool>,
+    pub prg_ram_size: Option<usize>,
+    pub prg_nvram_size: Option<usize>,
+    pub chr_ram_size: Option<usize>,
+    pub chr_nvram_size: Option<usize>,
+    pub region: Option<Region>
+}
+
+// Header field that differed from the database
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Correction {
+    pub field: &'static str,
+    pub header: String,
+    pub database: String
+}
+
+impl fmt::Display for Correction {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {} -> {}", self.field, self.header, self.database)
+    }
+
+}
+
+// Known dumps in the NES 2.0 XML format, keyed by the CRC-32 of PRG + CHR ROM
+#[derive(Default)]
+pub struct RomDatabase {
+    entries: HashMap<u32, RomEntry>
+}
+
+impl RomDatabase {
+
+    pub fn bundled() -> &'static RomDatabase {
+        &BUNDLED
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self::parse(&fs::read_to_string(path)?))
+    }
+
+    // Reads every <game> element, games without a <rom crc32> are skipped
+    pub fn parse(xml: &str) -> Self {
+        let xml = strip_comments(xml);
+        let mut entries = HashMap::new();
+        for game in elements(&xml, "game") {
+            let mut crc = None;
+            let mut entry = RomEntry::default();
+            for (tag, attributes) in tags(game) {
+                let attr = |name: &str| attributes.get(name).map(String::as_str);
+                match tag {
+                    "rom" => {
+                        crc = attr("crc32").and_then(|crc| u32::from_str_radix(crc, 16).ok());
+                        entry.sha1 = attr("sha1").map(str::to_uppercase);
+                    },
+                    "pcb" => {
+                        entry.mapper = attr("mapper").and_then(|v| v.parse().ok());
+                        entry.submapper = attr("submapper").and_then(|v| v.parse().ok());
+                        entry.battery = attr("battery").map(|v| v == "1");
+                        entry.mirroring = match attr("mirroring") {
+                            Some("H") => Some(Mirroring::Horizontal),
+                            Some("V") => Some(Mirroring::Vertical),
+                            Some("4") => Some(Mirroring::FourScreen),
+                            _ => None
+                        };
+                    },
+                    "prgram" => entry.prg_ram_size = attr("size").and_then(|v| v.parse().ok()),
+                    "prgnvram" => entry.prg_nvram_size = attr("size").and_then(|v| v.parse().ok()),
+                    "chrram" => entry.chr_ram_size = attr("size").and_then(|v| v.parse().ok()),
+                    "chrnvram" => entry.chr_nvram_size = attr("size").and_then(|v| v.parse().ok()),
+                    "console" => {
+                        entry.region = match attr("region") {
+                            Some("0") => Some(Region::Ntsc),
+                            Some("1") => Some(Region::Pal),
+                            Some("2") => Some(Region::Multi),
+                            Some("3") => Some(Region::Dendy),
+                            _ => None
+                        };
+                    },
+                    _ => {}
+                }
+            }
+            if let Some(crc) = crc {
+                entries.insert(crc, entry);
+            }
+        }
+        Self { entries }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn insert(&mut self, crc: u32, entry: RomEntry) {
+        self.entries.insert(crc, entry);
+    }
+
+    pub fn lookup(&self, prg_rom: &[u8], chr_rom: &[u8]) -> Option<&RomEntry> {
+        let crc = hash::crc32_update(hash::crc32(prg_rom), chr_rom);
+        let entry = self.entries.get(&crc)?;
+
+        // CRC-32 collisions are possible, SHA-1 settles it when present
+        if let Some(sha1) = &entry.sha1 {
+            let mut rom = prg_rom.to_vec();
+            rom.extend_from_slice(chr_rom);
+            if *sha1 != hash::to_hex(&hash::sha1(&rom)) {
+                return None;
+            }
+        }
+        Some(entry)
+    }
+
+    // Overrides header fields with the database entry, returns what was changed
+    pub fn correct(&self, image: &mut RomImage) -> Vec<Correction> {
+        let entry = match self.lookup(&image.prg_rom, &image.chr_rom) {
+            Some(entry) => entry.clone(),
+            None => return Vec::new()
+        };
+
+        let mut corrections = Vec::new();
+        correct(&mut corrections, "mapper", &mut image.mapper, entry.mapper);
+        correct(&mut corrections, "submapper", &mut image.submapper, entry.submapper);
+        correct(&mut corrections, "mirroring", &mut image.mirroring, entry.mirroring);
+        correct(&mut corrections, "battery", &mut image.battery, entry.battery);
+        correct(&mut corrections, "PRG RAM", &mut image.prg_ram_size, entry.prg_ram_size);
+        correct(&mut corrections, "PRG NVRAM", &mut image.prg_nvram_size, entry.prg_nvram_size);
+        correct(&mut corrections, "CHR RAM", &mut image.chr_ram_size, entry.chr_ram_size);
+        correct(&mut corrections, "CHR NVRAM", &mut image.chr_nvram_size, entry.chr_nvram_size);
+        correct(&mut corrections, "region", &mut image.region, entry.region);
+        corrections
+    }
+
+}
+
+fn correct<T: PartialEq + fmt::Debug>(corrections: &mut Vec<Correction>, field: &'static str, value: &mut T, database: Option<T>) {
+    if let Some(database) = database {
+        if *value != database {
+            corrections.push(Correction {
+                field,
+                header: format!("{:?}", value),
+                database: format!("{:?}", database)
+            });
+            *value = database;
+        }
+    }
+}
+
+fn strip_comments(xml: &str) -> String {
+    let mut output = String::with_capacity(xml.len());
+    let mut rest = xml;
+    while let Some(start) = rest.find("<!--") {
+        output.push_str(&rest[..start]);
+        rest = rest[start..].find("-->").map_or("", |end| &rest[start + end + 3..]);
+    }
+    output.push_str(rest);
+    output
+}
+
+// Bodies of all <name>...</name> elements, no nesting of the same element
+fn elements<'a>(xml: &'a str, name: &str) -> Vec<&'a str> {
+    let open = format!("<{}", name);
+    let close = format!("</{}>", name);
+    let mut bodies = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        let body_start = match rest[start..].find('>') {
+            Some(end) => start + end + 1,
+            None => break
+        };
+        let body_end = match rest[body_start..].find(&close) {
+            Some(end) => body_start + end,
+            None => break
+        };
+        bodies.push(&rest[body_start..body_end]);
+        rest = &rest[body_end + close.len()..];
+    }
+    bodies
+}
+
+// Name and attributes of every tag in `xml`, skipping closing tags
+fn tags(xml: &str) -> Vec<(&str, HashMap<String, String>)> {
+    let mut tags = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let end = match rest.find('>') {
+            Some(end) => end,
+            None => break
+        };
+        let tag = rest[..end].trim_end_matches('/');
+        rest = &rest[end + 1..];
+        if tag.starts_with('/') || tag.starts_with('?') {
+            continue;
+        }
+
+        let name_end = tag.find(char::is_whitespace).unwrap_or(tag.len());
+        let mut attributes = HashMap::new();
+        let mut attrs = &tag[name_end..];
+        while let Some(eq) = attrs.find('=') {
+            let key = attrs[..eq].trim().to_string();
+            let value_start = match attrs[eq + 1..].find('"') {
+                Some(quote) => eq + 1 + quote + 1,
+                None => break
+            };
+            let value_end = match attrs[value_start..].find('"') {
+                Some(quote) => value_start + quote,
+                None => break
+            };
+            attributes.insert(key, attrs[value_start..value_end].to_string());
+            attrs = &attrs[value_end + 1..];
+        }
+        tags.push((&tag[..name_end], attributes));
+    }
+    tags
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn image() -> RomImage {
+        RomImage::nrom(vec![0xea; 16 * 1024], false)
+    }
+
+    #[test]
+    pub fn corrects_header_from_database() {
+        let mut image = image();
+        let mut rom = image.prg_rom.clone();
+        rom.extend_from_slice(&image.chr_rom);
+        let xml = format!(r#"<nes20db>
+            <game>
+                <!-- Test <game> -->
+                <rom size="24576" crc32="{:08X}" sha1="{}"/>
+                <pcb mapper="4" submapper="0" mirroring="V" battery="1"/>
+                <prgnvram size="8192"/>
+                <console type="0" region="1"/>
+            </game>
+        </nes20db>"#, hash::crc32(&rom), hash::to_hex(&hash::sha1(&rom)));
+
+        let database = RomDatabase::parse(&xml);
+        assert_eq!(database.len(), 1);
+
+        let corrections = database.correct(&mut image);
+        assert_eq!(image.mapper, 4);
+        assert_eq!(image.mirroring, Mirroring::Vertical);
+        assert!(image.battery);
+        assert_eq!(image.prg_nvram_size, 8192);
+        assert_eq!(image.region, Region::Pal);
+        assert_eq!(corrections.len(), 5);
+        assert_eq!(corrections[0].to_string(), "mapper: 0 -> 4");
+    }
+
+}
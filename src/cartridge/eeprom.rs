@@ -0,0 +1,257 @@
+use crate::cartridge::mapper;
+
+// Serial EEPROMs found on Bandai FCG boards
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EepromChip {
+    // 128 bytes, 7 bit address sent LSB first without a device byte
+    X24C01,
+    // 256 bytes, standard I2C with device byte and word address
+    X24C02
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Mode {
+    Idle,
+    // First byte after a start condition
+    Command,
+    // 24C02 word address
+    Address,
+    Write,
+    Read
+}
+
+pub struct Eeprom {
+    chip: EepromChip,
+    data: Vec<u8>,
+    mode: Mode,
+    // Mode entered once the current byte has been acknowledged
+    next_mode: Mode,
+    scl: bool,
+    sda: bool,
+    shift: u8,
+    bit: u8,
+    address: u8,
+    output: bool
+}
+
+impl Eeprom {
+
+    pub fn new(chip: EepromChip) -> Self {
+        let size = match chip {
+            EepromChip::X24C01 => 128,
+            EepromChip::X24C02 => 256
+        };
+        Self {
+            chip,
+            data: vec![0x00; size],
+            mode: Mode::Idle,
+            next_mode: Mode::Idle,
+            scl: false,
+            sda: false,
+            shift: 0,
+            bit: 0,
+            address: 0,
+            output: true
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn load(&mut self, data: &[u8]) {
+        mapper::load_into(&mut self.data, data);
+    }
+
+    // Level the chip drives on SDA, the bus is pulled high when released
+    pub fn output(&self) -> bool {
+        self.output
+    }
+
+    // New levels of the clock and data lines as driven by the mapper
+    pub fn write(&mut self, scl: bool, sda: bool) {
+        if self.scl && scl && sda != self.sda {
+            if sda {
+                // Stop condition
+                self.mode = Mode::Idle;
+                self.output = true;
+            } else {
+                // Start condition
+                self.mode = Mode::Command;
+                self.bit = 0;
+                self.output = true;
+            }
+        } else if !self.scl && scl {
+            self.rising_edge(sda);
+        } else if self.scl && !scl {
+            self.falling_edge();
+        }
+        self.scl = scl;
+        self.sda = sda;
+    }
+
+    fn rising_edge(&mut self, sda: bool) {
+        match self.mode {
+            Mode::Idle => {},
+            Mode::Read => {
+                if self.bit < 8 {
+                    self.bit += 1;
+                } else {
+                    // Master acknowledge, a NAK ends the transfer
+                    self.bit = 0;
+                    if sda {
+                        self.mode = Mode::Idle;
+                    } else {
+                        self.address = self.address.wrapping_add(1) & self.address_mask();
+                    }
+                }
+            },
+            _ => {
+                if self.bit < 8 {
+                    self.shift = (self.shift << 1) | sda as u8;
+                    self.bit += 1;
+                } else {
+                    self.bit = 0;
+                    self.mode = self.next_mode;
+                }
+            }
+        }
+    }
+
+    fn falling_edge(&mut self) {
+        match self.mode {
+            Mode::Idle => {},
+            Mode::Read => {
+                self.output = if self.bit < 8 {
+                    let data = self.data[self.address as usize];
+                    let shift = if self.chip == EepromChip::X24C01 { self.bit } else { 7 - self.bit };
+                    (data >> shift) & 0x01 != 0
+                } else {
+                    true
+                };
+            },
+            _ => {
+                if self.bit == 8 {
+                    self.byte_received();
+                } else {
+                    self.output = true;
+                }
+            }
+        }
+    }
+
+    fn address_mask(&self) -> u8 {
+        (self.data.len() - 1) as u8
+    }
+
+    fn byte_received(&mut self) {
+        let byte = if self.chip == EepromChip::X24C01 { self.shift.reverse_bits() } else { self.shift };
+
+        // Acknowledge by pulling SDA low, a byte that is not for us is left unanswered
+        self.output = false;
+        self.next_mode = match (self.mode, self.chip) {
+            (Mode::Command, EepromChip::X24C01) => {
+                self.address = byte & 0x7f;
+                if byte & 0x80 != 0 { Mode::Read } else { Mode::Write }
+            },
+            (Mode::Command, EepromChip::X24C02) => {
+                if byte & 0xf0 != 0xa0 {
+                    self.output = true;
+                    Mode::Idle
+                } else if byte & 0x01 != 0 {
+                    Mode::Read
+                } else {
+                    Mode::Address
+                }
+            },
+            (Mode::Address, _) => {
+                self.address = byte;
+                Mode::Write
+            },
+            _ => {
+                self.data[self.address as usize] = byte;
+                // Writes wrap inside a 4 (24C01) or 8 (24C02) byte page
+                let page_mask = if self.chip == EepromChip::X24C01 { 0x03 } else { 0x07 };
+                self.address = (self.address & !page_mask) | (self.address.wrapping_add(1) & page_mask);
+                Mode::Write
+            }
+        };
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn start(eeprom: &mut Eeprom) {
+        eeprom.write(false, true);
+        eeprom.write(true, true);
+        eeprom.write(true, false);
+        eeprom.write(false, false);
+    }
+
+    fn stop(eeprom: &mut Eeprom) {
+        eeprom.write(false, false);
+        eeprom.write(true, false);
+        eeprom.write(true, true);
+    }
+
+    // Clocks out one byte MSB first and returns whether it was acknowledged
+    fn send(eeprom: &mut Eeprom, byte: u8) -> bool {
+        for i in (0..8).rev() {
+            let bit = (byte >> i) & 0x01 != 0;
+            eeprom.write(false, bit);
+            eeprom.write(true, bit);
+            eeprom.write(false, bit);
+        }
+        let ack = !eeprom.output();
+        eeprom.write(true, true);
+        eeprom.write(false, true);
+        ack
+    }
+
+    fn receive(eeprom: &mut Eeprom, ack: bool) -> u8 {
+        let mut byte = 0;
+        for _ in 0..8 {
+            byte = (byte << 1) | eeprom.output() as u8;
+            eeprom.write(true, true);
+            eeprom.write(false, true);
+        }
+        eeprom.write(false, !ack);
+        eeprom.write(true, !ack);
+        eeprom.write(false, !ack);
+        byte
+    }
+
+    #[test]
+    pub fn x24c02_write_then_read() {
+        let mut eeprom = Eeprom::new(EepromChip::X24C02);
+
+        start(&mut eeprom);
+        assert!(send(&mut eeprom, 0xa0));
+        assert!(send(&mut eeprom, 0x10));
+        assert!(send(&mut eeprom, 0x5a));
+        assert!(send(&mut eeprom, 0xc3));
+        stop(&mut eeprom);
+        assert_eq!(&eeprom.data()[0x10..0x12], &[0x5a, 0xc3]);
+
+        start(&mut eeprom);
+        assert!(send(&mut eeprom, 0xa0));
+        assert!(send(&mut eeprom, 0x10));
+        start(&mut eeprom);
+        assert!(send(&mut eeprom, 0xa1));
+        assert_eq!(receive(&mut eeprom, true), 0x5a);
+        assert_eq!(receive(&mut eeprom, false), 0xc3);
+        stop(&mut eeprom);
+    }
+
+    #[test]
+    pub fn x24c02_ignores_other_devices() {
+        let mut eeprom = Eeprom::new(EepromChip::X24C02);
+        start(&mut eeprom);
+        assert!(!send(&mut eeprom, 0x50));
+    }
+
+}
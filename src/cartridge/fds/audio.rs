@@ -0,0 +1,359 @@
+use crate::apu::expansion::ExpansionAudio;
+use crate::cartridge::nsf::ExpansionChip;
+
+// Modulation table entries, 4 resets the counter
+const MOD_ADJUSTMENTS: [i8; 8] = [0, 1, 2, 4, 0, -4, -2, -1];
+// $4089 master volume 2/2, 2/3, 2/4 and 2/5
+const MASTER_VOLUME: [f32; 4] = [1.0, 2.0 / 3.0, 2.0 / 4.0, 2.0 / 5.0];
+// Full wave at full volume is about 2.4 times as loud as an APU pulse
+const LEVEL: f32 = 2.4;
+
+// Volume or modulation envelope
+#[derive(Default)]
+struct Envelope {
+    disabled: bool,
+    increase: bool,
+    speed: u8,
+    gain: u8,
+    timer: u32
+}
+
+impl Envelope {
+
+    // $4080 / $4084
+    fn write(&mut self, data: u8) {
+        self.disabled = data & 0x80 != 0;
+        self.increase = data & 0x40 != 0;
+        self.speed = data & 0x3f;
+        if self.disabled {
+            self.gain = data & 0x3f;
+        }
+        self.timer = 0;
+    }
+
+    fn clock(&mut self, master_speed: u8) {
+        if self.disabled {
+            return;
+        }
+        self.timer += 1;
+        if self.timer < 8 * (self.speed as u32 + 1) * master_speed as u32 {
+            return;
+        }
+        self.timer = 0;
+        if self.increase && self.gain < 32 {
+            self.gain += 1;
+        } else if !self.increase && self.gain > 0 {
+            self.gain -= 1;
+        }
+    }
+
+}
+
+// RAM adapter sound, a 64 step wavetable with frequency modulation
+pub struct FdsAudio {
+    // $4023 bit 1
+    pub enabled: bool,
+    wave_table: [u8; 64],
+    wave_write: bool,
+    wave_position: usize,
+    wave_accumulator: u16,
+    wave_halt: bool,
+    frequency: u16,
+    master_volume: usize,
+    envelopes_halted: bool,
+    envelope_speed: u8,
+    volume: Envelope,
+    modulation: Envelope,
+    mod_table: [u8; 64],
+    mod_position: usize,
+    mod_accumulator: u16,
+    mod_frequency: u16,
+    mod_halt: bool,
+    // 7 bit signed sweep bias
+    mod_counter: i8,
+    output: u8
+}
+
+impl FdsAudio {
+
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            wave_table: [0; 64],
+            wave_write: false,
+            wave_position: 0,
+            wave_accumulator: 0,
+            wave_halt: true,
+            frequency: 0,
+            master_volume: 0,
+            envelopes_halted: false,
+            envelope_speed: 0xe8,
+            volume: Envelope::default(),
+            modulation: Envelope::default(),
+            mod_table: [0; 64],
+            mod_position: 0,
+            mod_accumulator: 0,
+            mod_frequency: 0,
+            mod_halt: true,
+            mod_counter: 0,
+            output: 0
+        }
+    }
+
+    pub fn read(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4040..=0x407f => Some(if self.wave_write { self.wave_table[addr as usize - 0x4040] } else { self.wave_table[self.wave_position] } | 0x40),
+            0x4090 => Some(self.volume.gain | 0x40),
+            0x4092 => Some(self.modulation.gain | 0x40),
+            _ => None
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        if !self.enabled {
+            return;
+        }
+        match addr {
+            0x4040..=0x407f if self.wave_write => self.wave_table[addr as usize - 0x4040] = data & 0x3f,
+            0x4080 => self.volume.write(data),
+            0x4082 => self.frequency = (self.frequency & 0x0f00) | data as u16,
+            0x4083 => {
+                self.frequency = (self.frequency & 0x00ff) | ((data & 0x0f) as u16) << 8;
+                self.wave_halt = data & 0x80 != 0;
+                self.envelopes_halted = data & 0x40 != 0;
+                if self.wave_halt {
+                    self.wave_position = 0;
+                    self.wave_accumulator = 0;
+                }
+            },
+            0x4084 => self.modulation.write(data),
+            0x4085 => self.mod_counter = sign_extend_7(data),
+            0x4086 => self.mod_frequency = (self.mod_frequency & 0x0f00) | data as u16,
+            0x4087 => {
+                self.mod_frequency = (self.mod_frequency & 0x00ff) | ((data & 0x0f) as u16) << 8;
+                self.mod_halt = data & 0x80 != 0;
+                if self.mod_halt {
+                    self.mod_accumulator = 0;
+                }
+            },
+            // Table is only writable while modulation is halted, each entry fills two steps
+            0x4088 if self.mod_halt => {
+                self.mod_table[self.mod_position] = data & 0x07;
+                self.mod_table[(self.mod_position + 1) & 0x3f] = data & 0x07;
+                self.mod_position = (self.mod_position + 2) & 0x3f;
+            },
+            0x4089 => {
+                self.wave_write = data & 0x80 != 0;
+                self.master_volume = (data & 0x03) as usize;
+            },
+            0x408a => self.envelope_speed = data,
+            _ => {}
+        }
+    }
+
+    // Called once per CPU cycle
+    pub fn clock(&mut self) {
+        if !self.wave_halt && !self.envelopes_halted && self.envelope_speed != 0 {
+            self.volume.clock(self.envelope_speed);
+            self.modulation.clock(self.envelope_speed);
+        }
+
+        if !self.mod_halt && self.mod_frequency != 0 {
+            let (accumulator, overflow) = self.mod_accumulator.overflowing_add(self.mod_frequency);
+            self.mod_accumulator = accumulator;
+            if overflow {
+                let entry = self.mod_table[self.mod_position];
+                self.mod_counter = if entry == 4 {
+                    0
+                } else {
+                    sign_extend_7((self.mod_counter + MOD_ADJUSTMENTS[entry as usize]) as u8)
+                };
+                self.mod_position = (self.mod_position + 1) & 0x3f;
+            }
+        }
+
+        if self.wave_halt {
+            self.output = self.wave_table[0];
+            return;
+        }
+
+        let pitch = self.frequency as i32 + self.modulation_offset();
+        if pitch > 0 && !self.wave_write {
+            let (accumulator, overflow) = self.wave_accumulator.overflowing_add(pitch as u16);
+            self.wave_accumulator = accumulator;
+            if overflow {
+                self.wave_position = (self.wave_position + 1) & 0x3f;
+            }
+        }
+
+        // Output is held while the wave table is writable
+        if !self.wave_write {
+            self.output = self.wave_table[self.wave_position];
+        }
+    }
+
+    // Pitch change caused by the modulator, as documented for the 2C33
+    fn modulation_offset(&self) -> i32 {
+        if self.mod_halt {
+            return 0;
+        }
+        let mut temp = self.mod_counter as i32 * self.modulation.gain as i32;
+        let remainder = temp & 0x0f;
+        temp >>= 4;
+        if remainder > 0 && temp & 0x80 == 0 {
+            temp += if self.mod_counter < 0 { -1 } else { 2 };
+        }
+        if temp >= 192 {
+            temp -= 256;
+        } else if temp < -64 {
+            temp += 256;
+        }
+        temp *= self.frequency as i32;
+        let remainder = temp & 0x3f;
+        temp >>= 6;
+        if remainder >= 32 {
+            temp += 1;
+        }
+        temp
+    }
+
+    // Unipolar output in APU pulse units, see `ExpansionAudio`
+    pub fn output(&self) -> f32 {
+        if !self.enabled {
+            return 0.0;
+        }
+        let gain = self.volume.gain.min(32) as f32;
+        self.output as f32 * gain * MASTER_VOLUME[self.master_volume] / (63.0 * 32.0) * LEVEL
+    }
+
+}
+
+impl ExpansionAudio for FdsAudio {
+
+    fn write(&mut self, addr: u16, data: u8) {
+        FdsAudio::write(self, addr, data);
+    }
+
+    fn read(&mut self, addr: u16) -> Option<u8> {
+        FdsAudio::read(self, addr)
+    }
+
+    fn clock(&mut self) {
+        FdsAudio::clock(self);
+    }
+
+    fn output(&self) -> f32 {
+        FdsAudio::output(self)
+    }
+
+    fn chip(&self) -> ExpansionChip {
+        ExpansionChip::Fds
+    }
+
+}
+
+impl Default for FdsAudio {
+
+    fn default() -> Self {
+        Self::new()
+    }
+
+}
+
+fn sign_extend_7(data: u8) -> i8 {
+    ((data << 1) as i8) >> 1
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // Square wave, full volume without envelope
+    fn audio() -> FdsAudio {
+        let mut audio = FdsAudio::new();
+        audio.enabled = true;
+        audio.write(0x4089, 0x80);
+        for i in 0..64 {
+            audio.write(0x4040 + i, if i < 32 { 0x3f } else { 0x00 });
+        }
+        audio.write(0x4089, 0x00);
+        audio.write(0x4080, 0x80 | 0x20);
+        audio
+    }
+
+    #[test]
+    pub fn plays_wave_table() {
+        let mut audio = audio();
+        assert_eq!(audio.read(0x4090), Some(0x60));
+        // Frequency $800 steps through the table every 32 cycles
+        audio.write(0x4082, 0x00);
+        audio.write(0x4083, 0x08);
+        audio.clock();
+        assert_eq!(audio.output(), LEVEL);
+        for _ in 0..32 * 32 {
+            audio.clock();
+        }
+        assert_eq!(audio.output(), 0.0);
+        assert_eq!(audio.read(0x4040), Some(0x40));
+
+        audio.write(0x4089, 0x03);
+        for _ in 0..32 * 32 {
+            audio.clock();
+        }
+        assert_eq!(audio.output(), LEVEL * 2.0 / 5.0);
+
+        // Writes need $4023 bit 1
+        audio.enabled = false;
+        audio.write(0x4089, 0x00);
+        assert_eq!(audio.output(), 0.0);
+        audio.enabled = true;
+        assert_eq!(audio.output(), LEVEL * 2.0 / 5.0);
+    }
+
+    #[test]
+    pub fn volume_envelope() {
+        let mut audio = audio();
+        audio.write(0x4083, 0x08);
+        // Decreasing at speed 0, one step per 8 * $E8 cycles
+        audio.write(0x4080, 0x00);
+        assert_eq!(audio.read(0x4090), Some(0x60));
+        for _ in 0..8 * 0xe8 {
+            audio.clock();
+        }
+        assert_eq!(audio.read(0x4090), Some(0x5f));
+        // Halting the envelopes through $4083
+        audio.write(0x4083, 0x48);
+        for _ in 0..8 * 0xe8 {
+            audio.clock();
+        }
+        assert_eq!(audio.read(0x4090), Some(0x5f));
+    }
+
+    #[test]
+    pub fn modulation() {
+        let mut audio = audio();
+        audio.write(0x4082, 0x00);
+        audio.write(0x4083, 0x01);
+        audio.write(0x4084, 0x80 | 0x20);
+        // Table of +1 steps, only writable while halted
+        audio.write(0x4087, 0x80);
+        for _ in 0..32 {
+            audio.write(0x4088, 0x01);
+        }
+        audio.write(0x4085, 0x01);
+        assert_eq!(audio.modulation_offset(), 0);
+        audio.write(0x4086, 0xff);
+        audio.write(0x4087, 0x0f);
+        // Bias 1 at gain 32 raises frequency $100 by 8
+        assert_eq!(audio.modulation_offset(), 8);
+        audio.write(0x4088, 0x04);
+        for _ in 0..50 {
+            audio.clock();
+        }
+        assert_eq!(audio.mod_counter, 4);
+        assert_eq!(audio.read(0x4092), Some(0x60));
+        assert_eq!(sign_extend_7(0x7f), -1);
+    }
+
+}
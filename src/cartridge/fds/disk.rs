@@ -0,0 +1,183 @@
+use crate::cartridge::CartridgeError;
+
+// Side size in .fds files, block data only
+pub const FDS_SIDE_SIZE: usize = 65500;
+// Side size in QD images, block data with CRCs
+pub const QD_SIDE_SIZE: usize = 65536;
+const FDS_HEADER_SIZE: usize = 16;
+
+// Gaps in front of the first block and between blocks, in bytes
+const LEADING_GAP: usize = 28300 / 8;
+const BLOCK_GAP: usize = 976 / 8;
+// Written by the drive in front of every block
+const BLOCK_START: u8 = 0x80;
+
+// Disk image as stored in a file, either .fds (optionally with fwNES header) or QD
+pub enum DiskFormat {
+    Fds,
+    Qd
+}
+
+pub fn is_disk_image(data: &[u8]) -> bool {
+    data.starts_with(b"FDS\x1a") || data.get(0..15) == Some(b"\x01*NINTENDO-HVC*")
+}
+
+// Splits a disk image into the sides in .fds layout, without header or CRCs
+pub fn split_sides(data: &[u8]) -> Result<Vec<Vec<u8>>, CartridgeError> {
+    let data = if data.starts_with(b"FDS\x1a") { &data[FDS_HEADER_SIZE.min(data.len())..] } else { data };
+    let format = if !data.is_empty() && data.len() % QD_SIDE_SIZE == 0 && data.len() % FDS_SIDE_SIZE != 0 {
+        DiskFormat::Qd
+    } else {
+        DiskFormat::Fds
+    };
+
+    let side_size = match format {
+        DiskFormat::Fds => FDS_SIDE_SIZE,
+        DiskFormat::Qd => QD_SIDE_SIZE
+    };
+    if data.len() < side_size {
+        return Err(CartridgeError::Truncated);
+    }
+
+    Ok(data.chunks(side_size)
+        .filter(|side| side.len() == side_size)
+        .map(|side| match format {
+            DiskFormat::Fds => side.to_vec(),
+            DiskFormat::Qd => strip_crcs(side)
+        })
+        .collect())
+}
+
+// Length of the block starting with `block_type`, file data length comes from the preceding header block
+fn block_length(block_type: u8, file_size: usize) -> Option<usize> {
+    match block_type {
+        1 => Some(56),
+        2 => Some(2),
+        3 => Some(16),
+        4 => Some(1 + file_size),
+        _ => None
+    }
+}
+
+// Walks the blocks of a side in .fds layout, calling `block` for each of them
+fn for_each_block<F: FnMut(&[u8])>(side: &[u8], crc_size: usize, mut block: F) {
+    let mut pos = 0;
+    let mut file_size = 0;
+    while let Some(len) = side.get(pos).and_then(|t| block_length(*t, file_size)) {
+        let data = match side.get(pos..pos + len) {
+            Some(data) => data,
+            None => break
+        };
+        if data[0] == 3 {
+            file_size = data[13] as usize | (data[14] as usize) << 8;
+        }
+        block(data);
+        pos += len + crc_size;
+    }
+}
+
+fn strip_crcs(side: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(FDS_SIDE_SIZE);
+    for_each_block(side, 2, |block| output.extend_from_slice(block));
+    output.resize(FDS_SIDE_SIZE, 0);
+    output
+}
+
+// Expands a side to the bit stream seen by the drive head: gaps, start marks and CRCs
+pub fn encode_side(side: &[u8]) -> Vec<u8> {
+    let mut raw = vec![0x00; LEADING_GAP];
+    for_each_block(side, 0, |block| {
+        let start = raw.len();
+        raw.push(BLOCK_START);
+        raw.extend_from_slice(block);
+        let crc = crc(&raw[start..]);
+        raw.extend_from_slice(&crc.to_le_bytes());
+        raw.resize(raw.len() + BLOCK_GAP, 0x00);
+    });
+    raw.resize(raw.len().max(FDS_SIDE_SIZE + LEADING_GAP), 0x00);
+    raw
+}
+
+// Recovers the .fds layout from a (possibly rewritten) bit stream
+pub fn decode_side(raw: &[u8]) -> Vec<u8> {
+    let mut side = Vec::with_capacity(FDS_SIDE_SIZE);
+    let mut pos = 0;
+    let mut file_size = 0;
+    loop {
+        // Skip the gap up to the next start mark
+        while pos < raw.len() && raw[pos] != BLOCK_START {
+            pos += 1;
+        }
+        pos += 1;
+
+        let len = match raw.get(pos).and_then(|t| block_length(*t, file_size)) {
+            Some(len) => len,
+            None => break
+        };
+        let block = match raw.get(pos..pos + len) {
+            Some(block) => block,
+            None => break
+        };
+        if block[0] == 3 {
+            file_size = block[13] as usize | (block[14] as usize) << 8;
+        }
+        side.extend_from_slice(block);
+        pos += len + 2;
+    }
+    side.resize(FDS_SIDE_SIZE, 0x00);
+    side
+}
+
+// CRC as computed by the RAM adapter, bytes are fed LSB first
+pub fn update_crc(crc: u16, data: u8) -> u16 {
+    let mut crc = crc;
+    for bit in 0..8 {
+        let carry = crc & 0x01 != 0;
+        crc >>= 1;
+        if carry {
+            crc ^= 0x8408;
+        }
+        if data & (1 << bit) != 0 {
+            crc ^= 0x8000;
+        }
+    }
+    crc
+}
+
+// CRC of a block including its start mark; appending it makes the running CRC zero
+pub fn crc(data: &[u8]) -> u16 {
+    data.iter().chain(&[0x00, 0x00]).fold(0, |crc, byte| update_crc(crc, *byte))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn side() -> Vec<u8> {
+        let mut side = vec![0x01];
+        side.extend_from_slice(b"*NINTENDO-HVC*");
+        side.resize(56, 0x00);
+        side.extend_from_slice(&[0x02, 0x01]);
+        let mut header = vec![0x03, 0x00, 0x00, b'F', b'I', b'L', b'E', b' ', b' ', b' ', b' ', 0x00, 0x60, 0x04, 0x00, 0x00];
+        side.append(&mut header);
+        side.extend_from_slice(&[0x04, 1, 2, 3, 4]);
+        side.resize(FDS_SIDE_SIZE, 0x00);
+        side
+    }
+
+    #[test]
+    pub fn encode_decode_round_trip() {
+        let side = side();
+        assert_eq!(decode_side(&encode_side(&side)), side);
+    }
+
+    #[test]
+    pub fn appended_crc_clears_running_crc() {
+        let raw = encode_side(&side());
+        let start = LEADING_GAP;
+        let end = start + 1 + 56 + 2;
+        assert_eq!(raw[start..end].iter().fold(0, |crc, byte| update_crc(crc, *byte)), 0);
+    }
+
+}
@@ -0,0 +1,469 @@
+pub mod audio;
+pub mod disk;
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use crate::apu::expansion::{ExpansionAudio, ExpansionLevels};
+use crate::cartridge::mapper::Mapper;
+use crate::cartridge::nsf::ExpansionChip;
+use crate::cartridge::patch::ips;
+use crate::cartridge::{CartridgeError, Mirroring};
+use audio::FdsAudio;
+
+pub const BIOS_SIZE: usize = 8 * 1024;
+const PRG_RAM_SIZE: usize = 32 * 1024;
+const CHR_RAM_SIZE: usize = 8 * 1024;
+
+// CPU cycles per byte passing the head, and from motor start to the first byte
+const BYTE_CYCLES: u32 = 150;
+const SPIN_UP_CYCLES: u32 = 50000;
+
+// Famicom Disk System RAM adapter and disk drive
+pub struct Fds {
+    bios: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr_ram: Vec<u8>,
+    // Sides as loaded, in .fds layout, used as base for the save diff
+    original: Vec<Vec<u8>>,
+    // Sides as the head sees them, including gaps and CRCs
+    sides: Vec<Vec<u8>>,
+    // Save data, the IPS patch of `sides` against `original`; None until it is asked for
+    // after the drive wrote to the disk, building it means decoding every side
+    patch: RefCell<Option<Vec<u8>>>,
+    side: Option<usize>,
+    // $4020 - $4022 timer IRQ
+    timer_reload: u16,
+    timer_counter: u16,
+    timer_repeat: bool,
+    timer_enabled: bool,
+    timer_irq: bool,
+    // $4023
+    disk_registers_enabled: bool,
+    // $4025
+    motor_on: bool,
+    reset_transfer: bool,
+    read_mode: bool,
+    mirroring: Mirroring,
+    crc_control: bool,
+    transfer_enabled: bool,
+    disk_irq_enabled: bool,
+    // Drive state
+    position: usize,
+    delay: u32,
+    end_of_head: bool,
+    scanning: bool,
+    gap_ended: bool,
+    previous_crc_control: bool,
+    crc: u16,
+    read_data: u8,
+    write_data: u8,
+    transfer_complete: bool,
+    disk_irq: bool,
+    external_write: u8,
+    audio: FdsAudio
+}
+
+impl Fds {
+
+    pub fn new(disk: &[u8], bios: &[u8]) -> Result<Self, CartridgeError> {
+        if bios.len() != BIOS_SIZE {
+            return Err(CartridgeError::InvalidBios);
+        }
+        let original = disk::split_sides(disk)?;
+        Ok(Self {
+            bios: bios.to_vec(),
+            prg_ram: vec![0x00; PRG_RAM_SIZE],
+            chr_ram: vec![0x00; CHR_RAM_SIZE],
+            sides: original.iter().map(|side| disk::encode_side(side)).collect(),
+            original,
+            patch: RefCell::new(None),
+            side: Some(0),
+            timer_reload: 0,
+            timer_counter: 0,
+            timer_repeat: false,
+            timer_enabled: false,
+            timer_irq: false,
+            disk_registers_enabled: false,
+            motor_on: false,
+            reset_transfer: false,
+            read_mode: true,
+            mirroring: Mirroring::Vertical,
+            crc_control: false,
+            transfer_enabled: false,
+            disk_irq_enabled: false,
+            position: 0,
+            delay: 0,
+            end_of_head: true,
+            scanning: false,
+            gap_ended: false,
+            previous_crc_control: false,
+            crc: 0,
+            read_data: 0,
+            write_data: 0,
+            transfer_complete: false,
+            disk_irq: false,
+            external_write: 0,
+            audio: FdsAudio::new()
+        })
+    }
+
+    pub fn side_count(&self) -> usize {
+        self.sides.len()
+    }
+
+    pub fn current_side(&self) -> Option<usize> {
+        self.side
+    }
+
+    // Inserts side `side` (disk number * 2 + side B), false if the image has no such side
+    pub fn insert_disk(&mut self, side: usize) -> bool {
+        if side >= self.sides.len() {
+            return false;
+        }
+        self.side = Some(side);
+        self.end_of_head = true;
+        true
+    }
+
+    pub fn eject_disk(&mut self) {
+        self.side = None;
+    }
+
+    // All sides in .fds layout including everything the game has written
+    pub fn disk_image(&self) -> Vec<u8> {
+        self.sides.iter().flat_map(|raw| disk::decode_side(raw)).collect()
+    }
+
+    fn status(&mut self) -> u8 {
+        let status = self.timer_irq as u8
+            | (self.transfer_complete as u8) << 1
+            | ((self.crc != 0 && self.crc_control) as u8) << 4
+            | (self.end_of_head as u8) << 6
+            | (self.transfer_enabled as u8) << 7;
+        self.timer_irq = false;
+        self.transfer_complete = false;
+        self.disk_irq = false;
+        status
+    }
+
+    fn drive_status(&self) -> u8 {
+        let inserted = self.side.is_some();
+        // Bit 2 would report write protection, images are always writable
+        (!inserted as u8) | ((!inserted || !self.scanning) as u8) << 1 | 0x40
+    }
+
+    fn write_control(&mut self, data: u8) {
+        self.motor_on = data & 0x01 != 0;
+        self.reset_transfer = data & 0x02 != 0;
+        self.read_mode = data & 0x04 != 0;
+        self.mirroring = if data & 0x08 != 0 { Mirroring::Horizontal } else { Mirroring::Vertical };
+        self.crc_control = data & 0x10 != 0;
+        self.transfer_enabled = data & 0x40 != 0;
+        self.disk_irq_enabled = data & 0x80 != 0;
+        self.disk_irq = false;
+    }
+
+    fn clock_timer(&mut self) {
+        if !self.timer_enabled || !self.disk_registers_enabled {
+            return;
+        }
+        if self.timer_counter == 0 {
+            self.timer_irq = true;
+            self.timer_counter = self.timer_reload;
+            if !self.timer_repeat {
+                self.timer_enabled = false;
+            }
+        } else {
+            self.timer_counter -= 1;
+        }
+    }
+
+    fn clock_drive(&mut self) {
+        let side = match self.side {
+            Some(side) if self.motor_on => side,
+            _ => {
+                self.end_of_head = true;
+                self.scanning = false;
+                return;
+            }
+        };
+        if self.reset_transfer && !self.scanning {
+            return;
+        }
+        if self.end_of_head {
+            // Head returns to the start of the disk
+            self.delay = SPIN_UP_CYCLES;
+            self.end_of_head = false;
+            self.position = 0;
+            self.gap_ended = false;
+            return;
+        }
+        if self.delay > 0 {
+            self.delay -= 1;
+            return;
+        }
+
+        self.scanning = true;
+        let raise_irq = self.disk_irq_enabled;
+        if self.read_mode {
+            let data = self.sides[side][self.position];
+            if !self.previous_crc_control {
+                self.crc = disk::update_crc(self.crc, data);
+            }
+            if !self.transfer_enabled {
+                self.gap_ended = false;
+                self.crc = 0;
+            } else if data != 0 && !self.gap_ended {
+                // Start mark, the first byte handed to the CPU follows it
+                self.gap_ended = true;
+            } else if self.gap_ended {
+                self.read_data = data;
+                self.transfer_complete = true;
+                self.disk_irq |= raise_irq;
+            }
+        } else {
+            let mut data = 0x00;
+            if !self.crc_control {
+                self.transfer_complete = true;
+                self.disk_irq |= raise_irq;
+                data = self.write_data;
+            }
+            if !self.transfer_enabled {
+                data = 0x00;
+            }
+            if !self.crc_control {
+                self.crc = disk::update_crc(self.crc, data);
+            } else {
+                if !self.previous_crc_control {
+                    self.crc = disk::update_crc(disk::update_crc(self.crc, 0x00), 0x00);
+                }
+                data = self.crc as u8;
+                self.crc >>= 8;
+            }
+            self.sides[side][self.position] = data;
+            *self.patch.get_mut() = None;
+            self.gap_ended = false;
+        }
+        self.previous_crc_control = self.crc_control;
+
+        self.position += 1;
+        if self.position >= self.sides[side].len() {
+            self.motor_on = false;
+            self.end_of_head = true;
+        } else {
+            self.delay = BYTE_CYCLES;
+        }
+    }
+
+}
+
+impl Mapper for Fds {
+
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4030 if self.disk_registers_enabled => Some(self.status()),
+            0x4031 if self.disk_registers_enabled => {
+                self.transfer_complete = false;
+                self.disk_irq = false;
+                Some(self.read_data)
+            },
+            0x4032 if self.disk_registers_enabled => Some(self.drive_status()),
+            // Expansion port, bit 7 reports a good battery
+            0x4033 if self.disk_registers_enabled => Some(0x80 | (self.external_write & 0x7f)),
+            0x4040..=0x409f => self.audio.read(addr),
+            0x6000..=0xdfff => Some(self.prg_ram[addr as usize - 0x6000]),
+            0xe000..=0xffff => Some(self.bios[addr as usize - 0xe000]),
+            _ => None
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4020 => self.timer_reload = (self.timer_reload & 0xff00) | data as u16,
+            0x4021 => self.timer_reload = (self.timer_reload & 0x00ff) | (data as u16) << 8,
+            0x4022 if self.disk_registers_enabled => {
+                self.timer_repeat = data & 0x01 != 0;
+                self.timer_enabled = data & 0x02 != 0;
+                self.timer_counter = self.timer_reload;
+                if !self.timer_enabled {
+                    self.timer_irq = false;
+                }
+            },
+            0x4023 => {
+                self.disk_registers_enabled = data & 0x01 != 0;
+                self.audio.enabled = data & 0x02 != 0;
+                if !self.disk_registers_enabled {
+                    self.timer_enabled = false;
+                    self.timer_irq = false;
+                }
+            },
+            0x4024 if self.disk_registers_enabled => {
+                self.write_data = data;
+                self.transfer_complete = false;
+                self.disk_irq = false;
+            },
+            0x4025 if self.disk_registers_enabled => self.write_control(data),
+            0x4026 if self.disk_registers_enabled => self.external_write = data,
+            0x4040..=0x408a => self.audio.write(addr, data),
+            0x6000..=0xdfff => self.prg_ram[addr as usize - 0x6000] = data,
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr_ram[addr as usize & (CHR_RAM_SIZE - 1)]
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        self.chr_ram[addr as usize & (CHR_RAM_SIZE - 1)] = data;
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn clock_cpu(&mut self) {
+        self.clock_timer();
+        self.clock_drive();
+        self.audio.clock();
+    }
+
+    fn irq(&self) -> bool {
+        self.timer_irq || self.disk_irq
+    }
+
+    fn audio_output(&self, levels: &mut ExpansionLevels) {
+        self.audio.add_output(levels);
+    }
+
+    fn expansion_chips(&self) -> u8 {
+        ExpansionChip::Fds as u8
+    }
+
+    // Writes to the disk are kept as IPS patch against the loaded image
+    fn save_data(&self) -> Option<Cow<'_, [u8]>> {
+        let mut patch = self.patch.borrow_mut();
+        let patch = patch.get_or_insert_with(|| ips::create(&self.original.concat(), &self.disk_image()));
+        Some(Cow::Owned(patch.clone()))
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        // A damaged diff leaves the disk as loaded
+        if let Ok(image) = ips::apply(data, &self.original.concat()) {
+            let sides = image.chunks(disk::FDS_SIDE_SIZE).map(disk::encode_side);
+            self.sides = sides.take(self.original.len()).collect();
+            *self.patch.get_mut() = None;
+        }
+    }
+
+    fn save_is_patch(&self) -> bool {
+        true
+    }
+
+    fn fds(&mut self) -> Option<&mut Fds> {
+        Some(self)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // One side with the disk info and file amount blocks
+    fn fds() -> Fds {
+        let mut side = vec![0x01];
+        side.extend_from_slice(b"*NINTENDO-HVC*");
+        side.resize(56, 0x00);
+        side.extend_from_slice(&[0x02, 0x00]);
+        side.resize(disk::FDS_SIDE_SIZE, 0x00);
+        let mut fds = Fds::new(&side, &[0x00; BIOS_SIZE]).unwrap();
+        fds.cpu_write(0x4023, 0x01);
+        fds
+    }
+
+    // Clocks until an IRQ, returns the cycles it took
+    fn cycles_to_irq(fds: &mut Fds, limit: u32) -> Option<u32> {
+        (1..=limit).find(|_| {
+            fds.clock_cpu();
+            fds.irq()
+        })
+    }
+
+    #[test]
+    pub fn timer_irq() {
+        let mut fds = fds();
+        fds.cpu_write(0x4020, 0x02);
+        fds.cpu_write(0x4021, 0x00);
+        fds.cpu_write(0x4022, 0x02);
+        assert_eq!(cycles_to_irq(&mut fds, 10), Some(3));
+        // $4030 reports and acknowledges it, without repeat the timer stops
+        assert_eq!(fds.cpu_read(0x4030).unwrap() & 0x01, 0x01);
+        assert!(!fds.irq());
+        assert_eq!(cycles_to_irq(&mut fds, 10), None);
+
+        fds.cpu_write(0x4022, 0x03);
+        assert_eq!(cycles_to_irq(&mut fds, 10), Some(3));
+        fds.cpu_read(0x4030);
+        assert_eq!(cycles_to_irq(&mut fds, 10), Some(3));
+
+        // Disabling the disk registers stops the timer and hides $4030 - $4033
+        fds.cpu_write(0x4023, 0x00);
+        assert!(!fds.irq());
+        assert_eq!(fds.cpu_read(0x4030), None);
+        fds.cpu_write(0x4022, 0x02);
+        assert_eq!(cycles_to_irq(&mut fds, 10), None);
+    }
+
+    #[test]
+    pub fn control_register() {
+        let mut fds = fds();
+        fds.cpu_write(0x4025, 0x08);
+        assert_eq!(fds.mirroring(), Mirroring::Horizontal);
+        fds.cpu_write(0x4025, 0x00);
+        assert_eq!(fds.mirroring(), Mirroring::Vertical);
+        // Not scanning, then no disk
+        assert_eq!(fds.cpu_read(0x4032), Some(0x42));
+        fds.eject_disk();
+        assert_eq!(fds.cpu_read(0x4032), Some(0x43));
+        assert!(!fds.insert_disk(1));
+        assert!(fds.insert_disk(0));
+    }
+
+    #[test]
+    pub fn reads_blocks_with_transfer_irqs() {
+        let mut fds = fds();
+        // Motor on, read mode, transfer and its IRQ enabled
+        fds.cpu_write(0x4025, 0xc5);
+        assert!(cycles_to_irq(&mut fds, 1_000_000).is_some());
+        assert_eq!(fds.cpu_read(0x4032), Some(0x40));
+        assert_eq!(fds.cpu_read(0x4031), Some(0x01));
+        assert!(!fds.irq());
+        assert_eq!(cycles_to_irq(&mut fds, 1000), Some(BYTE_CYCLES + 1));
+        // Status has the transfer flag, reading it acknowledges
+        assert_eq!(fds.cpu_read(0x4030).unwrap() & 0x02, 0x02);
+        assert!(!fds.irq());
+        assert_eq!(fds.cpu_read(0x4031), Some(b'*'));
+    }
+
+    #[test]
+    pub fn save_is_rebuilt_after_writes() {
+        let mut fds = fds();
+        let unchanged = fds.save_data().unwrap().into_owned();
+        assert!(fds.patch.borrow().is_some());
+        assert_eq!(fds.save_data().unwrap(), unchanged);
+
+        // Motor on, write mode with transfer enabled: zeros over the start of the disk
+        fds.cpu_write(0x4024, 0x00);
+        fds.cpu_write(0x4025, 0x41);
+        for _ in 0..SPIN_UP_CYCLES + 4000 * (BYTE_CYCLES + 1) {
+            fds.clock_cpu();
+        }
+        assert!(fds.patch.borrow().is_none());
+        assert_ne!(fds.save_data().unwrap(), unchanged);
+        assert_eq!(fds.disk_image()[0], 0x00);
+    }
+
+}
@@ -0,0 +1,154 @@
+use crate::cartridge::mapper;
+
+const SECTOR_SIZE: usize = 4 * 1024;
+
+// SST39SF0x0 manufacturer and device id
+const MANUFACTURER_ID: u8 = 0xbf;
+const DEVICE_ID: u8 = 0xb7;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum State {
+    Ready,
+    // Number of unlock cycles seen ($AA to $5555, $55 to $2AAA)
+    Unlock1,
+    Unlock2,
+    Program,
+    EraseReady,
+    EraseUnlock1,
+    EraseUnlock2,
+    SoftwareId
+}
+
+// SST39SF0x0 style NOR flash used for self-writable PRG ROM
+pub struct Flash {
+    data: Vec<u8>,
+    state: State
+}
+
+impl Flash {
+
+    pub fn new(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            state: State::Ready
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn load(&mut self, data: &[u8]) {
+        mapper::load_into(&mut self.data, data);
+    }
+
+    // Read at an address inside the chip
+    pub fn read(&self, addr: usize) -> u8 {
+        if self.state == State::SoftwareId {
+            return if addr & 0x01 == 0 { MANUFACTURER_ID } else { DEVICE_ID };
+        }
+        self.data[addr % self.data.len()]
+    }
+
+    // Write at an address inside the chip, only the low 15 bits take part in command decoding
+    pub fn write(&mut self, addr: usize, data: u8) {
+        let command_addr = addr & 0x7fff;
+        self.state = match (self.state, command_addr, data) {
+            (State::Program, _, _) => {
+                // Programming can only clear bits
+                let len = self.data.len();
+                self.data[addr % len] &= data;
+                State::Ready
+            },
+            (State::Ready, 0x5555, 0xaa) => State::Unlock1,
+            (State::Unlock1, 0x2aaa, 0x55) => State::Unlock2,
+            (State::Unlock2, 0x5555, 0xa0) => State::Program,
+            (State::Unlock2, 0x5555, 0x80) => State::EraseReady,
+            (State::Unlock2, 0x5555, 0x90) => State::SoftwareId,
+            (State::EraseReady, 0x5555, 0xaa) => State::EraseUnlock1,
+            (State::EraseUnlock1, 0x2aaa, 0x55) => State::EraseUnlock2,
+            (State::EraseUnlock2, 0x5555, 0x10) => {
+                self.data.iter_mut().for_each(|byte| *byte = 0xff);
+                State::Ready
+            },
+            (State::EraseUnlock2, _, 0x30) => {
+                let start = (addr % self.data.len()) & !(SECTOR_SIZE - 1);
+                let end = (start + SECTOR_SIZE).min(self.data.len());
+                self.data[start..end].iter_mut().for_each(|byte| *byte = 0xff);
+                State::Ready
+            },
+            (State::SoftwareId, _, 0xf0) => State::Ready,
+            (State::SoftwareId, _, _) => State::SoftwareId,
+            _ => State::Ready
+        };
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn command(flash: &mut Flash, command: u8) {
+        flash.write(0x5555, 0xaa);
+        flash.write(0x2aaa, 0x55);
+        flash.write(0x5555, command);
+    }
+
+    #[test]
+    pub fn programs_bytes_after_unlock() {
+        let mut flash = Flash::new(vec![0xff; 64 * 1024]);
+        // Without the unlock sequence writes are ignored
+        flash.write(0x1234, 0x00);
+        assert_eq!(flash.read(0x1234), 0xff);
+        // A wrong second cycle starts over
+        flash.write(0x5555, 0xaa);
+        flash.write(0x2aab, 0x55);
+        flash.write(0x5555, 0xa0);
+        flash.write(0x1234, 0x00);
+        assert_eq!(flash.read(0x1234), 0xff);
+
+        command(&mut flash, 0xa0);
+        flash.write(0x1234, 0x5a);
+        assert_eq!(flash.read(0x1234), 0x5a);
+        // One byte per command, and only ones can be cleared
+        flash.write(0x1235, 0x00);
+        assert_eq!(flash.read(0x1235), 0xff);
+        command(&mut flash, 0xa0);
+        flash.write(0x1234, 0xa5);
+        assert_eq!(flash.read(0x1234), 0x00);
+        // Command addresses only decode the low 15 bits
+        flash.write(0xd555, 0xaa);
+        flash.write(0xaaaa, 0x55);
+        flash.write(0xd555, 0xa0);
+        flash.write(0x9000, 0x12);
+        assert_eq!(flash.read(0x9000), 0x12);
+    }
+
+    #[test]
+    pub fn erases_sectors_and_chip() {
+        let mut flash = Flash::new(vec![0x00; 64 * 1024]);
+        command(&mut flash, 0x80);
+        flash.write(0x5555, 0xaa);
+        flash.write(0x2aaa, 0x55);
+        flash.write(0x1234, 0x30);
+        assert_eq!(flash.read(0x0fff), 0x00);
+        assert!(flash.data()[0x1000..0x2000].iter().all(|&byte| byte == 0xff));
+        assert_eq!(flash.read(0x2000), 0x00);
+
+        command(&mut flash, 0x80);
+        command(&mut flash, 0x10);
+        assert!(flash.data().iter().all(|&byte| byte == 0xff));
+    }
+
+    #[test]
+    pub fn software_id() {
+        let mut flash = Flash::new(vec![0x00; 64 * 1024]);
+        command(&mut flash, 0x90);
+        assert_eq!((flash.read(0x0000), flash.read(0x0001)), (MANUFACTURER_ID, DEVICE_ID));
+        flash.write(0x0000, 0xf0);
+        assert_eq!(flash.read(0x0000), 0x00);
+    }
+
+}
@@ -0,0 +1,175 @@
+use crate::cartridge::{CartridgeError, Mirroring, Region, RomImage};
+
+const HEADER_SIZE: usize = 16;
+const TRAINER_SIZE: usize = 512;
+const PRG_BANK_SIZE: usize = 16 * 1024;
+const CHR_BANK_SIZE: usize = 8 * 1024;
+
+pub fn is_ines(data: &[u8]) -> bool {
+    data.len() >= HEADER_SIZE && data[0..4] == [b'N', b'E', b'S', 0x1a]
+}
+
+// Parses an iNES or NES 2.0 file
+pub fn parse(data: &[u8]) -> Result<RomImage, CartridgeError> {
+
+    if !is_ines(data) {
+        return Err(CartridgeError::InvalidHeader);
+    }
+
+    let header = &data[0..HEADER_SIZE];
+    let nes2 = header[7] & 0x0c == 0x08;
+
+    let mut mapper = ((header[6] >> 4) | (header[7] & 0xf0)) as u16;
+    let mut submapper = 0;
+    let prg_rom_size;
+    let chr_rom_size;
+    let prg_ram_size;
+    let prg_nvram_size;
+    let chr_ram_size;
+    let chr_nvram_size;
+    let region;
+
+    if nes2 {
+        mapper |= ((header[8] & 0x0f) as u16) << 8;
+        submapper = header[8] >> 4;
+        prg_rom_size = rom_size(header[4], header[9] & 0x0f, PRG_BANK_SIZE)?;
+        chr_rom_size = rom_size(header[5], header[9] >> 4, CHR_BANK_SIZE)?;
+        prg_ram_size = ram_size(header[10] & 0x0f);
+        prg_nvram_size = ram_size(header[10] >> 4);
+        chr_ram_size = ram_size(header[11] & 0x0f);
+        chr_nvram_size = ram_size(header[11] >> 4);
+        region = match header[12] & 0x03 {
+            0 => Region::Ntsc,
+            1 => Region::Pal,
+            2 => Region::Multi,
+            _ => Region::Dendy
+        };
+    } else {
+        // Old dumps often carry garbage ("DiskDude!") in bytes 7 - 15
+        let garbage = header[12..16].iter().any(|b| *b != 0);
+        if garbage {
+            mapper &= 0x0f;
+        }
+        prg_rom_size = header[4] as usize * PRG_BANK_SIZE;
+        chr_rom_size = header[5] as usize * CHR_BANK_SIZE;
+        let battery = header[6] & 0x02 != 0;
+        let work_ram = if header[8] == 0 { 8 * 1024 } else { header[8] as usize * 8 * 1024 };
+        prg_ram_size = if battery { 0 } else { work_ram };
+        prg_nvram_size = if battery { work_ram } else { 0 };
+        chr_ram_size = if chr_rom_size == 0 { CHR_BANK_SIZE } else { 0 };
+        chr_nvram_size = 0;
+        // Rarely set, most PAL dumps only get identified by the ROM database
+        region = if !garbage && header[9] & 0x01 != 0 { Region::Pal } else { Region::Ntsc };
+    }
+
+    let mirroring = if header[6] & 0x08 != 0 {
+        Mirroring::FourScreen
+    } else if header[6] & 0x01 != 0 {
+        Mirroring::Vertical
+    } else {
+        Mirroring::Horizontal
+    };
+
+    let mut offset = HEADER_SIZE;
+    let trainer = if header[6] & 0x04 != 0 {
+        let trainer = slice(data, offset, TRAINER_SIZE)?.to_vec();
+        offset += TRAINER_SIZE;
+        Some(trainer)
+    } else {
+        None
+    };
+
+    let prg_rom = slice(data, offset, prg_rom_size)?.to_vec();
+    offset += prg_rom_size;
+    let chr_rom = slice(data, offset, chr_rom_size)?.to_vec();
+
+    Ok(RomImage {
+        mapper,
+        submapper,
+        mirroring,
+        battery: header[6] & 0x02 != 0,
+        prg_rom,
+        chr_rom,
+        prg_ram_size,
+        prg_nvram_size,
+        chr_ram_size,
+        chr_nvram_size,
+        region,
+        trainer
+    })
+}
+
+fn slice(data: &[u8], offset: usize, len: usize) -> Result<&[u8], CartridgeError> {
+    let end = offset.checked_add(len).ok_or(CartridgeError::Truncated)?;
+    data.get(offset..end).ok_or(CartridgeError::Truncated)
+}
+
+// NES 2.0 ROM sizes are either a bank count or, with MSB nibble $F, an exponent-multiplier pair.
+// Sizes too large to count can't be in the file either
+fn rom_size(lsb: u8, msb: u8, bank_size: usize) -> Result<usize, CartridgeError> {
+    let size = if msb == 0x0f {
+        let exponent = (lsb >> 2) as u32;
+        let multiplier = (lsb & 0x03) as usize * 2 + 1;
+        1usize.checked_shl(exponent).and_then(|size| size.checked_mul(multiplier))
+    } else {
+        ((msb as usize) << 8 | lsb as usize).checked_mul(bank_size)
+    };
+    size.ok_or(CartridgeError::Truncated)
+}
+
+// NES 2.0 RAM sizes are stored as shift counts, 64 << n bytes
+fn ram_size(shift: u8) -> usize {
+    if shift == 0 { 0 } else { 64 << shift }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn header(bytes: [u8; 16], prg_banks: usize, chr_banks: usize) -> Vec<u8> {
+        let mut data = bytes.to_vec();
+        data.resize(HEADER_SIZE + prg_banks * PRG_BANK_SIZE + chr_banks * CHR_BANK_SIZE, 0);
+        data
+    }
+
+    #[test]
+    pub fn parses_ines_header() {
+        let data = header([b'N', b'E', b'S', 0x1a, 2, 1, 0x13, 0x40, 0, 0, 0, 0, 0, 0, 0, 0], 2, 1);
+        let image = parse(&data).unwrap();
+        assert_eq!(image.mapper, 0x41);
+        assert_eq!(image.mirroring, Mirroring::Vertical);
+        assert!(image.battery);
+        assert_eq!(image.prg_rom.len(), 2 * PRG_BANK_SIZE);
+        assert_eq!(image.chr_rom.len(), CHR_BANK_SIZE);
+        assert_eq!(image.prg_nvram_size, 8 * 1024);
+    }
+
+    #[test]
+    pub fn parses_nes2_header() {
+        let data = header([b'N', b'E', b'S', 0x1a, 1, 0, 0x90, 0x48, 0x21, 0, 0x07, 0x07, 0, 0, 0, 0], 1, 0);
+        let image = parse(&data).unwrap();
+        assert_eq!(image.mapper, 0x149);
+        assert_eq!(image.submapper, 2);
+        assert_eq!(image.prg_ram_size, 8 * 1024);
+        assert_eq!(image.chr_ram_size, 8 * 1024);
+    }
+
+    #[test]
+    pub fn rejects_truncated_file() {
+        let mut data = header([b'N', b'E', b'S', 0x1a, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], 2, 0);
+        data.truncate(100);
+        assert!(matches!(parse(&data), Err(CartridgeError::Truncated)));
+    }
+
+    #[test]
+    pub fn rejects_oversized_nes2_rom() {
+        // Exponent 63, multiplier 7
+        let data = header([b'N', b'E', b'S', 0x1a, 0xff, 0, 0, 0x08, 0, 0x0f, 0, 0, 0, 0, 0, 0], 1, 0);
+        assert!(matches!(parse(&data), Err(CartridgeError::Truncated)));
+        // 2^63 bytes
+        let data = header([b'N', b'E', b'S', 0x1a, 0xfc, 0, 0, 0x08, 0, 0x0f, 0, 0, 0, 0, 0, 0], 1, 0);
+        assert!(matches!(parse(&data), Err(CartridgeError::Truncated)));
+    }
+
+}
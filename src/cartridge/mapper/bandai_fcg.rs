@@ -0,0 +1,245 @@
+use std::borrow::Cow;
+
+use crate::cartridge::eeprom::{Eeprom, EepromChip};
+use crate::cartridge::mapper::{self, Mapper};
+use crate::cartridge::{Mirroring, RomImage};
+
+const PRG_BANK_SIZE: usize = 16 * 1024;
+const CHR_BANK_SIZE: usize = 1024;
+
+// Mappers 16 and 159, Bandai FCG-1/2 and LZ93D50 with serial EEPROM
+pub struct BandaiFcg {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    chr_banks: [u8; 8],
+    prg_bank: u8,
+    mirroring: Mirroring,
+    irq_enabled: bool,
+    irq_counter: u16,
+    irq_latch: u16,
+    irq_pending: bool,
+    // Only present on battery backed boards
+    eeprom: Option<Eeprom>
+}
+
+impl BandaiFcg {
+
+    pub fn new(image: &RomImage, chip: EepromChip) -> Self {
+        let (chr, chr_is_ram) = mapper::chr_memory(image);
+        Self {
+            prg_rom: image.prg_rom.clone(),
+            chr,
+            chr_is_ram,
+            chr_banks: [0; 8],
+            prg_bank: 0,
+            mirroring: Mirroring::Vertical,
+            irq_enabled: false,
+            irq_counter: 0,
+            irq_latch: 0,
+            irq_pending: false,
+            eeprom: if image.battery { Some(Eeprom::new(chip)) } else { None }
+        }
+    }
+
+    fn chr_address(&self, addr: u16) -> usize {
+        let bank = self.chr_banks[(addr as usize >> 10) & 0x07] as usize;
+        mapper::bank_offset(bank, CHR_BANK_SIZE, self.chr.len()) + (addr as usize & (CHR_BANK_SIZE - 1))
+    }
+
+    fn write_register(&mut self, addr: u16, data: u8) {
+        match addr & 0x0f {
+            0x0..=0x7 => self.chr_banks[(addr & 0x07) as usize] = data,
+            0x8 => self.prg_bank = data & 0x0f,
+            0x9 => {
+                self.mirroring = match data & 0x03 {
+                    0 => Mirroring::Vertical,
+                    1 => Mirroring::Horizontal,
+                    2 => Mirroring::SingleScreenA,
+                    _ => Mirroring::SingleScreenB
+                }
+            },
+            0xa => {
+                self.irq_enabled = data & 0x01 != 0;
+                // LZ93D50 behaviour, the counter is reloaded from the latch
+                self.irq_counter = self.irq_latch;
+                self.irq_pending = false;
+            },
+            0xb => {
+                self.irq_latch = (self.irq_latch & 0xff00) | data as u16;
+                self.irq_counter = (self.irq_counter & 0xff00) | data as u16;
+            },
+            0xc => {
+                self.irq_latch = (self.irq_latch & 0x00ff) | (data as u16) << 8;
+                self.irq_counter = (self.irq_counter & 0x00ff) | (data as u16) << 8;
+            },
+            0xd => {
+                if let Some(eeprom) = self.eeprom.as_mut() {
+                    eeprom.write(data & 0x20 != 0, data & 0x40 != 0);
+                }
+            },
+            _ => {}
+        }
+    }
+
+}
+
+impl Mapper for BandaiFcg {
+
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            // EEPROM data out on bit 4
+            0x6000..=0x7fff => {
+                self.eeprom.as_ref().map(|eeprom| if eeprom.output() { 0x10 } else { 0x00 })
+            },
+            0x8000..=0xbfff => {
+                let offset = mapper::bank_offset(self.prg_bank as usize, PRG_BANK_SIZE, self.prg_rom.len());
+                Some(self.prg_rom[offset + (addr as usize & (PRG_BANK_SIZE - 1))])
+            },
+            0xc000..=0xffff => {
+                let offset = mapper::bank_offset(0xff, PRG_BANK_SIZE, self.prg_rom.len());
+                Some(self.prg_rom[offset + (addr as usize & (PRG_BANK_SIZE - 1))])
+            },
+            _ => None
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        // FCG-1/2 decode $6000 - $7FFF, LZ93D50 decodes $8000 - $FFFF
+        if addr >= 0x6000 {
+            self.write_register(addr, data);
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr[self.chr_address(addr)]
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            let addr = self.chr_address(addr);
+            self.chr[addr] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn clock_cpu(&mut self) {
+        if self.irq_enabled {
+            if self.irq_counter == 0 {
+                self.irq_pending = true;
+            }
+            self.irq_counter = self.irq_counter.wrapping_sub(1);
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn save_data(&self) -> Option<Cow<'_, [u8]>> {
+        self.eeprom.as_ref().map(|eeprom| Cow::Borrowed(eeprom.data()))
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        if let Some(eeprom) = self.eeprom.as_mut() {
+            eeprom.load(data);
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn image() -> RomImage {
+        RomImage {
+            mapper: 16,
+            chr_rom: (0..16).flat_map(|bank| vec![bank as u8; CHR_BANK_SIZE]).collect(),
+            ..RomImage::nrom((0..8).flat_map(|bank| vec![bank as u8; PRG_BANK_SIZE]).collect(), false)
+        }
+    }
+
+    #[test]
+    pub fn switches_banks() {
+        let mut mapper = BandaiFcg::new(&image(), EepromChip::X24C02);
+        mapper.cpu_write(0x8008, 0x03);
+        mapper.cpu_write(0x8002, 0x09);
+        mapper.cpu_write(0x8009, 0x01);
+        assert_eq!(mapper.cpu_read(0x8000), Some(3));
+        assert_eq!(mapper.cpu_read(0xc000), Some(7));
+        assert_eq!(mapper.ppu_read(0x0800), 9);
+        assert_eq!(mapper.mirroring(), Mirroring::Horizontal);
+        // FCG-1/2 registers at $6000
+        mapper.cpu_write(0x6008, 0x05);
+        assert_eq!(mapper.cpu_read(0x8000), Some(5));
+    }
+
+    #[test]
+    pub fn irq_counts_down_from_latch() {
+        let mut mapper = BandaiFcg::new(&image(), EepromChip::X24C02);
+        mapper.cpu_write(0x800b, 0x02);
+        mapper.cpu_write(0x800c, 0x00);
+        mapper.cpu_write(0x800a, 0x01);
+        mapper.clock_cpu();
+        mapper.clock_cpu();
+        assert!(!mapper.irq());
+        mapper.clock_cpu();
+        assert!(mapper.irq());
+        // Acknowledged and reloaded by $800A
+        mapper.cpu_write(0x800a, 0x01);
+        assert!(!mapper.irq());
+        for _ in 0..3 {
+            mapper.clock_cpu();
+        }
+        assert!(mapper.irq());
+        mapper.cpu_write(0x800a, 0x00);
+        mapper.clock_cpu();
+        assert!(!mapper.irq());
+    }
+
+    // SCL in bit 5 and SDA in bit 6 of $800D, data out in bit 4 of $6000
+    fn lines(mapper: &mut BandaiFcg, scl: bool, sda: bool) {
+        mapper.cpu_write(0x800d, (scl as u8) << 5 | (sda as u8) << 6);
+    }
+
+    fn send(mapper: &mut BandaiFcg, byte: u8) -> bool {
+        for i in (0..8).rev() {
+            let bit = (byte >> i) & 0x01 != 0;
+            lines(mapper, false, bit);
+            lines(mapper, true, bit);
+            lines(mapper, false, bit);
+        }
+        let ack = mapper.cpu_read(0x6000) == Some(0x00);
+        lines(mapper, true, true);
+        lines(mapper, false, true);
+        ack
+    }
+
+    #[test]
+    pub fn eeprom_behind_800d_and_6000() {
+        let mut mapper = BandaiFcg::new(&image(), EepromChip::X24C02);
+        assert_eq!(mapper.cpu_read(0x6000), None);
+        assert!(mapper.save_data().is_none());
+
+        let mut mapper = BandaiFcg::new(&RomImage { battery: true, ..image() }, EepromChip::X24C02);
+        assert_eq!(mapper.cpu_read(0x6000), Some(0x10));
+        // Start, device, word address, data, stop
+        lines(&mut mapper, false, true);
+        lines(&mut mapper, true, true);
+        lines(&mut mapper, true, false);
+        lines(&mut mapper, false, false);
+        assert!(send(&mut mapper, 0xa0));
+        assert!(send(&mut mapper, 0x05));
+        assert!(send(&mut mapper, 0x77));
+        lines(&mut mapper, false, false);
+        lines(&mut mapper, true, false);
+        lines(&mut mapper, true, true);
+        assert_eq!(mapper.save_data().unwrap()[0x05], 0x77);
+    }
+
+}
@@ -0,0 +1,524 @@
+use std::borrow::Cow;
+
+use lazy_static::lazy_static;
+
+use crate::apu::expansion::{ExpansionAudio, ExpansionLevels};
+use crate::cartridge::mapper::{self, Mapper};
+use crate::cartridge::nsf::ExpansionChip;
+use crate::cartridge::{Mirroring, RomImage};
+
+const PRG_BANK_SIZE: usize = 8 * 1024;
+const CHR_BANK_SIZE: usize = 1024;
+// One channel at volume 12 is about as loud as an APU pulse, 12 is 9 dB below full
+const LEVEL: f32 = 2.818;
+
+// Mapper 69, Sunsoft FME-7 and its 5A / 5B variants
+pub struct Fme7 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    battery: bool,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    // Register selected through $8000
+    command: u8,
+    chr_banks: [u8; 8],
+    // $6000 bank, bit 6 selects RAM, bit 7 enables RAM
+    prg_bank_6000: u8,
+    prg_banks: [u8; 3],
+    mirroring: Mirroring,
+    irq_enabled: bool,
+    irq_counter_enabled: bool,
+    irq_counter: u16,
+    irq_pending: bool,
+    audio: Sunsoft5b
+}
+
+impl Fme7 {
+
+    pub fn new(image: &RomImage) -> Self {
+        let (chr, chr_is_ram) = mapper::chr_memory(image);
+        Self {
+            prg_rom: image.prg_rom.clone(),
+            prg_ram: vec![0x00; mapper::prg_ram_size(image).max(8 * 1024)],
+            battery: image.battery,
+            chr,
+            chr_is_ram,
+            command: 0,
+            chr_banks: [0; 8],
+            prg_bank_6000: 0,
+            prg_banks: [0; 3],
+            mirroring: Mirroring::Vertical,
+            irq_enabled: false,
+            irq_counter_enabled: false,
+            irq_counter: 0,
+            irq_pending: false,
+            audio: Sunsoft5b::new()
+        }
+    }
+
+    fn prg_rom_read(&self, bank: u8, addr: u16) -> u8 {
+        let offset = mapper::bank_offset(bank as usize, PRG_BANK_SIZE, self.prg_rom.len());
+        self.prg_rom[offset + (addr as usize & (PRG_BANK_SIZE - 1))]
+    }
+
+    fn chr_address(&self, addr: u16) -> usize {
+        let bank = self.chr_banks[(addr as usize >> 10) & 0x07] as usize;
+        mapper::bank_offset(bank, CHR_BANK_SIZE, self.chr.len()) + (addr as usize & (CHR_BANK_SIZE - 1))
+    }
+
+    fn write_parameter(&mut self, data: u8) {
+        match self.command {
+            0x0..=0x7 => self.chr_banks[self.command as usize] = data,
+            0x8 => self.prg_bank_6000 = data,
+            0x9..=0xb => self.prg_banks[self.command as usize - 0x9] = data & 0x3f,
+            0xc => {
+                self.mirroring = match data & 0x03 {
+                    0 => Mirroring::Vertical,
+                    1 => Mirroring::Horizontal,
+                    2 => Mirroring::SingleScreenA,
+                    _ => Mirroring::SingleScreenB
+                }
+            },
+            0xd => {
+                self.irq_enabled = data & 0x01 != 0;
+                self.irq_counter_enabled = data & 0x80 != 0;
+                self.irq_pending = false;
+            },
+            0xe => self.irq_counter = (self.irq_counter & 0xff00) | data as u16,
+            _ => self.irq_counter = (self.irq_counter & 0x00ff) | (data as u16) << 8
+        }
+    }
+
+}
+
+impl Mapper for Fme7 {
+
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7fff => {
+                if self.prg_bank_6000 & 0x40 == 0 {
+                    Some(self.prg_rom_read(self.prg_bank_6000 & 0x3f, addr))
+                } else if self.prg_bank_6000 & 0x80 != 0 {
+                    let offset = mapper::bank_offset((self.prg_bank_6000 & 0x3f) as usize, PRG_BANK_SIZE, self.prg_ram.len());
+                    Some(self.prg_ram[offset + (addr as usize & (PRG_BANK_SIZE - 1))])
+                } else {
+                    None
+                }
+            },
+            0x8000..=0x9fff => Some(self.prg_rom_read(self.prg_banks[0], addr)),
+            0xa000..=0xbfff => Some(self.prg_rom_read(self.prg_banks[1], addr)),
+            0xc000..=0xdfff => Some(self.prg_rom_read(self.prg_banks[2], addr)),
+            0xe000..=0xffff => Some(self.prg_rom_read(0xff, addr)),
+            _ => None
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7fff if self.prg_bank_6000 & 0xc0 == 0xc0 => {
+                let offset = mapper::bank_offset((self.prg_bank_6000 & 0x3f) as usize, PRG_BANK_SIZE, self.prg_ram.len());
+                self.prg_ram[offset + (addr as usize & (PRG_BANK_SIZE - 1))] = data;
+            },
+            0x8000..=0x9fff => self.command = data & 0x0f,
+            0xa000..=0xbfff => self.write_parameter(data),
+            0xc000..=0xdfff => self.audio.select(data),
+            0xe000..=0xffff => self.audio.write(data),
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr[self.chr_address(addr)]
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            let addr = self.chr_address(addr);
+            self.chr[addr] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn clock_cpu(&mut self) {
+        if self.irq_counter_enabled {
+            self.irq_counter = self.irq_counter.wrapping_sub(1);
+            if self.irq_counter == 0xffff && self.irq_enabled {
+                self.irq_pending = true;
+            }
+        }
+        self.audio.clock();
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn audio_output(&self, levels: &mut ExpansionLevels) {
+        self.audio.add_output(levels);
+    }
+
+    fn expansion_chips(&self) -> u8 {
+        ExpansionChip::Sunsoft5b as u8
+    }
+
+    fn save_data(&self) -> Option<Cow<'_, [u8]>> {
+        if self.battery { Some(Cow::Borrowed(&self.prg_ram)) } else { None }
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        mapper::load_into(&mut self.prg_ram, data);
+    }
+
+}
+
+lazy_static! {
+
+    // 5B DAC levels, 32 steps of 1.5 dB
+    static ref VOLUME_TABLE: [f32; 32] = {
+        let mut table = [0.0; 32];
+        for (level, volume) in table.iter_mut().enumerate().skip(1) {
+            *volume = 10f32.powf(-((31 - level) as f32 * 1.5) / 20.0);
+        }
+        table
+    };
+}
+
+// Sunsoft 5B sound, a YM2149F with three square channels, noise and an envelope generator
+pub struct Sunsoft5b {
+    registers: [u8; 16],
+    selected: u8,
+    // Tone, noise and envelope run at CPU clock / 16
+    divider: u8,
+    tone_counters: [u16; 3],
+    tone_outputs: [bool; 3],
+    noise_counter: u8,
+    noise_half: bool,
+    noise_lfsr: u32,
+    envelope_counter: u16,
+    envelope_step: u8,
+    envelope_attack: bool,
+    envelope_holding: bool
+}
+
+impl Sunsoft5b {
+
+    pub fn new() -> Self {
+        Self {
+            registers: [0; 16],
+            selected: 0,
+            divider: 0,
+            tone_counters: [0; 3],
+            tone_outputs: [false; 3],
+            noise_counter: 0,
+            noise_half: false,
+            noise_lfsr: 1,
+            envelope_counter: 0,
+            envelope_step: 0,
+            envelope_attack: false,
+            envelope_holding: false
+        }
+    }
+
+    // $C000 - $DFFF, audio register select; upper nibble must be zero
+    pub fn select(&mut self, data: u8) {
+        self.selected = data;
+    }
+
+    // $E000 - $FFFF, write to the selected audio register
+    pub fn write(&mut self, data: u8) {
+        if self.selected & 0xf0 != 0 {
+            return;
+        }
+        self.registers[self.selected as usize] = data;
+        if self.selected == 0x0d {
+            // Restart envelope with the new shape
+            self.envelope_step = 0;
+            self.envelope_counter = 0;
+            self.envelope_attack = data & 0x04 != 0;
+            self.envelope_holding = false;
+        }
+    }
+
+    pub fn clock(&mut self) {
+        self.divider += 1;
+        if self.divider < 16 {
+            return;
+        }
+        self.divider = 0;
+
+        for channel in 0..3 {
+            let period = self.tone_period(channel).max(1);
+            self.tone_counters[channel] += 1;
+            if self.tone_counters[channel] >= period {
+                self.tone_counters[channel] = 0;
+                self.tone_outputs[channel] = !self.tone_outputs[channel];
+            }
+        }
+
+        // Noise runs at half the tone rate
+        self.noise_half = !self.noise_half;
+        if self.noise_half {
+            let period = (self.registers[0x06] & 0x1f).max(1);
+            self.noise_counter += 1;
+            if self.noise_counter >= period {
+                self.noise_counter = 0;
+                let feedback = (self.noise_lfsr ^ (self.noise_lfsr >> 3)) & 0x01;
+                self.noise_lfsr = (self.noise_lfsr >> 1) | (feedback << 16);
+            }
+        }
+
+        let period = (self.registers[0x0b] as u16 | (self.registers[0x0c] as u16) << 8).max(1);
+        self.envelope_counter += 1;
+        if self.envelope_counter >= period {
+            self.envelope_counter = 0;
+            self.clock_envelope();
+        }
+    }
+
+    fn clock_envelope(&mut self) {
+        if self.envelope_holding {
+            return;
+        }
+        self.envelope_step += 1;
+        if self.envelope_step < 32 {
+            return;
+        }
+
+        let shape = self.registers[0x0d];
+        let cont = shape & 0x08 != 0;
+        let alternate = shape & 0x02 != 0;
+        let hold = shape & 0x01 != 0;
+        if !cont {
+            // Shapes 0 - 7 end silent
+            self.envelope_attack = false;
+            self.envelope_step = 31;
+            self.envelope_holding = true;
+        } else if hold {
+            if alternate {
+                self.envelope_attack = !self.envelope_attack;
+            }
+            self.envelope_step = 31;
+            self.envelope_holding = true;
+        } else {
+            if alternate {
+                self.envelope_attack = !self.envelope_attack;
+            }
+            self.envelope_step = 0;
+        }
+    }
+
+    fn tone_period(&self, channel: usize) -> u16 {
+        self.registers[channel * 2] as u16 | ((self.registers[channel * 2 + 1] & 0x0f) as u16) << 8
+    }
+
+    fn envelope_level(&self) -> usize {
+        if self.envelope_attack { self.envelope_step as usize } else { 31 - self.envelope_step as usize }
+    }
+
+    pub fn output(&self) -> f32 {
+        let mixer = self.registers[0x07];
+        let noise = self.noise_lfsr & 0x01 != 0;
+        let mut output = 0.0;
+        for channel in 0..3 {
+            let tone_off = mixer & (1 << channel) != 0;
+            let noise_off = mixer & (1 << (channel + 3)) != 0;
+            if (self.tone_outputs[channel] || tone_off) && (noise || noise_off) {
+                let volume = self.registers[0x08 + channel];
+                let level = if volume & 0x10 != 0 {
+                    self.envelope_level()
+                } else if volume & 0x0f == 0 {
+                    0
+                } else {
+                    (volume & 0x0f) as usize * 2 + 1
+                };
+                output += VOLUME_TABLE[level];
+            }
+        }
+        output * LEVEL
+    }
+
+}
+
+impl ExpansionAudio for Sunsoft5b {
+
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0xc000..=0xdfff => self.select(data),
+            0xe000..=0xffff => Sunsoft5b::write(self, data),
+            _ => {}
+        }
+    }
+
+    fn clock(&mut self) {
+        Sunsoft5b::clock(self);
+    }
+
+    fn output(&self) -> f32 {
+        Sunsoft5b::output(self)
+    }
+
+    fn chip(&self) -> ExpansionChip {
+        ExpansionChip::Sunsoft5b
+    }
+
+}
+
+impl Default for Sunsoft5b {
+
+    fn default() -> Self {
+        Self::new()
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn image() -> RomImage {
+        RomImage {
+            mapper: 69,
+            prg_ram_size: 8 * 1024,
+            chr_rom: (0..8).flat_map(|bank| vec![bank as u8; CHR_BANK_SIZE]).collect(),
+            ..RomImage::nrom((0..16).flat_map(|bank| vec![bank as u8; PRG_BANK_SIZE]).collect(), false)
+        }
+    }
+
+    fn command(mapper: &mut Fme7, command: u8, parameter: u8) {
+        mapper.cpu_write(0x8000, command);
+        mapper.cpu_write(0xa000, parameter);
+    }
+
+    #[test]
+    pub fn switches_banks() {
+        let mut mapper = Fme7::new(&image());
+        command(&mut mapper, 0x9, 0x03);
+        command(&mut mapper, 0xb, 0x0c);
+        assert_eq!(mapper.cpu_read(0x8000), Some(3));
+        assert_eq!(mapper.cpu_read(0xc000), Some(12));
+        assert_eq!(mapper.cpu_read(0xe000), Some(15));
+
+        command(&mut mapper, 0x0, 0x05);
+        command(&mut mapper, 0x7, 0x02);
+        assert_eq!(mapper.ppu_read(0x0000), 5);
+        assert_eq!(mapper.ppu_read(0x1c00), 2);
+
+        command(&mut mapper, 0xc, 0x01);
+        assert_eq!(mapper.mirroring(), Mirroring::Horizontal);
+        command(&mut mapper, 0xc, 0x03);
+        assert_eq!(mapper.mirroring(), Mirroring::SingleScreenB);
+    }
+
+    #[test]
+    pub fn selects_rom_or_ram_at_6000() {
+        let mut mapper = Fme7::new(&image());
+        command(&mut mapper, 0x8, 0x02);
+        assert_eq!(mapper.cpu_read(0x6000), Some(2));
+        // RAM selected but disabled: open bus, writes are dropped
+        command(&mut mapper, 0x8, 0x40);
+        mapper.cpu_write(0x6000, 0x55);
+        assert_eq!(mapper.cpu_read(0x6000), None);
+        command(&mut mapper, 0x8, 0xc0);
+        assert_eq!(mapper.cpu_read(0x6000), Some(0x00));
+        mapper.cpu_write(0x6000, 0x55);
+        assert_eq!(mapper.cpu_read(0x6000), Some(0x55));
+    }
+
+    #[test]
+    pub fn irq_fires_when_counter_wraps() {
+        let mut mapper = Fme7::new(&image());
+        command(&mut mapper, 0xe, 0x01);
+        command(&mut mapper, 0xf, 0x00);
+        command(&mut mapper, 0xd, 0x81);
+        mapper.clock_cpu();
+        assert!(!mapper.irq());
+        mapper.clock_cpu();
+        assert!(mapper.irq());
+        // Any write to command $D acknowledges
+        command(&mut mapper, 0xd, 0x81);
+        assert!(!mapper.irq());
+
+        // Counting without IRQs enabled
+        command(&mut mapper, 0xd, 0x80);
+        for _ in 0..0x10000 {
+            mapper.clock_cpu();
+        }
+        assert!(!mapper.irq());
+    }
+
+    fn write_5b(audio: &mut Sunsoft5b, register: u8, data: u8) {
+        audio.select(register);
+        audio.write(data);
+    }
+
+    #[test]
+    pub fn tone_and_select_guard() {
+        let mut audio = Sunsoft5b::new();
+        write_5b(&mut audio, 0x00, 0x01);
+        write_5b(&mut audio, 0x08, 0x0f);
+        // Only tone A
+        write_5b(&mut audio, 0x07, 0x3e);
+        // Register numbers with the upper nibble set are ignored
+        write_5b(&mut audio, 0x18, 0x00);
+        assert_eq!(audio.output(), 0.0);
+        for _ in 0..16 {
+            audio.clock();
+        }
+        assert_eq!(audio.output(), LEVEL);
+        for _ in 0..16 {
+            audio.clock();
+        }
+        assert_eq!(audio.output(), 0.0);
+    }
+
+    #[test]
+    pub fn noise() {
+        let mut audio = Sunsoft5b::new();
+        write_5b(&mut audio, 0x06, 0x01);
+        write_5b(&mut audio, 0x08, 0x0f);
+        // Only noise A
+        write_5b(&mut audio, 0x07, 0x37);
+        let outputs: Vec<f32> = (0..16 * 64).map(|_| {
+            audio.clock();
+            audio.output()
+        }).collect();
+        assert!(outputs.contains(&0.0));
+        assert!(outputs.contains(&LEVEL));
+    }
+
+    #[test]
+    pub fn envelope_shapes() {
+        let mut audio = Sunsoft5b::new();
+        write_5b(&mut audio, 0x08, 0x10);
+        write_5b(&mut audio, 0x07, 0x3f);
+        write_5b(&mut audio, 0x0b, 0x01);
+        // Decay, then silence
+        write_5b(&mut audio, 0x0d, 0x00);
+        assert_eq!(audio.output(), LEVEL);
+        for _ in 0..16 * 31 {
+            audio.clock();
+        }
+        assert_eq!(audio.output(), 0.0);
+        for _ in 0..16 * 64 {
+            audio.clock();
+        }
+        assert_eq!(audio.output(), 0.0);
+
+        // Attack, then hold at full volume
+        write_5b(&mut audio, 0x0d, 0x0d);
+        assert_eq!(audio.output(), 0.0);
+        for _ in 0..16 * 31 {
+            audio.clock();
+        }
+        assert_eq!(audio.output(), LEVEL);
+        for _ in 0..16 * 64 {
+            audio.clock();
+        }
+        assert_eq!(audio.output(), LEVEL);
+    }
+
+}
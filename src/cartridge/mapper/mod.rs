@@ -0,0 +1,124 @@
+pub mod nrom;
+pub mod unrom512;
+pub mod bandai_fcg;
+pub mod fme7;
+pub mod namco163;
+pub mod vrc6;
+pub mod vrc7;
+pub mod vrc_irq;
+
+use std::borrow::Cow;
+
+use crate::apu::expansion::ExpansionLevels;
+use crate::cartridge::eeprom::EepromChip;
+use crate::cartridge::fds::Fds;
+use crate::cartridge::{CartridgeError, Mirroring, RomImage};
+
+pub trait Mapper {
+    // CPU address space $4020 - $FFFF, None if nothing drives the bus
+    fn cpu_read(&mut self, addr: u16) -> Option<u8>;
+    fn cpu_write(&mut self, addr: u16, data: u8);
+    // PPU pattern table space $0000 - $1FFF, and nametables the board maps to its own memory
+    fn ppu_read(&mut self, addr: u16) -> u8;
+    fn ppu_write(&mut self, addr: u16, data: u8);
+    fn mirroring(&self) -> Mirroring;
+    // Memory behind a PPU address $0000 - $3EFF, by default nametables go to console VRAM
+    // arranged by `mirroring`
+    fn ppu_target(&self, addr: u16) -> PpuTarget {
+        if addr < 0x2000 {
+            PpuTarget::Cartridge
+        } else {
+            nametable_target(self.mirroring(), addr)
+        }
+    }
+    // Called once for every CPU cycle
+    fn clock_cpu(&mut self) {}
+    // State of the cartridge IRQ line
+    fn irq(&self) -> bool {
+        false
+    }
+    // Adds the output of each of the board's sound chips to its slot with
+    // `ExpansionAudio::add_output`, the mixer gives every chip its own channel
+    fn audio_output(&self, _levels: &mut ExpansionLevels) {}
+    // `ExpansionChip` flags of those chips
+    fn expansion_chips(&self) -> u8 {
+        0
+    }
+    // Battery backed memory (PRG RAM, EEPROM or flash) kept in the .sav file
+    fn save_data(&self) -> Option<Cow<'_, [u8]>> {
+        None
+    }
+    fn load_save_data(&mut self, _data: &[u8]) {}
+    // Save data is a patch of varying length instead of a memory image
+    fn save_is_patch(&self) -> bool {
+        false
+    }
+    // Disk System access for disk side switching
+    fn fds(&mut self) -> Option<&mut Fds> {
+        None
+    }
+}
+
+// Memory the cartridge selects for a PPU access
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PpuTarget {
+    // Offset into the 2 KiB console VRAM (CIRAM)
+    Ciram(usize),
+    // Memory on the cartridge
+    Cartridge
+}
+
+// Nametable arrangement for one of the standard mirroring modes. Four-screen boards wire
+// the lower two nametables to CIRAM and the upper two to VRAM on the cartridge
+pub fn nametable_target(mirroring: Mirroring, addr: u16) -> PpuTarget {
+    let table = (addr >> 10) & 0x03;
+    let page = match mirroring {
+        Mirroring::Horizontal => table >> 1,
+        Mirroring::Vertical => table & 0x01,
+        Mirroring::SingleScreenA => 0,
+        Mirroring::SingleScreenB => 1,
+        Mirroring::FourScreen if table < 2 => table,
+        Mirroring::FourScreen => return PpuTarget::Cartridge
+    };
+    PpuTarget::Ciram(page as usize * 0x400 + (addr & 0x03ff) as usize)
+}
+
+pub fn create(image: &RomImage) -> Result<Box<dyn Mapper>, CartridgeError> {
+    match image.mapper {
+        0 => Ok(Box::new(nrom::Nrom::new(image))),
+        16 => Ok(Box::new(bandai_fcg::BandaiFcg::new(image, EepromChip::X24C02))),
+        19 => Ok(Box::new(namco163::Namco163::new(image))),
+        24 | 26 => Ok(Box::new(vrc6::Vrc6::new(image))),
+        30 => Ok(Box::new(unrom512::Unrom512::new(image))),
+        69 => Ok(Box::new(fme7::Fme7::new(image))),
+        85 => Ok(Box::new(vrc7::Vrc7::new(image))),
+        159 => Ok(Box::new(bandai_fcg::BandaiFcg::new(image, EepromChip::X24C01))),
+        id => Err(CartridgeError::UnsupportedMapper(id))
+    }
+}
+
+// CHR ROM, or CHR RAM if the board has none
+pub(crate) fn chr_memory(image: &RomImage) -> (Vec<u8>, bool) {
+    if image.chr_rom.is_empty() {
+        let size = (image.chr_ram_size + image.chr_nvram_size).max(8 * 1024);
+        (vec![0x00; size], true)
+    } else {
+        (image.chr_rom.clone(), false)
+    }
+}
+
+pub(crate) fn prg_ram_size(image: &RomImage) -> usize {
+    image.prg_ram_size + image.prg_nvram_size
+}
+
+// Offset of a bank inside a memory of given length, wrapping like missing address lines
+pub(crate) fn bank_offset(bank: usize, bank_size: usize, len: usize) -> usize {
+    let banks = (len / bank_size).max(1);
+    (bank % banks) * bank_size
+}
+
+// Copies as much of a save file as fits into battery backed RAM
+pub(crate) fn load_into(memory: &mut [u8], data: &[u8]) {
+    let len = memory.len().min(data.len());
+    memory[..len].copy_from_slice(&data[..len]);
+}
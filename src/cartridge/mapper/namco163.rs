@@ -0,0 +1,412 @@
+use std::borrow::Cow;
+
+use crate::apu::expansion::{ExpansionAudio, ExpansionLevels};
+use crate::cartridge::mapper::{self, Mapper, PpuTarget};
+use crate::cartridge::nsf::ExpansionChip;
+use crate::cartridge::{Mirroring, RomImage};
+
+const PRG_BANK_SIZE: usize = 8 * 1024;
+const CHR_BANK_SIZE: usize = 1024;
+
+// Mapper 19, Namco 129 / 163
+pub struct Namco163 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    battery: bool,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    // Banks for PPU $0000 - $1FFF and the four nametables
+    chr_banks: [u8; 8],
+    nametable_banks: [u8; 4],
+    prg_banks: [u8; 3],
+    // $E800 bits 6 and 7, CIRAM can not be mapped into the pattern tables if set
+    chr_ram_disabled: [bool; 2],
+    // $F800, write protection for PRG RAM
+    write_protect: u8,
+    irq_counter: u16,
+    irq_enabled: bool,
+    irq_pending: bool,
+    audio: Namco163Audio
+}
+
+impl Namco163 {
+
+    pub fn new(image: &RomImage) -> Self {
+        let (chr, chr_is_ram) = mapper::chr_memory(image);
+        Self {
+            prg_rom: image.prg_rom.clone(),
+            prg_ram: vec![0x00; mapper::prg_ram_size(image)],
+            battery: image.battery,
+            chr,
+            chr_is_ram,
+            chr_banks: [0; 8],
+            nametable_banks: [0xe0, 0xe1, 0xe0, 0xe1],
+            prg_banks: [0; 3],
+            chr_ram_disabled: [false; 2],
+            write_protect: 0,
+            irq_counter: 0,
+            irq_enabled: false,
+            irq_pending: false,
+            audio: Namco163Audio::new()
+        }
+    }
+
+    fn prg_rom_read(&self, bank: u8, addr: u16) -> u8 {
+        let offset = mapper::bank_offset(bank as usize, PRG_BANK_SIZE, self.prg_rom.len());
+        self.prg_rom[offset + (addr as usize & (PRG_BANK_SIZE - 1))]
+    }
+
+    // 1 KiB bank selected for a pattern table or nametable address
+    fn bank(&self, addr: u16) -> u8 {
+        let slot = (addr as usize >> 10) & 0x07;
+        if addr < 0x2000 { self.chr_banks[slot] } else { self.nametable_banks[slot & 0x03] }
+    }
+
+    fn chr_address(&self, addr: u16) -> usize {
+        let bank = self.bank(addr) as usize;
+        mapper::bank_offset(bank, CHR_BANK_SIZE, self.chr.len()) + (addr as usize & (CHR_BANK_SIZE - 1))
+    }
+
+    // Banks $E0 - $FF select console VRAM, for pattern tables unless disabled for that half
+    fn maps_ciram(&self, addr: u16) -> bool {
+        let disabled = addr < 0x2000 && self.chr_ram_disabled[(addr as usize >> 12) & 0x01];
+        self.bank(addr) >= 0xe0 && !disabled
+    }
+
+    fn prg_ram_writable(&self, addr: u16) -> bool {
+        // Upper nibble must be %0100, each low bit protects one 2 KiB window
+        let window = (addr as usize - 0x6000) / 0x800;
+        self.write_protect & 0xf0 == 0x40 && self.write_protect & (1 << window) == 0
+    }
+
+}
+
+impl Mapper for Namco163 {
+
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4800..=0x4fff => Some(self.audio.read()),
+            0x5000..=0x57ff => {
+                Some(self.irq_counter as u8)
+            },
+            0x5800..=0x5fff => {
+                Some((self.irq_counter >> 8) as u8 & 0x7f | if self.irq_enabled { 0x80 } else { 0x00 })
+            },
+            0x6000..=0x7fff if !self.prg_ram.is_empty() => {
+                Some(self.prg_ram[(addr as usize - 0x6000) % self.prg_ram.len()])
+            },
+            0x8000..=0x9fff => Some(self.prg_rom_read(self.prg_banks[0], addr)),
+            0xa000..=0xbfff => Some(self.prg_rom_read(self.prg_banks[1], addr)),
+            0xc000..=0xdfff => Some(self.prg_rom_read(self.prg_banks[2], addr)),
+            0xe000..=0xffff => Some(self.prg_rom_read(0xff, addr)),
+            _ => None
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4800..=0x4fff => self.audio.write(data),
+            0x5000..=0x57ff => {
+                self.irq_counter = (self.irq_counter & 0x7f00) | data as u16;
+                self.irq_pending = false;
+            },
+            0x5800..=0x5fff => {
+                self.irq_counter = (self.irq_counter & 0x00ff) | ((data & 0x7f) as u16) << 8;
+                self.irq_enabled = data & 0x80 != 0;
+                self.irq_pending = false;
+            },
+            0x6000..=0x7fff if !self.prg_ram.is_empty() && self.prg_ram_writable(addr) => {
+                let len = self.prg_ram.len();
+                self.prg_ram[(addr as usize - 0x6000) % len] = data;
+            },
+            0x8000..=0xbfff => self.chr_banks[(addr as usize - 0x8000) >> 11] = data,
+            0xc000..=0xdfff => self.nametable_banks[(addr as usize - 0xc000) >> 11] = data,
+            0xe000..=0xe7ff => {
+                self.prg_banks[0] = data & 0x3f;
+                self.audio.enabled = data & 0x40 == 0;
+            },
+            0xe800..=0xefff => {
+                self.prg_banks[1] = data & 0x3f;
+                self.chr_ram_disabled = [data & 0x40 != 0, data & 0x80 != 0];
+            },
+            0xf000..=0xf7ff => self.prg_banks[2] = data & 0x3f,
+            0xf800..=0xffff => {
+                self.write_protect = data;
+                self.audio.set_address(data);
+            },
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr[self.chr_address(addr)]
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            let addr = self.chr_address(addr);
+            self.chr[addr] = data;
+        }
+    }
+
+    // Every pattern table and nametable slot can hold a CHR bank or a CIRAM page
+    fn ppu_target(&self, addr: u16) -> PpuTarget {
+        if self.maps_ciram(addr) {
+            PpuTarget::Ciram((self.bank(addr) as usize & 0x01) * 0x400 + (addr as usize & 0x03ff))
+        } else {
+            PpuTarget::Cartridge
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        // Closest standard arrangement, nametables are mapped by ppu_target
+        let banks = self.nametable_banks;
+        match [banks[0] & 0x01, banks[1] & 0x01, banks[2] & 0x01, banks[3] & 0x01] {
+            [0, 0, 1, 1] => Mirroring::Horizontal,
+            [0, 1, 0, 1] => Mirroring::Vertical,
+            [1, 1, 1, 1] => Mirroring::SingleScreenB,
+            _ => Mirroring::SingleScreenA
+        }
+    }
+
+    fn clock_cpu(&mut self) {
+        if self.irq_enabled && self.irq_counter < 0x7fff {
+            self.irq_counter += 1;
+            if self.irq_counter == 0x7fff {
+                self.irq_pending = true;
+            }
+        }
+        self.audio.clock();
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn audio_output(&self, levels: &mut ExpansionLevels) {
+        self.audio.add_output(levels);
+    }
+
+    fn expansion_chips(&self) -> u8 {
+        ExpansionChip::N163 as u8
+    }
+
+    fn save_data(&self) -> Option<Cow<'_, [u8]>> {
+        if self.battery { Some(Cow::Borrowed(&self.prg_ram)) } else { None }
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        mapper::load_into(&mut self.prg_ram, data);
+    }
+
+}
+
+// Namco 163 wavetable sound, up to eight channels sharing 128 bytes of internal RAM
+pub struct Namco163Audio {
+    ram: [u8; 128],
+    address: u8,
+    auto_increment: bool,
+    pub enabled: bool,
+    // One channel is updated every 15 CPU cycles
+    divider: u8,
+    current_channel: usize,
+    outputs: [i16; 8]
+}
+
+impl Namco163Audio {
+
+    pub fn new() -> Self {
+        Self {
+            ram: [0; 128],
+            address: 0,
+            auto_increment: false,
+            enabled: true,
+            divider: 0,
+            current_channel: 7,
+            outputs: [0; 8]
+        }
+    }
+
+    // $F800 - $FFFF, RAM address and auto increment flag
+    pub fn set_address(&mut self, data: u8) {
+        self.address = data & 0x7f;
+        self.auto_increment = data & 0x80 != 0;
+    }
+
+    // $4800 - $4FFF read
+    pub fn read(&mut self) -> u8 {
+        let data = self.ram[self.address as usize];
+        self.advance_address();
+        data
+    }
+
+    // $4800 - $4FFF write
+    pub fn write(&mut self, data: u8) {
+        self.ram[self.address as usize] = data;
+        self.advance_address();
+    }
+
+    fn advance_address(&mut self) {
+        if self.auto_increment {
+            self.address = (self.address + 1) & 0x7f;
+        }
+    }
+
+    fn channel_count(&self) -> usize {
+        ((self.ram[0x7f] >> 4) & 0x07) as usize + 1
+    }
+
+    pub fn clock(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        self.divider += 1;
+        if self.divider < 15 {
+            return;
+        }
+        self.divider = 0;
+
+        self.update_channel(self.current_channel);
+
+        // Channels run from 7 downwards, only the enabled ones are visited
+        let first = 8 - self.channel_count();
+        self.current_channel = if self.current_channel <= first { 7 } else { self.current_channel - 1 };
+    }
+
+    fn update_channel(&mut self, channel: usize) {
+        let base = 0x40 + channel * 8;
+        let regs = &self.ram[base..base + 8];
+        let frequency = regs[0] as u32 | (regs[2] as u32) << 8 | ((regs[4] & 0x03) as u32) << 16;
+        let length = 256 - (regs[4] & 0xfc) as u32;
+        let wave_address = regs[6] as u32;
+        let volume = (regs[7] & 0x0f) as i16;
+        let mut phase = regs[1] as u32 | (regs[3] as u32) << 8 | (regs[5] as u32) << 16;
+
+        phase = (phase + frequency) % (length << 16);
+
+        let sample_address = ((wave_address + (phase >> 16)) & 0xff) as usize;
+        let byte = self.ram[sample_address >> 1];
+        let sample = if sample_address & 0x01 == 0 { byte & 0x0f } else { byte >> 4 };
+        self.outputs[channel] = (sample as i16 - 8) * volume;
+
+        self.ram[base + 1] = phase as u8;
+        self.ram[base + 3] = (phase >> 8) as u8;
+        self.ram[base + 5] = (phase >> 16) as u8;
+    }
+
+    // Channels are multiplexed in hardware, averaged here to avoid the switching whine. A
+    // single channel at full volume swings about as far as an APU pulse on each side
+    pub fn output(&self) -> f32 {
+        if !self.enabled {
+            return 0.0;
+        }
+        let first = 8 - self.channel_count();
+        let sum: i16 = self.outputs[first..].iter().sum();
+        sum as f32 / (self.channel_count() as f32 * 120.0)
+    }
+
+}
+
+impl ExpansionAudio for Namco163Audio {
+
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4800..=0x4fff => Namco163Audio::write(self, data),
+            0xf800..=0xffff => self.set_address(data),
+            _ => {}
+        }
+    }
+
+    fn read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4800..=0x4fff => Some(Namco163Audio::read(self)),
+            _ => None
+        }
+    }
+
+    fn clock(&mut self) {
+        Namco163Audio::clock(self);
+    }
+
+    fn output(&self) -> f32 {
+        Namco163Audio::output(self)
+    }
+
+    fn chip(&self) -> ExpansionChip {
+        ExpansionChip::N163
+    }
+
+}
+
+impl Default for Namco163Audio {
+
+    fn default() -> Self {
+        Self::new()
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn image() -> RomImage {
+        RomImage {
+            mapper: 19,
+            prg_ram_size: 8 * 1024,
+            ..RomImage::nrom((0..8).flat_map(|bank| vec![bank as u8; PRG_BANK_SIZE]).collect(), false)
+        }
+    }
+
+    #[test]
+    pub fn switches_prg_banks() {
+        let mut mapper = Namco163::new(&image());
+        mapper.cpu_write(0xe000, 0x02);
+        mapper.cpu_write(0xf000, 0x05);
+        assert_eq!(mapper.cpu_read(0x8000), Some(2));
+        assert_eq!(mapper.cpu_read(0xc000), Some(5));
+        assert_eq!(mapper.cpu_read(0xffff), Some(7));
+    }
+
+    #[test]
+    pub fn irq_fires_at_7fff() {
+        let mut mapper = Namco163::new(&image());
+        mapper.cpu_write(0x5000, 0xfd);
+        mapper.cpu_write(0x5800, 0xff);
+        mapper.clock_cpu();
+        assert!(!mapper.irq());
+        mapper.clock_cpu();
+        assert!(mapper.irq());
+        mapper.cpu_write(0x5800, 0x00);
+        assert!(!mapper.irq());
+    }
+
+    #[test]
+    pub fn nametables_from_chr_rom() {
+        let mut image = image();
+        image.chr_rom = (0..8).flat_map(|bank| vec![bank as u8; CHR_BANK_SIZE]).collect();
+        let mut mapper = Namco163::new(&image);
+        assert_eq!(mapper.ppu_target(0x2400), PpuTarget::Ciram(0x400));
+        mapper.cpu_write(0xc800, 0x05);
+        assert_eq!(mapper.ppu_target(0x2400), PpuTarget::Cartridge);
+        assert_eq!(mapper.ppu_read(0x2400), 5);
+        // CIRAM in the pattern tables until $E800 bit 6 disables it
+        mapper.cpu_write(0x8000, 0xe1);
+        assert_eq!(mapper.ppu_target(0x0010), PpuTarget::Ciram(0x410));
+        mapper.cpu_write(0xe800, 0x40);
+        assert_eq!(mapper.ppu_target(0x0010), PpuTarget::Cartridge);
+    }
+
+    #[test]
+    pub fn sound_ram_auto_increments() {
+        let mut mapper = Namco163::new(&image());
+        mapper.cpu_write(0xf800, 0x80 | 0x10);
+        mapper.cpu_write(0x4800, 0xaa);
+        mapper.cpu_write(0x4800, 0xbb);
+        mapper.cpu_write(0xf800, 0x80 | 0x10);
+        assert_eq!(mapper.cpu_read(0x4800), Some(0xaa));
+        assert_eq!(mapper.cpu_read(0x4800), Some(0xbb));
+    }
+
+}
@@ -0,0 +1,78 @@
+use std::borrow::Cow;
+
+use crate::cartridge::mapper::{self, Mapper};
+use crate::cartridge::{Mirroring, RomImage};
+
+// Mapper 0, no bank switching
+pub struct Nrom {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    battery: bool,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    mirroring: Mirroring
+}
+
+impl Nrom {
+
+    pub fn new(image: &RomImage) -> Self {
+        let (chr, chr_is_ram) = mapper::chr_memory(image);
+        Self {
+            prg_rom: image.prg_rom.clone(),
+            prg_ram: vec![0x00; mapper::prg_ram_size(image)],
+            battery: image.battery,
+            chr,
+            chr_is_ram,
+            mirroring: image.mirroring
+        }
+    }
+
+}
+
+impl Mapper for Nrom {
+
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7fff if !self.prg_ram.is_empty() => {
+                Some(self.prg_ram[(addr as usize - 0x6000) % self.prg_ram.len()])
+            },
+            0x8000..=0xffff if !self.prg_rom.is_empty() => {
+                Some(self.prg_rom[(addr as usize - 0x8000) % self.prg_rom.len()])
+            },
+            _ => None
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if let 0x6000..=0x7fff = addr {
+            if !self.prg_ram.is_empty() {
+                let len = self.prg_ram.len();
+                self.prg_ram[(addr as usize - 0x6000) % len] = data;
+            }
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr[addr as usize % self.chr.len()]
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            let len = self.chr.len();
+            self.chr[addr as usize % len] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn save_data(&self) -> Option<Cow<'_, [u8]>> {
+        if self.battery { Some(Cow::Borrowed(&self.prg_ram)) } else { None }
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        mapper::load_into(&mut self.prg_ram, data);
+    }
+
+}
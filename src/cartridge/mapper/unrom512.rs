@@ -0,0 +1,164 @@
+use std::borrow::Cow;
+
+use crate::cartridge::flash::Flash;
+use crate::cartridge::mapper::{self, Mapper};
+use crate::cartridge::{Mirroring, RomImage};
+
+const PRG_BANK_SIZE: usize = 16 * 1024;
+const CHR_BANK_SIZE: usize = 8 * 1024;
+
+// Mapper 30, UNROM 512 with optional self-flashable PRG ROM
+pub struct Unrom512 {
+    prg: Flash,
+    // Battery flag marks boards that save by rewriting their own flash
+    flashable: bool,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    prg_bank: u8,
+    chr_bank: u8,
+    // Header four-screen flag selects mapper controlled one-screen mirroring
+    mirroring: Mirroring,
+    one_screen_b: bool
+}
+
+impl Unrom512 {
+
+    pub fn new(image: &RomImage) -> Self {
+        let (chr, chr_is_ram) = mapper::chr_memory(image);
+        let chr = if chr_is_ram { vec![0x00; chr.len().max(4 * CHR_BANK_SIZE)] } else { chr };
+        Self {
+            prg: Flash::new(image.prg_rom.clone()),
+            flashable: image.battery,
+            chr,
+            chr_is_ram,
+            prg_bank: 0,
+            chr_bank: 0,
+            mirroring: image.mirroring,
+            one_screen_b: false
+        }
+    }
+
+    fn flash_address(&self, bank: u8, addr: u16) -> usize {
+        mapper::bank_offset(bank as usize, PRG_BANK_SIZE, self.prg.data().len()) + (addr as usize & (PRG_BANK_SIZE - 1))
+    }
+
+    fn chr_address(&self, addr: u16) -> usize {
+        mapper::bank_offset(self.chr_bank as usize, CHR_BANK_SIZE, self.chr.len()) + (addr as usize & (CHR_BANK_SIZE - 1))
+    }
+
+}
+
+impl Mapper for Unrom512 {
+
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x8000..=0xbfff => Some(self.prg.read(self.flash_address(self.prg_bank, addr))),
+            0xc000..=0xffff => Some(self.prg.read(self.flash_address(0xff, addr))),
+            _ => None
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            // Flash commands go through the currently selected bank
+            0x8000..=0xbfff if self.flashable => {
+                let addr = self.flash_address(self.prg_bank, addr);
+                self.prg.write(addr, data);
+            },
+            0x8000..=0xffff => {
+                self.prg_bank = data & 0x1f;
+                self.chr_bank = (data >> 5) & 0x03;
+                self.one_screen_b = data & 0x80 != 0;
+            },
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr[self.chr_address(addr)]
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            let addr = self.chr_address(addr);
+            self.chr[addr] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.mirroring {
+            Mirroring::FourScreen if self.one_screen_b => Mirroring::SingleScreenB,
+            Mirroring::FourScreen => Mirroring::SingleScreenA,
+            mirroring => mirroring
+        }
+    }
+
+    fn save_data(&self) -> Option<Cow<'_, [u8]>> {
+        if self.flashable { Some(Cow::Borrowed(self.prg.data())) } else { None }
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        self.prg.load(data);
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn image() -> RomImage {
+        RomImage {
+            mapper: 30,
+            chr_ram_size: 32 * 1024,
+            ..RomImage::nrom((0..32).flat_map(|bank| vec![bank as u8; PRG_BANK_SIZE]).collect(), true)
+        }
+    }
+
+    #[test]
+    pub fn switches_prg_and_chr_banks() {
+        let mut mapper = Unrom512::new(&image());
+        assert_eq!(mapper.cpu_read(0xc000), Some(31));
+        // PPPPP in bits 0 - 4, CHR RAM bank in bits 5 - 6
+        mapper.cpu_write(0x8000, 0x43);
+        assert_eq!(mapper.cpu_read(0x8000), Some(3));
+        assert_eq!(mapper.cpu_read(0xffff), Some(31));
+        mapper.ppu_write(0x0000, 0x22);
+        mapper.cpu_write(0x8000, 0x00);
+        assert_eq!(mapper.ppu_read(0x0000), 0x00);
+        mapper.cpu_write(0x8000, 0x40);
+        assert_eq!(mapper.ppu_read(0x0000), 0x22);
+    }
+
+    #[test]
+    pub fn one_screen_mirroring_with_four_screen_flag() {
+        let mapper = Unrom512::new(&image());
+        assert_eq!(mapper.mirroring(), Mirroring::Horizontal);
+        let mut mapper = Unrom512::new(&RomImage { mirroring: Mirroring::FourScreen, ..image() });
+        assert_eq!(mapper.mirroring(), Mirroring::SingleScreenA);
+        mapper.cpu_write(0x8000, 0x80);
+        assert_eq!(mapper.mirroring(), Mirroring::SingleScreenB);
+    }
+
+    #[test]
+    pub fn flashes_through_selected_bank() {
+        let mut mapper = Unrom512::new(&RomImage { battery: true, ..image() });
+        // $5555 is $9555 in bank 1, $2AAA is $AAAA in bank 0; bank writes go to $C000 - $FFFF
+        let mut flash_write = |bank: u8, addr: u16, data: u8| {
+            mapper.cpu_write(0xc000, bank);
+            mapper.cpu_write(addr, data);
+        };
+        flash_write(1, 0x9555, 0xaa);
+        flash_write(0, 0xaaaa, 0x55);
+        flash_write(1, 0x9555, 0xa0);
+        flash_write(2, 0x8010, 0x00);
+        assert_eq!(mapper.cpu_read(0x8010), Some(0x00));
+        assert_eq!(mapper.cpu_read(0x8011), Some(2));
+        assert_eq!(mapper.save_data().unwrap()[2 * PRG_BANK_SIZE + 0x10], 0x00);
+        // No unlock, no change
+        mapper.cpu_write(0x8011, 0x00);
+        assert_eq!(mapper.cpu_read(0x8011), Some(2));
+    }
+
+}
@@ -0,0 +1,238 @@
+use std::borrow::Cow;
+
+use crate::apu::expansion::vrc6::Vrc6Audio;
+use crate::apu::expansion::{ExpansionAudio, ExpansionLevels};
+use crate::cartridge::mapper::vrc_irq::VrcIrq;
+use crate::cartridge::mapper::{self, Mapper};
+use crate::cartridge::nsf::ExpansionChip;
+use crate::cartridge::{Mirroring, RomImage};
+
+const PRG_BANK_SIZE: usize = 8 * 1024;
+const CHR_BANK_SIZE: usize = 1024;
+
+// Mappers 24 and 26, Konami VRC6 with its sound chip. Mapper 26 boards swap A0 and A1
+pub struct Vrc6 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    battery: bool,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    swap_lines: bool,
+    // 16 KiB bank at $8000 and 8 KiB bank at $C000
+    prg_bank_8000: u8,
+    prg_bank_c000: u8,
+    chr_banks: [u8; 8],
+    // $B003 banking style
+    chr_mode: u8,
+    mirroring: Mirroring,
+    prg_ram_enabled: bool,
+    irq: VrcIrq,
+    audio: Vrc6Audio
+}
+
+impl Vrc6 {
+
+    pub fn new(image: &RomImage) -> Self {
+        let (chr, chr_is_ram) = mapper::chr_memory(image);
+        Self {
+            prg_rom: image.prg_rom.clone(),
+            prg_ram: vec![0x00; mapper::prg_ram_size(image).max(8 * 1024)],
+            battery: image.battery,
+            chr,
+            chr_is_ram,
+            swap_lines: image.mapper == 26,
+            prg_bank_8000: 0,
+            prg_bank_c000: 0,
+            chr_banks: [0; 8],
+            chr_mode: 0,
+            mirroring: Mirroring::Vertical,
+            prg_ram_enabled: false,
+            irq: VrcIrq::new(),
+            audio: Vrc6Audio::new()
+        }
+    }
+
+    fn prg_rom_read(&self, bank: usize, addr: u16) -> u8 {
+        let offset = mapper::bank_offset(bank, PRG_BANK_SIZE, self.prg_rom.len());
+        self.prg_rom[offset + (addr as usize & (PRG_BANK_SIZE - 1))]
+    }
+
+    // 1 KiB bank for a pattern table address. In the 2 KiB modes PPU A10 replaces the
+    // lowest bank bit
+    fn chr_bank(&self, addr: u16) -> usize {
+        let slot = (addr as usize >> 10) & 0x07;
+        let a10 = slot & 0x01;
+        match (self.chr_mode, slot) {
+            (0, _) => self.chr_banks[slot] as usize,
+            (1, _) => (self.chr_banks[slot >> 1] as usize & !0x01) | a10,
+            (_, 0..=3) => self.chr_banks[slot] as usize,
+            _ => (self.chr_banks[4 + ((slot - 4) >> 1)] as usize & !0x01) | a10
+        }
+    }
+
+    fn chr_address(&self, addr: u16) -> usize {
+        mapper::bank_offset(self.chr_bank(addr), CHR_BANK_SIZE, self.chr.len()) + (addr as usize & (CHR_BANK_SIZE - 1))
+    }
+
+}
+
+impl Mapper for Vrc6 {
+
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7fff if self.prg_ram_enabled => Some(self.prg_ram[addr as usize & 0x1fff]),
+            0x8000..=0xbfff => Some(self.prg_rom_read(self.prg_bank_8000 as usize * 2 + (addr as usize >> 13 & 0x01), addr)),
+            0xc000..=0xdfff => Some(self.prg_rom_read(self.prg_bank_c000 as usize, addr)),
+            0xe000..=0xffff => Some(self.prg_rom_read(0xff, addr)),
+            _ => None
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if (0x6000..=0x7fff).contains(&addr) {
+            if self.prg_ram_enabled {
+                self.prg_ram[addr as usize & 0x1fff] = data;
+            }
+            return;
+        }
+        let addr = if self.swap_lines { (addr & 0xfffc) | (addr & 0x01) << 1 | (addr & 0x02) >> 1 } else { addr };
+        match addr & 0xf003 {
+            0x8000..=0x8003 => self.prg_bank_8000 = data & 0x0f,
+            0x9000..=0x9003 | 0xa000..=0xa002 | 0xb000..=0xb002 => self.audio.write(addr & 0xf003, data),
+            0xb003 => {
+                self.chr_mode = data & 0x03;
+                self.mirroring = match (data >> 2) & 0x03 {
+                    0 => Mirroring::Vertical,
+                    1 => Mirroring::Horizontal,
+                    2 => Mirroring::SingleScreenA,
+                    _ => Mirroring::SingleScreenB
+                };
+                self.prg_ram_enabled = data & 0x80 != 0;
+            },
+            0xc000..=0xc003 => self.prg_bank_c000 = data & 0x1f,
+            0xd000..=0xd003 => self.chr_banks[(addr & 0x03) as usize] = data,
+            0xe000..=0xe003 => self.chr_banks[4 + (addr & 0x03) as usize] = data,
+            0xf000 => self.irq.write_latch(data),
+            0xf001 => self.irq.write_control(data),
+            0xf002 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr[self.chr_address(addr)]
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            let addr = self.chr_address(addr);
+            self.chr[addr] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn clock_cpu(&mut self) {
+        self.irq.clock();
+        self.audio.clock();
+    }
+
+    fn irq(&self) -> bool {
+        self.irq.pending()
+    }
+
+    fn audio_output(&self, levels: &mut ExpansionLevels) {
+        self.audio.add_output(levels);
+    }
+
+    fn expansion_chips(&self) -> u8 {
+        ExpansionChip::Vrc6 as u8
+    }
+
+    fn save_data(&self) -> Option<Cow<'_, [u8]>> {
+        if self.battery { Some(Cow::Borrowed(&self.prg_ram)) } else { None }
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        mapper::load_into(&mut self.prg_ram, data);
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn image(mapper: u16) -> RomImage {
+        RomImage {
+            mapper,
+            prg_ram_size: 8 * 1024,
+            chr_rom: (0..16).flat_map(|bank| vec![bank as u8; CHR_BANK_SIZE]).collect(),
+            ..RomImage::nrom((0..16).flat_map(|bank| vec![bank as u8; PRG_BANK_SIZE]).collect(), false)
+        }
+    }
+
+    #[test]
+    pub fn switches_banks() {
+        let mut mapper = Vrc6::new(&image(24));
+        mapper.cpu_write(0x8000, 0x02);
+        mapper.cpu_write(0xc000, 0x09);
+        assert_eq!(mapper.cpu_read(0x8000), Some(4));
+        assert_eq!(mapper.cpu_read(0xa000), Some(5));
+        assert_eq!(mapper.cpu_read(0xc000), Some(9));
+        assert_eq!(mapper.cpu_read(0xe000), Some(15));
+
+        mapper.cpu_write(0xd001, 0x03);
+        mapper.cpu_write(0xe003, 0x0b);
+        assert_eq!(mapper.ppu_read(0x0400), 3);
+        assert_eq!(mapper.ppu_read(0x1c00), 11);
+        // 2 KiB banks, A10 from the PPU
+        mapper.cpu_write(0xb003, 0x01);
+        mapper.cpu_write(0xd000, 0x07);
+        assert_eq!(mapper.ppu_read(0x0000), 6);
+        assert_eq!(mapper.ppu_read(0x0400), 7);
+
+        mapper.cpu_write(0xb003, 0x04);
+        assert_eq!(mapper.mirroring(), Mirroring::Horizontal);
+        assert_eq!(mapper.cpu_read(0x6000), None);
+        mapper.cpu_write(0xb003, 0x80);
+        mapper.cpu_write(0x6000, 0x55);
+        assert_eq!(mapper.cpu_read(0x6000), Some(0x55));
+    }
+
+    #[test]
+    pub fn mapper_26_swaps_address_lines() {
+        let mut mapper = Vrc6::new(&image(26));
+        // $D001 on the board is register $D002
+        mapper.cpu_write(0xd001, 0x03);
+        assert_eq!(mapper.ppu_read(0x0800), 3);
+        // $9001 enables pulse 1
+        mapper.cpu_write(0x9000, 0x8f);
+        mapper.cpu_write(0x9001, 0x80);
+        let mut levels = [0.0; 6];
+        mapper.audio_output(&mut levels);
+        assert_eq!(levels[ExpansionChip::Vrc6.index()], 1.0);
+    }
+
+    #[test]
+    pub fn irq_and_sound() {
+        let mut mapper = Vrc6::new(&image(24));
+        mapper.cpu_write(0xf000, 0xff);
+        mapper.cpu_write(0xf001, 0x06);
+        mapper.clock_cpu();
+        assert!(mapper.irq());
+        mapper.cpu_write(0xf002, 0x00);
+        assert!(!mapper.irq());
+
+        let mut levels = [0.0; 6];
+        mapper.cpu_write(0x9000, 0x8f);
+        mapper.cpu_write(0x9002, 0x80);
+        mapper.audio_output(&mut levels);
+        assert_eq!(levels[ExpansionChip::Vrc6.index()], 1.0);
+        assert_eq!(levels.iter().sum::<f32>(), 1.0);
+    }
+
+}
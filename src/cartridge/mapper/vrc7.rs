@@ -0,0 +1,228 @@
+use std::borrow::Cow;
+
+use crate::apu::expansion::vrc7::Vrc7Audio;
+use crate::apu::expansion::{ExpansionAudio, ExpansionLevels};
+use crate::cartridge::mapper::vrc_irq::VrcIrq;
+use crate::cartridge::mapper::{self, Mapper};
+use crate::cartridge::nsf::ExpansionChip;
+use crate::cartridge::{Mirroring, RomImage};
+use crate::timing::Timing;
+
+const PRG_BANK_SIZE: usize = 8 * 1024;
+const CHR_BANK_SIZE: usize = 1024;
+
+// Mapper 85, Konami VRC7. VRC7a boards select the second register of a pair with A4,
+// VRC7b boards with A3; both are decoded. Only VRC7a carts wire up the sound chip
+pub struct Vrc7 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    battery: bool,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    prg_banks: [u8; 3],
+    chr_banks: [u8; 8],
+    mirroring: Mirroring,
+    prg_ram_enabled: bool,
+    // $E000 bit 6 holds the sound chip in reset
+    audio_silenced: bool,
+    irq: VrcIrq,
+    audio: Vrc7Audio
+}
+
+impl Vrc7 {
+
+    pub fn new(image: &RomImage) -> Self {
+        let (chr, chr_is_ram) = mapper::chr_memory(image);
+        Self {
+            prg_rom: image.prg_rom.clone(),
+            prg_ram: vec![0x00; mapper::prg_ram_size(image).max(8 * 1024)],
+            battery: image.battery,
+            chr,
+            chr_is_ram,
+            prg_banks: [0; 3],
+            chr_banks: [0; 8],
+            mirroring: Mirroring::Vertical,
+            prg_ram_enabled: false,
+            audio_silenced: false,
+            irq: VrcIrq::new(),
+            audio: Vrc7Audio::new(&Timing::new(image.region))
+        }
+    }
+
+    fn prg_rom_read(&self, bank: u8, addr: u16) -> u8 {
+        let offset = mapper::bank_offset(bank as usize, PRG_BANK_SIZE, self.prg_rom.len());
+        self.prg_rom[offset + (addr as usize & (PRG_BANK_SIZE - 1))]
+    }
+
+    fn chr_address(&self, addr: u16) -> usize {
+        let bank = self.chr_banks[(addr as usize >> 10) & 0x07] as usize;
+        mapper::bank_offset(bank, CHR_BANK_SIZE, self.chr.len()) + (addr as usize & (CHR_BANK_SIZE - 1))
+    }
+
+}
+
+impl Mapper for Vrc7 {
+
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7fff if self.prg_ram_enabled => Some(self.prg_ram[addr as usize & 0x1fff]),
+            0x8000..=0x9fff => Some(self.prg_rom_read(self.prg_banks[0], addr)),
+            0xa000..=0xbfff => Some(self.prg_rom_read(self.prg_banks[1], addr)),
+            0xc000..=0xdfff => Some(self.prg_rom_read(self.prg_banks[2], addr)),
+            0xe000..=0xffff => Some(self.prg_rom_read(0xff, addr)),
+            _ => None
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if (0x6000..=0x7fff).contains(&addr) {
+            if self.prg_ram_enabled {
+                self.prg_ram[addr as usize & 0x1fff] = data;
+            }
+            return;
+        }
+        // Sound registers need A5 as well
+        if addr & 0xf030 == 0x9010 || addr & 0xf030 == 0x9030 {
+            self.audio.write(addr & 0xf030, data);
+            return;
+        }
+        let second = addr & 0x18 != 0;
+        match (addr & 0xf000, second) {
+            (0x8000, false) => self.prg_banks[0] = data & 0x3f,
+            (0x8000, true) => self.prg_banks[1] = data & 0x3f,
+            (0x9000, false) => self.prg_banks[2] = data & 0x3f,
+            (0xa000..=0xd000, _) => {
+                let register = ((addr as usize >> 12) - 0xa) * 2 + second as usize;
+                self.chr_banks[register] = data;
+            },
+            (0xe000, false) => {
+                self.mirroring = match data & 0x03 {
+                    0 => Mirroring::Vertical,
+                    1 => Mirroring::Horizontal,
+                    2 => Mirroring::SingleScreenA,
+                    _ => Mirroring::SingleScreenB
+                };
+                self.audio_silenced = data & 0x40 != 0;
+                self.prg_ram_enabled = data & 0x80 != 0;
+            },
+            (0xe000, true) => self.irq.write_latch(data),
+            (0xf000, false) => self.irq.write_control(data),
+            (0xf000, true) => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr[self.chr_address(addr)]
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            let addr = self.chr_address(addr);
+            self.chr[addr] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn clock_cpu(&mut self) {
+        self.irq.clock();
+        self.audio.clock();
+    }
+
+    fn irq(&self) -> bool {
+        self.irq.pending()
+    }
+
+    fn audio_output(&self, levels: &mut ExpansionLevels) {
+        if !self.audio_silenced {
+            self.audio.add_output(levels);
+        }
+    }
+
+    fn expansion_chips(&self) -> u8 {
+        ExpansionChip::Vrc7 as u8
+    }
+
+    fn save_data(&self) -> Option<Cow<'_, [u8]>> {
+        if self.battery { Some(Cow::Borrowed(&self.prg_ram)) } else { None }
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        mapper::load_into(&mut self.prg_ram, data);
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn image() -> RomImage {
+        RomImage {
+            mapper: 85,
+            prg_ram_size: 8 * 1024,
+            chr_rom: (0..16).flat_map(|bank| vec![bank as u8; CHR_BANK_SIZE]).collect(),
+            ..RomImage::nrom((0..16).flat_map(|bank| vec![bank as u8; PRG_BANK_SIZE]).collect(), false)
+        }
+    }
+
+    #[test]
+    pub fn switches_banks_on_both_board_variants() {
+        let mut mapper = Vrc7::new(&image());
+        mapper.cpu_write(0x8000, 0x03);
+        // VRC7a and VRC7b second registers
+        mapper.cpu_write(0x8010, 0x04);
+        mapper.cpu_write(0x9000, 0x05);
+        assert_eq!(mapper.cpu_read(0x8000), Some(3));
+        assert_eq!(mapper.cpu_read(0xa000), Some(4));
+        assert_eq!(mapper.cpu_read(0xc000), Some(5));
+        assert_eq!(mapper.cpu_read(0xe000), Some(15));
+        mapper.cpu_write(0x8008, 0x06);
+        assert_eq!(mapper.cpu_read(0xa000), Some(6));
+
+        mapper.cpu_write(0xa000, 0x07);
+        mapper.cpu_write(0xd008, 0x0c);
+        assert_eq!(mapper.ppu_read(0x0000), 7);
+        assert_eq!(mapper.ppu_read(0x1c00), 12);
+
+        mapper.cpu_write(0xe000, 0x81);
+        assert_eq!(mapper.mirroring(), Mirroring::Horizontal);
+        mapper.cpu_write(0x6000, 0x55);
+        assert_eq!(mapper.cpu_read(0x6000), Some(0x55));
+    }
+
+    #[test]
+    pub fn irq_and_sound() {
+        let mut mapper = Vrc7::new(&image());
+        mapper.cpu_write(0xe010, 0xff);
+        mapper.cpu_write(0xf000, 0x06);
+        mapper.clock_cpu();
+        assert!(mapper.irq());
+        mapper.cpu_write(0xf008, 0x00);
+        assert!(!mapper.irq());
+
+        // Instrument 1 at full volume, keyed on
+        for (register, data) in [(0x30, 0x10), (0x10, 0x80), (0x20, 0x18)] {
+            mapper.cpu_write(0x9010, register);
+            mapper.cpu_write(0x9030, data);
+        }
+        let mut peak = 0.0f32;
+        for _ in 0..10_000 {
+            mapper.clock_cpu();
+            let mut levels = [0.0; 6];
+            mapper.audio_output(&mut levels);
+            peak = peak.max(levels[ExpansionChip::Vrc7.index()].abs());
+        }
+        assert!(peak > 0.0);
+
+        mapper.cpu_write(0xe000, 0x40);
+        let mut levels = [0.0; 6];
+        mapper.audio_output(&mut levels);
+        assert_eq!(levels, [0.0; 6]);
+    }
+
+}
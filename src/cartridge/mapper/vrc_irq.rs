@@ -0,0 +1,114 @@
+// Scanline length in CPU cycles times 3, the prescaler counts in PPU dots
+const PRESCALER_PERIOD: i16 = 341;
+
+// IRQ counter shared by the later Konami VRC boards (VRC4, VRC6, VRC7). An 8 bit counter
+// counts up to $FF and reloads from the latch, clocked either by every CPU cycle or by a
+// prescaler that approximates scanlines
+#[derive(Debug, Default, Clone)]
+pub struct VrcIrq {
+    latch: u8,
+    counter: u8,
+    prescaler: i16,
+    // Control bits: A (enable after acknowledge), E (enable) and M (cycle mode)
+    enable_after_ack: bool,
+    enabled: bool,
+    cycle_mode: bool,
+    pending: bool
+}
+
+impl VrcIrq {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn write_latch(&mut self, data: u8) {
+        self.latch = data;
+    }
+
+    pub fn write_control(&mut self, data: u8) {
+        self.enable_after_ack = data & 0x01 != 0;
+        self.enabled = data & 0x02 != 0;
+        self.cycle_mode = data & 0x04 != 0;
+        if self.enabled {
+            self.counter = self.latch;
+            self.prescaler = PRESCALER_PERIOD;
+        }
+        self.pending = false;
+    }
+
+    pub fn acknowledge(&mut self) {
+        self.pending = false;
+        self.enabled = self.enable_after_ack;
+    }
+
+    // Called once for every CPU cycle
+    pub fn clock(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        if self.cycle_mode {
+            self.clock_counter();
+        } else {
+            self.prescaler -= 3;
+            if self.prescaler <= 0 {
+                self.prescaler += PRESCALER_PERIOD;
+                self.clock_counter();
+            }
+        }
+    }
+
+    fn clock_counter(&mut self) {
+        if self.counter == 0xff {
+            self.counter = self.latch;
+            self.pending = true;
+        } else {
+            self.counter += 1;
+        }
+    }
+
+    pub fn pending(&self) -> bool {
+        self.pending
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    pub fn counts_cycles_or_scanlines() {
+        let mut irq = VrcIrq::new();
+        irq.write_latch(0xfe);
+        irq.write_control(0x06);
+        irq.clock();
+        assert!(!irq.pending());
+        irq.clock();
+        assert!(irq.pending());
+        // Acknowledge copies A into E
+        irq.acknowledge();
+        assert!(!irq.pending());
+        for _ in 0..4 {
+            irq.clock();
+        }
+        assert!(!irq.pending());
+
+        // Scanline mode, one clock per 113 2/3 CPU cycles
+        irq.write_control(0x03);
+        for _ in 0..227 {
+            irq.clock();
+        }
+        assert!(!irq.pending());
+        irq.clock();
+        assert!(irq.pending());
+        irq.acknowledge();
+        assert!(!irq.pending());
+        for _ in 0..114 * 256 {
+            irq.clock();
+        }
+        assert!(irq.pending());
+    }
+
+}
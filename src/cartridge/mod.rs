@@ -0,0 +1,465 @@
+pub mod ines;
+pub mod unif;
+pub mod mapper;
+pub mod eeprom;
+pub mod flash;
+pub mod save;
+pub mod patch;
+pub mod fds;
+pub mod hash;
+pub mod database;
+pub mod nsf;
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::apu::expansion::ExpansionLevels;
+use database::{Correction, RomDatabase};
+use fds::Fds;
+use mapper::{Mapper, PpuTarget};
+use nsf::{Nsf, NsfMapper};
+use patch::PatchError;
+use save::{SaveFile, SaveStatus};
+
+// Nametable arrangement selected by the board or the mapper
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    SingleScreenA,
+    SingleScreenB,
+    FourScreen
+}
+
+// TV system the game was made for
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Region {
+    Ntsc,
+    Pal,
+    // Runs on both, NTSC is used
+    Multi,
+    Dendy
+}
+
+#[derive(Debug)]
+pub enum CartridgeError {
+    // File could not be read
+    Io(std::io::Error),
+    // Data does not start with a known header
+    InvalidHeader,
+    // Header announces more data than the file contains
+    Truncated,
+    // No implementation for the requested mapper number
+    UnsupportedMapper(u16),
+    // UNIF board name without a matching mapper
+    UnsupportedBoard(String),
+    // Disk System BIOS is not an 8 KiB image
+    InvalidBios,
+    // IPS, UPS or BPS patch could not be applied
+    Patch(PatchError),
+    // NSFe chunk the file needs but the player does not know
+    UnsupportedNsfChunk(String)
+}
+
+impl fmt::Display for CartridgeError {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CartridgeError::Io(err) => write!(f, "could not read ROM file: {}", err),
+            CartridgeError::InvalidHeader => write!(f, "not a valid iNES, NES 2.0, UNIF or NSF file"),
+            CartridgeError::Truncated => write!(f, "ROM file is shorter than its header announces"),
+            CartridgeError::UnsupportedMapper(id) => write!(f, "mapper {} is not supported", id),
+            CartridgeError::UnsupportedBoard(board) => write!(f, "UNIF board {} is not supported", board),
+            CartridgeError::InvalidBios => write!(f, "Disk System BIOS must be 8 KiB"),
+            CartridgeError::Patch(err) => write!(f, "could not apply patch: {}", err),
+            CartridgeError::UnsupportedNsfChunk(id) => write!(f, "NSFe chunk {} is not supported", id)
+        }
+    }
+
+}
+
+impl std::error::Error for CartridgeError {}
+
+impl From<std::io::Error> for CartridgeError {
+
+    fn from(err: std::io::Error) -> Self {
+        CartridgeError::Io(err)
+    }
+
+}
+
+impl From<PatchError> for CartridgeError {
+
+    fn from(err: PatchError) -> Self {
+        CartridgeError::Patch(err)
+    }
+
+}
+
+// Board description and ROM contents, independent of the file format they came from
+#[derive(Debug, Clone)]
+pub struct RomImage {
+    pub mapper: u16,
+    pub submapper: u8,
+    pub mirroring: Mirroring,
+    // Board has battery backed memory
+    pub battery: bool,
+    pub prg_rom: Vec<u8>,
+    pub chr_rom: Vec<u8>,
+    // Volatile and battery backed work RAM at $6000 - $7FFF
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    // CHR RAM, only used when there is no CHR ROM
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub region: Region,
+    // 512 byte trainer loaded to $7000
+    pub trainer: Option<Vec<u8>>
+}
+
+#[cfg(test)]
+impl RomImage {
+
+    // NROM board for tests, 8 KB of CHR RAM or of empty CHR ROM
+    pub fn nrom(prg_rom: Vec<u8>, chr_ram: bool) -> Self {
+        let chr_size = 8 * 1024;
+        RomImage {
+            mapper: 0,
+            submapper: 0,
+            mirroring: Mirroring::Horizontal,
+            battery: false,
+            prg_rom,
+            chr_rom: if chr_ram { Vec::new() } else { vec![0x00; chr_size] },
+            prg_ram_size: 0,
+            prg_nvram_size: 0,
+            chr_ram_size: if chr_ram { chr_size } else { 0 },
+            chr_nvram_size: 0,
+            region: Region::Ntsc,
+            trainer: None
+        }
+    }
+
+}
+
+// How a ROM file is turned into a cartridge
+#[derive(Clone, Copy)]
+pub struct LoadOptions<'a> {
+    // Fix header fields of known dumps from the ROM database
+    pub use_database: bool,
+    // Database to use instead of the bundled one
+    pub database: Option<&'a RomDatabase>,
+    // Apply a .bps, .ups or .ips patch with the same name as the ROM
+    pub auto_patch: bool,
+    // Patch to apply instead of the one found next to the ROM
+    pub patch: Option<&'a Path>
+}
+
+impl Default for LoadOptions<'_> {
+
+    fn default() -> Self {
+        Self {
+            use_database: true,
+            database: None,
+            auto_patch: true,
+            patch: None
+        }
+    }
+
+}
+
+pub struct Cartridge {
+    pub mapper_id: u16,
+    pub submapper: u8,
+    pub battery: bool,
+    pub region: Region,
+    pub mapper: Box<dyn Mapper>,
+    // Header fields replaced by the ROM database
+    pub corrections: Vec<Correction>,
+    // Extra 2 KiB for the upper nametables of four-screen boards
+    vram: Vec<u8>,
+    save: Option<SaveFile>,
+    save_status: SaveStatus
+}
+
+impl Cartridge {
+
+    pub fn new(image: RomImage) -> Result<Self, CartridgeError> {
+        let mut mapper = mapper::create(&image)?;
+
+        // Trainer is copied into work RAM before the game starts
+        if let Some(trainer) = &image.trainer {
+            for (i, data) in trainer.iter().enumerate() {
+                mapper.cpu_write(0x7000 + i as u16, *data);
+            }
+        }
+
+        let vram = if mapper.mirroring() == Mirroring::FourScreen { vec![0x00; 2048] } else { Vec::new() };
+
+        Ok(Self {
+            mapper_id: image.mapper,
+            submapper: image.submapper,
+            battery: image.battery,
+            region: image.region,
+            mapper,
+            corrections: Vec::new(),
+            vram,
+            save: None,
+            save_status: SaveStatus::NoBattery
+        })
+    }
+
+    // Loads an iNES, NES 2.0 or UNIF image
+    pub fn from_bytes(data: &[u8]) -> Result<Self, CartridgeError> {
+        Self::from_bytes_with(data, &LoadOptions::default())
+    }
+
+    pub fn from_bytes_with(data: &[u8], options: &LoadOptions) -> Result<Self, CartridgeError> {
+        let mut image = if unif::is_unif(data) {
+            unif::parse(data)?.image
+        } else {
+            ines::parse(data)?
+        };
+
+        let corrections = if options.use_database {
+            options.database.unwrap_or_else(|| RomDatabase::bundled()).correct(&mut image)
+        } else {
+            Vec::new()
+        };
+
+        let mut cartridge = Self::new(image)?;
+        cartridge.corrections = corrections;
+        Ok(cartridge)
+    }
+
+    // Loads a ROM and, for battery backed boards, the .sav file next to it
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, CartridgeError> {
+        Self::from_file_with(path, &LoadOptions::default())
+    }
+
+    // Patches are applied to the whole file, before the header is parsed
+    pub fn from_file_with<P: AsRef<Path>>(path: P, options: &LoadOptions) -> Result<Self, CartridgeError> {
+        let mut data = fs::read(&path)?;
+        let patch_path = match options.patch {
+            Some(patch) => Some(patch.to_path_buf()),
+            None if options.auto_patch => patch::find_patch(&path),
+            None => None
+        };
+        if let Some(patch_path) = patch_path {
+            data = patch::apply(&fs::read(patch_path)?, &data)?;
+        }
+
+        let mut cartridge = Self::from_bytes_with(&data, options)?;
+        cartridge.attach_save(save::save_path(&path))?;
+        Ok(cartridge)
+    }
+
+    // Famicom Disk System with a .fds or QD image and the user supplied BIOS
+    pub fn from_disk(disk: &[u8], bios: &[u8]) -> Result<Self, CartridgeError> {
+        Ok(Self {
+            mapper_id: 20,
+            submapper: 0,
+            battery: true,
+            region: Region::Ntsc,
+            mapper: Box::new(Fds::new(disk, bios)?),
+            corrections: Vec::new(),
+            vram: Vec::new(),
+            save: None,
+            save_status: SaveStatus::NoBattery
+        })
+    }
+
+    // Board of an NSF player, mapper 31 is the NES 2.0 number for NSF style bank switching
+    pub fn from_nsf(nsf: &Nsf) -> Self {
+        Self {
+            mapper_id: 31,
+            submapper: 0,
+            battery: false,
+            region: nsf.region,
+            mapper: Box::new(NsfMapper::new(nsf)),
+            corrections: Vec::new(),
+            vram: Vec::new(),
+            save: None,
+            save_status: SaveStatus::NoBattery
+        }
+    }
+
+    // Loads a disk image and the changes the game has written to it before
+    pub fn from_disk_file<P: AsRef<Path>>(path: P, bios: &[u8]) -> Result<Self, CartridgeError> {
+        let mut cartridge = Self::from_disk(&fs::read(&path)?, bios)?;
+        cartridge.attach_save(save::save_path(&path))?;
+        Ok(cartridge)
+    }
+
+    pub fn fds(&mut self) -> Option<&mut Fds> {
+        self.mapper.fds()
+    }
+
+    // Loads battery backed memory from `path` and keeps it as flush target. A file of the wrong
+    // size most likely belongs to another dump or emulator, it is neither loaded nor overwritten
+    pub fn attach_save<P: AsRef<Path>>(&mut self, path: P) -> io::Result<SaveStatus> {
+        let memory = match self.mapper.save_data() {
+            Some(memory) => memory,
+            None => return Ok(SaveStatus::NoBattery)
+        };
+
+        let (save, status, contents) = SaveFile::open(path, &memory, self.mapper.save_is_patch())?;
+        self.save_status = status;
+        if let SaveStatus::SizeMismatch { .. } = status {
+            self.save = None;
+            return Ok(status);
+        }
+        if let Some(contents) = contents {
+            self.mapper.load_save_data(&contents);
+        }
+        self.save = Some(save);
+        Ok(status)
+    }
+
+    // How the last attached .sav file matched the board
+    pub fn save_status(&self) -> SaveStatus {
+        self.save_status
+    }
+
+    pub fn set_save_interval(&mut self, interval: Duration) {
+        if let Some(save) = self.save.as_mut() {
+            save.set_interval(interval);
+        }
+    }
+
+    // Writes battery backed memory to the attached .sav file if it changed
+    pub fn flush_save(&mut self) -> io::Result<bool> {
+        match (self.save.as_mut(), self.mapper.save_data()) {
+            (Some(save), Some(memory)) => save.flush(&memory),
+            _ => Ok(false)
+        }
+    }
+
+    // Periodic flush, meant to be called once per frame
+    pub fn flush_save_if_due(&mut self) -> io::Result<bool> {
+        match self.save.as_ref() {
+            Some(save) if save.is_due() => self.flush_save(),
+            _ => Ok(false)
+        }
+    }
+
+    pub fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        self.mapper.cpu_read(addr)
+    }
+
+    pub fn cpu_write(&mut self, addr: u16, data: u8) {
+        self.mapper.cpu_write(addr, data);
+    }
+
+    // PPU $0000 - $3EFF, `ciram` is the console VRAM the board can select instead of its own memory
+    pub fn ppu_read(&mut self, addr: u16, ciram: &[u8]) -> u8 {
+        match self.mapper.ppu_target(addr) {
+            PpuTarget::Ciram(offset) => ciram[offset],
+            PpuTarget::Cartridge if addr >= 0x2000 && !self.vram.is_empty() => self.vram[addr as usize & 0x07ff],
+            PpuTarget::Cartridge => self.mapper.ppu_read(addr)
+        }
+    }
+
+    pub fn ppu_write(&mut self, addr: u16, data: u8, ciram: &mut [u8]) {
+        match self.mapper.ppu_target(addr) {
+            PpuTarget::Ciram(offset) => ciram[offset] = data,
+            PpuTarget::Cartridge if addr >= 0x2000 && !self.vram.is_empty() => self.vram[addr as usize & 0x07ff] = data,
+            PpuTarget::Cartridge => self.mapper.ppu_write(addr, data)
+        }
+    }
+
+    pub fn mirroring(&self) -> Mirroring {
+        self.mapper.mirroring()
+    }
+
+    pub fn irq(&self) -> bool {
+        self.mapper.irq()
+    }
+
+    pub fn clock_cpu(&mut self) {
+        self.mapper.clock_cpu();
+    }
+
+    // `ExpansionChip` flags of the board's sound chips
+    pub fn expansion_chips(&self) -> u8 {
+        self.mapper.expansion_chips()
+    }
+
+    // Expansion audio level of every chip, see `Mapper::audio_output`
+    pub fn audio_output(&self) -> ExpansionLevels {
+        let mut levels = [0.0; 6];
+        self.mapper.audio_output(&mut levels);
+        levels
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    pub fn keeps_mismatched_save_file() {
+        let dir = std::env::temp_dir().join(format!("nes_emulator_cartridge_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let rom = dir.join("battery.nes");
+        // NROM with battery backed work RAM
+        let mut data = vec![b'N', b'E', b'S', 0x1a, 0x01, 0x01, 0x02, 0x00, 0, 0, 0, 0, 0, 0, 0, 0];
+        data.resize(16 + 24 * 1024, 0x00);
+        fs::write(&rom, &data).unwrap();
+        let sav = save::save_path(&rom);
+        fs::write(&sav, [0xaa; 100]).unwrap();
+
+        let options = LoadOptions { use_database: false, auto_patch: false, ..LoadOptions::default() };
+        let mut cartridge = Cartridge::from_file_with(&rom, &options).unwrap();
+        assert_eq!(cartridge.save_status(), SaveStatus::SizeMismatch { expected: 8 * 1024, found: 100 });
+        assert_eq!(cartridge.cpu_read(0x6000), Some(0x00));
+        cartridge.cpu_write(0x6000, 0x55);
+        assert!(!cartridge.flush_save().unwrap());
+        assert_eq!(fs::read(&sav).unwrap(), vec![0xaa; 100]);
+
+        // Without a save the file only appears once the game wrote something
+        fs::remove_file(&sav).unwrap();
+        let mut cartridge = Cartridge::from_file_with(&rom, &options).unwrap();
+        assert_eq!(cartridge.save_status(), SaveStatus::Created);
+        assert!(!cartridge.flush_save().unwrap());
+        assert!(!sav.exists());
+        cartridge.cpu_write(0x6000, 0x55);
+        assert!(cartridge.flush_save().unwrap());
+        assert_eq!(fs::read(&sav).unwrap()[0], 0x55);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    pub fn database_fixes_bad_header_on_load() {
+        // Header says NROM with horizontal mirroring, the dump is a battery backed FME-7 game
+        let mut data = vec![b'N', b'E', b'S', 0x1a, 0x02, 0x01, 0x00, 0x00, 0, 0, 0, 0, 0, 0, 0, 0];
+        data.resize(16 + 40 * 1024, 0xea);
+        let rom = &data[16..];
+        let xml = format!(r#"<nes20db><game>
+            <rom size="40960" crc32="{:08X}"/>
+            <pcb mapper="69" submapper="0" mirroring="V" battery="1"/>
+            <prgnvram size="8192"/>
+        </game></nes20db>"#, hash::crc32(rom));
+        let database = RomDatabase::parse(&xml);
+
+        let options = LoadOptions { database: Some(&database), ..LoadOptions::default() };
+        let cartridge = Cartridge::from_bytes_with(&data, &options).unwrap();
+        assert_eq!(cartridge.mapper_id, 69);
+        assert!(cartridge.battery);
+        let fields: Vec<&str> = cartridge.corrections.iter().map(|correction| correction.field).collect();
+        assert_eq!(fields, ["mapper", "mirroring", "battery", "PRG NVRAM"]);
+        // The corrected board is what gets built
+        assert!(cartridge.mapper.save_data().is_some());
+
+        let options = LoadOptions { database: Some(&database), use_database: false, ..LoadOptions::default() };
+        let cartridge = Cartridge::from_bytes_with(&data, &options).unwrap();
+        assert_eq!(cartridge.mapper_id, 0);
+        assert!(cartridge.corrections.is_empty());
+    }
+
+}
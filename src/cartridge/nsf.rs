@@ -0,0 +1,548 @@
+use std::time::Duration;
+
+use crate::apu::expansion::mmc5::Mmc5Audio;
+use crate::apu::expansion::vrc6::Vrc6Audio;
+use crate::apu::expansion::vrc7::Vrc7Audio;
+use crate::apu::expansion::{ExpansionAudio, ExpansionLevels};
+use crate::cartridge::fds::audio::FdsAudio;
+use crate::cartridge::mapper::fme7::Sunsoft5b;
+use crate::cartridge::mapper::namco163::Namco163Audio;
+use crate::cartridge::mapper::Mapper;
+use crate::cartridge::{CartridgeError, Mirroring, Region};
+use crate::timing::Timing;
+
+const NSF_MAGIC: &[u8] = b"NESM\x1a";
+const NSFE_MAGIC: &[u8] = b"NSFE";
+const HEADER_SIZE: usize = 0x80;
+const BANK_SIZE: usize = 0x1000;
+
+// Play routine rates in microseconds when the file leaves them at 0
+const NTSC_SPEED: u16 = 16639;
+const PAL_SPEED: u16 = 19997;
+
+// Address of the idle loop INIT and PLAY return to, in the unused register area
+pub const IDLE_LOOP: u16 = 0x5ff0;
+
+// Extra sound chips an NSF can use
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ExpansionChip {
+    Vrc6 = 1 << 0,
+    Vrc7 = 1 << 1,
+    Fds = 1 << 2,
+    Mmc5 = 1 << 3,
+    N163 = 1 << 4,
+    Sunsoft5b = 1 << 5
+}
+
+impl ExpansionChip {
+
+    pub const ALL: [ExpansionChip; 6] = [
+        ExpansionChip::Vrc6, ExpansionChip::Vrc7, ExpansionChip::Fds,
+        ExpansionChip::Mmc5, ExpansionChip::N163, ExpansionChip::Sunsoft5b
+    ];
+
+    // Position in `ALL` and in `ExpansionLevels`
+    pub fn index(&self) -> usize {
+        (*self as u8).trailing_zeros() as usize
+    }
+
+}
+
+// Per track metadata from NSFe chunks
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrackInfo {
+    pub name: Option<String>,
+    pub duration: Option<Duration>,
+    pub fade: Option<Duration>
+}
+
+// NES Sound Format file, plain NSF or NSFe
+#[derive(Debug, Clone)]
+pub struct Nsf {
+    pub total_songs: u8,
+    // First track to play, 1-based
+    pub starting_song: u8,
+    pub load_addr: u16,
+    pub init_addr: u16,
+    pub play_addr: u16,
+    pub title: String,
+    pub artist: String,
+    pub copyright: String,
+    pub ripper: String,
+    // Play routine period in microseconds
+    pub ntsc_speed: u16,
+    pub pal_speed: u16,
+    // Initial $5FF8 - $5FFF values, all zero if the file does not switch banks
+    pub bank_init: [u8; 8],
+    pub region: Region,
+    // ExpansionChip flags
+    pub chips: u8,
+    pub tracks: Vec<TrackInfo>,
+    // Track order from the NSFe playlist, 0-based
+    pub playlist: Option<Vec<u8>>,
+    pub data: Vec<u8>
+}
+
+impl Nsf {
+
+    pub fn is_nsf(data: &[u8]) -> bool {
+        data.starts_with(NSF_MAGIC) || data.starts_with(NSFE_MAGIC)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self, CartridgeError> {
+        if data.starts_with(NSF_MAGIC) {
+            Self::parse_nsf(data)
+        } else if data.starts_with(NSFE_MAGIC) {
+            let mut nsf = Self::empty();
+            let mut info = false;
+            nsf.read_chunks(&data[NSFE_MAGIC.len()..], &mut info)?;
+            if !info || nsf.data.is_empty() {
+                return Err(CartridgeError::InvalidHeader);
+            }
+            nsf.tracks.resize(nsf.total_songs as usize, TrackInfo::default());
+            Ok(nsf)
+        } else {
+            Err(CartridgeError::InvalidHeader)
+        }
+    }
+
+    fn empty() -> Self {
+        Self {
+            total_songs: 1,
+            starting_song: 1,
+            load_addr: 0x8000,
+            init_addr: 0x8000,
+            play_addr: 0x8000,
+            title: String::new(),
+            artist: String::new(),
+            copyright: String::new(),
+            ripper: String::new(),
+            ntsc_speed: NTSC_SPEED,
+            pal_speed: PAL_SPEED,
+            bank_init: [0; 8],
+            region: Region::Ntsc,
+            chips: 0,
+            tracks: Vec::new(),
+            playlist: None,
+            data: Vec::new()
+        }
+    }
+
+    fn parse_nsf(data: &[u8]) -> Result<Self, CartridgeError> {
+        if data.len() < HEADER_SIZE {
+            return Err(CartridgeError::Truncated);
+        }
+        let word = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+
+        let mut nsf = Self::empty();
+        let version = data[0x05];
+        nsf.total_songs = data[0x06].max(1);
+        nsf.starting_song = data[0x07].clamp(1, nsf.total_songs);
+        nsf.load_addr = word(0x08);
+        nsf.init_addr = word(0x0a);
+        nsf.play_addr = word(0x0c);
+        nsf.title = text(&data[0x0e..0x2e]);
+        nsf.artist = text(&data[0x2e..0x4e]);
+        nsf.copyright = text(&data[0x4e..0x6e]);
+        nsf.ntsc_speed = speed(word(0x6e), NTSC_SPEED);
+        nsf.bank_init.copy_from_slice(&data[0x70..0x78]);
+        nsf.pal_speed = speed(word(0x78), PAL_SPEED);
+        nsf.region = region(data[0x7a]);
+        nsf.chips = data[0x7b];
+
+        // NSF2 gives the program length, NSFe metadata chunks follow the program
+        let length = u32::from_le_bytes([data[0x7d], data[0x7e], data[0x7f], 0]) as usize;
+        if version >= 2 && length != 0 {
+            let end = HEADER_SIZE + length;
+            if data.len() < end {
+                return Err(CartridgeError::Truncated);
+            }
+            nsf.data = data[HEADER_SIZE..end].to_vec();
+            nsf.read_chunks(&data[end..], &mut false)?;
+        } else {
+            nsf.data = data[HEADER_SIZE..].to_vec();
+        }
+        nsf.tracks.resize(nsf.total_songs as usize, TrackInfo::default());
+        Ok(nsf)
+    }
+
+    // NSFe chunks: length, four character id, contents; unknown chunks starting with an
+    // upper case letter are required to play the file
+    fn read_chunks(&mut self, mut data: &[u8], info: &mut bool) -> Result<(), CartridgeError> {
+        while data.len() >= 8 {
+            let length = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize;
+            let id = &data[4..8];
+            if data.len() < 8 + length {
+                return Err(CartridgeError::Truncated);
+            }
+            let chunk = &data[8..8 + length];
+            data = &data[8 + length..];
+
+            match id {
+                b"INFO" => {
+                    if chunk.len() < 9 {
+                        return Err(CartridgeError::Truncated);
+                    }
+                    let word = |offset: usize| u16::from_le_bytes([chunk[offset], chunk[offset + 1]]);
+                    self.load_addr = word(0);
+                    self.init_addr = word(2);
+                    self.play_addr = word(4);
+                    self.region = region(chunk[6]);
+                    self.chips = chunk[7];
+                    self.total_songs = chunk[8].max(1);
+                    self.starting_song = chunk.get(9).map_or(1, |song| song.saturating_add(1)).min(self.total_songs);
+                    *info = true;
+                },
+                b"DATA" => self.data = chunk.to_vec(),
+                b"BANK" => {
+                    let len = chunk.len().min(8);
+                    self.bank_init = [0; 8];
+                    self.bank_init[..len].copy_from_slice(&chunk[..len]);
+                },
+                b"RATE" => {
+                    if chunk.len() >= 2 {
+                        self.ntsc_speed = speed(u16::from_le_bytes([chunk[0], chunk[1]]), NTSC_SPEED);
+                    }
+                    if chunk.len() >= 4 {
+                        self.pal_speed = speed(u16::from_le_bytes([chunk[2], chunk[3]]), PAL_SPEED);
+                    }
+                },
+                b"auth" => {
+                    let mut strings = chunk.split(|byte| *byte == 0).map(text);
+                    self.title = strings.next().unwrap_or_default();
+                    self.artist = strings.next().unwrap_or_default();
+                    self.copyright = strings.next().unwrap_or_default();
+                    self.ripper = strings.next().unwrap_or_default();
+                },
+                b"tlbl" => {
+                    for (i, name) in chunk.split(|byte| *byte == 0).take(self.total_songs as usize).enumerate() {
+                        self.track_mut(i).name = Some(text(name));
+                    }
+                },
+                b"time" | b"fade" => {
+                    for (i, value) in chunk.chunks_exact(4).enumerate() {
+                        // Negative values mean the player's default
+                        let ms = i32::from_le_bytes([value[0], value[1], value[2], value[3]]);
+                        let time = if ms >= 0 { Some(Duration::from_millis(ms as u64)) } else { None };
+                        if id == b"time" {
+                            self.track_mut(i).duration = time;
+                        } else {
+                            self.track_mut(i).fade = time;
+                        }
+                    }
+                },
+                b"plst" => self.playlist = Some(chunk.to_vec()),
+                b"NEND" => break,
+                _ if id[0].is_ascii_uppercase() => {
+                    return Err(CartridgeError::UnsupportedNsfChunk(String::from_utf8_lossy(id).into_owned()));
+                },
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn track_mut(&mut self, index: usize) -> &mut TrackInfo {
+        if self.tracks.len() <= index {
+            self.tracks.resize(index + 1, TrackInfo::default());
+        }
+        &mut self.tracks[index]
+    }
+
+    // Metadata of a 1-based track number
+    pub fn track(&self, track: u8) -> Option<&TrackInfo> {
+        self.tracks.get((track as usize).checked_sub(1)?)
+    }
+
+    pub fn has_chip(&self, chip: ExpansionChip) -> bool {
+        self.chips & chip as u8 != 0
+    }
+
+    pub fn bankswitched(&self) -> bool {
+        self.bank_init.iter().any(|bank| *bank != 0)
+    }
+
+    // Values for $5FF6 - $5FFF at the start of a track, $5FF6 and $5FF7 only matter with
+    // the FDS, where they select the banks at $6000 and $7000
+    pub fn initial_banks(&self) -> [u8; 10] {
+        let mut banks = [0; 10];
+        if self.bankswitched() {
+            banks[0] = self.bank_init[6];
+            banks[1] = self.bank_init[7];
+            banks[2..].copy_from_slice(&self.bank_init);
+        } else {
+            // Unbanked images are laid out from $6000, see `NsfMapper`
+            for (i, bank) in banks.iter_mut().enumerate() {
+                *bank = i as u8;
+            }
+        }
+        banks
+    }
+
+    // Play routine period in microseconds for the region the player runs at
+    pub fn play_speed(&self, region: Region) -> u16 {
+        if region == Region::Pal { self.pal_speed } else { self.ntsc_speed }
+    }
+
+}
+
+fn text(data: &[u8]) -> String {
+    let end = data.iter().position(|byte| *byte == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).trim().to_string()
+}
+
+fn speed(value: u16, default: u16) -> u16 {
+    if value == 0 { default } else { value }
+}
+
+// Bit 0 selects PAL, bit 1 marks tunes that play on both
+fn region(flags: u8) -> Region {
+    if flags & 0x02 != 0 {
+        Region::Multi
+    } else if flags & 0x01 != 0 {
+        Region::Pal
+    } else {
+        Region::Ntsc
+    }
+}
+
+// Hardware an NSF player provides: 4 KiB banks at $8000 - $FFFF selected with $5FF8 - $5FFF,
+// work RAM at $6000 and the expansion chips the file asks for. With the FDS all of
+// $6000 - $FFFF is RAM, $5FF6 - $5FFF copy banks into it. MMC5 tunes also get its ExRAM
+// and multiplier
+pub struct NsfMapper {
+    // Program split into 4 KiB banks, the first one starts at the load address rounded down
+    prg: Vec<u8>,
+    // Banks at $6000 - $FFFF
+    banks: [u8; 10],
+    ram: Vec<u8>,
+    fds_ram: bool,
+    chr_ram: Vec<u8>,
+    // MMC5 $5C00 - $5FF5 and the $5205 / $5206 multiplier
+    exram: Vec<u8>,
+    multiplier: [u8; 2],
+    // `ExpansionChip` flags and the chips themselves
+    chip_flags: u8,
+    chips: Vec<Box<dyn ExpansionAudio>>
+}
+
+impl NsfMapper {
+
+    pub fn new(nsf: &Nsf) -> Self {
+        let prg = if nsf.bankswitched() {
+            let mut prg = vec![0x00; nsf.load_addr as usize & (BANK_SIZE - 1)];
+            prg.extend_from_slice(&nsf.data);
+            prg
+        } else {
+            // Image of $6000 - $FFFF
+            let mut prg = vec![0x00; 10 * BANK_SIZE];
+            let start = (nsf.load_addr as usize).saturating_sub(0x6000).min(prg.len());
+            let len = nsf.data.len().min(prg.len() - start);
+            prg[start..start + len].copy_from_slice(&nsf.data[..len]);
+            prg
+        };
+
+        let fds_ram = nsf.has_chip(ExpansionChip::Fds);
+        let mut chips: Vec<Box<dyn ExpansionAudio>> = Vec::new();
+        if nsf.has_chip(ExpansionChip::Vrc6) {
+            chips.push(Box::new(Vrc6Audio::new()));
+        }
+        if nsf.has_chip(ExpansionChip::Vrc7) {
+            chips.push(Box::new(Vrc7Audio::new(&Timing::new(nsf.region))));
+        }
+        if fds_ram {
+            let mut audio = FdsAudio::new();
+            audio.enabled = true;
+            chips.push(Box::new(audio));
+        }
+        if nsf.has_chip(ExpansionChip::Mmc5) {
+            chips.push(Box::new(Mmc5Audio::new()));
+        }
+        if nsf.has_chip(ExpansionChip::N163) {
+            chips.push(Box::new(Namco163Audio::new()));
+        }
+        if nsf.has_chip(ExpansionChip::Sunsoft5b) {
+            chips.push(Box::new(Sunsoft5b::new()));
+        }
+
+        let mut mapper = Self {
+            prg,
+            banks: [0; 10],
+            ram: vec![0x00; if fds_ram { 10 * BANK_SIZE } else { 2 * BANK_SIZE }],
+            fds_ram,
+            chr_ram: vec![0x00; 8 * 1024],
+            exram: if nsf.has_chip(ExpansionChip::Mmc5) { vec![0x00; 0x3f6] } else { Vec::new() },
+            multiplier: [0xff; 2],
+            chip_flags: nsf.chips,
+            chips
+        };
+        for (slot, bank) in nsf.initial_banks().iter().enumerate() {
+            mapper.select_bank(slot, *bank);
+        }
+        mapper
+    }
+
+    fn bank_data(&self, bank: u8) -> &[u8] {
+        let banks = (self.prg.len() + BANK_SIZE - 1) / BANK_SIZE;
+        let start = (bank as usize % banks.max(1)) * BANK_SIZE;
+        &self.prg[start..(start + BANK_SIZE).min(self.prg.len())]
+    }
+
+    // Slot 0 is $6000, slot 2 is $8000
+    fn select_bank(&mut self, slot: usize, bank: u8) {
+        if self.fds_ram {
+            let data = self.bank_data(bank).to_vec();
+            let start = slot * BANK_SIZE;
+            self.ram[start..start + BANK_SIZE].fill(0x00);
+            self.ram[start..start + data.len()].copy_from_slice(&data);
+        } else if slot >= 2 {
+            self.banks[slot] = bank;
+        }
+    }
+
+}
+
+impl Mapper for NsfMapper {
+
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            // JMP IDLE_LOOP
+            IDLE_LOOP => Some(0x4c),
+            0x5ff1 => Some(IDLE_LOOP as u8),
+            0x5ff2 => Some((IDLE_LOOP >> 8) as u8),
+            0x5205 if !self.exram.is_empty() => Some((self.multiplier[0] as u16 * self.multiplier[1] as u16) as u8),
+            0x5206 if !self.exram.is_empty() => Some(((self.multiplier[0] as u16 * self.multiplier[1] as u16) >> 8) as u8),
+            0x5c00..=0x5ff5 if !self.exram.is_empty() => Some(self.exram[addr as usize - 0x5c00]),
+            0x4020..=0x5fef => self.chips.iter_mut().find_map(|chip| chip.read(addr)),
+            0x6000..=0xffff if self.fds_ram => Some(self.ram[addr as usize - 0x6000]),
+            0x6000..=0x7fff => Some(self.ram[addr as usize - 0x6000]),
+            0x8000..=0xffff => {
+                let slot = (addr as usize - 0x6000) / BANK_SIZE;
+                self.bank_data(self.banks[slot]).get(addr as usize & (BANK_SIZE - 1)).copied()
+            },
+            _ => None
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x5205 | 0x5206 if !self.exram.is_empty() => self.multiplier[addr as usize - 0x5205] = data,
+            0x5c00..=0x5ff5 if !self.exram.is_empty() => self.exram[addr as usize - 0x5c00] = data,
+            0x5ff6..=0x5fff => self.select_bank(addr as usize - 0x5ff6, data),
+            0x6000..=0xffff if self.fds_ram => self.ram[addr as usize - 0x6000] = data,
+            0x6000..=0x7fff => self.ram[addr as usize - 0x6000] = data,
+            _ => {}
+        }
+        // Sound registers, some of them overlap ROM
+        for chip in self.chips.iter_mut() {
+            chip.write(addr, data);
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr_ram[addr as usize & 0x1fff]
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        self.chr_ram[addr as usize & 0x1fff] = data;
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        Mirroring::Horizontal
+    }
+
+    fn clock_cpu(&mut self) {
+        for chip in self.chips.iter_mut() {
+            chip.clock();
+        }
+    }
+
+    fn audio_output(&self, levels: &mut ExpansionLevels) {
+        for chip in &self.chips {
+            chip.add_output(levels);
+        }
+    }
+
+    fn expansion_chips(&self) -> u8 {
+        self.chip_flags
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn header() -> Vec<u8> {
+        let mut data = vec![0x00; HEADER_SIZE];
+        data[..5].copy_from_slice(NSF_MAGIC);
+        data[0x05] = 1;
+        data[0x06] = 3;
+        data[0x07] = 2;
+        data[0x08..0x0e].copy_from_slice(&[0x00, 0x80, 0x03, 0x80, 0x06, 0x80]);
+        data[0x0e..0x13].copy_from_slice(b"Title");
+        data[0x2e..0x34].copy_from_slice(b"Artist");
+        data
+    }
+
+    fn chunk(data: &mut Vec<u8>, id: &[u8; 4], contents: &[u8]) {
+        data.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+        data.extend_from_slice(id);
+        data.extend_from_slice(contents);
+    }
+
+    #[test]
+    pub fn parses_nsf_and_nsfe() {
+        let mut data = header();
+        data.extend_from_slice(&[0xea; 16]);
+        let nsf = Nsf::parse(&data).unwrap();
+        assert_eq!((nsf.total_songs, nsf.starting_song), (3, 2));
+        assert_eq!((nsf.load_addr, nsf.init_addr, nsf.play_addr), (0x8000, 0x8003, 0x8006));
+        assert_eq!((nsf.title.as_str(), nsf.artist.as_str()), ("Title", "Artist"));
+        assert_eq!(nsf.ntsc_speed, NTSC_SPEED);
+        assert_eq!(nsf.data.len(), 16);
+        assert!(!nsf.bankswitched());
+
+        let mut data = NSFE_MAGIC.to_vec();
+        chunk(&mut data, b"INFO", &[0x00, 0x80, 0x03, 0x80, 0x06, 0x80, 0x01, 0x20, 0x02, 0x01]);
+        chunk(&mut data, b"DATA", &[0xea; 4]);
+        chunk(&mut data, b"auth", b"Song\0Composer\0\0Ripper\0");
+        chunk(&mut data, b"tlbl", b"Intro\0Boss\0");
+        chunk(&mut data, b"time", &[0x10, 0x27, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff]);
+        chunk(&mut data, b"NEND", &[]);
+        let nsf = Nsf::parse(&data).unwrap();
+        assert_eq!((nsf.total_songs, nsf.starting_song, nsf.region), (2, 2, Region::Pal));
+        assert!(nsf.has_chip(ExpansionChip::Sunsoft5b));
+        assert_eq!((nsf.title.as_str(), nsf.ripper.as_str()), ("Song", "Ripper"));
+        assert_eq!(nsf.track(1).unwrap().name.as_deref(), Some("Intro"));
+        assert_eq!(nsf.track(1).unwrap().duration, Some(Duration::from_secs(10)));
+        assert_eq!(nsf.track(2).unwrap().duration, None);
+
+        let mut data = NSFE_MAGIC.to_vec();
+        chunk(&mut data, b"INFO", &[0x00, 0x80, 0x03, 0x80, 0x06, 0x80, 0x00, 0x00, 0x01]);
+        chunk(&mut data, b"XTRA", &[]);
+        assert!(matches!(Nsf::parse(&data), Err(CartridgeError::UnsupportedNsfChunk(_))));
+    }
+
+    #[test]
+    pub fn switches_banks() {
+        let mut data = header();
+        data[0x08] = 0x80;
+        data[0x70..0x78].copy_from_slice(&[0, 1, 2, 3, 4, 5, 6, 1]);
+        let mut program = vec![0x00; 2 * BANK_SIZE];
+        program[BANK_SIZE - 0x80] = 0x11;
+        data.extend_from_slice(&program);
+        let nsf = Nsf::parse(&data).unwrap();
+        let mut mapper = NsfMapper::new(&nsf);
+
+        // Bank 0 starts $80 bytes before the load address
+        assert_eq!(mapper.cpu_read(0x8080), Some(0x00));
+        assert_eq!(mapper.cpu_read(0x9000), Some(0x11));
+        assert_eq!(mapper.cpu_read(0xf000), Some(0x11));
+        mapper.cpu_write(0x5ff8, 0x01);
+        assert_eq!(mapper.cpu_read(0x8000), Some(0x11));
+        assert_eq!(mapper.cpu_read(IDLE_LOOP), Some(0x4c));
+    }
+
+}
@@ -0,0 +1,149 @@
+use crate::cartridge::patch::{self, PatchError};
+
+const MAGIC: &[u8] = b"BPS1";
+// Source, target and patch CRC-32
+const FOOTER_SIZE: usize = 12;
+
+const SOURCE_READ: usize = 0;
+const TARGET_READ: usize = 1;
+const SOURCE_COPY: usize = 2;
+const TARGET_COPY: usize = 3;
+
+pub fn is_bps(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+// Builds the target from runs copied out of the source, the patch or the target itself
+pub fn apply(patch: &[u8], source: &[u8]) -> Result<Vec<u8>, PatchError> {
+
+    if !is_bps(patch) {
+        return Err(PatchError::UnknownFormat);
+    }
+    if patch.len() < MAGIC.len() + FOOTER_SIZE {
+        return Err(PatchError::Truncated);
+    }
+
+    let footer = patch::read_footer(patch)?;
+    patch::verify_source(source, footer.source)?;
+
+    let end = patch.len() - FOOTER_SIZE;
+    let mut pos = MAGIC.len();
+    let source_size = patch::read_number(patch, &mut pos, end)?;
+    let target_size = patch::read_number(patch, &mut pos, end)?;
+    let metadata_size = patch::read_number(patch, &mut pos, end)?;
+    pos = pos.checked_add(metadata_size).filter(|pos| *pos <= end).ok_or(PatchError::Truncated)?;
+    if source_size != source.len() {
+        return Err(PatchError::SourceSize { expected: source_size, actual: source.len() });
+    }
+    patch::check_target_size(target_size)?;
+
+    let mut output = Vec::with_capacity(target_size);
+    let mut source_offset = 0;
+    let mut target_offset = 0;
+    while pos < end {
+        let action = patch::read_number(patch, &mut pos, end)?;
+        let length = (action >> 2) + 1;
+        if length > target_size - output.len() {
+            return Err(PatchError::OutOfBounds);
+        }
+        match action & 0x03 {
+            SOURCE_READ => {
+                let start = output.len();
+                let data = source.get(start..start + length).ok_or(PatchError::OutOfBounds)?;
+                output.extend_from_slice(data);
+            },
+            TARGET_READ => {
+                let data = patch[..end].get(pos..pos + length).ok_or(PatchError::Truncated)?;
+                output.extend_from_slice(data);
+                pos += length;
+            },
+            SOURCE_COPY => {
+                source_offset = relative(source_offset, patch::read_number(patch, &mut pos, end)?)?;
+                let copy_end = source_offset.checked_add(length).ok_or(PatchError::OutOfBounds)?;
+                let data = source.get(source_offset..copy_end).ok_or(PatchError::OutOfBounds)?;
+                output.extend_from_slice(data);
+                source_offset += length;
+            },
+            TARGET_COPY => {
+                target_offset = relative(target_offset, patch::read_number(patch, &mut pos, end)?)?;
+                // Byte by byte, the copy may overlap the bytes it produces
+                for _ in 0..length {
+                    let byte = *output.get(target_offset).ok_or(PatchError::OutOfBounds)?;
+                    output.push(byte);
+                    target_offset += 1;
+                }
+            },
+            _ => unreachable!()
+        }
+    }
+
+    if output.len() != target_size {
+        return Err(PatchError::OutOfBounds);
+    }
+    patch::verify_target(&output, footer.target)?;
+    Ok(output)
+}
+
+// Offsets are stored as sign (bit 0) and magnitude
+fn relative(offset: usize, data: usize) -> Result<usize, PatchError> {
+    let delta = data >> 1;
+    if data & 0x01 != 0 {
+        offset.checked_sub(delta).ok_or(PatchError::OutOfBounds)
+    } else {
+        offset.checked_add(delta).ok_or(PatchError::OutOfBounds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::cartridge::hash;
+
+    #[test]
+    pub fn applies_all_actions() {
+        let source = [1, 2, 3, 4];
+        let target = [1, 2, 9, 9, 9, 3, 4, 1];
+        let mut patch = MAGIC.to_vec();
+        patch.extend_from_slice(&[0x84, 0x88, 0x80]);
+        // Source read 2, target read 1, target copy 2 from offset 2, source copy 2 from offset 2
+        patch.extend_from_slice(&[0x80 | 1 << 2, 0x80 | 1, 9]);
+        patch.extend_from_slice(&[0x80 | (1 << 2 | 3), 0x80 | 2 << 1]);
+        patch.extend_from_slice(&[0x80 | (1 << 2 | 2), 0x80 | 2 << 1]);
+        // Source copy 1 from offset 0, 4 back
+        patch.extend_from_slice(&[0x80 | 2, 0x80 | (4 << 1 | 1)]);
+        patch.extend_from_slice(&hash::crc32(&source).to_le_bytes());
+        patch.extend_from_slice(&hash::crc32(&target).to_le_bytes());
+        let crc = hash::crc32(&patch);
+        patch.extend_from_slice(&crc.to_le_bytes());
+
+        assert_eq!(apply(&patch, &source).unwrap(), target);
+    }
+
+    // Header numbers and actions for a 4 byte source, with the footer
+    fn bps(numbers: &[u8]) -> Vec<u8> {
+        let mut patch = MAGIC.to_vec();
+        patch.extend_from_slice(numbers);
+        patch.extend_from_slice(&hash::crc32(&[1, 2, 3, 4]).to_le_bytes());
+        patch.extend_from_slice(&0u32.to_le_bytes());
+        let crc = hash::crc32(&patch);
+        patch.extend_from_slice(&crc.to_le_bytes());
+        patch
+    }
+
+    #[test]
+    pub fn rejects_corrupt_sizes() {
+        let source = [1, 2, 3, 4];
+        // Metadata size of usize::MAX - 2
+        let mut numbers = vec![0x84, 0x84, 0x7d];
+        numbers.extend_from_slice(&[0x7e; 8]);
+        numbers.push(0x80);
+        assert_eq!(apply(&bps(&numbers), &source), Err(PatchError::Truncated));
+        // Target of about 256 MiB
+        assert!(matches!(apply(&bps(&[0x84, 0x7f, 0x7f, 0x7f, 0x7f, 0x80, 0x80]), &source), Err(PatchError::TargetTooLarge(_))));
+        // Target copy of 16 bytes that keeps feeding itself, into an 8 byte target
+        let numbers = [0x84, 0x88, 0x80, 0x80 | 1 << 2, 0x80 | (15 << 2 | 3), 0x80];
+        assert_eq!(apply(&bps(&numbers), &source), Err(PatchError::OutOfBounds));
+    }
+
+}
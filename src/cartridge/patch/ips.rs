@@ -0,0 +1,118 @@
+use crate::cartridge::patch::PatchError;
+
+const MAGIC: &[u8] = b"PATCH";
+const EOF_MARKER: &[u8] = b"EOF";
+// A record at this offset would read as the end marker
+const EOF_OFFSET: usize = 0x454f46;
+const MAX_OFFSET: usize = 0xffffff;
+const MAX_RECORD: usize = 0xffff;
+
+pub fn is_ips(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+pub fn apply(patch: &[u8], source: &[u8]) -> Result<Vec<u8>, PatchError> {
+
+    if !is_ips(patch) {
+        return Err(PatchError::UnknownFormat);
+    }
+
+    let mut output = source.to_vec();
+    let mut pos = MAGIC.len();
+    loop {
+        let header = patch.get(pos..pos + 3).ok_or(PatchError::Truncated)?;
+        if header == EOF_MARKER {
+            pos += 3;
+            break;
+        }
+        let offset = (header[0] as usize) << 16 | (header[1] as usize) << 8 | header[2] as usize;
+        let size = read_u16(patch, pos + 3)?;
+        pos += 5;
+
+        if size == 0 {
+            // RLE record, count followed by the fill value
+            let count = read_u16(patch, pos)?;
+            let value = *patch.get(pos + 2).ok_or(PatchError::Truncated)?;
+            pos += 3;
+            write(&mut output, offset, &vec![value; count]);
+        } else {
+            let data = patch.get(pos..pos + size).ok_or(PatchError::Truncated)?;
+            pos += size;
+            write(&mut output, offset, data);
+        }
+    }
+
+    // Lunar IPS extension, a trailing 24 bit size truncates the output
+    if let Some(size) = patch.get(pos..pos + 3) {
+        output.truncate((size[0] as usize) << 16 | (size[1] as usize) << 8 | size[2] as usize);
+    }
+
+    Ok(output)
+}
+
+// Builds a patch turning `source` into `target`, both must have the same length
+pub fn create(source: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut patch = MAGIC.to_vec();
+    let len = target.len().min(MAX_OFFSET + 1);
+
+    let mut pos = 0;
+    while pos < len {
+        if source.get(pos) == Some(&target[pos]) {
+            pos += 1;
+            continue;
+        }
+
+        let mut start = pos;
+        let mut end = pos;
+        while end < len && end - start < MAX_RECORD && source.get(end) != Some(&target[end]) {
+            end += 1;
+        }
+        if start == EOF_OFFSET {
+            start -= 1;
+        }
+
+        patch.extend_from_slice(&[(start >> 16) as u8, (start >> 8) as u8, start as u8]);
+        patch.extend_from_slice(&((end - start) as u16).to_be_bytes());
+        patch.extend_from_slice(&target[start..end]);
+        pos = end;
+    }
+
+    patch.extend_from_slice(EOF_MARKER);
+    patch
+}
+
+fn read_u16(data: &[u8], pos: usize) -> Result<usize, PatchError> {
+    let bytes = data.get(pos..pos + 2).ok_or(PatchError::Truncated)?;
+    Ok((bytes[0] as usize) << 8 | bytes[1] as usize)
+}
+
+fn write(output: &mut Vec<u8>, offset: usize, data: &[u8]) {
+    if output.len() < offset + data.len() {
+        output.resize(offset + data.len(), 0);
+    }
+    output[offset..offset + data.len()].copy_from_slice(data);
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    pub fn create_then_apply() {
+        let source = vec![0u8; 64];
+        let mut target = source.clone();
+        target[3] = 1;
+        target[40..44].copy_from_slice(&[5, 6, 7, 8]);
+
+        let patch = create(&source, &target);
+        assert_eq!(apply(&patch, &source).unwrap(), target);
+    }
+
+    #[test]
+    pub fn applies_rle_records() {
+        let patch = [b'P', b'A', b'T', b'C', b'H', 0, 0, 2, 0, 0, 0, 3, 0xaa, b'E', b'O', b'F'];
+        assert_eq!(apply(&patch, &[0; 6]).unwrap(), vec![0, 0, 0xaa, 0xaa, 0xaa, 0]);
+    }
+
+}
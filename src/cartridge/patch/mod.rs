@@ -0,0 +1,138 @@
+pub mod ips;
+pub mod ups;
+pub mod bps;
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::cartridge::hash;
+
+// Extensions looked for next to a ROM, in order of preference
+pub const PATCH_EXTENSIONS: [&str; 3] = ["bps", "ups", "ips"];
+
+// Largest ROM a patch may produce, far beyond any real cartridge
+pub const MAX_TARGET_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PatchError {
+    // Patch does not start with a known magic
+    UnknownFormat,
+    // Patch ends in the middle of a record
+    Truncated,
+    // Patch was made for a ROM of another size
+    SourceSize { expected: usize, actual: usize },
+    // Patch was made for another ROM or revision
+    SourceChecksum { expected: u32, actual: u32 },
+    // Patched ROM does not match what the patch author produced
+    TargetChecksum { expected: u32, actual: u32 },
+    // Patch file itself is damaged
+    PatchChecksum { expected: u32, actual: u32 },
+    // Copy outside of the source or target
+    OutOfBounds,
+    // Target size above `MAX_TARGET_SIZE`, most likely a corrupt patch
+    TargetTooLarge(usize)
+}
+
+impl fmt::Display for PatchError {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchError::UnknownFormat => write!(f, "not an IPS, UPS or BPS patch"),
+            PatchError::Truncated => write!(f, "patch ends unexpectedly"),
+            PatchError::SourceSize { expected, actual } =>
+                write!(f, "patch expects a {} byte ROM, got {} bytes", expected, actual),
+            PatchError::SourceChecksum { expected, actual } =>
+                write!(f, "patch expects a ROM with CRC32 {:08x}, got {:08x}", expected, actual),
+            PatchError::TargetChecksum { expected, actual } =>
+                write!(f, "patched ROM has CRC32 {:08x}, expected {:08x}", actual, expected),
+            PatchError::PatchChecksum { expected, actual } =>
+                write!(f, "patch file is corrupt, CRC32 {:08x}, expected {:08x}", actual, expected),
+            PatchError::OutOfBounds => write!(f, "patch copies data outside of the ROM"),
+            PatchError::TargetTooLarge(size) =>
+                write!(f, "patch produces a {} byte ROM, more than the {} byte limit", size, MAX_TARGET_SIZE)
+        }
+    }
+
+}
+
+impl std::error::Error for PatchError {}
+
+// Applies an IPS, UPS or BPS patch, the format is taken from the magic
+pub fn apply(patch: &[u8], source: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if ips::is_ips(patch) {
+        ips::apply(patch, source)
+    } else if ups::is_ups(patch) {
+        ups::apply(patch, source)
+    } else if bps::is_bps(patch) {
+        bps::apply(patch, source)
+    } else {
+        Err(PatchError::UnknownFormat)
+    }
+}
+
+// Patch with the same name as the ROM, `game.nes` is patched by `game.bps`, `game.ups` or `game.ips`
+pub fn find_patch<P: AsRef<Path>>(rom: P) -> Option<PathBuf> {
+    PATCH_EXTENSIONS.iter()
+        .map(|extension| rom.as_ref().with_extension(extension))
+        .find(|path| path.is_file())
+}
+
+// CRC-32s at the end of UPS and BPS patches
+pub(crate) struct Footer {
+    pub source: u32,
+    pub target: u32
+}
+
+// Reads the footer, the patch checksum covers everything in front of it
+pub(crate) fn read_footer(patch: &[u8]) -> Result<Footer, PatchError> {
+    let footer = patch.get(patch.len().saturating_sub(12)..).filter(|f| f.len() == 12).ok_or(PatchError::Truncated)?;
+    let read = |i: usize| u32::from_le_bytes([footer[i], footer[i + 1], footer[i + 2], footer[i + 3]]);
+
+    let expected = read(8);
+    let actual = hash::crc32(&patch[..patch.len() - 4]);
+    if expected != actual {
+        return Err(PatchError::PatchChecksum { expected, actual });
+    }
+    Ok(Footer { source: read(0), target: read(4) })
+}
+
+pub(crate) fn verify_source(source: &[u8], expected: u32) -> Result<(), PatchError> {
+    let actual = hash::crc32(source);
+    if expected != actual {
+        return Err(PatchError::SourceChecksum { expected, actual });
+    }
+    Ok(())
+}
+
+// Size UPS and BPS patches declare for the target is checked before anything is allocated
+pub(crate) fn check_target_size(size: usize) -> Result<(), PatchError> {
+    if size > MAX_TARGET_SIZE {
+        return Err(PatchError::TargetTooLarge(size));
+    }
+    Ok(())
+}
+
+pub(crate) fn verify_target(target: &[u8], expected: u32) -> Result<(), PatchError> {
+    let actual = hash::crc32(target);
+    if expected != actual {
+        return Err(PatchError::TargetChecksum { expected, actual });
+    }
+    Ok(())
+}
+
+// Variable length number shared by UPS and BPS, 7 bits per byte with the last byte flagged by bit 7
+pub(crate) fn read_number(patch: &[u8], pos: &mut usize, end: usize) -> Result<usize, PatchError> {
+    let mut data = 0usize;
+    let mut shift = 1usize;
+    loop {
+        let byte = *patch[..end].get(*pos).ok_or(PatchError::Truncated)?;
+        *pos += 1;
+        let value = ((byte & 0x7f) as usize).checked_mul(shift).ok_or(PatchError::OutOfBounds)?;
+        data = data.checked_add(value).ok_or(PatchError::OutOfBounds)?;
+        if byte & 0x80 != 0 {
+            return Ok(data);
+        }
+        shift = shift.checked_mul(0x80).ok_or(PatchError::OutOfBounds)?;
+        data = data.checked_add(shift).ok_or(PatchError::OutOfBounds)?;
+    }
+}
@@ -0,0 +1,103 @@
+use crate::cartridge::patch::{self, PatchError};
+
+const MAGIC: &[u8] = b"UPS1";
+// Source, target and patch CRC-32
+const FOOTER_SIZE: usize = 12;
+
+pub fn is_ups(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+// Hunks are XORed into the source, each one ends with a zero byte
+pub fn apply(patch: &[u8], source: &[u8]) -> Result<Vec<u8>, PatchError> {
+
+    if !is_ups(patch) {
+        return Err(PatchError::UnknownFormat);
+    }
+    if patch.len() < MAGIC.len() + FOOTER_SIZE {
+        return Err(PatchError::Truncated);
+    }
+
+    let footer = patch::read_footer(patch)?;
+    patch::verify_source(source, footer.source)?;
+
+    let end = patch.len() - FOOTER_SIZE;
+    let mut pos = MAGIC.len();
+    let source_size = patch::read_number(patch, &mut pos, end)?;
+    let target_size = patch::read_number(patch, &mut pos, end)?;
+    if source_size != source.len() {
+        return Err(PatchError::SourceSize { expected: source_size, actual: source.len() });
+    }
+    patch::check_target_size(target_size)?;
+
+    let mut output = source.to_vec();
+    output.resize(target_size, 0);
+    let mut offset = 0usize;
+    while pos < end {
+        offset = offset.checked_add(patch::read_number(patch, &mut pos, end)?).ok_or(PatchError::OutOfBounds)?;
+        loop {
+            let xor = *patch[..end].get(pos).ok_or(PatchError::Truncated)?;
+            pos += 1;
+            if xor == 0 {
+                offset += 1;
+                break;
+            }
+            if let Some(byte) = output.get_mut(offset) {
+                *byte ^= xor;
+            }
+            offset += 1;
+        }
+    }
+
+    patch::verify_target(&output, footer.target)?;
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::cartridge::hash;
+
+    fn ups(source: &[u8], target: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut patch = MAGIC.to_vec();
+        patch.push(0x80 | source.len() as u8);
+        patch.push(0x80 | target.len() as u8);
+        patch.extend_from_slice(body);
+        patch.extend_from_slice(&hash::crc32(source).to_le_bytes());
+        patch.extend_from_slice(&hash::crc32(target).to_le_bytes());
+        let crc = hash::crc32(&patch);
+        patch.extend_from_slice(&crc.to_le_bytes());
+        patch
+    }
+
+    #[test]
+    pub fn applies_xor_hunks() {
+        let source = [1, 2, 3, 4, 5, 6];
+        let target = [1, 2, 7, 4, 5, 6, 9];
+        // Skip 2, XOR 3 ^ 7, skip the terminator and one byte to offset 6 and XOR 0 ^ 9
+        let patch = ups(&source, &target, &[0x82, 3 ^ 7, 0x00, 0x82, 9, 0x00]);
+        assert_eq!(apply(&patch, &source).unwrap(), target);
+    }
+
+    #[test]
+    pub fn rejects_wrong_source() {
+        let source = [1, 2, 3, 4, 5, 6];
+        let patch = ups(&source, &source, &[]);
+        assert!(matches!(apply(&patch, &[0; 6]), Err(PatchError::SourceChecksum { .. })));
+    }
+
+    #[test]
+    pub fn rejects_huge_target_before_allocating() {
+        let source = [1, 2, 3, 4, 5, 6];
+        let mut patch = MAGIC.to_vec();
+        // Target size of about 256 MiB
+        patch.extend_from_slice(&[0x86, 0x7f, 0x7f, 0x7f, 0x7f, 0x80]);
+        patch.extend_from_slice(&hash::crc32(&source).to_le_bytes());
+        patch.extend_from_slice(&0u32.to_le_bytes());
+        let crc = hash::crc32(&patch);
+        patch.extend_from_slice(&crc.to_le_bytes());
+        assert!(matches!(apply(&patch, &source), Err(PatchError::TargetTooLarge(_))));
+    }
+
+}
@@ -0,0 +1,117 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+// Outcome of attaching a .sav file to a cartridge
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SaveStatus {
+    // Board has no battery backed memory, nothing is written
+    NoBattery,
+    // No save yet, the file is created on the first flush
+    Created,
+    Loaded,
+    // File size does not match the board, it is left untouched and no save is attached
+    SizeMismatch { expected: usize, found: usize }
+}
+
+// .sav file next to a ROM holding battery backed PRG RAM, EEPROM or flash contents
+pub struct SaveFile {
+    path: PathBuf,
+    // Contents as last seen on disk, used to skip needless writes
+    written: Vec<u8>,
+    interval: Duration,
+    last_flush: Instant
+}
+
+impl SaveFile {
+
+    // Reads an existing save for `memory`, returns its contents and how the file matched;
+    // patches have no fixed size and are never reported as mismatching
+    pub fn open<P: AsRef<Path>>(path: P, memory: &[u8], is_patch: bool) -> io::Result<(Self, SaveStatus, Option<Vec<u8>>)> {
+        let path = path.as_ref().to_path_buf();
+        let (contents, status) = match fs::read(&path) {
+            Ok(contents) => {
+                let status = if is_patch || contents.len() == memory.len() {
+                    SaveStatus::Loaded
+                } else {
+                    SaveStatus::SizeMismatch { expected: memory.len(), found: contents.len() }
+                };
+                (Some(contents), status)
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (None, SaveStatus::Created),
+            Err(err) => return Err(err)
+        };
+
+        let save = Self {
+            path,
+            written: contents.clone().unwrap_or_else(|| memory.to_vec()),
+            interval: DEFAULT_FLUSH_INTERVAL,
+            last_flush: Instant::now()
+        };
+        Ok((save, status, contents))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.last_flush.elapsed() >= self.interval
+    }
+
+    // Writes `memory` if it changed since it was loaded or last flushed, a new save is only
+    // created once the game wrote something
+    pub fn flush(&mut self, memory: &[u8]) -> io::Result<bool> {
+        self.last_flush = Instant::now();
+        if self.written == memory {
+            return Ok(false);
+        }
+
+        // Write to a temporary file first so a crash never leaves a half written save
+        let tmp = self.path.with_extension("sav.tmp");
+        fs::write(&tmp, memory)?;
+        fs::rename(&tmp, &self.path)?;
+        self.written = memory.to_vec();
+        Ok(true)
+    }
+
+}
+
+// Default save location, the ROM path with a .sav extension
+pub fn save_path<P: AsRef<Path>>(rom_path: P) -> PathBuf {
+    rom_path.as_ref().with_extension("sav")
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    pub fn flush_and_reopen() {
+        let path = std::env::temp_dir().join(format!("nes_emulator_save_{}.sav", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let (mut save, status, contents) = SaveFile::open(&path, &[0; 4], false).unwrap();
+        assert_eq!(status, SaveStatus::Created);
+        assert!(contents.is_none());
+        assert!(!save.flush(&[0; 4]).unwrap());
+        assert!(!path.exists());
+        assert!(save.flush(&[1, 2, 3, 4]).unwrap());
+        assert!(!save.flush(&[1, 2, 3, 4]).unwrap());
+
+        let (_, status, contents) = SaveFile::open(&path, &[0; 8], false).unwrap();
+        assert_eq!(status, SaveStatus::SizeMismatch { expected: 8, found: 4 });
+        assert_eq!(contents, Some(vec![1, 2, 3, 4]));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+}
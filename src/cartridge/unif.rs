@@ -0,0 +1,177 @@
+use crate::cartridge::{CartridgeError, Mirroring, Region, RomImage};
+
+const HEADER_SIZE: usize = 32;
+
+// Board names without their NES- / HVC- / UNL- / BMC- / BTL- prefix and the mapper implementing them
+const BOARDS: &[(&str, u16)] = &[
+    ("NROM", 0),
+    ("NROM-128", 0),
+    ("NROM-256", 0),
+    ("FCG-1", 16),
+    ("FCG-2", 16),
+    ("LZ93D50+24C02", 16),
+    ("NAMCOT-163", 19),
+    ("NAMCOT-129", 19),
+    ("UNROM-512-8", 30),
+    ("UNROM-512-16", 30),
+    ("UNROM-512-32", 30),
+    ("BTR", 69),
+    ("JLROM", 69),
+    ("JSROM", 69),
+    ("SUNSOFT-FME-7", 69),
+    ("LZ93D50+24C01", 159)
+];
+
+const BOARD_PREFIXES: &[&str] = &["NES-", "HVC-", "UNL-", "BMC-", "BTL-", "BANDAI-"];
+
+// Parsed UNIF file, board specific data next to the common ROM image
+pub struct UnifFile {
+    pub board: String,
+    // CTRL chunk, bit field of supported input devices
+    pub controllers: Option<u8>,
+    pub image: RomImage
+}
+
+pub fn is_unif(data: &[u8]) -> bool {
+    data.len() >= HEADER_SIZE && &data[0..4] == b"UNIF"
+}
+
+pub fn parse(data: &[u8]) -> Result<UnifFile, CartridgeError> {
+
+    if !is_unif(data) {
+        return Err(CartridgeError::InvalidHeader);
+    }
+
+    let mut board = None;
+    let mut controllers = None;
+    let mut mirroring = Mirroring::Horizontal;
+    let mut battery = false;
+    // PRG0 - PRGF and CHR0 - CHRF are concatenated in chunk number order
+    let mut prg_chunks: [Option<&[u8]>; 16] = [None; 16];
+    let mut chr_chunks: [Option<&[u8]>; 16] = [None; 16];
+
+    let mut offset = HEADER_SIZE;
+    while offset < data.len() {
+        let header = data.get(offset..offset + 8).ok_or(CartridgeError::Truncated)?;
+        let id = &header[0..4];
+        let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let body = data.get(offset + 8..offset + 8 + len).ok_or(CartridgeError::Truncated)?;
+        offset += 8 + len;
+
+        match id {
+            b"MAPR" => {
+                let end = body.iter().position(|b| *b == 0).unwrap_or(body.len());
+                board = Some(String::from_utf8_lossy(&body[..end]).trim().to_string());
+            },
+            b"MIRR" => {
+                mirroring = match body.first() {
+                    Some(1) => Mirroring::Vertical,
+                    Some(2) => Mirroring::SingleScreenA,
+                    Some(3) => Mirroring::SingleScreenB,
+                    Some(4) => Mirroring::FourScreen,
+                    _ => Mirroring::Horizontal
+                };
+            },
+            // Presence of the chunk marks a battery
+            b"BATR" => battery = true,
+            b"CTRL" => controllers = body.first().copied(),
+            [b'P', b'R', b'G', n] => {
+                if let Some(i) = chunk_number(*n) {
+                    prg_chunks[i] = Some(body);
+                }
+            },
+            [b'C', b'H', b'R', n] => {
+                if let Some(i) = chunk_number(*n) {
+                    chr_chunks[i] = Some(body);
+                }
+            },
+            // Informational chunks (NAME, READ, DINF, TVCI, PCK0, ...) are skipped
+            _ => {}
+        }
+    }
+
+    let board = board.ok_or(CartridgeError::InvalidHeader)?;
+    let mapper = board_mapper(&board).ok_or_else(|| CartridgeError::UnsupportedBoard(board.clone()))?;
+    let prg_rom: Vec<u8> = prg_chunks.iter().flatten().flat_map(|chunk| chunk.iter().copied()).collect();
+    let chr_rom: Vec<u8> = chr_chunks.iter().flatten().flat_map(|chunk| chunk.iter().copied()).collect();
+
+    if prg_rom.is_empty() {
+        return Err(CartridgeError::Truncated);
+    }
+
+    let chr_ram_size = if chr_rom.is_empty() { 8 * 1024 } else { 0 };
+    let image = RomImage {
+        mapper,
+        submapper: 0,
+        mirroring,
+        battery,
+        prg_rom,
+        chr_rom,
+        prg_ram_size: if battery { 0 } else { 8 * 1024 },
+        prg_nvram_size: if battery { 8 * 1024 } else { 0 },
+        chr_ram_size,
+        chr_nvram_size: 0,
+        region: Region::Ntsc,
+        trainer: None
+    };
+
+    Ok(UnifFile {
+        board,
+        controllers,
+        image
+    })
+}
+
+// Mapper number for a UNIF board name
+pub fn board_mapper(board: &str) -> Option<u16> {
+    let name = board.to_uppercase();
+    let name = BOARD_PREFIXES.iter()
+        .find_map(|prefix| name.strip_prefix(prefix))
+        .unwrap_or(&name);
+    BOARDS.iter().find(|(board, _)| *board == name).map(|(_, mapper)| *mapper)
+}
+
+fn chunk_number(n: u8) -> Option<usize> {
+    (n as char).to_digit(16).map(|n| n as usize)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn chunk(id: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(body);
+        chunk
+    }
+
+    #[test]
+    pub fn parses_chunks_in_order() {
+        let mut data = b"UNIF".to_vec();
+        data.resize(HEADER_SIZE, 0);
+        data.extend(chunk(b"MAPR", b"NES-NROM-256\0"));
+        data.extend(chunk(b"PRG1", &[2; 4]));
+        data.extend(chunk(b"PRG0", &[1; 4]));
+        data.extend(chunk(b"CHR0", &[3; 8]));
+        data.extend(chunk(b"MIRR", &[1]));
+        data.extend(chunk(b"BATR", &[1]));
+
+        let unif = parse(&data).unwrap();
+        assert_eq!(unif.board, "NES-NROM-256");
+        assert_eq!(unif.image.mapper, 0);
+        assert_eq!(unif.image.prg_rom, vec![1, 1, 1, 1, 2, 2, 2, 2]);
+        assert_eq!(unif.image.chr_rom.len(), 8);
+        assert_eq!(unif.image.mirroring, Mirroring::Vertical);
+        assert!(unif.image.battery);
+    }
+
+    #[test]
+    pub fn maps_board_names() {
+        assert_eq!(board_mapper("BANDAI-LZ93D50+24C01"), Some(159));
+        assert_eq!(board_mapper("unl-sunsoft-fme-7"), Some(69));
+        assert_eq!(board_mapper("NES-SKROM"), None);
+    }
+
+}
@@ -1,13 +1,35 @@
 use crate::cpu::register;
-use crate::cpu::opcode;
+use crate::cpu::instruction::Access;
+use crate::cpu::opcode::{self, AddressingMode, Instruction, OpCode};
 use crate::bus::Bus;
 
+const STACK_BASE: u16 = 0x0100;
+const NMI_VECTOR: u16 = 0xfffa;
+const RESET_VECTOR: u16 = 0xfffc;
+const IRQ_VECTOR: u16 = 0xfffe;
+
 pub struct Cpu6502 {
     // CPU registers
     pub registers: register::Registers,
     // Communication bus
     pub bus: Bus,
-    pub cycles: u8
+    // Cycle of the current instruction or interrupt sequence, 0 fetches the next opcode
+    cycle: u8,
+    op: OpCode,
+    // Operand address, built up over the addressing cycles
+    addr: u16,
+    // Zero page pointer of the indirect modes, branch offset
+    ptr: u8,
+    // Byte held between the cycles of a read-modify-write
+    data: u8,
+    // Indexing carried into the high byte, the first read went to the wrong page
+    page_crossed: bool,
+    // Cycle the operand access starts on, once the address is known
+    operand_cycle: Option<u8>,
+    // Vector of the interrupt or reset sequence running instead of the next instruction
+    interrupt: Option<u16>,
+    // DMA wants the bus, the next read halts the CPU instead of completing
+    halting: bool
 }
 
 impl Cpu6502 {
@@ -22,33 +44,459 @@ impl Cpu6502 {
                 pcl: 0x0000,
                 status: 0x00,
             },
-            bus: bus,
-            cycles: 0x00
+            bus,
+            cycle: 0,
+            op: OpCode { instruction: Instruction::NOP, addr_mode: AddressingMode::Implied, clock_cycles: 0x02 },
+            addr: 0x0000,
+            ptr: 0x00,
+            data: 0x00,
+            page_crossed: false,
+            operand_cycle: None,
+            interrupt: None,
+            halting: false
         }
     }
 
+    // Runs one cycle, every cycle is exactly one bus read or write like on the real CPU
     pub fn clock_cycle(&mut self) {
+        self.step();
+    }
 
-        if self.cycles == 0 {
+    // Runs one cycle unless it is a read cycle. DMA can only halt the CPU on a read: the
+    // read still goes out on the bus and is repeated once the CPU runs again. Returns true
+    // if the CPU halted, write cycles go through and the DMA has to wait for the next one
+    pub fn halt_cycle(&mut self) -> bool {
+        self.halting = true;
+        let halted = self.step().is_none();
+        self.halting = false;
+        halted
+    }
+
+    // None if the cycle's read halted the CPU
+    fn step(&mut self) -> Option<()> {
+
+        if let Some(vector) = self.interrupt {
+            return self.interrupt_cycle(vector);
+        }
+
+        if self.cycle == 0 {
 
             // Read op code from bus at current program counter address
-            let op_code = self.bus.read(self.registers.pcl);
-            
-            // Increase program counter
-            self.registers.pcl += 1;
+            let op_code = self.fetch_u8()?;
+
+            let map = &opcode::INSTRUCTION_OP_CODE_MATRIX;
+            self.op = *map.get(&op_code).unwrap();
+            self.cycle = 1;
+            return Some(());
+
+        }
+
+        let done = match self.operand_cycle {
+            Some(start) => self.operand_access(self.cycle - start)?,
+            None => self.addressing_cycle(self.cycle)?
+        };
+        if done {
+            self.cycle = 0;
+            self.operand_cycle = None;
+        } else {
+            self.cycle += 1;
+        }
+        Some(())
+
+    }
+
+    // True between instructions, where interrupts are polled
+    pub fn instruction_complete(&self) -> bool {
+        self.cycle == 0 && self.interrupt.is_none()
+    }
+
+    // Starts the reset sequence, which takes 7 cycles like an interrupt but only reads
+    pub fn reset(&mut self) {
+        self.registers.acc = 0x00;
+        self.registers.x = 0x00;
+        self.registers.y = 0x00;
+        self.registers.status = StatusRegisterFlags::U as u8 | StatusRegisterFlags::I as u8;
+        self.abort();
+        self.interrupt = Some(RESET_VECTOR);
+    }
+
+    // Drops the instruction in progress, for callers that set the registers themselves
+    pub fn abort(&mut self) {
+        self.cycle = 0;
+        self.operand_cycle = None;
+        self.interrupt = None;
+    }
+
+    // Maskable interrupt, ignored while the I flag is set
+    pub fn irq(&mut self) {
+        if !self.get_flag(StatusRegisterFlags::I) {
+            self.interrupt = Some(IRQ_VECTOR);
+        }
+    }
+
+    pub fn nmi(&mut self) {
+        self.interrupt = Some(NMI_VECTOR);
+    }
+
+    // Two dummy reads, the return address and status pushed, then the vector read. Reset
+    // goes through the same steps with the writes turned into reads
+    fn interrupt_cycle(&mut self, vector: u16) -> Option<()> {
+        let reset = vector == RESET_VECTOR;
+        match self.cycle {
+            0 | 1 => {
+                self.read(self.registers.pcl)?;
+            },
+            2..=4 if reset => {
+                self.read(STACK_BASE + self.registers.sp as u16)?;
+                self.registers.sp = self.registers.sp.wrapping_sub(1);
+            },
+            2 => self.push((self.registers.pcl >> 8) as u8),
+            3 => self.push(self.registers.pcl as u8),
+            4 => {
+                self.push(self.pushed_status(false));
+                self.set_flag(StatusRegisterFlags::I, true);
+            },
+            5 => self.addr = self.read(vector)? as u16,
+            _ => {
+                let hi = self.read(vector.wrapping_add(1))? as u16;
+                self.registers.pcl = hi << 8 | self.addr;
+                self.interrupt = None;
+                self.cycle = 0;
+                return Some(());
+            }
+        }
+        self.cycle += 1;
+        Some(())
+    }
+
+    pub fn get_flag(&self, flag: StatusRegisterFlags) -> bool {
+        self.registers.status & flag as u8 != 0
+    }
+
+    pub fn set_flag(&mut self, flag: StatusRegisterFlags, value: bool) {
+        if value {
+            self.registers.status |= flag as u8;
+        } else {
+            self.registers.status &= !(flag as u8);
+        }
+    }
+
+    pub(crate) fn set_zn(&mut self, value: u8) {
+        self.set_flag(StatusRegisterFlags::Z, value == 0);
+        self.set_flag(StatusRegisterFlags::N, value & 0x80 != 0);
+    }
+
+    // Bus read, None instead of the data if DMA halts the CPU on it
+    fn read(&mut self, addr: u16) -> Option<u8> {
+        let data = self.bus.read(addr);
+        if self.halting { None } else { Some(data) }
+    }
+
+    fn fetch_u8(&mut self) -> Option<u8> {
+        let data = self.read(self.registers.pcl)?;
+        self.registers.pcl = self.registers.pcl.wrapping_add(1);
+        Some(data)
+    }
+
+    pub(crate) fn push(&mut self, data: u8) {
+        self.bus.write(STACK_BASE + self.registers.sp as u16, data);
+        self.registers.sp = self.registers.sp.wrapping_sub(1);
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        let data = self.read(STACK_BASE + self.registers.sp.wrapping_add(1) as u16)?;
+        self.registers.sp = self.registers.sp.wrapping_add(1);
+        Some(data)
+    }
+
+    pub(crate) fn push_u16(&mut self, data: u16) {
+        self.push((data >> 8) as u8);
+        self.push(data as u8);
+    }
+
+    // Dummy read of the byte after the opcode, taken by one byte instructions
+    fn read_next(&mut self) -> Option<()> {
+        self.read(self.registers.pcl)?;
+        Some(())
+    }
+
+    // The operand access starts on the next cycle
+    fn address_ready(&mut self) -> bool {
+        self.operand_cycle = Some(self.cycle + 1);
+        false
+    }
+
+    // Indexed address, reads through an index skip the fix up cycle if the page stays the same
+    fn index(&mut self, base: u16, index: u8) -> bool {
+        self.addr = base.wrapping_add(index as u16);
+        self.page_crossed = base & 0xff00 != self.addr & 0xff00;
+        if self.op.instruction.access() == Access::Read && !self.page_crossed {
+            self.address_ready()
+        } else {
+            false
+        }
+    }
+
+    // Read of the address with the index added to the low byte only
+    fn read_unfixed(&mut self) -> Option<bool> {
+        let addr = if self.page_crossed { self.addr.wrapping_sub(0x100) } else { self.addr };
+        self.read(addr)?;
+        Some(self.address_ready())
+    }
+
+    // Cycles after the opcode fetch until the operand address is known, returns true when
+    // the instruction is done without an operand access
+    fn addressing_cycle(&mut self, cycle: u8) -> Option<bool> {
+        let instruction = self.op.instruction;
+        match (self.op.addr_mode, cycle) {
+            (AddressingMode::Implied, _) => self.implied_cycle(cycle),
+            (AddressingMode::Accumulator, _) => {
+                self.read_next()?;
+                self.registers.acc = self.modify_value(instruction, self.registers.acc);
+                Some(true)
+            },
+            (AddressingMode::Immidiate, _) => {
+                let data = self.fetch_u8()?;
+                self.execute_read(instruction, data);
+                Some(true)
+            },
+            (AddressingMode::Relative, _) => self.branch_cycle(cycle),
+            (AddressingMode::ZeroPage, _) => {
+                self.addr = self.fetch_u8()? as u16;
+                Some(self.address_ready())
+            },
+            (AddressingMode::ZeroPageX, 1) | (AddressingMode::ZeroPageY, 1) | (AddressingMode::Absolute, 1) |
+            (AddressingMode::AbsoluteX, 1) | (AddressingMode::AbsoluteY, 1) | (AddressingMode::Indirect, 1) => {
+                self.addr = self.fetch_u8()? as u16;
+                Some(false)
+            },
+            (AddressingMode::ZeroPageX, _) => {
+                self.read(self.addr)?;
+                self.addr = (self.addr as u8).wrapping_add(self.registers.x) as u16;
+                Some(self.address_ready())
+            },
+            (AddressingMode::ZeroPageY, _) => {
+                self.read(self.addr)?;
+                self.addr = (self.addr as u8).wrapping_add(self.registers.y) as u16;
+                Some(self.address_ready())
+            },
+            (AddressingMode::Absolute, _) => match instruction {
+                Instruction::JMP => {
+                    let hi = self.read(self.registers.pcl)? as u16;
+                    self.registers.pcl = hi << 8 | self.addr;
+                    Some(true)
+                },
+                Instruction::JSR => self.jsr_cycle(cycle),
+                _ => {
+                    self.addr |= (self.fetch_u8()? as u16) << 8;
+                    Some(self.address_ready())
+                }
+            },
+            (AddressingMode::AbsoluteX, 2) => {
+                let base = self.addr | (self.fetch_u8()? as u16) << 8;
+                Some(self.index(base, self.registers.x))
+            },
+            (AddressingMode::AbsoluteY, 2) => {
+                let base = self.addr | (self.fetch_u8()? as u16) << 8;
+                Some(self.index(base, self.registers.y))
+            },
+            (AddressingMode::AbsoluteX, _) | (AddressingMode::AbsoluteY, _) => self.read_unfixed(),
+            (AddressingMode::Indirect, 2) => {
+                self.addr |= (self.fetch_u8()? as u16) << 8;
+                Some(false)
+            },
+            (AddressingMode::Indirect, 3) => {
+                self.data = self.read(self.addr)?;
+                Some(false)
+            },
+            (AddressingMode::Indirect, _) => {
+                // The high byte is read without carry into the page, JMP ($xxFF) wraps
+                let hi = self.read((self.addr & 0xff00) | (self.addr.wrapping_add(1) & 0x00ff))? as u16;
+                self.registers.pcl = hi << 8 | self.data as u16;
+                Some(true)
+            },
+            (AddressingMode::XIndirect, 1) | (AddressingMode::IndirectY, 1) => {
+                self.ptr = self.fetch_u8()?;
+                Some(false)
+            },
+            (AddressingMode::XIndirect, 2) => {
+                self.read(self.ptr as u16)?;
+                self.ptr = self.ptr.wrapping_add(self.registers.x);
+                Some(false)
+            },
+            (AddressingMode::XIndirect, 3) | (AddressingMode::IndirectY, 2) => {
+                self.addr = self.read(self.ptr as u16)? as u16;
+                Some(false)
+            },
+            (AddressingMode::XIndirect, _) => {
+                self.addr |= (self.read(self.ptr.wrapping_add(1) as u16)? as u16) << 8;
+                Some(self.address_ready())
+            },
+            (AddressingMode::IndirectY, 3) => {
+                let base = self.addr | (self.read(self.ptr.wrapping_add(1) as u16)? as u16) << 8;
+                Some(self.index(base, self.registers.y))
+            },
+            (AddressingMode::IndirectY, _) => self.read_unfixed()
+        }
+    }
 
-            let ref map = opcode::INSTRUCTION_OP_CODE_MATRIX;
-            self.cycles = map.get(&op_code).unwrap().clock_cycles;
+    // Operand read, write or read-modify-write at the resolved address
+    fn operand_access(&mut self, step: u8) -> Option<bool> {
+        let instruction = self.op.instruction;
+        match (instruction.access(), step) {
+            (Access::Read, _) => {
+                let data = self.read(self.addr)?;
+                self.execute_read(instruction, data);
+                Some(true)
+            },
+            (Access::Write, _) => {
+                self.bus.write(self.addr, self.store_value(instruction));
+                Some(true)
+            },
+            (Access::Modify, 0) => {
+                self.data = self.read(self.addr)?;
+                Some(false)
+            },
+            // The unmodified value is written back while the ALU works
+            (Access::Modify, 1) => {
+                self.bus.write(self.addr, self.data);
+                self.data = self.modify_value(instruction, self.data);
+                Some(false)
+            },
+            (Access::Modify, _) => {
+                self.bus.write(self.addr, self.data);
+                Some(true)
+            }
+        }
+    }
 
+    // Single byte instructions and the stack and return instructions
+    fn implied_cycle(&mut self, cycle: u8) -> Option<bool> {
+        let instruction = self.op.instruction;
+        match (instruction, cycle) {
+            // Skips a padding byte after the opcode
+            (Instruction::BRK, 1) => {
+                self.fetch_u8()?;
+                Some(false)
+            },
+            (Instruction::BRK, 2) => {
+                self.push((self.registers.pcl >> 8) as u8);
+                Some(false)
+            },
+            (Instruction::BRK, 3) => {
+                self.push(self.registers.pcl as u8);
+                Some(false)
+            },
+            (Instruction::BRK, 4) => {
+                self.push(self.pushed_status(true));
+                self.set_flag(StatusRegisterFlags::I, true);
+                Some(false)
+            },
+            (Instruction::BRK, 5) => {
+                self.addr = self.read(IRQ_VECTOR)? as u16;
+                Some(false)
+            },
+            (Instruction::BRK, _) => {
+                let hi = self.read(IRQ_VECTOR + 1)? as u16;
+                self.registers.pcl = hi << 8 | self.addr;
+                Some(true)
+            },
+            (_, 1) => {
+                self.read_next()?;
+                self.execute_implied(instruction);
+                Some(!matches!(instruction, Instruction::PHA | Instruction::PHP | Instruction::PLA | Instruction::PLP | Instruction::RTS | Instruction::RTI))
+            },
+            (Instruction::PHA, _) => {
+                self.push(self.registers.acc);
+                Some(true)
+            },
+            (Instruction::PHP, _) => {
+                // B and the unused bit are set in the pushed copy
+                self.push(self.pushed_status(true));
+                Some(true)
+            },
+            // Stack pointer is incremented while the current top is read
+            (_, 2) => {
+                self.read(STACK_BASE + self.registers.sp as u16)?;
+                Some(false)
+            },
+            (Instruction::PLA, _) => {
+                self.registers.acc = self.pop()?;
+                self.set_zn(self.registers.acc);
+                Some(true)
+            },
+            (Instruction::PLP, _) => {
+                let status = self.pop()?;
+                self.pull_status(status);
+                Some(true)
+            },
+            (Instruction::RTI, 3) => {
+                let status = self.pop()?;
+                self.pull_status(status);
+                Some(false)
+            },
+            (Instruction::RTI, 4) | (Instruction::RTS, 3) => {
+                self.addr = self.pop()? as u16;
+                Some(false)
+            },
+            (Instruction::RTI, _) | (Instruction::RTS, 4) => {
+                let hi = self.pop()? as u16;
+                self.registers.pcl = hi << 8 | self.addr;
+                Some(instruction == Instruction::RTI)
+            },
+            // RTS moves past the last byte of the JSR
+            _ => {
+                self.fetch_u8()?;
+                Some(true)
+            }
         }
+    }
 
-        self.cycles -= 1;
-    
+    // Low address byte, internal cycle, return address pushed, high address byte
+    fn jsr_cycle(&mut self, cycle: u8) -> Option<bool> {
+        match cycle {
+            2 => {
+                self.read(STACK_BASE + self.registers.sp as u16)?;
+                Some(false)
+            },
+            3 => {
+                self.push((self.registers.pcl >> 8) as u8);
+                Some(false)
+            },
+            4 => {
+                self.push(self.registers.pcl as u8);
+                Some(false)
+            },
+            _ => {
+                let hi = self.read(self.registers.pcl)? as u16;
+                self.registers.pcl = hi << 8 | self.addr;
+                Some(true)
+            }
+        }
     }
 
-    pub fn reset() {}
-    pub fn irq() {}
-    pub fn nmi() {}
+    // Taken branches take a cycle, two if the target is on another page
+    fn branch_cycle(&mut self, cycle: u8) -> Option<bool> {
+        match cycle {
+            1 => {
+                self.ptr = self.fetch_u8()?;
+                Some(!self.branch_taken(self.op.instruction))
+            },
+            2 => {
+                self.read_next()?;
+                let pcl = self.registers.pcl;
+                self.addr = pcl.wrapping_add(self.ptr as i8 as u16);
+                // The low byte is added first, the page is fixed up on the next cycle
+                self.registers.pcl = (pcl & 0xff00) | (self.addr & 0x00ff);
+                Some(self.addr == self.registers.pcl)
+            },
+            _ => {
+                self.read_next()?;
+                self.registers.pcl = self.addr;
+                Some(true)
+            }
+        }
+    }
 
 }
 
@@ -70,3 +518,143 @@ pub enum StatusRegisterFlags {
     // Negative, 1 = Negative
     N = 1 << 7
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // Program at $0200 in work RAM, executed until BRK
+    fn run(program: &[u8]) -> Cpu6502 {
+        let mut cpu = Cpu6502::new(Bus::new());
+        for (i, data) in program.iter().enumerate() {
+            cpu.bus.write(0x0200 + i as u16, *data);
+        }
+        cpu.registers.pcl = 0x0200;
+        cpu.registers.sp = 0xfd;
+        while cpu.bus.read(cpu.registers.pcl) != 0x00 {
+            cpu.clock_cycle();
+            while !cpu.instruction_complete() {
+                cpu.clock_cycle();
+            }
+        }
+        cpu
+    }
+
+    #[test]
+    pub fn arithmetic_and_flags() {
+        // LDA #$50, ADC #$50 overflows into the sign bit
+        let cpu = run(&[0xa9, 0x50, 0x69, 0x50]);
+        assert_eq!(cpu.registers.acc, 0xa0);
+        assert!(cpu.get_flag(StatusRegisterFlags::V));
+        assert!(cpu.get_flag(StatusRegisterFlags::N));
+        assert!(!cpu.get_flag(StatusRegisterFlags::C));
+
+        // SEC, LDA #$10, SBC #$20 borrows
+        let cpu = run(&[0x38, 0xa9, 0x10, 0xe9, 0x20]);
+        assert_eq!(cpu.registers.acc, 0xf0);
+        assert!(!cpu.get_flag(StatusRegisterFlags::C));
+    }
+
+    #[test]
+    pub fn loops_and_subroutines() {
+        // LDX #5, loop: INY, DEX, BNE loop, JSR sub, BRK, sub: LDA #$42, RTS
+        let cpu = run(&[0xa2, 0x05, 0xc8, 0xca, 0xd0, 0xfc, 0x20, 0x0a, 0x02, 0x00, 0xa9, 0x42, 0x60]);
+        assert_eq!(cpu.registers.y, 5);
+        assert_eq!(cpu.registers.acc, 0x42);
+        assert_eq!(cpu.registers.sp, 0xfd);
+    }
+
+    // Cycles until the instruction at $0200 is done
+    fn cycles(cpu: &mut Cpu6502, program: &[u8]) -> u8 {
+        for (i, data) in program.iter().enumerate() {
+            cpu.bus.write(0x0200 + i as u16, *data);
+        }
+        cpu.registers.pcl = 0x0200;
+        let mut cycles = 1;
+        cpu.clock_cycle();
+        while !cpu.instruction_complete() {
+            cpu.clock_cycle();
+            cycles += 1;
+        }
+        cycles
+    }
+
+    #[test]
+    pub fn indexed_page_cross_costs_a_cycle() {
+        let mut cpu = Cpu6502::new(Bus::new());
+        cpu.registers.x = 0xff;
+        // LDA $01f0,X and LDA $0100,X
+        assert_eq!(cycles(&mut cpu, &[0xbd, 0xf0, 0x01]), 5);
+        cpu.registers.x = 0x01;
+        assert_eq!(cycles(&mut cpu, &[0xbd, 0x00, 0x01]), 4);
+        // Stores always take the fix up cycle
+        assert_eq!(cycles(&mut cpu, &[0x9d, 0x00, 0x01]), 5);
+        // Taken branch to the next page
+        cpu.registers.status = 0x00;
+        assert_eq!(cycles(&mut cpu, &[0xd0, 0x80]), 4);
+    }
+
+    #[test]
+    pub fn cycle_counts_match_the_opcode_table() {
+        for (op_code, op) in opcode::INSTRUCTION_OP_CODE_MATRIX.iter() {
+            let mut cpu = Cpu6502::new(Bus::new());
+            cpu.registers.sp = 0xfd;
+            // Pointers at $10 lead to $0300, nothing is indexed across a page
+            cpu.bus.write(0x0010, 0x00);
+            cpu.bus.write(0x0011, 0x03);
+            let taken = matches!(op.addr_mode, AddressingMode::Relative) && cpu.branch_taken(op.instruction);
+            assert_eq!(cycles(&mut cpu, &[*op_code, 0x10, 0x00]), op.clock_cycles + taken as u8, "opcode {:02x}", op_code);
+        }
+    }
+
+    #[test]
+    pub fn read_modify_write_writes_twice() {
+        let mut cpu = Cpu6502::new(Bus::new());
+        cpu.bus.write(0x0010, 0x41);
+        // INC $10, the unmodified value goes out first
+        cycles(&mut cpu, &[0xe6, 0x10]);
+        assert_eq!(cpu.bus.read(0x0010), 0x42);
+
+        let mut cpu = Cpu6502::new(Bus::new());
+        cpu.registers.pcl = 0x0200;
+        cpu.bus.write(0x0200, 0xe6);
+        cpu.bus.write(0x0201, 0x10);
+        cpu.bus.write(0x0010, 0x41);
+        for _ in 0..4 {
+            cpu.clock_cycle();
+        }
+        assert_eq!(cpu.bus.read(0x0010), 0x41);
+        cpu.clock_cycle();
+        assert_eq!(cpu.bus.read(0x0010), 0x42);
+    }
+
+    #[test]
+    pub fn interrupt_and_reset_sequences() {
+        let mut cpu = Cpu6502::new(Bus::new());
+        cpu.reset();
+        let mut cycles = 0;
+        while cycles == 0 || !cpu.instruction_complete() {
+            cpu.clock_cycle();
+            cycles += 1;
+        }
+        assert_eq!(cycles, 7);
+        assert_eq!(cpu.registers.sp, 0xfd);
+
+        cpu.registers.pcl = 0x0234;
+        cpu.nmi();
+        for _ in 0..7 {
+            assert!(!cpu.instruction_complete());
+            cpu.clock_cycle();
+        }
+        assert!(cpu.instruction_complete());
+        assert_eq!(cpu.registers.sp, 0xfa);
+        assert_eq!(cpu.bus.read(0x01fd), 0x02);
+        assert_eq!(cpu.bus.read(0x01fc), 0x34);
+        assert!(cpu.get_flag(StatusRegisterFlags::I));
+        // Masked
+        cpu.irq();
+        assert!(cpu.instruction_complete());
+    }
+
+}
@@ -1,4 +1,199 @@
-pub fn lda() {
+use crate::cpu::cpu6502::{Cpu6502, StatusRegisterFlags};
+use crate::cpu::opcode::Instruction;
+
+// What an instruction does with the bus once its operand address is known
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Access {
+    // One read cycle
+    Read,
+    // One write cycle
+    Write,
+    // Read, write back the unmodified value, write the result
+    Modify
+}
+
+impl Instruction {
+
+    pub(crate) fn access(&self) -> Access {
+        match self {
+            Instruction::STA | Instruction::STX | Instruction::STY => Access::Write,
+            Instruction::ASL | Instruction::LSR | Instruction::ROL | Instruction::ROR | Instruction::INC | Instruction::DEC => Access::Modify,
+            _ => Access::Read
+        }
+    }
+
+}
+
+impl Cpu6502 {
+
+    // Instructions that read an operand, `data` is the byte read
+    pub(crate) fn execute_read(&mut self, instruction: Instruction, data: u8) {
+        match instruction {
+            Instruction::LDA => {
+                self.registers.acc = data;
+                self.set_zn(data);
+            },
+            Instruction::LDX => {
+                self.registers.x = data;
+                self.set_zn(data);
+            },
+            Instruction::LDY => {
+                self.registers.y = data;
+                self.set_zn(data);
+            },
+            Instruction::ADC | Instruction::ADD => self.add(data),
+            Instruction::SBC => self.add(!data),
+            Instruction::AND => {
+                self.registers.acc &= data;
+                self.set_zn(self.registers.acc);
+            },
+            Instruction::ORA => {
+                self.registers.acc |= data;
+                self.set_zn(self.registers.acc);
+            },
+            Instruction::EOR => {
+                self.registers.acc ^= data;
+                self.set_zn(self.registers.acc);
+            },
+            Instruction::CMP => self.compare(self.registers.acc, data),
+            Instruction::CPX => self.compare(self.registers.x, data),
+            Instruction::CPY => self.compare(self.registers.y, data),
+            Instruction::BIT => {
+                self.set_flag(StatusRegisterFlags::Z, self.registers.acc & data == 0);
+                self.set_flag(StatusRegisterFlags::V, data & 0x40 != 0);
+                self.set_flag(StatusRegisterFlags::N, data & 0x80 != 0);
+            },
+            _ => {}
+        }
+    }
+
+    // Register a store instruction writes
+    pub(crate) fn store_value(&self, instruction: Instruction) -> u8 {
+        match instruction {
+            Instruction::STX => self.registers.x,
+            Instruction::STY => self.registers.y,
+            _ => self.registers.acc
+        }
+    }
+
+    // Shifts, rotates, INC and DEC on memory or the accumulator, returns the result
+    pub(crate) fn modify_value(&mut self, instruction: Instruction, data: u8) -> u8 {
+        let carry = self.get_flag(StatusRegisterFlags::C) as u8;
+        let result = match instruction {
+            Instruction::ASL => {
+                self.set_flag(StatusRegisterFlags::C, data & 0x80 != 0);
+                data << 1
+            },
+            Instruction::LSR => {
+                self.set_flag(StatusRegisterFlags::C, data & 0x01 != 0);
+                data >> 1
+            },
+            Instruction::ROL => {
+                self.set_flag(StatusRegisterFlags::C, data & 0x80 != 0);
+                data << 1 | carry
+            },
+            Instruction::ROR => {
+                self.set_flag(StatusRegisterFlags::C, data & 0x01 != 0);
+                data >> 1 | carry << 7
+            },
+            Instruction::INC => data.wrapping_add(1),
+            Instruction::DEC => data.wrapping_sub(1),
+            _ => data
+        };
+        self.set_zn(result);
+        result
+    }
+
+    // Register transfers, increments and flag changes, done in the cycle after the opcode
+    pub(crate) fn execute_implied(&mut self, instruction: Instruction) {
+        match instruction {
+            Instruction::INX => {
+                self.registers.x = self.registers.x.wrapping_add(1);
+                self.set_zn(self.registers.x);
+            },
+            Instruction::INY => {
+                self.registers.y = self.registers.y.wrapping_add(1);
+                self.set_zn(self.registers.y);
+            },
+            Instruction::DEX => {
+                self.registers.x = self.registers.x.wrapping_sub(1);
+                self.set_zn(self.registers.x);
+            },
+            Instruction::DEY => {
+                self.registers.y = self.registers.y.wrapping_sub(1);
+                self.set_zn(self.registers.y);
+            },
+            Instruction::TAX => {
+                self.registers.x = self.registers.acc;
+                self.set_zn(self.registers.x);
+            },
+            Instruction::TAY => {
+                self.registers.y = self.registers.acc;
+                self.set_zn(self.registers.y);
+            },
+            Instruction::TXA => {
+                self.registers.acc = self.registers.x;
+                self.set_zn(self.registers.acc);
+            },
+            Instruction::TYA => {
+                self.registers.acc = self.registers.y;
+                self.set_zn(self.registers.acc);
+            },
+            Instruction::TSX => {
+                self.registers.x = self.registers.sp;
+                self.set_zn(self.registers.x);
+            },
+            Instruction::TXS => self.registers.sp = self.registers.x,
+            Instruction::CLC => self.set_flag(StatusRegisterFlags::C, false),
+            Instruction::SEC => self.set_flag(StatusRegisterFlags::C, true),
+            Instruction::CLI => self.set_flag(StatusRegisterFlags::I, false),
+            Instruction::SEI => self.set_flag(StatusRegisterFlags::I, true),
+            Instruction::CLD => self.set_flag(StatusRegisterFlags::D, false),
+            Instruction::SED => self.set_flag(StatusRegisterFlags::D, true),
+            Instruction::CLV => self.set_flag(StatusRegisterFlags::V, false),
+            _ => {}
+        }
+    }
+
+    pub(crate) fn branch_taken(&self, instruction: Instruction) -> bool {
+        match instruction {
+            Instruction::BCC => !self.get_flag(StatusRegisterFlags::C),
+            Instruction::BCS => self.get_flag(StatusRegisterFlags::C),
+            Instruction::BNE => !self.get_flag(StatusRegisterFlags::Z),
+            Instruction::BEQ => self.get_flag(StatusRegisterFlags::Z),
+            Instruction::BPL => !self.get_flag(StatusRegisterFlags::N),
+            Instruction::BMI => self.get_flag(StatusRegisterFlags::N),
+            Instruction::BVC => !self.get_flag(StatusRegisterFlags::V),
+            Instruction::BVS => self.get_flag(StatusRegisterFlags::V),
+            _ => false
+        }
+    }
+
+    // Status as pushed to the stack, B is only set by BRK and PHP
+    pub(crate) fn pushed_status(&self, b: bool) -> u8 {
+        let status = self.registers.status | StatusRegisterFlags::U as u8;
+        if b { status | StatusRegisterFlags::B as u8 } else { status & !(StatusRegisterFlags::B as u8) }
+    }
+
+    pub(crate) fn pull_status(&mut self, status: u8) {
+        self.registers.status = (status & !(StatusRegisterFlags::B as u8)) | StatusRegisterFlags::U as u8;
+    }
+
+    // Binary add with carry, the 2A03 has no decimal mode
+    fn add(&mut self, data: u8) {
+        let acc = self.registers.acc;
+        let sum = acc as u16 + data as u16 + self.get_flag(StatusRegisterFlags::C) as u16;
+        let result = sum as u8;
+        self.set_flag(StatusRegisterFlags::C, sum > 0xff);
+        self.set_flag(StatusRegisterFlags::V, (acc ^ result) & (data ^ result) & 0x80 != 0);
+        self.registers.acc = result;
+        self.set_zn(result);
+    }
+
+    fn compare(&mut self, register: u8, data: u8) {
+        self.set_flag(StatusRegisterFlags::C, register >= data);
+        self.set_zn(register.wrapping_sub(data));
+    }
 
 }
 
@@ -10,4 +205,4 @@ mod tests {
         assert_eq!(1, 1);
     }
 
-}
\ No newline at end of file
+}
@@ -1,4 +1,4 @@
 pub mod cpu6502;
 pub mod opcode;
-mod register;
-mod instruction;
\ No newline at end of file
+pub mod register;
+pub mod instruction;
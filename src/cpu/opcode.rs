@@ -27,7 +27,7 @@ pub enum AddressingMode {
 }
 
 // OP Codes
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Instruction {
     ADC, ADD, AND, ASL,
     BCC, BCS, BEQ, BIT, BMI, BNE, BPL, BRK, BVC, BVS,
@@ -179,13 +179,13 @@ lazy_static! {
         map.insert(0xb1, OpCode { instruction: Instruction::LDA, addr_mode: AddressingMode::IndirectY, clock_cycles: 0x05 });
         map.insert(0xb4, OpCode { instruction: Instruction::LDY, addr_mode: AddressingMode::ZeroPageX, clock_cycles: 0x04 });
         map.insert(0xb5, OpCode { instruction: Instruction::LDA, addr_mode: AddressingMode::ZeroPageX, clock_cycles: 0x04 });
-        map.insert(0xb6, OpCode { instruction: Instruction::LDX, addr_mode: AddressingMode::ZeroPageX, clock_cycles: 0x04 });
+        map.insert(0xb6, OpCode { instruction: Instruction::LDX, addr_mode: AddressingMode::ZeroPageY, clock_cycles: 0x04 });
         map.insert(0xb8, OpCode { instruction: Instruction::CLV, addr_mode: AddressingMode::Implied, clock_cycles: 0x02 });
         map.insert(0xb9, OpCode { instruction: Instruction::LDA, addr_mode: AddressingMode::AbsoluteY, clock_cycles: 0x04 });
         map.insert(0xba, OpCode { instruction: Instruction::TSX, addr_mode: AddressingMode::Implied, clock_cycles: 0x02 });
         map.insert(0xbc, OpCode { instruction: Instruction::LDY, addr_mode: AddressingMode::AbsoluteX, clock_cycles: 0x04 });
         map.insert(0xbd, OpCode { instruction: Instruction::LDA, addr_mode: AddressingMode::AbsoluteX, clock_cycles: 0x04 });
-        map.insert(0xbe, OpCode { instruction: Instruction::LDX, addr_mode: AddressingMode::AbsoluteX, clock_cycles: 0x04 });
+        map.insert(0xbe, OpCode { instruction: Instruction::LDX, addr_mode: AddressingMode::AbsoluteY, clock_cycles: 0x04 });
 
         // Row: 0xc0 - 0xcf
         map.insert(0xc0, OpCode { instruction: Instruction::CPY, addr_mode: AddressingMode::Immidiate, clock_cycles: 0x02 });
@@ -236,9 +236,7 @@ lazy_static! {
         // Fill map with NOP where its undefined in range 0x00 - 0xff
         let nop: OpCode = OpCode { instruction: Instruction::NOP, addr_mode: AddressingMode::Implied, clock_cycles: 0x02 };
         for i in 0x00..=0xff {
-            if ! map.contains_key(&i) {
-                map.insert(i, nop);
-            }
+            map.entry(i).or_insert(nop);
         }
     
         map
@@ -0,0 +1,126 @@
+// Sprite DMA transfer in progress
+#[derive(Debug, Copy, Clone)]
+struct OamTransfer {
+    page: u8,
+    // Next byte to read, 0 - 255
+    index: u16,
+    // Byte read on the last get cycle, written to $2004 on the following put cycle
+    data: Option<u8>
+}
+
+// DMA unit of the 2A03. It takes the bus from the CPU to copy sprite data to OAM ($4014)
+// and to fetch DMC samples. Transfers alternate between get (read) and put (write) cycles,
+// so the number of stolen cycles depends on the CPU cycle parity
+#[derive(Default)]
+pub struct Dma {
+    // Page written to $4014, the transfer starts once the CPU is halted on its next read
+    oam_request: Option<u8>,
+    oam: Option<OamTransfer>,
+    // Sample address the DMC wants, with the dummy cycle still to go
+    dmc_request: Option<u16>,
+    dmc_dummy: bool,
+    // Sample byte fetched for the DMC, picked up by the APU
+    pub dmc_sample: Option<u8>,
+    // CPU is held on the read it was halted on
+    halted: bool
+}
+
+// Bus operation to perform for a DMA cycle
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DmaCycle {
+    // Dummy and alignment cycles repeat the read the CPU was halted on
+    Dummy,
+    // Read for the DMC or OAM
+    Read(u16),
+    // OAM byte written to $2004
+    Write(u8)
+}
+
+impl Dma {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn request_oam(&mut self, page: u8) {
+        self.oam_request = Some(page);
+    }
+
+    pub fn request_dmc(&mut self, addr: u16) {
+        self.dmc_request = Some(addr);
+        self.dmc_dummy = true;
+    }
+
+    // A transfer is waiting or running. Until the CPU is halted it keeps running up to its
+    // next read cycle
+    pub fn wants_bus(&self) -> bool {
+        self.dmc_request.is_some() || self.oam.is_some() || self.oam_request.is_some()
+    }
+
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    // The CPU stopped on a read cycle, the transfer runs from the next cycle on
+    pub fn halt(&mut self) {
+        self.halted = true;
+        if let Some(page) = self.oam_request.take() {
+            self.oam = Some(OamTransfer { page, index: 0, data: None });
+        }
+    }
+
+    // Decides what the DMA unit does on a cycle after the halt, `get` tells the cycle parity
+    pub fn cycle(&mut self, get: bool) -> DmaCycle {
+        if get {
+            if let Some(addr) = self.dmc_request {
+                if !self.dmc_dummy {
+                    return DmaCycle::Read(addr);
+                }
+            }
+            if let Some(oam) = self.oam.as_ref() {
+                if oam.data.is_none() {
+                    return DmaCycle::Read((oam.page as u16) << 8 | oam.index);
+                }
+            }
+        } else {
+            // The DMC dummy cycle can share a put cycle with an OAM write
+            self.dmc_dummy = false;
+            if let Some(OamTransfer { data: Some(data), .. }) = self.oam {
+                return DmaCycle::Write(data);
+            }
+        }
+        DmaCycle::Dummy
+    }
+
+    // Result of a read cycle
+    pub fn complete_read(&mut self, addr: u16, data: u8) {
+        match (self.dmc_request, self.oam.as_mut()) {
+            (Some(dmc), _) if dmc == addr && !self.dmc_dummy => {
+                self.dmc_request = None;
+                self.dmc_sample = Some(data);
+            },
+            (_, Some(oam)) => oam.data = Some(data),
+            _ => {}
+        }
+        self.release();
+    }
+
+    // A put cycle wrote the OAM byte
+    pub fn complete_write(&mut self) {
+        if let Some(oam) = self.oam.as_mut() {
+            oam.data = None;
+            oam.index += 1;
+            if oam.index == 256 {
+                self.oam = None;
+            }
+        }
+        self.release();
+    }
+
+    fn release(&mut self) {
+        if self.dmc_request.is_none() && self.oam.is_none() {
+            self.halted = false;
+        }
+    }
+
+}
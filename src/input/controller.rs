@@ -0,0 +1,126 @@
+use crate::input::Peripheral;
+use crate::ppu::Ppu;
+
+// Buttons in the order the controller shifts them out
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Button {
+    A = 1 << 0,
+    B = 1 << 1,
+    Select = 1 << 2,
+    Start = 1 << 3,
+    Up = 1 << 4,
+    Down = 1 << 5,
+    Left = 1 << 6,
+    Right = 1 << 7
+}
+
+impl Button {
+
+    pub const ALL: [Button; 8] = [
+        Button::A, Button::B, Button::Select, Button::Start, Button::Up, Button::Down, Button::Left, Button::Right
+    ];
+
+}
+
+// Standard joypad, a 4021 shift register. While the strobe ($4016 bit 0) is high it keeps
+// loading the buttons, so reads return A; once it drops every read shifts out the next button
+#[derive(Debug, Clone, Default)]
+pub struct Controller {
+    // Pressed buttons, `Button` flags
+    buttons: u8,
+    shift: u8,
+    strobe: bool
+}
+
+impl Controller {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn buttons(&self) -> u8 {
+        self.buttons
+    }
+
+    pub fn set_buttons(&mut self, buttons: u8) {
+        self.buttons = buttons;
+        if self.strobe {
+            self.shift = buttons;
+        }
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        if pressed {
+            self.set_buttons(self.buttons | button as u8);
+        } else {
+            self.set_buttons(self.buttons & !(button as u8));
+        }
+    }
+
+    pub fn pressed(&self, button: Button) -> bool {
+        self.buttons & button as u8 != 0
+    }
+
+    // $4016 write, bit 0 is the strobe
+    pub fn write(&mut self, data: u8) {
+        self.strobe = data & 0x01 != 0;
+        if self.strobe {
+            self.shift = self.buttons;
+        }
+    }
+
+    // Serial data bit of a $4016 / $4017 read; every read clocks the register
+    pub fn read(&mut self) -> u8 {
+        if self.strobe {
+            return self.buttons & 0x01;
+        }
+        let bit = self.shift & 0x01;
+        // The serial input is tied high, official controllers return 1 after the 8 buttons
+        self.shift = self.shift >> 1 | 0x80;
+        bit
+    }
+
+}
+
+impl Peripheral for Controller {
+
+    fn write(&mut self, data: u8) {
+        Controller::write(self, data);
+    }
+
+    fn read(&mut self, _addr: u16, _ppu: &Ppu) -> u8 {
+        Controller::read(self)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    pub fn strobe_and_shift() {
+        let mut controller = Controller::new();
+        controller.set_button(Button::A, true);
+        controller.set_button(Button::Start, true);
+        controller.set_button(Button::Left, true);
+
+        // Strobe high keeps returning A
+        controller.write(0x01);
+        assert_eq!(controller.read(), 1);
+        assert_eq!(controller.read(), 1);
+        controller.write(0x00);
+        let bits: Vec<u8> = (0..10).map(|_| controller.read()).collect();
+        assert_eq!(bits, [1, 0, 0, 1, 0, 0, 1, 0, 1, 1]);
+
+        // Buttons changed after the strobe dropped are not seen until the next one
+        controller.set_button(Button::A, false);
+        controller.write(0x01);
+        controller.write(0x00);
+        controller.set_button(Button::B, true);
+        assert_eq!(controller.read(), 0);
+        assert_eq!(controller.read(), 0);
+    }
+
+}
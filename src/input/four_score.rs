@@ -0,0 +1,136 @@
+use crate::input::controller::Controller;
+use crate::input::{Peripheral, Port};
+use crate::ppu::Ppu;
+
+// Four Score signatures, the byte after both controllers. Games collect them most significant
+// bit first and see $10 on port 1 and $20 on port 2; the Hori adapter has them swapped
+const SIGNATURE_PORT_1: u32 = 0x08;
+const SIGNATURE_PORT_2: u32 = 0x04;
+
+// Half of the NES Four Score, which takes both controller ports. The half in port 1 shifts out
+// players 1 and 3 and then its signature, the half in port 2 players 2 and 4
+pub struct FourScore {
+    pub controllers: [Controller; 2],
+    signature: u32,
+    shift: u32,
+    strobe: bool
+}
+
+impl FourScore {
+
+    pub fn new(port: Port) -> Self {
+        let signature = if port == Port::Two { SIGNATURE_PORT_2 } else { SIGNATURE_PORT_1 };
+        Self { controllers: [Controller::new(), Controller::new()], signature, shift: 0xffff_ffff, strobe: false }
+    }
+
+    fn latch(&mut self) {
+        let [first, second] = &self.controllers;
+        self.shift = first.buttons() as u32 | (second.buttons() as u32) << 8 | self.signature << 16 | 0xff00_0000;
+    }
+
+}
+
+impl Peripheral for FourScore {
+
+    fn write(&mut self, data: u8) {
+        self.strobe = data & 0x01 != 0;
+        if self.strobe {
+            self.latch();
+        }
+    }
+
+    fn read(&mut self, _addr: u16, _ppu: &Ppu) -> u8 {
+        if self.strobe {
+            self.latch();
+        }
+        let bit = self.shift & 0x01;
+        self.shift = self.shift >> 1 | 0x8000_0000;
+        bit as u8
+    }
+
+}
+
+// Hori 4 Players Adapter for the Famicom expansion port: players 3 and 4 in D1 of $4016 and
+// $4017. In 4 player mode the signatures follow, in 2 player mode they read like two extra
+// controllers
+pub struct HoriAdapter {
+    pub controllers: [Controller; 2],
+    pub four_player: bool,
+    shifts: [u32; 2],
+    strobe: bool
+}
+
+impl HoriAdapter {
+
+    pub fn new(four_player: bool) -> Self {
+        Self { controllers: [Controller::new(), Controller::new()], four_player, shifts: [0xffff_ffff; 2], strobe: false }
+    }
+
+    fn latch(&mut self) {
+        let signatures = if self.four_player { [SIGNATURE_PORT_2, SIGNATURE_PORT_1] } else { [0xff; 2] };
+        for (i, shift) in self.shifts.iter_mut().enumerate() {
+            *shift = self.controllers[i].buttons() as u32 | signatures[i] << 8 | 0xffff_0000;
+        }
+    }
+
+}
+
+impl Peripheral for HoriAdapter {
+
+    fn write(&mut self, data: u8) {
+        self.strobe = data & 0x01 != 0;
+        if self.strobe {
+            self.latch();
+        }
+    }
+
+    fn read(&mut self, addr: u16, _ppu: &Ppu) -> u8 {
+        if self.strobe {
+            self.latch();
+        }
+        let shift = &mut self.shifts[addr as usize & 0x01];
+        let bit = *shift & 0x01;
+        *shift = *shift >> 1 | 0x8000_0000;
+        (bit as u8) << 1
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::input::controller::Button;
+
+    // Bits of 24 reads collected the way games do it, most significant bit first per byte
+    fn bytes(device: &mut dyn Peripheral, addr: u16, bit: u8) -> [u8; 3] {
+        let ppu = Ppu::new();
+        device.write(0x01);
+        device.write(0x00);
+        let mut bytes = [0; 3];
+        for byte in bytes.iter_mut() {
+            for _ in 0..8 {
+                *byte = *byte << 1 | (device.read(addr, &ppu) >> bit & 0x01);
+            }
+        }
+        bytes
+    }
+
+    #[test]
+    pub fn signatures() {
+        let mut port1 = FourScore::new(Port::One);
+        port1.controllers[0].set_button(Button::A, true);
+        port1.controllers[1].set_button(Button::Right, true);
+        assert_eq!(bytes(&mut port1, 0x4016, 0), [0x80, 0x01, 0x10]);
+        let mut port2 = FourScore::new(Port::Two);
+        assert_eq!(bytes(&mut port2, 0x4017, 0), [0x00, 0x00, 0x20]);
+
+        let mut hori = HoriAdapter::new(true);
+        hori.controllers[1].set_button(Button::Start, true);
+        assert_eq!(bytes(&mut hori, 0x4016, 1), [0x00, 0x20, 0xff]);
+        assert_eq!(bytes(&mut hori, 0x4017, 1), [0x10, 0x10, 0xff]);
+        hori.four_player = false;
+        assert_eq!(bytes(&mut hori, 0x4017, 1), [0x10, 0xff, 0xff]);
+    }
+
+}
@@ -0,0 +1,41 @@
+pub mod controller;
+pub mod zapper;
+pub mod paddle;
+pub mod power_pad;
+pub mod four_score;
+
+use std::any::Any;
+
+use crate::ppu::Ppu;
+
+// Where a device is plugged in. The NES ports drive D0, D3 and D4 of their own register
+// ($4016 or $4017), the Famicom expansion port drives D1 - D4 of both
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Port {
+    One = 0,
+    Two = 1,
+    Expansion = 2
+}
+
+// Device behind a controller port or the expansion port. $4016 writes reach every device,
+// reads only go to the devices wired to the register that is read
+pub trait Peripheral: AsAny {
+    // OUT0 (bit 0) is the strobe, OUT1 and OUT2 only reach the expansion port
+    fn write(&mut self, data: u8);
+    // Bits of a $4016 / $4017 read in their final position, undriven bits are 0. Light guns
+    // look at what the PPU has drawn so far
+    fn read(&mut self, addr: u16, ppu: &Ppu) -> u8;
+}
+
+// Lets the console hand out the concrete type of a plugged in device
+pub trait AsAny {
+    fn as_any(&mut self) -> &mut dyn Any;
+}
+
+impl<T: Any> AsAny for T {
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+
+}
@@ -0,0 +1,98 @@
+use crate::input::Peripheral;
+use crate::ppu::Ppu;
+
+// Vaus controller from Arkanoid, a knob and a fire button. The strobe latches the knob position,
+// which is then shifted out inverted, most significant bit first. On the NES it goes into port 2
+// with fire in $4017 D3 and the position in D4; the Famicom version in the expansion port has
+// fire in $4016 D1 and the position in $4017 D1
+pub struct Paddle {
+    // Knob position as the game sees it once it undoes the inversion
+    pub position: u8,
+    pub fire: bool,
+    famicom: bool,
+    shift: u8,
+    strobe: bool
+}
+
+impl Paddle {
+
+    pub fn new() -> Self {
+        Self { position: 0x80, fire: false, famicom: false, shift: 0xff, strobe: false }
+    }
+
+    // Expansion port version
+    pub fn famicom() -> Self {
+        Self { famicom: true, ..Self::new() }
+    }
+
+    fn shift_out(&mut self) -> u8 {
+        if self.strobe {
+            self.shift = !self.position;
+        }
+        let bit = self.shift >> 7;
+        // Zeros follow, which read as ones after the inversion
+        self.shift <<= 1;
+        bit
+    }
+
+}
+
+impl Peripheral for Paddle {
+
+    fn write(&mut self, data: u8) {
+        self.strobe = data & 0x01 != 0;
+        if self.strobe {
+            self.shift = !self.position;
+        }
+    }
+
+    fn read(&mut self, addr: u16, _ppu: &Ppu) -> u8 {
+        match (self.famicom, addr) {
+            (false, 0x4017) => (self.fire as u8) << 3 | self.shift_out() << 4,
+            (true, 0x4016) => (self.fire as u8) << 1,
+            (true, 0x4017) => self.shift_out() << 1,
+            _ => 0x00
+        }
+    }
+
+}
+
+impl Default for Paddle {
+
+    fn default() -> Self {
+        Self::new()
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    pub fn inverted_position_and_fire() {
+        let ppu = Ppu::new();
+        let mut paddle = Paddle::new();
+        paddle.position = 0x5a;
+        paddle.fire = true;
+        paddle.write(0x01);
+        paddle.write(0x00);
+        // !$5a = $a5 most significant bit first in D4, fire in D3, then zeros
+        let reads: Vec<u8> = (0..10).map(|_| paddle.read(0x4017, &ppu)).collect();
+        assert_eq!(reads, [0x18, 0x08, 0x18, 0x08, 0x08, 0x18, 0x08, 0x18, 0x08, 0x08]);
+        assert_eq!(paddle.read(0x4016, &ppu), 0x00);
+
+        let mut paddle = Paddle::famicom();
+        paddle.position = 0x7f;
+        paddle.write(0x01);
+        paddle.write(0x00);
+        assert_eq!(paddle.read(0x4016, &ppu), 0x00);
+        paddle.fire = true;
+        assert_eq!(paddle.read(0x4016, &ppu), 0x02);
+        // !$7f = $80 in D1 of $4017
+        let reads: Vec<u8> = (0..3).map(|_| paddle.read(0x4017, &ppu)).collect();
+        assert_eq!(reads, [0x02, 0x00, 0x00]);
+    }
+
+}
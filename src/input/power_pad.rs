@@ -0,0 +1,143 @@
+use crate::input::Peripheral;
+use crate::ppu::Ppu;
+
+// Buttons shifted out on D3 and D4 of the NES Power Pad, numbered as printed on side B
+const D3_ORDER: [u8; 8] = [2, 1, 5, 9, 6, 10, 11, 7];
+const D4_ORDER: [u8; 4] = [4, 3, 12, 8];
+
+// Power Pad / Family Trainer mat with 12 buttons in three rows of four. The NES version in
+// port 2 latches all of them on the strobe and shifts them out on $4017 D3 and D4. The Famicom
+// version in the expansion port is a matrix instead: pulling OUT0, OUT1 or OUT2 low selects
+// buttons 1 - 4, 5 - 8 or 9 - 12, which read in $4017 D4 - D1 with 0 for pressed
+pub struct PowerPad {
+    // Bit n - 1 is button n
+    buttons: u16,
+    famicom: bool,
+    d3: u8,
+    d4: u8,
+    strobe: bool,
+    // OUT0 - OUT2 of the last $4016 write
+    select: u8
+}
+
+impl PowerPad {
+
+    pub fn new() -> Self {
+        Self { buttons: 0, famicom: false, d3: 0xff, d4: 0xff, strobe: false, select: 0x07 }
+    }
+
+    // Family Trainer in the expansion port
+    pub fn famicom() -> Self {
+        Self { famicom: true, ..Self::new() }
+    }
+
+    // Buttons 1 - 12
+    pub fn set_button(&mut self, button: u8, pressed: bool) {
+        if !(1..=12).contains(&button) {
+            return;
+        }
+        let bit = 1 << (button - 1);
+        if pressed {
+            self.buttons |= bit;
+        } else {
+            self.buttons &= !bit;
+        }
+    }
+
+    pub fn pressed(&self, button: u8) -> bool {
+        (1..=12).contains(&button) && self.buttons & 1 << (button - 1) != 0
+    }
+
+    fn latch(&mut self) {
+        let buttons = self.buttons;
+        let bits = |order: &[u8]| order.iter().enumerate().fold(0u8, |bits, (i, button)| {
+            bits | ((buttons >> (button - 1)) as u8 & 0x01) << i
+        });
+        self.d3 = bits(&D3_ORDER);
+        // Ones after the fourth bit
+        self.d4 = bits(&D4_ORDER) | 0xf0;
+    }
+
+    fn matrix(&self) -> u8 {
+        let mut data = 0x1e;
+        for row in 0..3 {
+            if self.select & 1 << row != 0 {
+                continue;
+            }
+            for column in 0..4 {
+                // First button of the row in D4
+                if self.pressed(row * 4 + column + 1) {
+                    data &= !(0x10 >> column);
+                }
+            }
+        }
+        data
+    }
+
+}
+
+impl Peripheral for PowerPad {
+
+    fn write(&mut self, data: u8) {
+        self.select = data & 0x07;
+        self.strobe = data & 0x01 != 0;
+        if self.strobe {
+            self.latch();
+        }
+    }
+
+    fn read(&mut self, addr: u16, _ppu: &Ppu) -> u8 {
+        if addr != 0x4017 {
+            return 0x00;
+        }
+        if self.famicom {
+            return self.matrix();
+        }
+        if self.strobe {
+            self.latch();
+        }
+        let data = (self.d3 & 0x01) << 3 | (self.d4 & 0x01) << 4;
+        self.d3 = self.d3 >> 1 | 0x80;
+        self.d4 = self.d4 >> 1 | 0x80;
+        data
+    }
+
+}
+
+impl Default for PowerPad {
+
+    fn default() -> Self {
+        Self::new()
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    pub fn serial_and_matrix_reads() {
+        let ppu = Ppu::new();
+        let mut pad = PowerPad::new();
+        pad.set_button(1, true);
+        pad.set_button(12, true);
+        pad.write(0x01);
+        pad.write(0x00);
+        let reads: Vec<u8> = (0..9).map(|_| pad.read(0x4017, &ppu)).collect();
+        // Button 1 is the second D3 bit, 12 the third D4 bit
+        assert_eq!(reads, [0x00, 0x08, 0x10, 0x00, 0x10, 0x10, 0x10, 0x10, 0x18]);
+
+        let mut trainer = PowerPad::famicom();
+        trainer.set_button(2, true);
+        trainer.set_button(12, true);
+        trainer.write(0x06);
+        assert_eq!(trainer.read(0x4017, &ppu), 0x16);
+        trainer.write(0x03);
+        assert_eq!(trainer.read(0x4017, &ppu), 0x1c);
+        trainer.write(0x07);
+        assert_eq!(trainer.read(0x4017, &ppu), 0x1e);
+    }
+
+}
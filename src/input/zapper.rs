@@ -0,0 +1,119 @@
+use crate::input::Peripheral;
+use crate::ppu::palette::Palette;
+use crate::ppu::render::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::ppu::Ppu;
+
+// Pixels around the aim point the photodiode sees
+const SENSE_RADIUS: usize = 2;
+// Scanlines the photodiode keeps reporting light after the beam drew something bright
+const SENSE_LINES: usize = 24;
+// Luma, 0 - 255, a pixel needs to count as light
+const BRIGHTNESS: f32 = 192.0;
+
+// Light gun. It reports light when the area it points at was drawn bright within the last few
+// scanlines, so games flash targets and check it while the picture is drawn. It reads in D3 and
+// D4 of its port's register, the Famicom version in the expansion port in those of $4017
+pub struct Zapper {
+    // Screen pixel the gun points at, None when it points away from the screen
+    pub aim: Option<(usize, usize)>,
+    pub trigger: bool,
+    famicom: bool,
+    palette: Palette
+}
+
+impl Zapper {
+
+    pub fn new() -> Self {
+        Self { aim: None, trigger: false, famicom: false, palette: Palette::default() }
+    }
+
+    // Expansion port version
+    pub fn famicom() -> Self {
+        Self { famicom: true, ..Self::new() }
+    }
+
+    pub fn light_detected(&self, ppu: &Ppu) -> bool {
+        let (x, y) = match self.aim {
+            Some(aim) => aim,
+            None => return false
+        };
+        // Pixel x is drawn on dot x + 1
+        let beam = ppu.scanline as usize;
+        let drawn = |row: usize, column: usize| {
+            (row < beam || (row == beam && column < ppu.dot as usize)) && beam - row <= SENSE_LINES
+        };
+
+        let rows = y.saturating_sub(SENSE_RADIUS)..=(y + SENSE_RADIUS).min(SCREEN_HEIGHT - 1);
+        rows.into_iter().any(|row| {
+            let columns = x.saturating_sub(SENSE_RADIUS)..=(x + SENSE_RADIUS).min(SCREEN_WIDTH - 1);
+            columns.into_iter().any(|column| {
+                drawn(row, column) && self.bright(ppu.framebuffer[row * SCREEN_WIDTH + column])
+            })
+        })
+    }
+
+    fn bright(&self, pixel: u16) -> bool {
+        let [r, g, b] = self.palette.rgb(pixel);
+        0.299 * r as f32 + 0.587 * g as f32 + 0.114 * b as f32 >= BRIGHTNESS
+    }
+
+}
+
+impl Peripheral for Zapper {
+
+    // Not strobed
+    fn write(&mut self, _data: u8) {}
+
+    // D3 is 0 while light is sensed, D4 is the trigger
+    fn read(&mut self, addr: u16, ppu: &Ppu) -> u8 {
+        if self.famicom && addr != 0x4017 {
+            return 0x00;
+        }
+        let light = if self.light_detected(ppu) { 0x00 } else { 0x08 };
+        light | (self.trigger as u8) << 4
+    }
+
+}
+
+impl Default for Zapper {
+
+    fn default() -> Self {
+        Self::new()
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    pub fn senses_light_behind_the_beam() {
+        let mut ppu = Ppu::new();
+        ppu.framebuffer.fill(0x0f);
+        // White box at 100, 100
+        for row in 96..104 {
+            ppu.framebuffer[row * SCREEN_WIDTH + 96..row * SCREEN_WIDTH + 104].fill(0x30);
+        }
+        let mut zapper = Zapper::famicom();
+        zapper.aim = Some((100, 100));
+        zapper.trigger = true;
+
+        // Not drawn yet, then just drawn, then faded
+        ppu.scanline = 90;
+        assert_eq!(zapper.read(0x4017, &ppu), 0x18);
+        ppu.scanline = 110;
+        assert_eq!(zapper.read(0x4017, &ppu), 0x10);
+        ppu.scanline = 140;
+        assert_eq!(zapper.read(0x4017, &ppu), 0x18);
+
+        // Aiming at the dark part of the screen
+        ppu.scanline = 110;
+        zapper.aim = Some((20, 100));
+        zapper.trigger = false;
+        assert_eq!(zapper.read(0x4017, &ppu), 0x08);
+        assert_eq!(zapper.read(0x4016, &ppu), 0x00);
+    }
+
+}
@@ -0,0 +1,10 @@
+pub mod cpu;
+pub mod bus;
+pub mod cartridge;
+pub mod ppu;
+pub mod apu;
+pub mod nes;
+pub mod timing;
+pub mod dma;
+pub mod player;
+pub mod input;
@@ -1,15 +1,390 @@
-pub mod cpu;
-pub mod bus;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Duration;
+
+use nes_emulator::apu::register_log::VgmTags;
+use nes_emulator::apu::wav::{AudioRecorder, SampleFormat};
+use nes_emulator::cartridge::nsf::Nsf;
+use nes_emulator::cartridge::save::SaveStatus;
+use nes_emulator::cartridge::database::RomDatabase;
+use nes_emulator::cartridge::fds::disk;
+use nes_emulator::cartridge::{Cartridge, LoadOptions};
+use nes_emulator::nes::Nes;
+use nes_emulator::player::NsfPlayer;
+use nes_emulator::ppu::debug;
+use nes_emulator::ppu::frame::Frame;
+use nes_emulator::ppu::ntsc::NtscFilter;
+use nes_emulator::ppu::palette::{Palette, PpuModel};
+
+const USAGE: &str = "Usage: nes_emulator <rom> [options]
+
+Runs a ROM without a display. NSF and NSFe files are played and can be rendered to WAV.
+
+Options:
+  --frames <n>            Number of frames to run (default 60)
+  --screenshot <file>     Writes the last frame to <file> (.png or .ppm)
+  --screenshot-every <n>  Writes every n-th frame to the screenshot directory
+  --screenshot-dir <dir>  Directory for --screenshot-every (default .)
+  --format <png|ppm>      File format for --screenshot-every (default png)
+  --scale <n>             Scales screenshots by a whole factor
+  --crop-overscan         Leaves out the top and bottom 8 lines
+  --ntsc                  Runs screenshots through the NTSC composite filter
+  --palette <file>        Colors from a 192 or 1536 byte .pal file
+  --rom-db <file>         NES 2.0 XML database to correct headers with instead of the bundled one
+  --no-rom-db             Uses the iNES header as it is
+  --fds-bios <file>       8 KiB Disk System BIOS, needed to run .fds and QD disk images
+  --dump-vram <dir>       Writes pattern tables, nametables, sprites and palette RAM at the end
+  --pattern-palette <n>   Palette 0 - 7 for the pattern table dump (default 0)
+  --record-audio <file>   Writes the mixed audio to a WAV file
+  --record-channels       Also writes every APU channel next to it (<file>_pulse1.wav, ...)
+  --audio-format <f>      pcm16 or float for recorded audio (default pcm16)
+  --sample-rate <n>       Sample rate of recorded audio (default 44100)
+  --record-vgm <file>     Writes the sound register writes to a VGM file
+  --record-registers <f>  Writes the sound register writes to a CSV file
+  --track <n>             NSF track to play (default the file's starting track)
+  --seconds <n>           NSF play time (default the track length from the file, or 150)";
+
+// Play time of NSF tracks without a length
+const DEFAULT_TRACK_SECONDS: u64 = 150;
+
+// Command line options
+struct Options {
+    rom: PathBuf,
+    frames: u64,
+    screenshot: Option<PathBuf>,
+    screenshot_every: Option<u64>,
+    screenshot_dir: PathBuf,
+    format: String,
+    scale: usize,
+    crop_overscan: bool,
+    ntsc: bool,
+    palette: Option<PathBuf>,
+    rom_db: Option<PathBuf>,
+    no_rom_db: bool,
+    fds_bios: Option<PathBuf>,
+    dump_vram: Option<PathBuf>,
+    pattern_palette: u8,
+    record_audio: Option<PathBuf>,
+    record_channels: bool,
+    audio_format: SampleFormat,
+    sample_rate: u32,
+    record_vgm: Option<PathBuf>,
+    record_registers: Option<PathBuf>,
+    track: Option<u8>,
+    seconds: Option<u64>
+}
+
+impl Options {
+
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut rom = None;
+        let mut options = Options {
+            rom: PathBuf::new(),
+            frames: 60,
+            screenshot: None,
+            screenshot_every: None,
+            screenshot_dir: PathBuf::from("."),
+            format: String::from("png"),
+            scale: 1,
+            crop_overscan: false,
+            ntsc: false,
+            palette: None,
+            rom_db: None,
+            no_rom_db: false,
+            fds_bios: None,
+            dump_vram: None,
+            pattern_palette: 0,
+            record_audio: None,
+            record_channels: false,
+            audio_format: SampleFormat::Pcm16,
+            sample_rate: 44_100,
+            record_vgm: None,
+            record_registers: None,
+            track: None,
+            seconds: None
+        };
+
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+            match arg.as_str() {
+                "--frames" => options.frames = number(&value()?)?,
+                "--screenshot" => options.screenshot = Some(PathBuf::from(value()?)),
+                "--screenshot-every" => options.screenshot_every = Some(number(&value()?)?.max(1)),
+                "--screenshot-dir" => options.screenshot_dir = PathBuf::from(value()?),
+                "--format" => {
+                    options.format = value()?;
+                    if options.format != "png" && options.format != "ppm" {
+                        return Err(format!("unknown format {}", options.format));
+                    }
+                },
+                "--scale" => options.scale = number(&value()?)? as usize,
+                "--crop-overscan" => options.crop_overscan = true,
+                "--ntsc" => options.ntsc = true,
+                "--palette" => options.palette = Some(PathBuf::from(value()?)),
+                "--rom-db" => options.rom_db = Some(PathBuf::from(value()?)),
+                "--no-rom-db" => options.no_rom_db = true,
+                "--fds-bios" => options.fds_bios = Some(PathBuf::from(value()?)),
+                "--dump-vram" => options.dump_vram = Some(PathBuf::from(value()?)),
+                "--pattern-palette" => options.pattern_palette = number(&value()?)?.min(7) as u8,
+                "--record-audio" => options.record_audio = Some(PathBuf::from(value()?)),
+                "--record-channels" => options.record_channels = true,
+                "--audio-format" => {
+                    options.audio_format = match value()?.as_str() {
+                        "pcm16" => SampleFormat::Pcm16,
+                        "float" => SampleFormat::Float32,
+                        format => return Err(format!("unknown audio format {}", format))
+                    };
+                },
+                "--record-vgm" => options.record_vgm = Some(PathBuf::from(value()?)),
+                "--record-registers" => options.record_registers = Some(PathBuf::from(value()?)),
+                "--track" => options.track = Some(number(&value()?)?.clamp(1, 255) as u8),
+                "--seconds" => options.seconds = Some(number(&value()?)?),
+                "--sample-rate" => options.sample_rate = number(&value()?)?.clamp(8_000, 192_000) as u32,
+                _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+                _ if rom.is_none() => rom = Some(PathBuf::from(arg)),
+                _ => return Err(format!("unexpected argument {}", arg))
+            }
+        }
+
+        options.rom = rom.ok_or_else(|| String::from("no ROM given"))?;
+        Ok(options)
+    }
+
+    fn log_registers(&self) -> bool {
+        self.record_vgm.is_some() || self.record_registers.is_some()
+    }
+
+}
+
+fn number(value: &str) -> Result<u64, String> {
+    value.parse().map_err(|_| format!("{} is not a number", value))
+}
+
+// Turns the current picture into the requested screenshot
+struct Screenshots {
+    palette: Palette,
+    ntsc: Option<NtscFilter>,
+    scale: usize,
+    crop_overscan: bool
+}
+
+impl Screenshots {
+
+    fn frame(&self, nes: &Nes) -> Frame {
+        let ppu = nes.ppu();
+        let mut frame = match &self.ntsc {
+            Some(filter) => Frame::from_ntsc(&ppu.framebuffer, filter, ppu.burst_phase),
+            None => Frame::from_framebuffer(&ppu.framebuffer, &self.palette)
+        };
+        if self.crop_overscan {
+            frame = frame.crop_overscan();
+        }
+        if self.scale > 1 {
+            frame = frame.scale(self.scale);
+        }
+        frame
+    }
+
+}
+
+// PPU debug views of the current state
+fn dump_vram(nes: &mut Nes, dir: &Path, colors: &Palette, pattern_palette: u8) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let ppu = nes.ppu_mut();
+    debug::pattern_tables(ppu, colors, pattern_palette).save(dir.join("patterns.png"))?;
+    debug::nametables(ppu, colors).save(dir.join("nametables.png"))?;
+    debug::sprite_sheet(ppu, colors).save(dir.join("sprites.png"))?;
+    debug::palette_ram(ppu, colors).save(dir.join("palette.png"))?;
+    let sprites: Vec<String> = debug::sprites(ppu).iter().map(|sprite| sprite.to_string()).collect();
+    fs::write(dir.join("sprites.txt"), sprites.join("\n") + "\n")
+}
+
+// Writes the register log to the VGM and CSV files that were asked for
+fn save_register_log(options: &Options, nes: &mut Nes, tags: &VgmTags) -> Result<(), String> {
+    let log = match nes.stop_register_log() {
+        Some(log) => log,
+        None => return Ok(())
+    };
+    if let Some(path) = &options.record_vgm {
+        log.save_vgm(path, tags).map_err(|err| format!("{}: {}", path.display(), err))?;
+    }
+    if let Some(path) = &options.record_registers {
+        log.save_csv(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    }
+    Ok(())
+}
+
+// Plays an NSF track, recording it if asked to
+fn play_nsf(options: &Options, data: &[u8]) -> Result<(), String> {
+    let nsf = Nsf::parse(data).map_err(|err| err.to_string())?;
+    println!("{} - {} ({})", nsf.title, nsf.artist, nsf.copyright);
+    for track in 1..=nsf.total_songs {
+        let info = nsf.track(track).cloned().unwrap_or_default();
+        let length = info.duration.map(|time| format!(" {}:{:02}", time.as_secs() / 60, time.as_secs() % 60)).unwrap_or_default();
+        println!("{:3} {}{}", track, info.name.unwrap_or_default(), length);
+    }
+
+    let mut player = NsfPlayer::new(nsf);
+    player.nes().set_sample_rate(options.sample_rate);
+    // Restarted with the log running, so it has everything INIT writes
+    if options.log_registers() {
+        player.nes().start_register_log();
+    }
+    let track = options.track.unwrap_or_else(|| player.track());
+    if options.track.is_some() || options.log_registers() {
+        player.start_track(track);
+    }
+    let info = player.nsf().track(player.track()).cloned().unwrap_or_default();
+    let seconds = match (options.seconds, info.duration) {
+        (Some(seconds), _) => Duration::from_secs(seconds),
+        (None, Some(duration)) => duration + info.fade.unwrap_or_default(),
+        (None, None) => Duration::from_secs(DEFAULT_TRACK_SECONDS)
+    };
+
+    let mut recorder = match &options.record_audio {
+        Some(path) => {
+            player.nes().apu_mut().mixer.set_channel_capture(options.record_channels);
+            let recorder = AudioRecorder::create(path, options.sample_rate, options.audio_format, options.record_channels);
+            Some(recorder.map_err(|err| format!("{}: {}", path.display(), err))?)
+        },
+        None => None
+    };
+    // Discards what INIT produced before the recording starts
+    player.nes().audio_samples();
+    player.nes().audio_channel_samples();
+
+    // Rendered in steps of a tenth of a second
+    let step = Duration::from_millis(100);
+    let mut elapsed = Duration::from_secs(0);
+    while elapsed < seconds {
+        let length = step.min(seconds - elapsed);
+        player.run_for(length);
+        elapsed += length;
+        if let Some(recorder) = &mut recorder {
+            let samples = player.nes().audio_samples();
+            let channels = player.nes().audio_channel_samples();
+            recorder.record(&samples, &channels).map_err(|err| format!("recording audio: {}", err))?;
+        }
+    }
+    if let Some(recorder) = recorder {
+        recorder.finish().map_err(|err| format!("recording audio: {}", err))?;
+    }
+    let nsf = player.nsf();
+    let tags = VgmTags {
+        track: info.name.unwrap_or_else(|| format!("Track {}", player.track())),
+        game: nsf.title.clone(),
+        author: nsf.artist.clone(),
+        date: nsf.copyright.clone(),
+        ripper: nsf.ripper.clone(),
+        notes: String::new()
+    };
+    save_register_log(options, player.nes(), &tags)
+}
+
+fn run(options: Options) -> Result<(), String> {
+    let data = fs::read(&options.rom).map_err(|err| format!("{}: {}", options.rom.display(), err))?;
+    if Nsf::is_nsf(&data) {
+        return play_nsf(&options, &data);
+    }
+    let cartridge = if disk::is_disk_image(&data) {
+        let path = options.fds_bios.as_ref().ok_or("Disk System images need the BIOS, pass it with --fds-bios")?;
+        let bios = fs::read(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+        Cartridge::from_disk_file(&options.rom, &bios)
+    } else {
+        let database = match &options.rom_db {
+            Some(path) => Some(RomDatabase::load(path).map_err(|err| format!("{}: {}", path.display(), err))?),
+            None => None
+        };
+        let load_options = LoadOptions { use_database: !options.no_rom_db, database: database.as_ref(), ..LoadOptions::default() };
+        Cartridge::from_file_with(&options.rom, &load_options)
+    };
+    let cartridge = cartridge.map_err(|err| err.to_string())?;
+    for correction in &cartridge.corrections {
+        eprintln!("Header corrected from the ROM database, {}", correction);
+    }
+    if let SaveStatus::SizeMismatch { expected, found } = cartridge.save_status() {
+        eprintln!("Ignoring save file of {} bytes, the board has {}; progress will not be saved", found, expected);
+    }
+    let palette = match &options.palette {
+        Some(path) => Palette::load(path, PpuModel::Rp2c02).map_err(|err| err.to_string())?,
+        None => Palette::default()
+    };
+    let screenshots = Screenshots {
+        palette,
+        ntsc: if options.ntsc { Some(NtscFilter::default()) } else { None },
+        scale: options.scale,
+        crop_overscan: options.crop_overscan
+    };
+
+    let mut nes = Nes::new(cartridge);
+    let result = emulate(&options, &mut nes, &screenshots);
+    // Also written when the run failed, so an error does not lose the progress made so far
+    let flushed = nes.cartridge().flush_save().map_err(|err| format!("writing save: {}", err));
+    result.and(flushed.map(|_| ()))
+}
+
+// Runs the frames and writes the recordings, screenshots and dumps that were asked for
+fn emulate(options: &Options, nes: &mut Nes, screenshots: &Screenshots) -> Result<(), String> {
+    nes.set_sample_rate(options.sample_rate);
+    let mut recorder = match &options.record_audio {
+        Some(path) => {
+            nes.apu_mut().mixer.set_channel_capture(options.record_channels);
+            let recorder = AudioRecorder::create(path, options.sample_rate, options.audio_format, options.record_channels);
+            Some(recorder.map_err(|err| format!("{}: {}", path.display(), err))?)
+        },
+        None => None
+    };
+    if options.log_registers() {
+        nes.start_register_log();
+    }
+
+    for frame in 1..=options.frames {
+        nes.run_frame().map_err(|err| format!("writing save: {}", err))?;
+        // Taken after every frame, so recordings of the same run line up sample for sample
+        if let Some(recorder) = &mut recorder {
+            let samples = nes.audio_samples();
+            let channels = nes.audio_channel_samples();
+            recorder.record(&samples, &channels).map_err(|err| format!("recording audio: {}", err))?;
+        }
+        if let Some(every) = options.screenshot_every {
+            if frame % every == 0 {
+                let path = options.screenshot_dir.join(format!("frame_{:06}.{}", frame, options.format));
+                screenshots.frame(nes).save(&path).map_err(|err| format!("{}: {}", path.display(), err))?;
+            }
+        }
+    }
+
+    if let Some(recorder) = recorder {
+        recorder.finish().map_err(|err| format!("recording audio: {}", err))?;
+    }
+    let game = options.rom.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+    save_register_log(options, nes, &VgmTags { game, ..VgmTags::default() })?;
+    if let Some(path) = &options.screenshot {
+        screenshots.frame(nes).save(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    }
+    if let Some(dir) = &options.dump_vram {
+        dump_vram(nes, dir, &screenshots.palette, options.pattern_palette)
+            .map_err(|err| format!("{}: {}", dir.display(), err))?;
+    }
+    Ok(())
+}
 
 fn main() {
-    
-    let bus = bus::Bus::new();
-    let cpu = cpu::cpu6502::Cpu6502::new(bus);
-
-    let op_codes = &cpu::opcode::INSTRUCTION_OP_CODE_MATRIX;
-    println!("Size: {}, correct size {}", op_codes.len(), op_codes.len() == (16 * 16));
-    for (k, opc) in op_codes.iter() {
-        println!("{}: AddrMode: {:?}, Instruction: {:?}, Num of cycles: {}", k, opc.addr_mode, opc.instruction, opc.clock_cycles);
+
+    let options = match Options::parse(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, USAGE);
+            process::exit(2);
+        }
+    };
+
+    if let Err(err) = run(options) {
+        eprintln!("{}", err);
+        process::exit(1);
     }
 
 }
@@ -0,0 +1,559 @@
+use std::any::Any;
+use std::cell::RefMut;
+use std::io;
+
+use crate::apu::register_log::RegisterLog;
+use crate::apu::Apu;
+use crate::bus::Bus;
+use crate::cartridge::{Cartridge, Region};
+use crate::cpu::cpu6502::Cpu6502;
+use crate::dma::DmaCycle;
+use crate::input::controller::{Button, Controller};
+use crate::input::four_score::FourScore;
+use crate::input::{Peripheral, Port};
+use crate::ppu::Ppu;
+use crate::timing::Timing;
+
+// Whole console: CPU with its bus, PPU, APU and cartridge, driven from one master clock
+pub struct Nes {
+    pub cpu: Cpu6502,
+    timing: Timing,
+    // Master clock cycles since power on
+    master_clock: u64,
+    // Master clock cycle of the next PPU dot
+    next_ppu_dot: u64,
+    // NMI is edge triggered, the PPU output level seen on the last cycle
+    nmi_line: bool,
+    nmi_pending: bool
+}
+
+impl Nes {
+
+    // Runs with the timing of the region from the header or ROM database
+    pub fn new(cartridge: Cartridge) -> Self {
+        let region = cartridge.region;
+        Self::with_region(cartridge, region)
+    }
+
+    // Runs with the timing of `region`, regardless of what the cartridge asks for
+    pub fn with_region(cartridge: Cartridge, region: Region) -> Self {
+        let timing = Timing::new(region);
+        let mut bus = Bus::new();
+        bus.ppu.set_timing(timing);
+        bus.apu.set_timing(timing);
+        bus.insert_cartridge(cartridge);
+        let mut nes = Self {
+            cpu: Cpu6502::new(bus),
+            timing,
+            master_clock: 0,
+            next_ppu_dot: 0,
+            nmi_line: false,
+            nmi_pending: false
+        };
+        nes.reset();
+        nes
+    }
+
+    pub fn reset(&mut self) {
+        self.cpu.bus.ppu.reset();
+        self.cpu.bus.apu.reset();
+        self.cpu.reset();
+        self.nmi_pending = false;
+    }
+
+    pub fn timing(&self) -> &Timing {
+        &self.timing
+    }
+
+    pub fn ppu(&self) -> &Ppu {
+        &self.cpu.bus.ppu
+    }
+
+    pub fn ppu_mut(&mut self) -> &mut Ppu {
+        &mut self.cpu.bus.ppu
+    }
+
+    pub fn apu(&self) -> &Apu {
+        &self.cpu.bus.apu
+    }
+
+    pub fn apu_mut(&mut self) -> &mut Apu {
+        &mut self.cpu.bus.apu
+    }
+
+    // Changes the rate of the samples from `audio_samples`
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.cpu.bus.apu.mixer.set_rates(self.timing.cpu_frequency(), sample_rate);
+    }
+
+    // Mono samples produced since the last call, at the mixer's sample rate
+    pub fn audio_samples(&mut self) -> Vec<f32> {
+        self.cpu.bus.apu.mixer.take_samples()
+    }
+
+    // Samples of every APU channel on its own, aligned with `audio_samples`; needs
+    // `mixer.set_channel_capture` on the APU
+    pub fn audio_channel_samples(&mut self) -> Vec<Vec<f32>> {
+        self.cpu.bus.apu.mixer.take_channel_samples()
+    }
+
+    // Plugs a device into a port, replacing what was there
+    pub fn connect(&mut self, port: Port, device: Box<dyn Peripheral>) {
+        self.cpu.bus.ports[port as usize] = Some(device);
+    }
+
+    pub fn disconnect(&mut self, port: Port) -> Option<Box<dyn Peripheral>> {
+        self.cpu.bus.ports[port as usize].take()
+    }
+
+    // Four Score in both controller ports
+    pub fn connect_four_score(&mut self) {
+        self.connect(Port::One, Box::new(FourScore::new(Port::One)));
+        self.connect(Port::Two, Box::new(FourScore::new(Port::Two)));
+    }
+
+    // Device in `port` if it is a `T`
+    pub fn device<T: Any>(&mut self, port: Port) -> Option<&mut T> {
+        self.cpu.bus.ports[port as usize].as_deref_mut()?.as_any().downcast_mut()
+    }
+
+    pub fn controller(&mut self, port: Port) -> Option<&mut Controller> {
+        self.device(port)
+    }
+
+    // Does nothing without a standard controller in `port`
+    pub fn set_button(&mut self, port: Port, button: Button, pressed: bool) {
+        if let Some(controller) = self.controller(port) {
+            controller.set_button(button, pressed);
+        }
+    }
+
+    // All buttons of a controller at once, `Button` flags
+    pub fn set_buttons(&mut self, port: Port, buttons: u8) {
+        if let Some(controller) = self.controller(port) {
+            controller.set_buttons(buttons);
+        }
+    }
+
+    // Starts logging writes to the sound registers, see `RegisterLog`
+    pub fn start_register_log(&mut self) {
+        let chips = self.cartridge().expansion_chips();
+        let bus = &mut self.cpu.bus;
+        bus.register_log = Some(RegisterLog::new(self.timing.cpu_frequency(), chips, bus.apu.cycles()));
+    }
+
+    // Stops logging and hands out what was logged
+    pub fn stop_register_log(&mut self) -> Option<RegisterLog> {
+        let bus = &mut self.cpu.bus;
+        let mut log = bus.register_log.take()?;
+        log.close(bus.apu.cycles());
+        Some(log)
+    }
+
+    pub fn cartridge(&self) -> RefMut<'_, Cartridge> {
+        self.cpu.bus.cartridge.as_ref().expect("console without cartridge").borrow_mut()
+    }
+
+    // CPU cycles since power on
+    pub fn cpu_cycles(&self) -> u64 {
+        self.master_clock / self.timing.cpu_divider
+    }
+
+    // Advances by one CPU cycle and the PPU dots that fall into it
+    pub fn step_cycle(&mut self) {
+        let end = self.master_clock + self.timing.cpu_divider;
+        while self.next_ppu_dot < end {
+            self.cpu.bus.ppu.clock();
+            self.next_ppu_dot += self.timing.ppu_divider;
+        }
+
+        let dma = self.cpu.bus.dma.wants_bus();
+        if dma && self.cpu.bus.dma.halted() {
+            // Get cycles are the even ones
+            self.dma_cycle(self.cpu_cycles() & 0x01 == 0);
+        } else {
+            // Interrupts are taken between instructions
+            if self.cpu.instruction_complete() {
+                if self.nmi_pending {
+                    self.nmi_pending = false;
+                    self.cpu.nmi();
+                } else if self.cartridge().irq() || self.cpu.bus.apu.irq() {
+                    self.cpu.irq();
+                }
+            }
+            if !dma {
+                self.cpu.clock_cycle();
+            } else if self.cpu.halt_cycle() {
+                // The DMA unit can only halt the CPU on a read, writes go through
+                self.cpu.bus.dma.halt();
+            }
+        }
+        self.clock_apu();
+        self.cartridge().clock_cpu();
+
+        let nmi = self.cpu.bus.ppu.nmi();
+        if nmi && !self.nmi_line {
+            self.nmi_pending = true;
+        }
+        self.nmi_line = nmi;
+        self.master_clock = end;
+    }
+
+    // DMC sample fetches go through the DMA unit, which steals the cycles from the CPU
+    fn clock_apu(&mut self) {
+        let expansion = self.cartridge().audio_output();
+        let bus = &mut self.cpu.bus;
+        bus.apu.set_expansion_output(expansion);
+        bus.apu.clock();
+        if let Some(data) = bus.dma.dmc_sample.take() {
+            bus.apu.dmc.load_sample(data);
+        }
+        if let Some(addr) = bus.apu.dmc.sample_request() {
+            bus.dma.request_dmc(addr);
+        }
+    }
+
+    // CPU cycle taken over by the DMA unit, the CPU does not advance
+    fn dma_cycle(&mut self, get: bool) {
+        let bus = &mut self.cpu.bus;
+        match bus.dma.cycle(get) {
+            // Repeating the halted read is what corrupts $4016 and $2007 reads during DMC fetches
+            DmaCycle::Dummy => bus.repeat_read(),
+            DmaCycle::Read(addr) => {
+                let data = bus.dma_read(addr);
+                bus.dma.complete_read(addr, data);
+                if let (Some(log), Some(_)) = (&mut bus.register_log, bus.dma.dmc_sample) {
+                    log.record_sample(addr, data);
+                }
+            },
+            DmaCycle::Write(data) => {
+                bus.write(0x2004, data);
+                bus.dma.complete_write();
+            }
+        }
+    }
+
+    // Runs until the current instruction (or interrupt sequence) has finished, returns the cycles
+    // taken including those of DMA transfers that halted it
+    pub fn step_instruction(&mut self) -> u64 {
+        let start = self.cpu_cycles();
+        loop {
+            // Cycles the CPU is halted count towards the instruction it was halted in
+            let held = self.cpu.bus.dma.halted();
+            self.step_cycle();
+            if !held && !self.cpu.bus.dma.halted() && self.cpu.instruction_complete() {
+                break;
+            }
+        }
+        self.cpu_cycles() - start
+    }
+
+    // Runs until the PPU enters vblank, the finished picture is in the PPU framebuffer. Battery
+    // backed memory is written out periodically, failing that is the only error
+    pub fn run_frame(&mut self) -> io::Result<()> {
+        self.cpu.bus.ppu.frame_complete = false;
+        while !self.cpu.bus.ppu.frame_complete {
+            self.step_cycle();
+        }
+        self.cartridge().flush_save_if_due()?;
+        Ok(())
+    }
+
+    // Runs until the CPU cycle counter reaches `cycles`
+    pub fn run_until(&mut self, cycles: u64) {
+        while self.cpu_cycles() < cycles {
+            self.step_cycle();
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::cartridge::RomImage;
+
+    // NROM program: enable NMI and spin, the NMI handler counts frames in $00
+    fn cartridge() -> Cartridge {
+        let mut prg_rom = vec![0xea; 16 * 1024];
+        let program = [
+            0xa9, 0x80,       // LDA #$80
+            0x8d, 0x00, 0x20, // STA $2000
+            0x4c, 0x05, 0xc0, // JMP *
+            0xe6, 0x00,       // NMI: INC $00
+            0x40              // RTI
+        ];
+        prg_rom[..program.len()].copy_from_slice(&program);
+        prg_rom[0x3ffa..].copy_from_slice(&[0x08, 0xc0, 0x00, 0xc0, 0x00, 0xc0]);
+
+        Cartridge::new(RomImage::nrom(prg_rom, false)).unwrap()
+    }
+
+    fn nes() -> Nes {
+        Nes::new(cartridge())
+    }
+
+    #[test]
+    pub fn runs_frames_with_nmi() {
+        let mut nes = nes();
+        nes.run_frame().unwrap();
+        let start = nes.cpu_cycles();
+        nes.run_frame().unwrap();
+        nes.run_frame().unwrap();
+        // 341 * 262 / 3 CPU cycles per frame
+        assert!((59561..=59562).contains(&(nes.cpu_cycles() - start)));
+        // The NMI of the last vblank follows right after run_frame returns
+        assert_eq!(nes.cpu.bus.ram[0], 2);
+        nes.step_instruction();
+        nes.step_instruction();
+        assert_eq!(nes.cpu.bus.ram[0], 3);
+    }
+
+    #[test]
+    pub fn steps_instructions_and_cycles() {
+        let mut nes = nes();
+        // Reset sequence, then LDA # and STA abs
+        assert_eq!(nes.step_instruction(), 7);
+        assert_eq!(nes.step_instruction(), 2);
+        assert_eq!(nes.step_instruction(), 4);
+        assert_eq!(nes.cpu.bus.ppu.ctrl, 0x80);
+
+        nes.run_until(1000);
+        assert_eq!(nes.cpu_cycles(), 1000);
+        assert_eq!(nes.ppu().frame, 0);
+        assert_eq!(nes.ppu().scanline, 3000 / 341);
+    }
+
+    // Program in work RAM at $0300, started right away
+    fn run_from_ram(nes: &mut Nes, program: &[u8]) {
+        nes.cpu.bus.ram[0x0300..0x0300 + program.len()].copy_from_slice(program);
+        nes.cpu.abort();
+        nes.cpu.registers.pcl = 0x0300;
+    }
+
+    #[test]
+    pub fn reads_happen_on_their_own_cycle() {
+        // LDA $2002 reads on its fourth cycle: vblank set by that cycle's dots is seen, one
+        // cycle later it is not
+        for (dot, vblank) in [(332, true), (329, false)] {
+            let mut nes = nes();
+            nes.step_instruction();
+            run_from_ram(&mut nes, &[0xad, 0x02, 0x20]);
+            let scanline = nes.timing().vblank_scanline - 1;
+            nes.ppu_mut().scanline = scanline;
+            nes.ppu_mut().dot = dot;
+            assert_eq!(nes.step_instruction(), 4);
+            assert_eq!(nes.cpu.registers.acc & 0x80 != 0, vblank);
+        }
+    }
+
+    #[test]
+    pub fn pal_and_dendy_frames() {
+        // 341 * 312 dots at 3.2 dots per CPU cycle
+        let mut nes = Nes::with_region(cartridge(), Region::Pal);
+        nes.run_frame().unwrap();
+        let start = nes.cpu_cycles();
+        nes.run_frame().unwrap();
+        assert!((33247..=33248).contains(&(nes.cpu_cycles() - start)));
+
+        // Dendy: 3 dots per cycle, vblank starts 50 lines later than on NTSC
+        let mut nes = Nes::with_region(cartridge(), Region::Dendy);
+        nes.run_frame().unwrap();
+        assert_eq!(nes.ppu().scanline, 291);
+        let start = nes.cpu_cycles();
+        nes.run_frame().unwrap();
+        assert!((35464..=35465).contains(&(nes.cpu_cycles() - start)));
+    }
+
+    #[test]
+    pub fn audio_samples_follow_cycles() {
+        let mut nes = nes();
+        nes.cpu.bus.apu.mixer.set_channel_capture(true);
+        nes.set_sample_rate(48_000);
+        let start = nes.cpu_cycles();
+        let mut total = 0;
+        for _ in 0..3 {
+            nes.run_frame().unwrap();
+            let samples = nes.audio_samples();
+            let channels = nes.audio_channel_samples();
+            assert_eq!(channels.len(), 11);
+            assert!(channels.iter().all(|channel| channel.len() == samples.len()));
+            total += samples.len();
+        }
+        let expected = (nes.cpu_cycles() - start) as f64 * 48_000.0 / nes.timing().cpu_frequency();
+        assert!((total as f64 - expected.floor()).abs() <= 1.0);
+    }
+
+    #[test]
+    pub fn oam_dma_stalls_cpu() {
+        let mut nes = nes();
+        for i in 0..256 {
+            nes.cpu.bus.ram[0x0200 + i] = i as u8;
+        }
+        nes.run_until(100);
+        let start = nes.cpu_cycles();
+        nes.cpu.bus.write(0x4014, 0x02);
+        while nes.cpu.bus.ppu.oam[255] != 255 {
+            nes.step_cycle();
+        }
+        let stall = nes.cpu_cycles() - start;
+        assert!(stall == 513 || stall == 514);
+        assert_eq!(nes.cpu.bus.ppu.oam[0x80], 0x80);
+    }
+
+    #[test]
+    pub fn dmc_dma_fetches_sample() {
+        let mut nes = nes();
+        nes.run_until(100);
+        // One byte sample at $C000, the first program byte
+        nes.cpu.bus.write(0x4012, 0x00);
+        nes.cpu.bus.write(0x4013, 0x00);
+        nes.cpu.bus.write(0x4015, 0x10);
+        let start = nes.cpu_cycles();
+        while nes.cpu.bus.apu.dmc.sample_buffer().is_none() {
+            nes.step_cycle();
+        }
+        // Request on the next APU cycle, then 3 or 4 stolen cycles
+        assert!((4..=5).contains(&(nes.cpu_cycles() - start)));
+        assert_eq!(nes.cpu.bus.apu.dmc.sample_buffer(), Some(0xa9));
+        assert_eq!(nes.cpu.bus.read(0x4015) & 0x10, 0x00);
+    }
+
+    // Starts a one byte sample at $C000 and hands its fetch to the DMA unit at once, like the
+    // APU does at the end of a cycle
+    fn request_dmc_fetch(nes: &mut Nes) {
+        nes.cpu.bus.write(0x4012, 0x00);
+        nes.cpu.bus.write(0x4013, 0x00);
+        nes.cpu.bus.write(0x4015, 0x10);
+        let addr = nes.cpu.bus.apu.dmc.sample_request().unwrap();
+        nes.cpu.bus.dma.request_dmc(addr);
+    }
+
+    // Runs the first `cycles` of the next instruction, requests a DMC fetch so that the CPU
+    // halts on the following cycle, then finishes the instruction. Returns the cycles taken
+    fn dmc_fetch_after(nes: &mut Nes, cycles: usize) -> u64 {
+        let start = nes.cpu_cycles();
+        for _ in 0..cycles {
+            nes.step_cycle();
+        }
+        request_dmc_fetch(nes);
+        nes.step_instruction();
+        nes.cpu_cycles() - start
+    }
+
+    #[test]
+    pub fn dmc_fetch_during_ppu_data_read_skips_bytes() {
+        let mut nes = nes();
+        nes.step_instruction();
+        nes.cpu.bus.write(0x2006, 0x20);
+        nes.cpu.bus.write(0x2006, 0x00);
+        // LDA $2007
+        run_from_ram(&mut nes, &[0xad, 0x07, 0x20]);
+        let cycles = dmc_fetch_after(&mut nes, 3);
+        // Every repeat of the halted read increments the VRAM address
+        let increments = nes.cpu.bus.ppu.v.addr() - 0x2000;
+        assert_eq!(increments as u64, cycles - 4);
+        assert!((3..=4).contains(&increments));
+    }
+
+    #[test]
+    pub fn dmc_fetch_waits_for_a_read_cycle() {
+        let mut nes = nes();
+        nes.step_instruction();
+        // STA $0400 writes on its fourth cycle, LDA $0400
+        nes.cpu.registers.acc = 0x55;
+        run_from_ram(&mut nes, &[0x8d, 0x00, 0x04, 0xad, 0x00, 0x04]);
+        let cycles = dmc_fetch_after(&mut nes, 3);
+        // The write goes through, the CPU halts on the opcode fetch of the LDA
+        assert_eq!(cycles, 4);
+        assert_eq!(nes.cpu.bus.ram[0x0400], 0x55);
+        assert!(nes.cpu.bus.dma.wants_bus() && !nes.cpu.bus.dma.halted());
+        nes.cpu.registers.acc = 0x00;
+        let cycles = nes.step_instruction();
+        assert!((7..=8).contains(&cycles));
+        assert_eq!(nes.cpu.registers.acc, 0x55);
+        assert_eq!(nes.cpu.bus.apu.dmc.sample_buffer(), Some(0xa9));
+    }
+
+    #[test]
+    pub fn dmc_disabled_while_its_fetch_waits() {
+        let mut nes = nes();
+        nes.step_instruction();
+        // LDA #$00, STA $4015, NOP
+        run_from_ram(&mut nes, &[0xa9, 0x00, 0x8d, 0x15, 0x40, 0xea]);
+        nes.step_instruction();
+        // Requested before the write cycle of the STA, the fetch completes after it
+        assert_eq!(dmc_fetch_after(&mut nes, 3), 4);
+        nes.step_instruction();
+        assert_eq!(nes.cpu.bus.apu.dmc.sample_buffer(), Some(0xa9));
+        assert_eq!(nes.cpu.bus.read(0x4015) & 0x10, 0x00);
+        for _ in 0..1000 {
+            nes.step_cycle();
+        }
+        assert!(!nes.cpu.bus.dma.wants_bus());
+    }
+
+    #[test]
+    pub fn dmc_fetch_during_oam_dma() {
+        for dmc in [false, true] {
+            let mut nes = nes();
+            for i in 0..256 {
+                nes.cpu.bus.ram[0x0200 + i] = i as u8;
+            }
+            nes.step_instruction();
+            // LDA #$02, STA $4014, NOP
+            run_from_ram(&mut nes, &[0xa9, 0x02, 0x8d, 0x14, 0x40, 0xea]);
+            nes.step_instruction();
+            nes.step_instruction();
+            // The NOP is halted on its opcode fetch and done 2 cycles after the transfer
+            let start = nes.cpu_cycles();
+            for _ in 0..100 {
+                nes.step_cycle();
+            }
+            if dmc {
+                request_dmc_fetch(&mut nes);
+            }
+            nes.step_instruction();
+            let cycles = nes.cpu_cycles() - start;
+
+            // The DMC fetch takes a get cycle from the sprite transfer and realigns it
+            let expected = if dmc { 517..=518 } else { 515..=516 };
+            assert!(expected.contains(&cycles), "{} cycles", cycles);
+            assert_eq!(nes.cpu.bus.ppu.oam[0x80], 0x80);
+            assert_eq!(nes.cpu.bus.ppu.oam[0xff], 0xff);
+            if dmc {
+                assert_eq!(nes.cpu.bus.apu.dmc.sample_buffer(), Some(0xa9));
+            }
+        }
+    }
+
+    #[test]
+    pub fn dmc_fetch_during_controller_read_drops_a_bit() {
+        for dmc in [false, true] {
+            let mut nes = nes();
+            nes.step_instruction();
+            nes.set_buttons(Port::One, Button::A as u8 | Button::B as u8);
+            nes.cpu.bus.write(0x4016, 0x01);
+            nes.cpu.bus.write(0x4016, 0x00);
+            // LDA $4016, LDA $4016
+            run_from_ram(&mut nes, &[0xad, 0x16, 0x40, 0xad, 0x16, 0x40]);
+            nes.step_instruction();
+            assert_eq!(nes.cpu.registers.acc & 0x01, 0x01);
+
+            if dmc {
+                // Halted on the read of $4016 on the fourth cycle. The halt read clocks out B
+                // unseen, the dummy and alignment repeats on back to back cycles only count
+                // once, and the CPU gets Select when it reads again after the fetch
+                let cycles = dmc_fetch_after(&mut nes, 3);
+                assert!((7..=8).contains(&cycles));
+                assert_eq!(nes.cpu.registers.acc & 0x01, 0x00);
+                assert_eq!(nes.cpu.bus.apu.dmc.sample_buffer(), Some(0xa9));
+            } else {
+                assert_eq!(nes.step_instruction(), 4);
+                assert_eq!(nes.cpu.registers.acc & 0x01, 0x01);
+            }
+        }
+    }
+
+}
@@ -0,0 +1,172 @@
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::cartridge::nsf::{Nsf, IDLE_LOOP};
+use crate::cartridge::{Cartridge, CartridgeError, Region};
+use crate::cpu::cpu6502::StatusRegisterFlags;
+use crate::nes::Nes;
+
+// Longest an INIT routine may run before the player gives up waiting for it, in seconds
+const INIT_TIMEOUT: f64 = 1.0;
+
+// Plays NSF music: the console runs INIT once per track and PLAY at the rate from the file,
+// both return to an idle loop the NSF board provides
+pub struct NsfPlayer {
+    nes: Nes,
+    nsf: Nsf,
+    // Current track, 1-based
+    track: u8,
+    // CPU cycles between PLAY calls and the cycle of the next one
+    play_period: f64,
+    next_play: f64
+}
+
+impl NsfPlayer {
+
+    pub fn new(nsf: Nsf) -> Self {
+        let nes = Nes::new(Cartridge::from_nsf(&nsf));
+        let play_period = nsf.play_speed(nes.timing().region) as f64 * nes.timing().cpu_frequency() / 1_000_000.0;
+        let track = nsf.starting_song;
+        let mut player = Self { nes, nsf, track, play_period, next_play: 0.0 };
+        player.start_track(track);
+        player
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, CartridgeError> {
+        Ok(Self::new(Nsf::parse(data)?))
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, CartridgeError> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+
+    pub fn nsf(&self) -> &Nsf {
+        &self.nsf
+    }
+
+    pub fn nes(&mut self) -> &mut Nes {
+        &mut self.nes
+    }
+
+    pub fn track(&self) -> u8 {
+        self.track
+    }
+
+    // Restarts the sound hardware and runs INIT for a 1-based track number
+    pub fn start_track(&mut self, track: u8) {
+        self.track = track.clamp(1, self.nsf.total_songs);
+
+        let bus = &mut self.nes.cpu.bus;
+        bus.ram.fill(0x00);
+        for addr in 0x6000..0x8000 {
+            bus.write(addr, 0x00);
+        }
+        for addr in 0x4000..0x4014 {
+            bus.write(addr, 0x00);
+        }
+        bus.write(0x4015, 0x00);
+        bus.write(0x4015, 0x0f);
+        bus.write(0x4017, 0x40);
+        for (i, bank) in self.nsf.initial_banks().iter().enumerate() {
+            bus.write(0x5ff6 + i as u16, *bank);
+        }
+
+        // X is 1 for PAL
+        let pal = self.nes.timing().region == Region::Pal;
+        let cpu = &mut self.nes.cpu;
+        cpu.registers.sp = 0xfd;
+        cpu.registers.status = StatusRegisterFlags::U as u8 | StatusRegisterFlags::I as u8;
+        cpu.registers.acc = self.track - 1;
+        cpu.registers.x = pal as u8;
+        self.call(self.nsf.init_addr);
+
+        let timeout = self.nes.cpu_cycles() + (INIT_TIMEOUT * self.nes.timing().cpu_frequency()) as u64;
+        while !self.idle() && self.nes.cpu_cycles() < timeout {
+            self.nes.step_cycle();
+        }
+        self.next_play = self.nes.cpu_cycles() as f64;
+    }
+
+    // JSR to `addr` from the idle loop
+    fn call(&mut self, addr: u16) {
+        let cpu = &mut self.nes.cpu;
+        cpu.abort();
+        cpu.push_u16(IDLE_LOOP - 1);
+        cpu.registers.pcl = addr;
+    }
+
+    // Between instructions in the idle loop, the last routine has returned
+    fn idle(&self) -> bool {
+        let cpu = &self.nes.cpu;
+        cpu.instruction_complete() && (IDLE_LOOP..IDLE_LOOP + 3).contains(&cpu.registers.pcl)
+    }
+
+    // Runs until the CPU cycle counter reaches `cycles`, calling PLAY when it is due
+    pub fn run_until(&mut self, cycles: u64) {
+        while self.nes.cpu_cycles() < cycles {
+            let now = self.nes.cpu_cycles() as f64;
+            if now >= self.next_play && self.idle() {
+                self.call(self.nsf.play_addr);
+                // A PLAY routine running late does not build up a backlog of calls
+                self.next_play = (self.next_play + self.play_period).max(now);
+            }
+            self.nes.step_cycle();
+        }
+    }
+
+    pub fn run_for(&mut self, duration: Duration) {
+        let cycles = (duration.as_secs_f64() * self.nes.timing().cpu_frequency()) as u64;
+        self.run_until(self.nes.cpu_cycles() + cycles);
+    }
+
+    // Mono samples produced since the last call, see `Nes::audio_samples`
+    pub fn audio_samples(&mut self) -> Vec<f32> {
+        self.nes.audio_samples()
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // INIT stores the track in $00, PLAY counts calls in $01 and keeps pulse 1 playing
+    fn nsf() -> Vec<u8> {
+        let mut data = vec![0x00; 0x80];
+        data[..5].copy_from_slice(b"NESM\x1a");
+        data[0x05] = 1;
+        data[0x06] = 2;
+        data[0x07] = 1;
+        data[0x08..0x0e].copy_from_slice(&[0x00, 0x80, 0x00, 0x80, 0x03, 0x80]);
+        data.extend_from_slice(&[
+            0x85, 0x00,             // INIT: STA $00
+            0x60,                   // RTS
+            0xe6, 0x01,             // PLAY: INC $01
+            0xa9, 0xbf,             // LDA #$bf
+            0x8d, 0x00, 0x40,       // STA $4000
+            0xa9, 0x80,             // LDA #$80
+            0x8d, 0x02, 0x40,       // STA $4002
+            0xa9, 0x08,             // LDA #$08
+            0x8d, 0x03, 0x40,       // STA $4003
+            0x60                    // RTS
+        ]);
+        data
+    }
+
+    #[test]
+    pub fn calls_init_and_play() {
+        let mut player = NsfPlayer::from_bytes(&nsf()).unwrap();
+        player.start_track(2);
+        assert_eq!(player.nes().cpu.bus.ram[0x00], 0x01);
+
+        // 60 PLAY calls in one second of NTSC time
+        player.run_for(Duration::from_secs(1));
+        let calls = player.nes().cpu.bus.ram[0x01];
+        assert!((60..=61).contains(&calls));
+        let samples = player.audio_samples();
+        assert!(samples.iter().any(|sample| sample.abs() > 0.01));
+    }
+
+}
@@ -0,0 +1,261 @@
+use std::fmt;
+
+use crate::ppu::frame::Frame;
+use crate::ppu::palette::Palette;
+use crate::ppu::register::CtrlFlags;
+use crate::ppu::render::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::ppu::{palette_index, Ppu};
+
+// Outline of the visible area in the nametable view
+const SCROLL_COLOR: [u8; 3] = [0xff, 0x00, 0xff];
+// Sprite sheet cells leave room for 8x16 sprites and a gap
+const SPRITE_CELL_WIDTH: usize = 10;
+const SPRITE_CELL_HEIGHT: usize = 18;
+// Palette RAM swatches
+const SWATCH: usize = 16;
+
+// Decoded OAM entry
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SpriteInfo {
+    pub index: usize,
+    // Screen position, sprites show up one line below their Y value
+    pub x: u8,
+    pub y: u8,
+    pub tile: u8,
+    // Sprite palette 0 - 3
+    pub palette: u8,
+    pub behind_background: bool,
+    pub flip_horizontal: bool,
+    pub flip_vertical: bool
+}
+
+impl SpriteInfo {
+
+    fn new(index: usize, entry: &[u8]) -> Self {
+        let attribute = entry[2];
+        Self {
+            index,
+            x: entry[3],
+            y: entry[0],
+            tile: entry[1],
+            palette: attribute & 0x03,
+            behind_background: attribute & 0x20 != 0,
+            flip_horizontal: attribute & 0x40 != 0,
+            flip_vertical: attribute & 0x80 != 0
+        }
+    }
+
+}
+
+impl fmt::Display for SpriteInfo {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{:02} x={:3} y={:3} tile=${:02x} palette={}", self.index, self.x, self.y, self.tile, self.palette)?;
+        if self.behind_background {
+            write!(f, " behind")?;
+        }
+        if self.flip_horizontal {
+            write!(f, " flip-h")?;
+        }
+        if self.flip_vertical {
+            write!(f, " flip-v")?;
+        }
+        Ok(())
+    }
+
+}
+
+// Colors of one palette RAM entry, greyscale is left out to show what is stored
+fn color(ppu: &Ppu, colors: &Palette, entry: usize) -> [u8; 3] {
+    colors.rgb(ppu.palette[palette_index(entry as u16)] as u16)
+}
+
+// Pixel values (0 - 3) of one tile row
+fn tile_row(ppu: &mut Ppu, addr: u16) -> [u8; 8] {
+    let low = ppu.vram_read(addr);
+    let high = ppu.vram_read(addr + 8);
+    let mut row = [0; 8];
+    for (x, pixel) in row.iter_mut().enumerate() {
+        let bit = 7 - x;
+        *pixel = (low >> bit & 0x01) | (high >> bit & 0x01) << 1;
+    }
+    row
+}
+
+// Both pattern tables side by side as 256x128, colored with palette 0 - 7 (4 - 7 are the sprite palettes)
+pub fn pattern_tables(ppu: &mut Ppu, colors: &Palette, palette: u8) -> Frame {
+    let mut frame = Frame::new(256, 128);
+    let base = (palette as usize & 0x07) * 4;
+    for table in 0..2u16 {
+        for tile in 0..256u16 {
+            for row in 0..8u16 {
+                let pixels = tile_row(ppu, table << 12 | tile << 4 | row);
+                for (x, pixel) in pixels.iter().enumerate() {
+                    let rgb = color(ppu, colors, base + *pixel as usize);
+                    let px = table as usize * 128 + (tile as usize & 0x0f) * 8 + x;
+                    let py = (tile as usize >> 4) * 8 + row as usize;
+                    frame.set_pixel(px, py, rgb);
+                }
+            }
+        }
+    }
+    frame
+}
+
+// All four nametables as 512x480 with the background pattern table and attributes in use,
+// the area the next frame starts scrolled to is outlined
+pub fn nametables(ppu: &mut Ppu, colors: &Palette) -> Frame {
+    let mut frame = Frame::new(2 * SCREEN_WIDTH, 2 * SCREEN_HEIGHT);
+    let pattern_table = if ppu.ctrl & CtrlFlags::BackgroundPattern as u8 != 0 { 0x1000 } else { 0x0000 };
+
+    for table in 0..4u16 {
+        let base = 0x2000 | table << 10;
+        for tile_y in 0..30u16 {
+            for tile_x in 0..32u16 {
+                let tile = ppu.vram_read(base | tile_y << 5 | tile_x) as u16;
+                let attribute = ppu.vram_read(base | 0x03c0 | (tile_y >> 2) << 3 | tile_x >> 2);
+                let shift = ((tile_y & 0x02) << 1) | (tile_x & 0x02);
+                let palette = ((attribute >> shift) & 0x03) as usize;
+                for row in 0..8u16 {
+                    let pixels = tile_row(ppu, pattern_table | tile << 4 | row);
+                    for (x, pixel) in pixels.iter().enumerate() {
+                        let entry = if *pixel == 0 { 0 } else { palette * 4 + *pixel as usize };
+                        let px = (table as usize & 0x01) * SCREEN_WIDTH + tile_x as usize * 8 + x;
+                        let py = (table as usize >> 1) * SCREEN_HEIGHT + tile_y as usize * 8 + row as usize;
+                        frame.set_pixel(px, py, color(ppu, colors, entry));
+                    }
+                }
+            }
+        }
+    }
+
+    // Scroll position from t, the window wraps around the edges
+    let t = ppu.t;
+    let scroll_x = (t.nametable() as usize & 0x01) * SCREEN_WIDTH + t.coarse_x() as usize * 8 + ppu.x as usize;
+    let scroll_y = (t.nametable() as usize >> 1) * SCREEN_HEIGHT + t.coarse_y() as usize * 8 + t.fine_y() as usize;
+    for i in 0..SCREEN_WIDTH {
+        let x = (scroll_x + i) % frame.width;
+        frame.set_pixel(x, scroll_y % frame.height, SCROLL_COLOR);
+        frame.set_pixel(x, (scroll_y + SCREEN_HEIGHT - 1) % frame.height, SCROLL_COLOR);
+    }
+    for i in 0..SCREEN_HEIGHT {
+        let y = (scroll_y + i) % frame.height;
+        frame.set_pixel(scroll_x % frame.width, y, SCROLL_COLOR);
+        frame.set_pixel((scroll_x + SCREEN_WIDTH - 1) % frame.width, y, SCROLL_COLOR);
+    }
+    frame
+}
+
+// Decoded primary OAM
+pub fn sprites(ppu: &Ppu) -> Vec<SpriteInfo> {
+    ppu.oam.chunks(4).enumerate().map(|(i, entry)| SpriteInfo::new(i, entry)).collect()
+}
+
+// All 64 sprites in an 8x8 grid, drawn with their palette, flipping and the current sprite size
+pub fn sprite_sheet(ppu: &mut Ppu, colors: &Palette) -> Frame {
+    let mut frame = Frame::new(8 * SPRITE_CELL_WIDTH, 8 * SPRITE_CELL_HEIGHT);
+    let height = ppu.sprite_height();
+    let backdrop = color(ppu, colors, 0);
+    for pixel in frame.pixels.chunks_mut(3) {
+        pixel.copy_from_slice(&backdrop);
+    }
+
+    for sprite in sprites(ppu) {
+        let cell_x = (sprite.index & 0x07) * SPRITE_CELL_WIDTH + 1;
+        let cell_y = (sprite.index >> 3) * SPRITE_CELL_HEIGHT + 1;
+        for row in 0..height {
+            let addr = if height == 16 {
+                let tile = (sprite.tile as u16 & 0xfe) + (row >> 3);
+                (sprite.tile as u16 & 0x01) << 12 | tile << 4 | (row & 0x07)
+            } else {
+                let table = if ppu.ctrl & CtrlFlags::SpritePattern as u8 != 0 { 0x1000 } else { 0x0000 };
+                table | (sprite.tile as u16) << 4 | row
+            };
+            let pixels = tile_row(ppu, addr);
+            let y = if sprite.flip_vertical { height - 1 - row } else { row };
+            for (x, pixel) in pixels.iter().enumerate() {
+                if *pixel == 0 {
+                    continue;
+                }
+                let x = if sprite.flip_horizontal { 7 - x } else { x };
+                let rgb = color(ppu, colors, 0x10 + sprite.palette as usize * 4 + *pixel as usize);
+                frame.set_pixel(cell_x + x, cell_y + y as usize, rgb);
+            }
+        }
+    }
+    frame
+}
+
+// Palette RAM as two rows of 16 swatches, background palettes on top
+pub fn palette_ram(ppu: &Ppu, colors: &Palette) -> Frame {
+    let mut frame = Frame::new(16 * SWATCH, 2 * SWATCH);
+    for entry in 0..32 {
+        let rgb = color(ppu, colors, entry);
+        for y in 0..SWATCH {
+            for x in 0..SWATCH {
+                frame.set_pixel((entry & 0x0f) * SWATCH + x, (entry >> 4) * SWATCH + y, rgb);
+            }
+        }
+    }
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::cartridge::{Cartridge, RomImage};
+
+    // CHR RAM, tile 1 is solid color 3
+    fn ppu() -> Ppu {
+        let image = RomImage::nrom(vec![0x00; 16 * 1024], true);
+        let mut ppu = Ppu::new();
+        ppu.connect_cartridge(Rc::new(RefCell::new(Cartridge::new(image).unwrap())));
+        for addr in 0x0010..0x0020 {
+            ppu.vram_write(addr, 0xff);
+        }
+        ppu.vram_write(0x3f00, 0x0f);
+        ppu.vram_write(0x3f03, 0x30);
+        ppu.vram_write(0x3f13, 0x16);
+        ppu
+    }
+
+    #[test]
+    pub fn pattern_and_nametable_views() {
+        let mut ppu = ppu();
+        let colors = Palette::default();
+        let patterns = pattern_tables(&mut ppu, &colors, 0);
+        assert_eq!(patterns.pixel(0, 0), colors.rgb(0x0f));
+        assert_eq!(patterns.pixel(8, 0), colors.rgb(0x30));
+
+        ppu.vram_write(0x2c21, 0x01);
+        ppu.t.set_coarse_x(4);
+        let view = nametables(&mut ppu, &colors);
+        assert_eq!((view.width, view.height), (512, 480));
+        // Horizontal mirroring, $2C00 shows up in the bottom half
+        assert_eq!(view.pixel(8, SCREEN_HEIGHT + 8), colors.rgb(0x30));
+        assert_eq!(view.pixel(8, 8), colors.rgb(0x0f));
+        assert_eq!(view.pixel(32, 100), SCROLL_COLOR);
+    }
+
+    #[test]
+    pub fn sprite_sheet_and_palette() {
+        let mut ppu = ppu();
+        let colors = Palette::default();
+        ppu.oam[4..8].copy_from_slice(&[0x20, 0x01, 0xe0, 0x40]);
+        let info = sprites(&ppu)[1];
+        assert_eq!(info.to_string(), "#01 x= 64 y= 32 tile=$01 palette=0 behind flip-h flip-v");
+
+        let sheet = sprite_sheet(&mut ppu, &colors);
+        assert_eq!(sheet.pixel(SPRITE_CELL_WIDTH + 1, 1), colors.rgb(0x16));
+        assert_eq!(sheet.pixel(1, 1), colors.rgb(0x0f));
+
+        let swatches = palette_ram(&ppu, &colors);
+        assert_eq!(swatches.pixel(3 * SWATCH, 0), colors.rgb(0x30));
+        assert_eq!(swatches.pixel(0, SWATCH), colors.rgb(0x0f));
+    }
+
+}
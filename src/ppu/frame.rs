@@ -0,0 +1,223 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::cartridge::hash;
+use crate::ppu::ntsc::{NtscFilter, NTSC_WIDTH};
+use crate::ppu::palette::Palette;
+use crate::ppu::render::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+// Lines at the top and bottom most NTSC TVs do not show
+pub const OVERSCAN_LINES: usize = 8;
+
+// Largest stored deflate block
+const DEFLATE_BLOCK: usize = 0xffff;
+
+// Picture as packed 24 bit RGB, ready to be shown or written to a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>
+}
+
+impl Frame {
+
+    // Black picture
+    pub fn new(width: usize, height: usize) -> Self {
+        Self { width, height, pixels: vec![0x00; width * height * 3] }
+    }
+
+    // Pixels outside of the frame are ignored
+    pub fn set_pixel(&mut self, x: usize, y: usize, rgb: [u8; 3]) {
+        if x < self.width && y < self.height {
+            let i = (y * self.width + x) * 3;
+            self.pixels[i..i + 3].copy_from_slice(&rgb);
+        }
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> [u8; 3] {
+        let i = (y * self.width + x) * 3;
+        [self.pixels[i], self.pixels[i + 1], self.pixels[i + 2]]
+    }
+
+    // Converts a PPU framebuffer with the given colors
+    pub fn from_framebuffer(framebuffer: &[u16], palette: &Palette) -> Self {
+        Self { width: SCREEN_WIDTH, height: SCREEN_HEIGHT, pixels: palette.to_rgb(framebuffer) }
+    }
+
+    // Converts a PPU framebuffer through the NTSC filter, the frame is NTSC_WIDTH wide
+    pub fn from_ntsc(framebuffer: &[u16], filter: &NtscFilter, burst_phase: u8) -> Self {
+        let pixels = filter.filter(framebuffer, burst_phase);
+        Self { width: NTSC_WIDTH, height: pixels.len() / (NTSC_WIDTH * 3), pixels }
+    }
+
+    // Part of the frame, clipped to its size
+    pub fn crop(&self, x: usize, y: usize, width: usize, height: usize) -> Self {
+        let x = x.min(self.width);
+        let y = y.min(self.height);
+        let width = width.min(self.width - x);
+        let height = height.min(self.height - y);
+        let mut pixels = Vec::with_capacity(width * height * 3);
+        for row in y..y + height {
+            let start = (row * self.width + x) * 3;
+            pixels.extend_from_slice(&self.pixels[start..start + width * 3]);
+        }
+        Self { width, height, pixels }
+    }
+
+    // Drops the lines hidden by the TV bezel
+    pub fn crop_overscan(&self) -> Self {
+        self.crop(0, OVERSCAN_LINES, self.width, self.height.saturating_sub(2 * OVERSCAN_LINES))
+    }
+
+    // Nearest neighbor scaling by a whole factor
+    pub fn scale(&self, factor: usize) -> Self {
+        let factor = factor.max(1);
+        let width = self.width * factor;
+        let mut pixels = Vec::with_capacity(width * self.height * factor * 3);
+        for row in self.rows() {
+            let start = pixels.len();
+            for pixel in row.chunks(3) {
+                for _ in 0..factor {
+                    pixels.extend_from_slice(pixel);
+                }
+            }
+            for _ in 1..factor {
+                pixels.extend_from_within(start..start + width * 3);
+            }
+        }
+        Self { width, height: self.height * factor, pixels }
+    }
+
+    // Rows of packed pixels, crops outside of the frame have rows of width 0
+    fn rows(&self) -> impl Iterator<Item = &[u8]> {
+        let stride = self.width * 3;
+        (0..self.height).map(move |row| &self.pixels[row * stride..(row + 1) * stride])
+    }
+
+    // Binary PPM (P6)
+    pub fn to_ppm(&self) -> Vec<u8> {
+        let mut data = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        data.extend_from_slice(&self.pixels);
+        data
+    }
+
+    // 8 bit RGB PNG, the image data is stored uncompressed. PNG has no empty images, so
+    // crops outside of the frame give None
+    pub fn to_png(&self) -> Option<Vec<u8>> {
+        if self.width == 0 || self.height == 0 {
+            return None;
+        }
+        let mut data = vec![0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+
+        let mut header = Vec::with_capacity(13);
+        header.extend_from_slice(&(self.width as u32).to_be_bytes());
+        header.extend_from_slice(&(self.height as u32).to_be_bytes());
+        // Bit depth 8, color type RGB, deflate, no filter method extensions, no interlace
+        header.extend_from_slice(&[8, 2, 0, 0, 0]);
+        png_chunk(&mut data, b"IHDR", &header);
+
+        // Every row starts with filter type 0
+        let mut raw = Vec::with_capacity((self.width * 3 + 1) * self.height);
+        for row in self.rows() {
+            raw.push(0x00);
+            raw.extend_from_slice(row);
+        }
+        png_chunk(&mut data, b"IDAT", &zlib_stored(&raw));
+        png_chunk(&mut data, b"IEND", &[]);
+        Some(data)
+    }
+
+    // Writes a PNG, or a PPM if the file name ends in .ppm
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let ppm = match path.as_ref().extension() {
+            Some(extension) => extension.eq_ignore_ascii_case("ppm"),
+            None => false
+        };
+        let data = if ppm { Some(self.to_ppm()) } else { self.to_png() };
+        let data = data.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty frame"))?;
+        fs::write(path, data)
+    }
+
+}
+
+fn png_chunk(data: &mut Vec<u8>, kind: &[u8; 4], contents: &[u8]) {
+    data.extend_from_slice(&(contents.len() as u32).to_be_bytes());
+    data.extend_from_slice(kind);
+    data.extend_from_slice(contents);
+    let crc = hash::crc32_update(hash::crc32(kind), contents);
+    data.extend_from_slice(&crc.to_be_bytes());
+}
+
+// zlib stream made of stored deflate blocks
+fn zlib_stored(raw: &[u8]) -> Vec<u8> {
+    let mut data = vec![0x78, 0x01];
+    let blocks: Vec<&[u8]> = raw.chunks(DEFLATE_BLOCK).collect();
+    for (i, block) in blocks.iter().enumerate() {
+        // Block type 0, the last one has BFINAL set
+        data.push((i + 1 == blocks.len()) as u8);
+        data.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        data.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        data.extend_from_slice(block);
+    }
+    data.extend_from_slice(&adler32(raw).to_be_bytes());
+    data
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), byte| {
+        let a = (a + *byte as u32) % 65521;
+        (a, (b + a) % 65521)
+    });
+    b << 16 | a
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn frame() -> Frame {
+        Frame { width: 2, height: 2, pixels: vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12] }
+    }
+
+    #[test]
+    pub fn crop_and_scale() {
+        let frame = frame();
+        assert_eq!(frame.crop(1, 0, 5, 1).pixels, vec![4, 5, 6]);
+        let scaled = frame.scale(2);
+        assert_eq!((scaled.width, scaled.height), (4, 4));
+        assert_eq!(&scaled.pixels[..12], &[1, 2, 3, 1, 2, 3, 4, 5, 6, 4, 5, 6]);
+        assert_eq!(&scaled.pixels[12..24], &scaled.pixels[..12]);
+
+        let screen = Frame::from_framebuffer(&[0x0f; SCREEN_WIDTH * SCREEN_HEIGHT], &Palette::default());
+        assert_eq!(screen.crop_overscan().height, 224);
+    }
+
+    #[test]
+    pub fn empty_crops() {
+        let empty = frame().crop(2, 0, 2, 2);
+        assert_eq!((empty.width, empty.height), (0, 2));
+        let scaled = empty.scale(3);
+        assert_eq!((scaled.width, scaled.height), (0, 6));
+        assert!(scaled.pixels.is_empty());
+        assert_eq!(empty.to_png(), None);
+        assert_eq!(frame().crop(0, 5, 2, 2).to_png(), None);
+        assert!(empty.save(std::env::temp_dir().join("empty_crop.png")).is_err());
+    }
+
+    #[test]
+    pub fn encodes_images() {
+        let frame = frame();
+        assert_eq!(&frame.to_ppm()[..11], b"P6\n2 2\n255\n");
+
+        let png = frame.to_png().unwrap();
+        assert_eq!(&png[12..16], b"IHDR");
+        // IHDR CRC of a 2x2 RGB image
+        assert_eq!(&png[29..33], &[0xfd, 0xd4, 0x9a, 0x73]);
+        assert_eq!(&png[png.len() - 8..png.len() - 4], b"IEND");
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+
+}
@@ -0,0 +1,471 @@
+pub mod debug;
+pub mod frame;
+pub mod ntsc;
+pub mod palette;
+pub mod register;
+pub mod render;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::cartridge::Cartridge;
+use crate::timing::Timing;
+use register::{CtrlFlags, Loopy, MaskFlags, StatusFlags};
+use render::{Background, Sprites, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+pub const DOTS_PER_SCANLINE: u16 = 341;
+
+// Ricoh 2C02 picture processing unit
+pub struct Ppu {
+    cartridge: Option<Rc<RefCell<Cartridge>>>,
+    // Frame layout of the TV system
+    timing: Timing,
+    pub ctrl: u8,
+    pub mask: u8,
+    pub status: u8,
+    pub oam_addr: u8,
+    // Primary OAM, 64 sprites of 4 bytes
+    pub oam: [u8; 256],
+    // Current VRAM address, temporary VRAM address, fine X scroll and the $2005/$2006 write toggle
+    pub v: Loopy,
+    pub t: Loopy,
+    pub x: u8,
+    pub w: bool,
+    // $2007 reads below the palettes return the previous read
+    read_buffer: u8,
+    // Last value driven on the CPU data bus of the PPU, returned for write only registers
+    io_latch: u8,
+    // 2 KiB CIRAM in the console
+    pub nametables: [u8; 2048],
+    pub palette: [u8; 32],
+    pub scanline: u16,
+    pub dot: u16,
+    pub frame: u64,
+    // $2002 read right before vblank starts, the flag is not set this frame
+    suppress_vblank: bool,
+    background: Background,
+    sprites: Sprites,
+    // 256x240 palette indices, emphasis bits in 6 - 8
+    pub framebuffer: Vec<u16>,
+    // Color burst phase of the first dot of the frame in master clock ticks (0 - 11), it moves
+    // from frame to frame and makes composite artifacts crawl
+    pub burst_phase: u8,
+    // Set when vblank starts, cleared by whoever consumes the frame
+    pub frame_complete: bool
+}
+
+impl Ppu {
+
+    pub fn new() -> Self {
+        Self {
+            cartridge: None,
+            timing: Timing::default(),
+            ctrl: 0x00,
+            mask: 0x00,
+            status: 0x00,
+            oam_addr: 0x00,
+            oam: [0x00; 256],
+            v: Loopy::default(),
+            t: Loopy::default(),
+            x: 0,
+            w: false,
+            read_buffer: 0x00,
+            io_latch: 0x00,
+            nametables: [0x00; 2048],
+            palette: [0x00; 32],
+            scanline: 0,
+            dot: 0,
+            frame: 0,
+            suppress_vblank: false,
+            background: Background::default(),
+            sprites: Sprites::default(),
+            framebuffer: vec![0x00; SCREEN_WIDTH * SCREEN_HEIGHT],
+            burst_phase: 0,
+            frame_complete: false
+        }
+    }
+
+    pub fn connect_cartridge(&mut self, cartridge: Rc<RefCell<Cartridge>>) {
+        self.cartridge = Some(cartridge);
+    }
+
+    pub fn set_timing(&mut self, timing: Timing) {
+        self.timing = timing;
+    }
+
+    pub fn timing(&self) -> &Timing {
+        &self.timing
+    }
+
+    pub fn pre_render_scanline(&self) -> u16 {
+        self.timing.pre_render_scanline()
+    }
+
+    pub fn reset(&mut self) {
+        self.ctrl = 0x00;
+        self.mask = 0x00;
+        self.w = false;
+        self.x = 0;
+        self.t = Loopy::default();
+        self.read_buffer = 0x00;
+        self.scanline = 0;
+        self.dot = 0;
+    }
+
+    fn ctrl_flag(&self, flag: CtrlFlags) -> bool {
+        self.ctrl & flag as u8 != 0
+    }
+
+    fn set_status_flag(&mut self, flag: StatusFlags, value: bool) {
+        if value {
+            self.status |= flag as u8;
+        } else {
+            self.status &= !(flag as u8);
+        }
+    }
+
+    pub fn rendering_enabled(&self) -> bool {
+        self.mask & (MaskFlags::ShowBackground as u8 | MaskFlags::ShowSprites as u8) != 0
+    }
+
+    // Visible and pre-render scanlines with rendering enabled, the PPU owns v and OAM
+    pub fn rendering(&self) -> bool {
+        self.rendering_enabled() && (self.scanline < 240 || self.scanline == self.pre_render_scanline())
+    }
+
+    // Level of the /NMI output, the CPU triggers on its falling edge
+    pub fn nmi(&self) -> bool {
+        self.status & StatusFlags::VerticalBlank as u8 != 0 && self.ctrl_flag(CtrlFlags::Nmi)
+    }
+
+    // CPU reads from $2000 - $3FFF
+    pub fn cpu_read(&mut self, addr: u16) -> u8 {
+        let data = match addr & 0x0007 {
+            0x0002 => {
+                let data = (self.status & 0xe0) | (self.io_latch & 0x1f);
+                // One dot before the flag is raised: it reads clear and stays clear for this frame
+                if self.scanline == self.timing.vblank_scanline && self.dot == 1 {
+                    self.suppress_vblank = true;
+                }
+                self.set_status_flag(StatusFlags::VerticalBlank, false);
+                self.w = false;
+                data
+            },
+            0x0004 => {
+                // Attribute bytes have no bits 2 - 4
+                let data = self.oam[self.oam_addr as usize];
+                if self.oam_addr & 0x03 == 0x02 { data & 0xe3 } else { data }
+            },
+            0x0007 => {
+                let addr = self.v.addr();
+                let data = if addr >= 0x3f00 {
+                    // Palette reads are immediate, the buffer gets the nametable byte underneath
+                    self.read_buffer = self.vram_read(addr - 0x1000);
+                    (self.read_palette(addr) & 0x3f) | (self.io_latch & 0xc0)
+                } else {
+                    let data = self.read_buffer;
+                    self.read_buffer = self.vram_read(addr);
+                    data
+                };
+                self.increment_vram_address();
+                data
+            },
+            _ => self.io_latch
+        };
+        self.io_latch = data;
+        data
+    }
+
+    // CPU writes to $2000 - $3FFF
+    pub fn cpu_write(&mut self, addr: u16, data: u8) {
+        self.io_latch = data;
+        match addr & 0x0007 {
+            0x0000 => {
+                self.ctrl = data;
+                self.t.set_nametable(data & 0x03);
+            },
+            0x0001 => self.mask = data,
+            0x0003 => self.oam_addr = data,
+            0x0004 => {
+                // During rendering the write is dropped and only the high 6 bits of the address move
+                if self.rendering() {
+                    self.oam_addr = self.oam_addr.wrapping_add(4);
+                } else {
+                    self.oam[self.oam_addr as usize] = data;
+                    self.oam_addr = self.oam_addr.wrapping_add(1);
+                }
+            },
+            0x0005 => {
+                if !self.w {
+                    self.t.set_coarse_x(data >> 3);
+                    self.x = data & 0x07;
+                } else {
+                    self.t.set_fine_y(data & 0x07);
+                    self.t.set_coarse_y(data >> 3);
+                }
+                self.w = !self.w;
+            },
+            0x0006 => {
+                if !self.w {
+                    // Bit 14 is cleared as well
+                    self.t.value = (self.t.value & 0x00ff) | ((data & 0x3f) as u16) << 8;
+                } else {
+                    self.t.value = (self.t.value & 0xff00) | data as u16;
+                    self.v = self.t;
+                }
+                self.w = !self.w;
+            },
+            0x0007 => {
+                let addr = self.v.addr();
+                self.vram_write(addr, data);
+                self.increment_vram_address();
+            },
+            _ => {}
+        }
+    }
+
+    // After $2007 accesses; while rendering both scroll increments happen instead
+    fn increment_vram_address(&mut self) {
+        if self.rendering() {
+            self.v.increment_x();
+            self.v.increment_y();
+        } else if self.ctrl_flag(CtrlFlags::Increment) {
+            self.v.value = self.v.value.wrapping_add(32) & 0x7fff;
+        } else {
+            self.v.value = self.v.value.wrapping_add(1) & 0x7fff;
+        }
+    }
+
+    // PPU address space: pattern tables and nametables are wired through the cartridge
+    pub fn vram_read(&mut self, addr: u16) -> u8 {
+        let addr = addr & 0x3fff;
+        match addr {
+            0x0000..=0x3eff => match &self.cartridge {
+                Some(cartridge) => cartridge.borrow_mut().ppu_read(addr, &self.nametables),
+                None if addr >= 0x2000 => self.nametables[Self::ciram_index(addr)],
+                None => 0x00
+            },
+            _ => self.read_palette(addr)
+        }
+    }
+
+    pub fn vram_write(&mut self, addr: u16, data: u8) {
+        let addr = addr & 0x3fff;
+        match addr {
+            0x0000..=0x3eff => match &self.cartridge {
+                Some(cartridge) => cartridge.borrow_mut().ppu_write(addr, data, &mut self.nametables),
+                None if addr >= 0x2000 => self.nametables[Self::ciram_index(addr)] = data,
+                None => {}
+            },
+            _ => self.palette[palette_index(addr)] = data & 0x3f
+        }
+    }
+
+    fn read_palette(&self, addr: u16) -> u8 {
+        let data = self.palette[palette_index(addr)];
+        if self.mask & MaskFlags::Greyscale as u8 != 0 { data & 0x30 } else { data }
+    }
+
+    // Without a cartridge the nametables are horizontally mirrored CIRAM
+    fn ciram_index(addr: u16) -> usize {
+        (addr as usize >> 1 & 0x0400) | (addr as usize & 0x03ff)
+    }
+
+    // Advances by one dot
+    pub fn clock(&mut self) {
+        self.render_dot();
+
+        let vblank_scanline = self.timing.vblank_scanline;
+        let pre_render_scanline = self.pre_render_scanline();
+        match (self.scanline, self.dot) {
+            (scanline, 1) if scanline == vblank_scanline => {
+                if !self.suppress_vblank {
+                    self.set_status_flag(StatusFlags::VerticalBlank, true);
+                }
+                self.suppress_vblank = false;
+                self.frame_complete = true;
+            },
+            (scanline, 1) if scanline == pre_render_scanline => {
+                self.set_status_flag(StatusFlags::VerticalBlank, false);
+                self.set_status_flag(StatusFlags::SpriteZeroHit, false);
+                self.set_status_flag(StatusFlags::SpriteOverflow, false);
+            },
+            _ => {}
+        }
+
+        // Odd frames skip the last dot of the pre-render line while rendering
+        let skip = self.timing.skip_odd_dot && self.scanline == pre_render_scanline
+            && self.dot == DOTS_PER_SCANLINE - 2 && self.frame % 2 == 1 && self.rendering_enabled();
+
+        self.dot += if skip { 2 } else { 1 };
+        if self.dot == DOTS_PER_SCANLINE {
+            self.dot = 0;
+            self.scanline += 1;
+            if self.scanline == self.timing.scanlines {
+                self.scanline = 0;
+                self.frame += 1;
+                let dots = DOTS_PER_SCANLINE as u32 * self.timing.scanlines as u32 - skip as u32;
+                self.burst_phase = ((self.burst_phase as u32 + dots * ntsc::SAMPLES_PER_DOT) % ntsc::SAMPLES_PER_CYCLE) as u8;
+            }
+        }
+    }
+
+}
+
+// Backdrop entries of the sprite palettes ($3F10/$3F14/$3F18/$3F1C) mirror the background ones
+pub(crate) fn palette_index(addr: u16) -> usize {
+    let index = (addr & 0x1f) as usize;
+    if index & 0x13 == 0x10 { index & 0x0f } else { index }
+}
+
+impl Default for Ppu {
+
+    fn default() -> Self {
+        Self::new()
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::cartridge::{Mirroring, RomImage};
+
+    fn run_to(ppu: &mut Ppu, scanline: u16, dot: u16) {
+        while ppu.scanline != scanline || ppu.dot != dot {
+            ppu.clock();
+        }
+    }
+
+    #[test]
+    pub fn scroll_and_address_writes() {
+        let mut ppu = Ppu::new();
+        ppu.cpu_write(0x2000, 0x03);
+        ppu.cpu_write(0x2005, 0x7d);
+        ppu.cpu_write(0x2005, 0x5e);
+        assert_eq!(ppu.t.value, 0x6d6f);
+        assert_eq!(ppu.x, 5);
+
+        ppu.cpu_write(0x2006, 0x3d);
+        ppu.cpu_write(0x2006, 0xf0);
+        assert_eq!(ppu.v.value, 0x3df0);
+
+        // $2002 resets the write toggle
+        ppu.cpu_write(0x2006, 0x21);
+        ppu.cpu_read(0x2002);
+        ppu.cpu_write(0x2006, 0x22);
+        ppu.cpu_write(0x2006, 0x08);
+        assert_eq!(ppu.v.value, 0x2208);
+    }
+
+    #[test]
+    pub fn buffered_data_reads() {
+        let mut ppu = Ppu::new();
+        ppu.cpu_write(0x2006, 0x20);
+        ppu.cpu_write(0x2006, 0x00);
+        ppu.cpu_write(0x2007, 0x11);
+        ppu.cpu_write(0x2007, 0x22);
+
+        ppu.cpu_write(0x2000, CtrlFlags::Increment as u8);
+        ppu.cpu_write(0x2006, 0x20);
+        ppu.cpu_write(0x2006, 0x00);
+        ppu.cpu_read(0x2007);
+        assert_eq!(ppu.cpu_read(0x2007), 0x11);
+        assert_eq!(ppu.v.value, 0x2040);
+
+        // Palette reads skip the buffer
+        ppu.cpu_write(0x2006, 0x3f);
+        ppu.cpu_write(0x2006, 0x01);
+        ppu.cpu_write(0x2007, 0x2a);
+        ppu.cpu_write(0x2006, 0x3f);
+        ppu.cpu_write(0x2006, 0x01);
+        assert_eq!(ppu.cpu_read(0x2007), 0x2a);
+    }
+
+    #[test]
+    pub fn vblank_flag_and_nmi() {
+        let mut ppu = Ppu::new();
+        ppu.cpu_write(0x2000, CtrlFlags::Nmi as u8);
+        run_to(&mut ppu, 241, 2);
+        assert!(ppu.nmi());
+        assert_eq!(ppu.cpu_read(0x2002) & 0x80, 0x80);
+        assert!(!ppu.nmi());
+        assert_eq!(ppu.cpu_read(0x2002) & 0x80, 0x00);
+    }
+
+    #[test]
+    pub fn read_before_vblank_suppresses_flag() {
+        let mut ppu = Ppu::new();
+        ppu.cpu_write(0x2000, CtrlFlags::Nmi as u8);
+        run_to(&mut ppu, 241, 1);
+        assert_eq!(ppu.cpu_read(0x2002) & 0x80, 0x00);
+        ppu.clock();
+        assert!(!ppu.nmi());
+        assert_eq!(ppu.cpu_read(0x2002) & 0x80, 0x00);
+    }
+
+    #[test]
+    pub fn burst_phase_crawls() {
+        // 341 * 262 dots of 8 ticks leave the phase 4 ticks further each frame
+        let mut ppu = Ppu::new();
+        run_to(&mut ppu, 0, 0);
+        ppu.clock();
+        run_to(&mut ppu, 0, 0);
+        assert_eq!(ppu.burst_phase, 4);
+
+        // With rendering on, odd frames are one dot shorter
+        let mut ppu = Ppu::new();
+        ppu.mask = MaskFlags::ShowBackground as u8;
+        for _ in 0..2 {
+            ppu.clock();
+            run_to(&mut ppu, 0, 0);
+        }
+        assert_eq!(ppu.frame, 2);
+        assert_eq!(ppu.burst_phase, 0);
+    }
+
+    fn with_mirroring(mirroring: Mirroring) -> Ppu {
+        let image = RomImage { mirroring, ..RomImage::nrom(vec![0x00; 16 * 1024], false) };
+        let mut ppu = Ppu::new();
+        ppu.connect_cartridge(Rc::new(RefCell::new(Cartridge::new(image).unwrap())));
+        ppu
+    }
+
+    // Writes one byte to each nametable, returns what every nametable reads back
+    fn nametable_contents(ppu: &mut Ppu) -> [u8; 4] {
+        for table in 0..4 {
+            ppu.vram_write(0x2000 + table * 0x400, table as u8 + 1);
+        }
+        [0x2000, 0x2400, 0x2800, 0x2c00].map(|addr| ppu.vram_read(addr))
+    }
+
+    #[test]
+    pub fn nametable_mirroring() {
+        assert_eq!(nametable_contents(&mut with_mirroring(Mirroring::Horizontal)), [2, 2, 4, 4]);
+        assert_eq!(nametable_contents(&mut with_mirroring(Mirroring::Vertical)), [3, 4, 3, 4]);
+        assert_eq!(nametable_contents(&mut with_mirroring(Mirroring::SingleScreenA)), [4, 4, 4, 4]);
+        assert_eq!(nametable_contents(&mut with_mirroring(Mirroring::FourScreen)), [1, 2, 3, 4]);
+
+        // $3000 - $3EFF mirrors the nametables
+        let mut ppu = with_mirroring(Mirroring::FourScreen);
+        nametable_contents(&mut ppu);
+        assert_eq!(ppu.vram_read(0x3c00), 4);
+        assert_eq!(ppu.nametables[0x400], 2);
+    }
+
+    #[test]
+    pub fn palette_mirrors() {
+        let mut ppu = Ppu::new();
+        ppu.vram_write(0x3f10, 0x21);
+        ppu.vram_write(0x3f05, 0x15);
+        assert_eq!(ppu.vram_read(0x3f00), 0x21);
+        assert_eq!(ppu.vram_read(0x3f25), 0x15);
+        ppu.vram_write(0x3f0c, 0x0c);
+        assert_eq!(ppu.vram_read(0x3f1c), 0x0c);
+        // $3F11 is a real sprite palette entry
+        ppu.vram_write(0x3f11, 0x30);
+        assert_eq!(ppu.vram_read(0x3f01), 0x00);
+    }
+
+}
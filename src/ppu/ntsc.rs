@@ -0,0 +1,267 @@
+use std::f32::consts::PI;
+
+use crate::ppu::render::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+// The PPU outputs one signal level per master clock tick, 8 per dot
+pub const SAMPLES_PER_DOT: u32 = 8;
+// Color subcarrier period in master clock ticks
+pub const SAMPLES_PER_CYCLE: u32 = 12;
+// Output width, keeps the 8:7 pixel aspect ratio of NTSC
+pub const NTSC_WIDTH: usize = 602;
+
+const LINE_SAMPLES: usize = SCREEN_WIDTH * SAMPLES_PER_DOT as usize;
+const CYCLE: usize = SAMPLES_PER_CYCLE as usize;
+
+// Composite voltages relative to sync: four luma levels for the low and high half of the wave
+const LEVELS_LOW: [f32; 4] = [0.350, 0.518, 0.962, 1.550];
+const LEVELS_HIGH: [f32; 4] = [1.094, 1.506, 1.962, 1.962];
+const BLACK: f32 = 0.518;
+const WHITE: f32 = 1.962;
+// Emphasis attenuates the signal during one third of the subcarrier cycle
+const EMPHASIS_ATTENUATION: f32 = 0.746;
+// Aligns the decoder with the color burst, color $x8 decodes as yellow
+const BURST_OFFSET: f32 = 4.0;
+
+// Decoder settings, each in range -1.0 - 1.0 with 0.0 as the default
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct NtscSettings {
+    // Rotates all colors, 1.0 = 180 degrees
+    pub hue: f32,
+    // -1.0 is monochrome, 1.0 doubles the color
+    pub saturation: f32,
+    // Edge enhancement, negative values blur
+    pub sharpness: f32,
+    // Chroma left in the luma signal, the dot patterns around colored edges
+    pub artifacts: f32,
+    // Luma taken for chroma, the colored fringes along sharp brightness changes
+    pub fringing: f32
+}
+
+impl NtscSettings {
+
+    pub const COMPOSITE: NtscSettings = NtscSettings { hue: 0.0, saturation: 0.0, sharpness: 0.0, artifacts: 0.0, fringing: 0.0 };
+    pub const SVIDEO: NtscSettings = NtscSettings { hue: 0.0, saturation: 0.0, sharpness: 0.2, artifacts: -1.0, fringing: -1.0 };
+    pub const MONOCHROME: NtscSettings = NtscSettings { hue: 0.0, saturation: -1.0, sharpness: 0.2, artifacts: -0.2, fringing: -0.2 };
+
+}
+
+impl Default for NtscSettings {
+
+    fn default() -> Self {
+        Self::COMPOSITE
+    }
+
+}
+
+// CPU side NTSC filter: encodes PPU pixels (palette index with emphasis bits) into the composite
+// signal the console outputs and decodes it again the way a TV does
+pub struct NtscFilter {
+    settings: NtscSettings,
+    // Normalized signal level for every pixel value and subcarrier phase
+    signal: Vec<[f32; CYCLE]>,
+    // Demodulation carriers for I and Q, hue included
+    carrier_i: [f32; CYCLE],
+    carrier_q: [f32; CYCLE]
+}
+
+impl NtscFilter {
+
+    pub fn new(settings: NtscSettings) -> Self {
+        let signal = (0..512u16).map(|pixel| {
+            let mut levels = [0.0; CYCLE];
+            for (phase, level) in levels.iter_mut().enumerate() {
+                *level = encode(pixel, phase);
+            }
+            levels
+        }).collect();
+
+        let mut filter = Self { settings, signal, carrier_i: [0.0; CYCLE], carrier_q: [0.0; CYCLE] };
+        filter.set_settings(settings);
+        filter
+    }
+
+    pub fn settings(&self) -> NtscSettings {
+        self.settings
+    }
+
+    pub fn set_settings(&mut self, settings: NtscSettings) {
+        self.settings = settings;
+        let hue = settings.hue * PI;
+        for phase in 0..CYCLE {
+            let angle = PI * (phase as f32 + BURST_OFFSET) / 6.0 + hue;
+            self.carrier_i[phase] = angle.cos();
+            self.carrier_q[phase] = angle.sin();
+        }
+    }
+
+    // Filters a 256x240 frame into NTSC_WIDTH x 240 packed 24 bit RGB. `burst_phase` is the
+    // phase the PPU reports for the frame, the artifacts move with it. An incomplete last line
+    // is left out
+    pub fn filter(&self, framebuffer: &[u16], burst_phase: u8) -> Vec<u8> {
+        let mut rgb = Vec::with_capacity(NTSC_WIDTH * SCREEN_HEIGHT * 3);
+        let mut samples = vec![0.0; LINE_SAMPLES];
+        let mut luma = vec![0.0; NTSC_WIDTH];
+        let mut chroma = vec![(0.0, 0.0); NTSC_WIDTH];
+
+        for (y, line) in framebuffer.chunks_exact(SCREEN_WIDTH).take(SCREEN_HEIGHT).enumerate() {
+            // A scanline is 341 dots, the phase moves by 4 ticks from line to line
+            let line_phase = burst_phase as usize + y * (341 * SAMPLES_PER_DOT as usize);
+            for (i, sample) in samples.iter_mut().enumerate() {
+                let pixel = line[i / SAMPLES_PER_DOT as usize] & 0x1ff;
+                *sample = self.signal[pixel as usize][(line_phase + i) % CYCLE];
+            }
+            self.decode_line(&samples, line_phase, &mut luma, &mut chroma);
+
+            let saturation = self.settings.saturation + 1.0;
+            for (y, (i, q)) in luma.iter().zip(chroma.iter()) {
+                let (i, q) = (i * saturation, q * saturation);
+                rgb.push(to_byte(y + 0.946882 * i + 0.623557 * q));
+                rgb.push(to_byte(y - 0.274788 * i - 0.635691 * q));
+                rgb.push(to_byte(y - 1.108545 * i + 1.709007 * q));
+            }
+        }
+        rgb
+    }
+
+    // YIQ for each output pixel of a line
+    fn decode_line(&self, samples: &[f32], line_phase: usize, luma: &mut [f32], chroma: &mut [(f32, f32)]) {
+        let artifacts = (self.settings.artifacts + 1.0) / 2.0;
+        let fringing = (self.settings.fringing + 1.0) / 2.0;
+
+        for (x, (y, iq)) in luma.iter_mut().zip(chroma.iter_mut()).enumerate() {
+            let center = ((x as f32 + 0.5) * LINE_SAMPLES as f32 / NTSC_WIDTH as f32) as isize;
+
+            // One subcarrier cycle cancels the chroma, one dot keeps part of it
+            let clean = self.average(samples, center, CYCLE as isize);
+            let dot = self.average(samples, center, SAMPLES_PER_DOT as isize);
+            *y = clean + artifacts * (dot - clean);
+
+            // A narrow chroma band picks up luma edges, a wide one smears them out
+            let narrow = self.demodulate(samples, line_phase, center, CYCLE as isize);
+            let wide = self.demodulate(samples, line_phase, center, 2 * CYCLE as isize);
+            *iq = (wide.0 + fringing * (narrow.0 - wide.0), wide.1 + fringing * (narrow.1 - wide.1));
+        }
+
+        let sharpness = self.settings.sharpness / 2.0;
+        if sharpness != 0.0 {
+            let original = luma.to_vec();
+            for x in 1..luma.len() - 1 {
+                let neighbors = (original[x - 1] + original[x + 1]) / 2.0;
+                luma[x] += sharpness * (original[x] - neighbors);
+            }
+        }
+    }
+
+    fn average(&self, samples: &[f32], center: isize, width: isize) -> f32 {
+        let sum: f32 = (center - width / 2..center + width / 2).map(|i| sample(samples, i)).sum();
+        sum / width as f32
+    }
+
+    fn demodulate(&self, samples: &[f32], line_phase: usize, center: isize, width: isize) -> (f32, f32) {
+        let (mut i, mut q) = (0.0, 0.0);
+        for n in center - width / 2..center + width / 2 {
+            let phase = (line_phase as isize + n).rem_euclid(CYCLE as isize) as usize;
+            let level = sample(samples, n);
+            i += level * self.carrier_i[phase];
+            q += level * self.carrier_q[phase];
+        }
+        (i / width as f32, q / width as f32)
+    }
+
+}
+
+impl Default for NtscFilter {
+
+    fn default() -> Self {
+        Self::new(NtscSettings::default())
+    }
+
+}
+
+// Outside the picture the signal sits at black
+fn sample(samples: &[f32], index: isize) -> f32 {
+    if index < 0 { 0.0 } else { samples.get(index as usize).copied().unwrap_or(0.0) }
+}
+
+// Signal level of a pixel at one subcarrier phase, 0.0 is black and 1.0 white
+fn encode(pixel: u16, phase: usize) -> f32 {
+    let color = (pixel & 0x0f) as usize;
+    let emphasis = pixel >> 6;
+    // Colors $xE and $xF are forced to level 1
+    let level = if color > 13 { 1 } else { (pixel >> 4 & 0x03) as usize };
+
+    // Square wave between two voltages, in phase with the subcarrier for half a cycle
+    let in_phase = |color: usize| (color + phase) % CYCLE < CYCLE / 2;
+    let mut low = LEVELS_LOW[level];
+    let mut high = LEVELS_HIGH[level];
+    if color == 0 {
+        low = high;
+    }
+    if color > 12 {
+        high = low;
+    }
+    let mut signal = if in_phase(color) { high } else { low };
+
+    if (emphasis & 0x01 != 0 && in_phase(0)) || (emphasis & 0x02 != 0 && in_phase(4)) || (emphasis & 0x04 != 0 && in_phase(8)) {
+        signal *= EMPHASIS_ATTENUATION;
+    }
+    (signal - BLACK) / (WHITE - BLACK)
+}
+
+fn to_byte(value: f32) -> u8 {
+    (value * 255.0).round().clamp(0.0, 255.0) as u8
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::ppu::frame::Frame;
+
+    fn frame(pixel: u16) -> Vec<u16> {
+        vec![pixel; SCREEN_WIDTH * SCREEN_HEIGHT]
+    }
+
+    // Color near the middle of the first line
+    fn center(rgb: &[u8]) -> [u8; 3] {
+        let i = NTSC_WIDTH / 2 * 3;
+        [rgb[i], rgb[i + 1], rgb[i + 2]]
+    }
+
+    #[test]
+    pub fn decodes_flat_colors() {
+        let filter = NtscFilter::default();
+        let rgb = filter.filter(&frame(0x30), 0);
+        assert_eq!(rgb.len(), NTSC_WIDTH * SCREEN_HEIGHT * 3);
+        assert_eq!(center(&rgb), [255, 255, 255]);
+        assert_eq!(center(&filter.filter(&frame(0x0f), 0)), [0, 0, 0]);
+
+        let [r, g, b] = center(&filter.filter(&frame(0x16), 0));
+        assert!(r > g && r > b);
+        let [r, g, b] = center(&filter.filter(&frame(0x1a), 0));
+        assert!(g > r && g > b);
+        let [r, g, b] = center(&filter.filter(&frame(0x12), 0));
+        assert!(b > r && b > g);
+
+        let monochrome = NtscFilter::new(NtscSettings::MONOCHROME);
+        let [r, g, b] = center(&monochrome.filter(&frame(0x16), 0));
+        assert!(r == g && g == b);
+    }
+
+    #[test]
+    pub fn artifacts_follow_burst_phase() {
+        // Alternating columns produce artifact colors that depend on the phase
+        let framebuffer: Vec<u16> = (0..SCREEN_WIDTH * SCREEN_HEIGHT).map(|i| if i & 0x01 == 0 { 0x30 } else { 0x0f }).collect();
+        let filter = NtscFilter::default();
+        assert_ne!(filter.filter(&framebuffer, 0), filter.filter(&framebuffer, 4));
+    }
+
+    #[test]
+    pub fn short_framebuffers() {
+        let filter = NtscFilter::default();
+        assert_eq!(filter.filter(&[0x0f; SCREEN_WIDTH * 2 + 100], 0).len(), NTSC_WIDTH * 2 * 3);
+        assert!(filter.filter(&[0x0f; 100], 0).is_empty());
+        assert_eq!(Frame::from_ntsc(&[0x0f; SCREEN_WIDTH * 3], &filter, 0).height, 3);
+    }
+
+}
@@ -0,0 +1,251 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+// 64 colors, 3 bytes each
+const COLORS: usize = 64;
+// All eight emphasis combinations
+const ENTRIES: usize = 8 * COLORS;
+
+// Composite 2C02 output as seen on a typical NTSC TV
+const RP2C02: [u8; COLORS * 3] = [
+    84, 84, 84, 0, 30, 116, 8, 16, 144, 48, 0, 136, 68, 0, 100, 92, 0, 48, 84, 4, 0, 60, 24, 0,
+    32, 42, 0, 8, 58, 0, 0, 64, 0, 0, 60, 0, 0, 50, 60, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    152, 150, 152, 8, 76, 196, 48, 50, 236, 92, 30, 228, 136, 20, 176, 160, 20, 100, 152, 34, 32, 120, 60, 0,
+    84, 90, 0, 40, 114, 0, 8, 124, 0, 0, 118, 40, 0, 102, 120, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    236, 238, 236, 76, 154, 236, 120, 124, 236, 176, 98, 236, 228, 84, 236, 236, 88, 180, 236, 106, 100, 212, 136, 32,
+    160, 170, 0, 116, 196, 0, 76, 208, 32, 56, 204, 108, 56, 180, 204, 60, 60, 60, 0, 0, 0, 0, 0, 0,
+    236, 238, 236, 168, 204, 236, 188, 188, 236, 212, 178, 236, 236, 174, 236, 236, 174, 212, 236, 180, 176, 228, 196, 144,
+    204, 210, 120, 180, 222, 120, 168, 226, 144, 152, 226, 180, 160, 214, 228, 160, 162, 160, 0, 0, 0, 0, 0, 0
+];
+
+// 2C03 / 2C05 DAC levels, one octal digit (0 - 7) per channel in RGB order
+const RP2C03: [u16; COLORS] = [
+    0o333, 0o014, 0o006, 0o326, 0o403, 0o503, 0o510, 0o420, 0o320, 0o120, 0o031, 0o040, 0o022, 0o000, 0o000, 0o000,
+    0o555, 0o036, 0o027, 0o407, 0o507, 0o704, 0o700, 0o630, 0o430, 0o140, 0o040, 0o053, 0o044, 0o000, 0o000, 0o000,
+    0o777, 0o357, 0o447, 0o637, 0o707, 0o737, 0o740, 0o750, 0o660, 0o360, 0o070, 0o276, 0o077, 0o000, 0o000, 0o000,
+    0o777, 0o567, 0o657, 0o757, 0o747, 0o755, 0o764, 0o772, 0o773, 0o572, 0o473, 0o276, 0o467, 0o000, 0o000, 0o000
+];
+
+// 2C04-0001 to 2C04-0004 in the same DAC levels. Each revision puts the same 64 colors in its
+// own order, so Vs. System games only look right on the revision they were made for
+const RP2C04: [[u16; COLORS]; 4] = [
+    [
+        0o755, 0o637, 0o700, 0o447, 0o044, 0o120, 0o222, 0o704, 0o777, 0o333, 0o750, 0o503, 0o403, 0o660, 0o320, 0o777,
+        0o357, 0o653, 0o310, 0o360, 0o467, 0o657, 0o764, 0o027, 0o760, 0o276, 0o000, 0o200, 0o666, 0o444, 0o707, 0o014,
+        0o003, 0o567, 0o757, 0o070, 0o077, 0o022, 0o053, 0o507, 0o000, 0o420, 0o747, 0o510, 0o407, 0o006, 0o740, 0o000,
+        0o000, 0o140, 0o555, 0o031, 0o572, 0o326, 0o770, 0o630, 0o020, 0o036, 0o040, 0o111, 0o773, 0o737, 0o430, 0o473
+    ],
+    [
+        0o000, 0o750, 0o430, 0o572, 0o473, 0o737, 0o044, 0o567, 0o700, 0o407, 0o773, 0o747, 0o777, 0o637, 0o467, 0o040,
+        0o020, 0o357, 0o510, 0o666, 0o053, 0o360, 0o200, 0o447, 0o222, 0o707, 0o003, 0o276, 0o657, 0o320, 0o000, 0o326,
+        0o403, 0o764, 0o740, 0o757, 0o036, 0o310, 0o555, 0o006, 0o507, 0o760, 0o333, 0o120, 0o027, 0o000, 0o660, 0o777,
+        0o653, 0o111, 0o070, 0o630, 0o022, 0o014, 0o704, 0o140, 0o000, 0o077, 0o420, 0o770, 0o755, 0o503, 0o031, 0o444
+    ],
+    [
+        0o507, 0o737, 0o473, 0o555, 0o040, 0o777, 0o567, 0o120, 0o014, 0o000, 0o764, 0o320, 0o704, 0o666, 0o653, 0o467,
+        0o447, 0o044, 0o503, 0o027, 0o140, 0o430, 0o630, 0o053, 0o333, 0o326, 0o000, 0o006, 0o700, 0o510, 0o747, 0o755,
+        0o637, 0o020, 0o003, 0o770, 0o111, 0o750, 0o740, 0o777, 0o360, 0o403, 0o357, 0o707, 0o036, 0o444, 0o000, 0o310,
+        0o077, 0o200, 0o572, 0o757, 0o420, 0o070, 0o660, 0o222, 0o031, 0o000, 0o657, 0o773, 0o407, 0o276, 0o760, 0o022
+    ],
+    [
+        0o430, 0o326, 0o044, 0o660, 0o000, 0o755, 0o014, 0o630, 0o555, 0o310, 0o070, 0o003, 0o764, 0o770, 0o040, 0o572,
+        0o737, 0o200, 0o027, 0o747, 0o000, 0o222, 0o510, 0o740, 0o653, 0o053, 0o447, 0o140, 0o403, 0o000, 0o473, 0o357,
+        0o503, 0o031, 0o420, 0o006, 0o407, 0o507, 0o333, 0o704, 0o022, 0o666, 0o036, 0o020, 0o111, 0o773, 0o444, 0o707,
+        0o757, 0o777, 0o320, 0o700, 0o760, 0o276, 0o777, 0o467, 0o000, 0o750, 0o637, 0o567, 0o360, 0o657, 0o077, 0o120
+    ]
+];
+
+// Level of the channels that are not emphasized on composite PPUs
+const EMPHASIS_ATTENUATION: f32 = 0.816;
+
+// PPU revision, decides the built-in colors and how PPUMASK emphasis acts on them
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PpuModel {
+    // NTSC composite PPU
+    Rp2c02,
+    // PAL composite PPU, the red and green emphasis bits are swapped
+    Rp2c07,
+    // RGB PPUs of the PlayChoice-10 and Vs. System, emphasis drives a channel to full brightness
+    Rp2c03,
+    // Vs. System PPUs with a scrambled color order, revision 1 - 4 for 2C04-0001 to 2C04-0004
+    Rp2c04(u8),
+    Rp2c05
+}
+
+impl PpuModel {
+
+    fn is_rgb(self) -> bool {
+        !matches!(self, PpuModel::Rp2c02 | PpuModel::Rp2c07)
+    }
+
+}
+
+#[derive(Debug)]
+pub enum PaletteError {
+    // File could not be read
+    Io(std::io::Error),
+    // Neither 64 nor 512 colors
+    InvalidSize(usize)
+}
+
+impl fmt::Display for PaletteError {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PaletteError::Io(err) => write!(f, "could not read palette file: {}", err),
+            PaletteError::InvalidSize(size) =>
+                write!(f, "palette file has {} bytes, expected {} or {}", size, COLORS * 3, ENTRIES * 3)
+        }
+    }
+
+}
+
+impl std::error::Error for PaletteError {}
+
+impl From<std::io::Error> for PaletteError {
+
+    fn from(err: std::io::Error) -> Self {
+        PaletteError::Io(err)
+    }
+
+}
+
+// Lookup from framebuffer pixels (palette index with emphasis bits 6 - 8) to RGB
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Palette {
+    colors: Vec<[u8; 3]>
+}
+
+impl Palette {
+
+    // Built-in colors of the PPU model
+    pub fn new(model: PpuModel) -> Self {
+        let level = |digit: u16| (digit as u32 * 255 / 7) as u8;
+        let dac = |levels: &[u16]| levels.iter().map(|c| [level(c >> 6 & 0x07), level(c >> 3 & 0x07), level(c & 0x07)]).collect();
+        let colors: Vec<[u8; 3]> = match model {
+            PpuModel::Rp2c02 | PpuModel::Rp2c07 => RP2C02.chunks(3).map(|c| [c[0], c[1], c[2]]).collect(),
+            PpuModel::Rp2c04(revision) => dac(&RP2C04[revision.clamp(1, 4) as usize - 1]),
+            _ => dac(&RP2C03)
+        };
+        Self::with_emphasis(&colors, model)
+    }
+
+    // Contents of a .pal file: 64 colors, emphasis is then derived as the PPU model would apply
+    // it, or 512 colors that include all eight emphasis combinations
+    pub fn from_bytes(data: &[u8], model: PpuModel) -> Result<Self, PaletteError> {
+        let colors: Vec<[u8; 3]> = data.chunks_exact(3).map(|c| [c[0], c[1], c[2]]).collect();
+        match data.len() {
+            len if len == COLORS * 3 => Ok(Self::with_emphasis(&colors, model)),
+            len if len == ENTRIES * 3 => Ok(Self { colors }),
+            len => Err(PaletteError::InvalidSize(len))
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P, model: PpuModel) -> Result<Self, PaletteError> {
+        Self::from_bytes(&fs::read(path)?, model)
+    }
+
+    // Extends 64 colors to all emphasis combinations
+    fn with_emphasis(base: &[[u8; 3]], model: PpuModel) -> Self {
+        let mut colors = Vec::with_capacity(ENTRIES);
+        for emphasis in 0..8 {
+            // Bits in red, green, blue order
+            let channels = match model {
+                PpuModel::Rp2c07 => [emphasis & 0x02 != 0, emphasis & 0x01 != 0, emphasis & 0x04 != 0],
+                _ => [emphasis & 0x01 != 0, emphasis & 0x02 != 0, emphasis & 0x04 != 0]
+            };
+            for color in base {
+                let mut rgb = *color;
+                for (value, emphasized) in rgb.iter_mut().zip(channels.iter()) {
+                    if model.is_rgb() {
+                        if *emphasized {
+                            *value = 0xff;
+                        }
+                    } else if emphasis != 0 && !emphasized {
+                        *value = (*value as f32 * EMPHASIS_ATTENUATION) as u8;
+                    }
+                }
+                colors.push(rgb);
+            }
+        }
+        Self { colors }
+    }
+
+    // Color of a framebuffer pixel
+    pub fn rgb(&self, pixel: u16) -> [u8; 3] {
+        self.colors[pixel as usize % ENTRIES]
+    }
+
+    // Converts a whole framebuffer to packed 24 bit RGB
+    pub fn to_rgb(&self, framebuffer: &[u16]) -> Vec<u8> {
+        framebuffer.iter().flat_map(|pixel| self.rgb(*pixel)).collect()
+    }
+
+}
+
+impl Default for Palette {
+
+    fn default() -> Self {
+        Self::new(PpuModel::Rp2c02)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    pub fn emphasis() {
+        let palette = Palette::default();
+        assert_eq!(palette.rgb(0x30), [236, 238, 236]);
+        // Red emphasis darkens green and blue
+        assert_eq!(palette.rgb(0x30 | 0x01 << 6), [236, 194, 192]);
+        // PAL swaps red and green
+        assert_eq!(Palette::new(PpuModel::Rp2c07).rgb(0x30 | 0x01 << 6), [192, 238, 192]);
+
+        // RGB PPUs drive the channel to full brightness instead
+        let palette = Palette::new(PpuModel::Rp2c03);
+        assert_eq!(palette.rgb(0x0f), [0, 0, 0]);
+        assert_eq!(palette.rgb(0x0f | 0x04 << 6), [0, 0, 255]);
+    }
+
+    #[test]
+    pub fn rp2c04_revisions() {
+        // Color $00 of each revision, and the 2C03 color it stands for
+        let expected = [([255, 182, 182], 0x35), ([0, 0, 0], 0x0f), ([182, 0, 255], 0x14), ([145, 109, 0], 0x18)];
+        let rp2c03 = Palette::new(PpuModel::Rp2c03);
+        let mut sorted = Vec::new();
+        for (revision, (rgb, rp2c03_index)) in (1..=4).zip(expected.iter()) {
+            let palette = Palette::new(PpuModel::Rp2c04(revision));
+            assert_eq!(palette.rgb(0x00), *rgb);
+            assert_eq!(palette.rgb(0x00), rp2c03.rgb(*rp2c03_index));
+            // Emphasis works as on the 2C03
+            assert_eq!(palette.rgb(0x04 << 6)[2], 255);
+            let mut colors: Vec<[u8; 3]> = (0..64).map(|pixel| palette.rgb(pixel)).collect();
+            colors.sort_unstable();
+            sorted.push(colors);
+        }
+        // The same colors in another order
+        assert!(sorted.iter().all(|colors| *colors == sorted[0]));
+        assert_ne!(Palette::new(PpuModel::Rp2c04(1)), Palette::new(PpuModel::Rp2c04(2)));
+    }
+
+    #[test]
+    pub fn loads_pal_files() {
+        let data: Vec<u8> = (0..192).map(|i| i as u8).collect();
+        let palette = Palette::from_bytes(&data, PpuModel::Rp2c02).unwrap();
+        assert_eq!(palette.rgb(0x01), [3, 4, 5]);
+        assert_eq!(palette.to_rgb(&[0x00, 0x3f]), vec![0, 1, 2, 189, 190, 191]);
+
+        // 512 colors are taken as they are
+        let data: Vec<u8> = (0..1536).map(|i| (i / 192) as u8).collect();
+        let palette = Palette::from_bytes(&data, PpuModel::Rp2c02).unwrap();
+        assert_eq!(palette.rgb(0x05 | 0x07 << 6), [7, 7, 7]);
+
+        assert!(matches!(Palette::from_bytes(&[0; 100], PpuModel::Rp2c02), Err(PaletteError::InvalidSize(100))));
+    }
+
+}
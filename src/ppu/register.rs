@@ -0,0 +1,124 @@
+// PPUCTRL ($2000)
+pub enum CtrlFlags {
+    // Base nametable, bits 0 and 1
+    NametableX = 1 << 0,
+    NametableY = 1 << 1,
+    // VRAM address increment, 0 = 1 (across), 1 = 32 (down)
+    Increment = 1 << 2,
+    // Sprite pattern table for 8x8 sprites, 1 = $1000
+    SpritePattern = 1 << 3,
+    // Background pattern table, 1 = $1000
+    BackgroundPattern = 1 << 4,
+    // Sprite size, 1 = 8x16
+    SpriteSize = 1 << 5,
+    // EXT pins, unused on the NES
+    MasterSlave = 1 << 6,
+    // Generate NMI at the start of vblank
+    Nmi = 1 << 7
+}
+
+// PPUMASK ($2001)
+pub enum MaskFlags {
+    Greyscale = 1 << 0,
+    // Show background / sprites in the leftmost 8 pixels
+    BackgroundLeft = 1 << 1,
+    SpritesLeft = 1 << 2,
+    ShowBackground = 1 << 3,
+    ShowSprites = 1 << 4,
+    EmphasizeRed = 1 << 5,
+    EmphasizeGreen = 1 << 6,
+    EmphasizeBlue = 1 << 7
+}
+
+// PPUSTATUS ($2002), the low 5 bits are open bus
+pub enum StatusFlags {
+    SpriteOverflow = 1 << 5,
+    SpriteZeroHit = 1 << 6,
+    VerticalBlank = 1 << 7
+}
+
+// Internal v and t registers as described by loopy:
+// yyy NN YYYYY XXXXX, fine Y, nametable, coarse Y and coarse X
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Loopy {
+    pub value: u16
+}
+
+impl Loopy {
+
+    pub fn coarse_x(&self) -> u16 {
+        self.value & 0x001f
+    }
+
+    pub fn set_coarse_x(&mut self, coarse_x: u8) {
+        self.value = (self.value & !0x001f) | (coarse_x as u16 & 0x1f);
+    }
+
+    pub fn coarse_y(&self) -> u16 {
+        (self.value >> 5) & 0x1f
+    }
+
+    pub fn set_coarse_y(&mut self, coarse_y: u8) {
+        self.value = (self.value & !0x03e0) | (coarse_y as u16 & 0x1f) << 5;
+    }
+
+    pub fn nametable(&self) -> u16 {
+        (self.value >> 10) & 0x03
+    }
+
+    pub fn set_nametable(&mut self, nametable: u8) {
+        self.value = (self.value & !0x0c00) | (nametable as u16 & 0x03) << 10;
+    }
+
+    pub fn fine_y(&self) -> u16 {
+        (self.value >> 12) & 0x07
+    }
+
+    pub fn set_fine_y(&mut self, fine_y: u8) {
+        self.value = (self.value & !0x7000) | (fine_y as u16 & 0x07) << 12;
+    }
+
+    // Address put on the PPU bus for $2007 accesses
+    pub fn addr(&self) -> u16 {
+        self.value & 0x3fff
+    }
+
+    // Next tile, wrapping into the horizontally adjacent nametable
+    pub fn increment_x(&mut self) {
+        if self.coarse_x() == 31 {
+            self.value &= !0x001f;
+            self.value ^= 0x0400;
+        } else {
+            self.value += 1;
+        }
+    }
+
+    // Next pixel row, wrapping into the vertically adjacent nametable after row 29;
+    // rows 30 and 31 (set by writes) wrap without switching nametables
+    pub fn increment_y(&mut self) {
+        if self.fine_y() < 7 {
+            self.value += 0x1000;
+            return;
+        }
+        self.value &= !0x7000;
+        match self.coarse_y() {
+            29 => {
+                self.set_coarse_y(0);
+                self.value ^= 0x0800;
+            },
+            31 => self.set_coarse_y(0),
+            y => self.set_coarse_y(y as u8 + 1)
+        }
+    }
+
+    // Horizontal position (coarse X and nametable X) from `t`
+    pub fn copy_x(&mut self, t: Loopy) {
+        self.value = (self.value & !0x041f) | (t.value & 0x041f);
+    }
+
+    // Vertical position (fine Y, coarse Y and nametable Y) from `t`
+    pub fn copy_y(&mut self, t: Loopy) {
+        self.value = (self.value & !0x7be0) | (t.value & 0x7be0);
+    }
+
+}
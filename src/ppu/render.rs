@@ -0,0 +1,386 @@
+use crate::ppu::register::{CtrlFlags, MaskFlags, StatusFlags};
+use crate::ppu::Ppu;
+
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 240;
+
+// Tile fetch latches and the 16 bit shifters feeding the background pixel
+#[derive(Default)]
+pub(super) struct Background {
+    nametable: u8,
+    attribute: u8,
+    pattern_low: u8,
+    pattern_high: u8,
+    shift_pattern_low: u16,
+    shift_pattern_high: u16,
+    shift_attribute_low: u16,
+    shift_attribute_high: u16
+}
+
+impl Background {
+
+    // Next tile goes into the low byte, the current one is in the high byte
+    fn reload(&mut self) {
+        self.shift_pattern_low = (self.shift_pattern_low & 0xff00) | self.pattern_low as u16;
+        self.shift_pattern_high = (self.shift_pattern_high & 0xff00) | self.pattern_high as u16;
+        self.shift_attribute_low = (self.shift_attribute_low & 0xff00) | if self.attribute & 0x01 != 0 { 0xff } else { 0x00 };
+        self.shift_attribute_high = (self.shift_attribute_high & 0xff00) | if self.attribute & 0x02 != 0 { 0xff } else { 0x00 };
+    }
+
+    fn shift(&mut self) {
+        self.shift_pattern_low <<= 1;
+        self.shift_pattern_high <<= 1;
+        self.shift_attribute_low <<= 1;
+        self.shift_attribute_high <<= 1;
+    }
+
+    // Pixel value and palette, selected by fine X
+    fn pixel(&self, fine_x: u8) -> (u8, u8) {
+        let bit = 0x8000 >> fine_x;
+        let pixel = (self.shift_pattern_low & bit != 0) as u8 | ((self.shift_pattern_high & bit != 0) as u8) << 1;
+        let palette = (self.shift_attribute_low & bit != 0) as u8 | ((self.shift_attribute_high & bit != 0) as u8) << 1;
+        (pixel, palette)
+    }
+
+}
+
+// One of the eight sprite output units, pattern bits are already flipped
+#[derive(Default, Copy, Clone)]
+struct SpriteUnit {
+    x: u8,
+    attribute: u8,
+    pattern_low: u8,
+    pattern_high: u8
+}
+
+pub(super) struct Sprites {
+    // Sprites found for the next scanline, unused entries are $FF
+    secondary_oam: [u8; 32],
+    found: usize,
+    zero_found: bool,
+    units: [SpriteUnit; 8],
+    // Sprites loaded for the current scanline, sprite 0 is in unit 0 if `zero_loaded`
+    loaded: usize,
+    zero_loaded: bool
+}
+
+impl Default for Sprites {
+
+    fn default() -> Self {
+        Self {
+            secondary_oam: [0xff; 32],
+            found: 0,
+            zero_found: false,
+            units: [SpriteUnit::default(); 8],
+            loaded: 0,
+            zero_loaded: false
+        }
+    }
+
+}
+
+impl Ppu {
+
+    // Fetches, scroll updates, sprite evaluation and pixel output for the current dot
+    pub(super) fn render_dot(&mut self) {
+        let visible = self.scanline < SCREEN_HEIGHT as u16;
+        let pre_render = self.scanline == self.pre_render_scanline();
+        if !visible && !pre_render {
+            return;
+        }
+        let dot = self.dot;
+
+        if !self.rendering_enabled() {
+            if visible && (1..=256).contains(&dot) {
+                let color = self.backdrop();
+                self.put_pixel(dot as usize - 1, color);
+            }
+            return;
+        }
+
+        // Background tile fetches, two dots each, and the scroll increments
+        if (2..=257).contains(&dot) || (321..=337).contains(&dot) {
+            self.background.shift();
+            match (dot - 1) % 8 {
+                0 => {
+                    self.background.reload();
+                    self.background.nametable = self.vram_read(0x2000 | (self.v.value & 0x0fff));
+                },
+                2 => {
+                    let v = self.v;
+                    let addr = 0x23c0 | v.nametable() << 10 | (v.coarse_y() >> 2) << 3 | v.coarse_x() >> 2;
+                    let shift = (v.coarse_y() & 0x02) << 1 | (v.coarse_x() & 0x02);
+                    self.background.attribute = (self.vram_read(addr) >> shift) & 0x03;
+                },
+                4 => self.background.pattern_low = self.vram_read(self.background_pattern_addr()),
+                6 => self.background.pattern_high = self.vram_read(self.background_pattern_addr() + 8),
+                7 => self.v.increment_x(),
+                _ => {}
+            }
+        }
+        if dot == 256 {
+            self.v.increment_y();
+        }
+        if dot == 257 {
+            self.v.copy_x(self.t);
+        }
+        if pre_render && (280..=304).contains(&dot) {
+            self.v.copy_y(self.t);
+        }
+        // Unused nametable fetches at the end of the line, seen by some mappers
+        if dot == 338 || dot == 340 {
+            self.background.nametable = self.vram_read(0x2000 | (self.v.value & 0x0fff));
+        }
+
+        // Secondary OAM is filled during dots 65 - 256, done in one step here
+        if dot == 256 {
+            if visible {
+                self.evaluate_sprites();
+            } else {
+                self.sprites.secondary_oam = [0xff; 32];
+                self.sprites.found = 0;
+                self.sprites.zero_found = false;
+            }
+        }
+        if (257..=320).contains(&dot) {
+            self.oam_addr = 0;
+            let slot = (dot - 257) as usize / 8;
+            if (dot - 257) % 8 == 7 {
+                self.fetch_sprite(slot);
+            }
+            if dot == 320 {
+                self.sprites.loaded = self.sprites.found;
+                self.sprites.zero_loaded = self.sprites.zero_found;
+            }
+        }
+
+        if visible && (1..=256).contains(&dot) {
+            let color = self.compose_pixel(dot as usize - 1);
+            self.put_pixel(dot as usize - 1, color);
+        }
+    }
+
+    fn background_pattern_addr(&self) -> u16 {
+        let table = if self.ctrl & CtrlFlags::BackgroundPattern as u8 != 0 { 0x1000 } else { 0x0000 };
+        table | (self.background.nametable as u16) << 4 | self.v.fine_y()
+    }
+
+    pub(crate) fn sprite_height(&self) -> u16 {
+        if self.ctrl & CtrlFlags::SpriteSize as u8 != 0 { 16 } else { 8 }
+    }
+
+    // Copies the first 8 sprites on the next line to secondary OAM
+    fn evaluate_sprites(&mut self) {
+        let height = self.sprite_height();
+        let in_range = |y: u8| self.scanline.wrapping_sub(y as u16) < height;
+
+        let mut secondary = [0xff; 32];
+        let mut found = 0;
+        let mut zero_found = false;
+        let mut n = 0;
+        while n < 64 && found < 8 {
+            if in_range(self.oam[n * 4]) {
+                secondary[found * 4..found * 4 + 4].copy_from_slice(&self.oam[n * 4..n * 4 + 4]);
+                zero_found |= n == 0;
+                found += 1;
+            }
+            n += 1;
+        }
+
+        // Looking for a ninth sprite the PPU also increments the byte index,
+        // so tile numbers, attributes and X positions get compared as Y
+        let mut overflow = false;
+        let mut m = 0;
+        while n < 64 {
+            if in_range(self.oam[n * 4 + m]) {
+                overflow = true;
+                break;
+            }
+            n += 1;
+            m = (m + 1) & 0x03;
+        }
+
+        self.sprites.secondary_oam = secondary;
+        self.sprites.found = found;
+        self.sprites.zero_found = zero_found;
+        if overflow {
+            self.status |= StatusFlags::SpriteOverflow as u8;
+        }
+    }
+
+    // Pattern fetch for a secondary OAM slot, empty slots fetch tile $FF
+    fn fetch_sprite(&mut self, slot: usize) {
+        let entry = &self.sprites.secondary_oam[slot * 4..slot * 4 + 4];
+        let (y, tile, attribute, x) = (entry[0], entry[1], entry[2], entry[3]);
+        let height = self.sprite_height();
+
+        let mut row = self.scanline.wrapping_sub(y as u16) & (height - 1);
+        if attribute & 0x80 != 0 {
+            row = height - 1 - row;
+        }
+        let addr = if height == 16 {
+            let table = (tile as u16 & 0x01) << 12;
+            let tile = (tile as u16 & 0xfe) + (row >> 3);
+            table | tile << 4 | (row & 0x07)
+        } else {
+            let table = if self.ctrl & CtrlFlags::SpritePattern as u8 != 0 { 0x1000 } else { 0x0000 };
+            table | (tile as u16) << 4 | row
+        };
+
+        let mut pattern_low = self.vram_read(addr);
+        let mut pattern_high = self.vram_read(addr + 8);
+        if slot >= self.sprites.found {
+            pattern_low = 0;
+            pattern_high = 0;
+        } else if attribute & 0x40 != 0 {
+            pattern_low = pattern_low.reverse_bits();
+            pattern_high = pattern_high.reverse_bits();
+        }
+        self.sprites.units[slot] = SpriteUnit { x, attribute, pattern_low, pattern_high };
+    }
+
+    // Pixel value, palette, behind background flag and sprite 0 flag of the front most opaque sprite
+    fn sprite_pixel(&self, x: usize) -> Option<(u8, u8, bool, bool)> {
+        for (i, unit) in self.sprites.units[..self.sprites.loaded].iter().enumerate() {
+            let column = x.wrapping_sub(unit.x as usize);
+            if column >= 8 {
+                continue;
+            }
+            let bit = 0x80 >> column;
+            let pixel = (unit.pattern_low & bit != 0) as u8 | ((unit.pattern_high & bit != 0) as u8) << 1;
+            if pixel != 0 {
+                return Some((pixel, (unit.attribute & 0x03) + 4, unit.attribute & 0x20 != 0, i == 0 && self.sprites.zero_loaded));
+            }
+        }
+        None
+    }
+
+    // Background / sprite priority multiplexer, returns the palette index with emphasis bits
+    fn compose_pixel(&mut self, x: usize) -> u16 {
+        let mask = self.mask;
+        let left = x >= 8;
+
+        let (bg_pixel, bg_palette) = if mask & MaskFlags::ShowBackground as u8 != 0 && (left || mask & MaskFlags::BackgroundLeft as u8 != 0) {
+            self.background.pixel(self.x)
+        } else {
+            (0, 0)
+        };
+        let sprite = if mask & MaskFlags::ShowSprites as u8 != 0 && (left || mask & MaskFlags::SpritesLeft as u8 != 0) {
+            self.sprite_pixel(x)
+        } else {
+            None
+        };
+
+        let (pixel, palette) = match sprite {
+            None => (bg_pixel, bg_palette),
+            Some((pixel, palette, _, _)) if bg_pixel == 0 => (pixel, palette),
+            Some((pixel, palette, behind, zero)) => {
+                if zero && x != 255 {
+                    self.status |= StatusFlags::SpriteZeroHit as u8;
+                }
+                if behind { (bg_pixel, bg_palette) } else { (pixel, palette) }
+            }
+        };
+
+        let addr = if pixel == 0 { 0x3f00 } else { 0x3f00 | (palette as u16) << 2 | pixel as u16 };
+        self.vram_read(addr) as u16 | self.emphasis()
+    }
+
+    // With rendering off the PPU shows the backdrop, or the palette entry v points at
+    fn backdrop(&mut self) -> u16 {
+        let addr = self.v.addr();
+        let addr = if addr >= 0x3f00 { addr } else { 0x3f00 };
+        self.vram_read(addr) as u16 | self.emphasis()
+    }
+
+    // PPUMASK emphasis bits, stored above the 6 bit palette index
+    fn emphasis(&self) -> u16 {
+        ((self.mask & 0xe0) as u16) << 1
+    }
+
+    fn put_pixel(&mut self, x: usize, color: u16) {
+        self.framebuffer[self.scanline as usize * SCREEN_WIDTH + x] = color;
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::cartridge::{Cartridge, Mirroring, RomImage};
+
+    // NROM with CHR RAM, tile 1 is solid color 1
+    fn ppu() -> Ppu {
+        let image = RomImage { mirroring: Mirroring::Vertical, ..RomImage::nrom(vec![0x00; 16 * 1024], true) };
+        let mut ppu = Ppu::new();
+        ppu.connect_cartridge(Rc::new(RefCell::new(Cartridge::new(image).unwrap())));
+        for row in 0..8 {
+            ppu.vram_write(0x0010 + row, 0xff);
+        }
+        ppu.vram_write(0x3f00, 0x0f);
+        ppu.vram_write(0x3f01, 0x16);
+        ppu.vram_write(0x3f11, 0x2a);
+        ppu.oam = [0xff; 256];
+        ppu
+    }
+
+    fn run_frame(ppu: &mut Ppu) {
+        ppu.frame_complete = false;
+        while !ppu.frame_complete {
+            ppu.clock();
+        }
+    }
+
+    #[test]
+    pub fn renders_background_tiles() {
+        let mut ppu = ppu();
+        ppu.vram_write(0x2000, 0x01);
+        ppu.cpu_write(0x2001, MaskFlags::ShowBackground as u8 | MaskFlags::BackgroundLeft as u8);
+        run_frame(&mut ppu);
+        run_frame(&mut ppu);
+        assert!(ppu.framebuffer[..8].iter().all(|pixel| *pixel == 0x16));
+        assert_eq!(ppu.framebuffer[8], 0x0f);
+        assert_eq!(ppu.framebuffer[8 * SCREEN_WIDTH], 0x0f);
+
+        // Fine X scroll moves the tile 3 pixels left
+        ppu.cpu_write(0x2005, 3);
+        ppu.cpu_write(0x2005, 0);
+        run_frame(&mut ppu);
+        assert_eq!(ppu.framebuffer[4], 0x16);
+        assert_eq!(ppu.framebuffer[5], 0x0f);
+    }
+
+    #[test]
+    pub fn sprite_zero_hit_and_priority() {
+        let mut ppu = ppu();
+        ppu.vram_write(0x2000, 0x01);
+        ppu.oam[0..4].copy_from_slice(&[0, 1, 0x00, 4]);
+        ppu.cpu_write(0x2001, 0x1e);
+        run_frame(&mut ppu);
+        run_frame(&mut ppu);
+        assert_ne!(ppu.status & StatusFlags::SpriteZeroHit as u8, 0);
+        // Sprite shows on the line after its Y position, in front of the background
+        assert_eq!(ppu.framebuffer[SCREEN_WIDTH + 4], 0x2a);
+        assert_eq!(ppu.framebuffer[SCREEN_WIDTH + 3], 0x16);
+        assert_eq!(ppu.status & StatusFlags::SpriteOverflow as u8, 0);
+    }
+
+    #[test]
+    pub fn sprite_overflow() {
+        let mut ppu = ppu();
+        for sprite in 0..9 {
+            ppu.oam[sprite * 4..sprite * 4 + 4].copy_from_slice(&[100, 1, 0x00, sprite as u8 * 8]);
+        }
+        ppu.cpu_write(0x2001, 0x18);
+        run_frame(&mut ppu);
+        assert_ne!(ppu.status & StatusFlags::SpriteOverflow as u8, 0);
+        // Ninth sprite is dropped
+        assert_eq!(ppu.framebuffer[101 * SCREEN_WIDTH + 64], 0x0f);
+        assert_eq!(ppu.framebuffer[101 * SCREEN_WIDTH + 56], 0x2a);
+    }
+
+}
@@ -0,0 +1,81 @@
+use crate::cartridge::Region;
+
+// Master clock frequencies in Hz
+const NTSC_MASTER_CLOCK: f64 = 236_250_000.0 / 11.0;
+const PAL_MASTER_CLOCK: f64 = 26_601_712.5;
+// CPU clock of NTSC consoles, the rate audio hardware without a console is usually clocked at
+pub const NTSC_CPU_FREQUENCY: f64 = NTSC_MASTER_CLOCK / 12.0;
+
+// Clock ratios and frame layout of a TV system
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Timing {
+    // Ntsc, Pal or Dendy, games marked Multi run on NTSC
+    pub region: Region,
+    pub master_clock: f64,
+    // Master clock cycles per CPU cycle and per PPU dot
+    pub cpu_divider: u64,
+    pub ppu_divider: u64,
+    pub scanlines: u16,
+    // Scanline where the vblank flag is raised and NMI fires
+    pub vblank_scanline: u16,
+    // NTSC skips a dot on odd frames while rendering
+    pub skip_odd_dot: bool
+}
+
+impl Timing {
+
+    pub fn new(region: Region) -> Self {
+        match region {
+            Region::Ntsc | Region::Multi => Self {
+                region: Region::Ntsc,
+                master_clock: NTSC_MASTER_CLOCK,
+                cpu_divider: 12,
+                ppu_divider: 4,
+                scanlines: 262,
+                vblank_scanline: 241,
+                skip_odd_dot: true
+            },
+            Region::Pal => Self {
+                region: Region::Pal,
+                master_clock: PAL_MASTER_CLOCK,
+                cpu_divider: 16,
+                ppu_divider: 5,
+                scanlines: 312,
+                vblank_scanline: 241,
+                skip_odd_dot: false
+            },
+            // Famiclone timing: PAL frame with NTSC-like 3:1 clock ratio and a late vblank,
+            // so NTSC games see the vblank length they expect
+            Region::Dendy => Self {
+                region: Region::Dendy,
+                master_clock: PAL_MASTER_CLOCK,
+                cpu_divider: 15,
+                ppu_divider: 5,
+                scanlines: 312,
+                vblank_scanline: 291,
+                skip_odd_dot: false
+            }
+        }
+    }
+
+    pub fn pre_render_scanline(&self) -> u16 {
+        self.scanlines - 1
+    }
+
+    pub fn cpu_frequency(&self) -> f64 {
+        self.master_clock / self.cpu_divider as f64
+    }
+
+    pub fn frame_rate(&self) -> f64 {
+        self.master_clock / self.ppu_divider as f64 / (341.0 * self.scanlines as f64)
+    }
+
+}
+
+impl Default for Timing {
+
+    fn default() -> Self {
+        Self::new(Region::Ntsc)
+    }
+
+}